@@ -0,0 +1,178 @@
+//! Benchmark of the throughput of the flow table in the count of shards.
+//!
+//! Frames of TCP connections are dispatched from one thread to the shards, each of which owns
+//! the receive caches of its connections in a worker thread, verifies the checksums of segments
+//! and reassembles their payload. Run with `cargo bench --bench shard`.
+
+use std::collections::HashMap;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use pcap2socks::capture::{FrameQueue, OverflowPolicy};
+use pcap2socks::pcap::LinkType;
+use pcap2socks::shard::{Dispatcher, Target};
+use pcap2socks::tcp::cache::Window;
+
+const FLOWS: usize = 512;
+const SEGMENTS: usize = 128;
+const PAYLOAD: usize = 1460;
+const HEADER: usize = 14 + 20 + 20;
+const QUEUE_SIZE: usize = 4096;
+const WORKERS: [usize; 4] = [1, 2, 4, 8];
+
+fn checksum(data: &[u8], initial: u32) -> u16 {
+    let mut sum = data.chunks(2).fold(initial, |sum, chunk| {
+        sum + match chunk.len() {
+            2 => u16::from_be_bytes([chunk[0], chunk[1]]) as u32,
+            _ => (chunk[0] as u32) << 8,
+        }
+    });
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+fn pseudo_header_sum(packet: &[u8]) -> u32 {
+    let length = (packet.len() - 20) as u32;
+
+    packet[12..20]
+        .chunks(2)
+        .map(|chunk| u16::from_be_bytes([chunk[0], chunk[1]]) as u32)
+        .sum::<u32>()
+        + 6
+        + length
+}
+
+/// Returns a frame of the segment of the connection from the sequence.
+fn frame(flow: usize, sequence: u32) -> Vec<u8> {
+    let mut frame = vec![0u8; HEADER + PAYLOAD];
+
+    // Ethernet
+    frame[12..14].copy_from_slice(&[0x08, 0x00]);
+
+    // IPv4
+    let packet = &mut frame[14..];
+    packet[0] = 0x45;
+    packet[2..4].copy_from_slice(&((20 + 20 + PAYLOAD) as u16).to_be_bytes());
+    packet[8] = 64;
+    packet[9] = 6;
+    packet[12..16].copy_from_slice(&[10, 6, 0, 2]);
+    packet[16..20].copy_from_slice(&[1, 1, (flow >> 8) as u8, flow as u8]);
+
+    // TCP
+    let segment = &mut packet[20..];
+    segment[0..2].copy_from_slice(&(40000 + flow as u16).to_be_bytes());
+    segment[2..4].copy_from_slice(&443u16.to_be_bytes());
+    segment[4..8].copy_from_slice(&sequence.to_be_bytes());
+    segment[12] = 5 << 4;
+    segment[13] = 0x10;
+    for (i, b) in segment[20..].iter_mut().enumerate() {
+        *b = (sequence as usize + i) as u8;
+    }
+    let sum = checksum(&packet[20..], pseudo_header_sum(packet));
+    packet[36..38].copy_from_slice(&sum.to_be_bytes());
+
+    frame
+}
+
+/// Returns the frames of all the connections interleaved, with every other pair of segments of
+/// a connection swapped, so the payload is reassembled.
+fn frames() -> Vec<Vec<u8>> {
+    let mut frames = Vec::with_capacity(FLOWS * SEGMENTS);
+    for segment in 0..SEGMENTS {
+        let segment = match segment % 4 {
+            2 => segment + 1,
+            3 => segment - 1,
+            _ => segment,
+        };
+        for flow in 0..FLOWS {
+            frames.push(frame(flow, (segment * PAYLOAD) as u32));
+        }
+    }
+
+    frames
+}
+
+/// Handles the frames of a shard, and returns the size of payload reassembled.
+fn work(queue: FrameQueue) -> usize {
+    let mut windows = HashMap::new();
+    let mut size = 0;
+    while let Ok(frame) = queue.pop(None) {
+        let packet = &frame[14..];
+        assert_eq!(checksum(&packet[20..], pseudo_header_sum(packet)), 0);
+
+        let segment = &packet[20..];
+        let key = (
+            u16::from_be_bytes([segment[0], segment[1]]),
+            u16::from_be_bytes([segment[2], segment[3]]),
+        );
+        let sequence = u32::from_be_bytes([segment[4], segment[5], segment[6], segment[7]]);
+        let window = windows
+            .entry(key)
+            .or_insert_with(|| Window::with_capacity(4 * PAYLOAD, 0));
+        if let Some(payload) = window.append(sequence, &segment[20..]).unwrap() {
+            size += payload.len();
+        }
+    }
+
+    size
+}
+
+fn run(workers: usize, frames: &[Vec<u8>]) -> Duration {
+    let queues = (0..workers)
+        .map(|_| FrameQueue::new(QUEUE_SIZE, OverflowPolicy::Block).unwrap())
+        .collect::<Vec<_>>();
+    let handles = queues
+        .iter()
+        .cloned()
+        .map(|queue| thread::spawn(move || work(queue)))
+        .collect::<Vec<_>>();
+    let mut dispatcher = Dispatcher::new(workers, LinkType::Ethernet).unwrap();
+
+    let instant = Instant::now();
+    for frame in frames {
+        match dispatcher.target(frame) {
+            Target::One(shard) => {
+                queues[shard].push(frame.clone());
+            }
+            Target::All => unreachable!(),
+        }
+    }
+    for queue in &queues {
+        queue.close(None);
+    }
+    let size: usize = handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .sum();
+    let elapsed = instant.elapsed();
+    assert_eq!(size, FLOWS * SEGMENTS * PAYLOAD);
+
+    elapsed
+}
+
+fn main() {
+    let frames = frames();
+
+    println!(
+        "{} frames of {} TCP connections in {} Bytes",
+        frames.len(),
+        FLOWS,
+        HEADER + PAYLOAD
+    );
+    let mut base = None;
+    for &workers in WORKERS.iter() {
+        let elapsed = run(workers, &frames);
+        let rate = frames.len() as f64 / elapsed.as_secs_f64();
+        let base = *base.get_or_insert(rate);
+        println!(
+            "{} shards: {:>10.0} frames/s, {:>8.1} Mbps, {:.2}x",
+            workers,
+            rate,
+            rate * ((HEADER + PAYLOAD) * 8) as f64 / 1_000_000.0,
+            rate / base
+        );
+    }
+}
@@ -0,0 +1,42 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use std::cmp::min;
+
+use pcap2socks::packet::{Defraggler, Indicator};
+
+fuzz_target!(|data: &[u8]| {
+    let indicator = match Indicator::from(data) {
+        Some(indicator) => indicator,
+        None => return,
+    };
+    let _ = indicator.brief();
+    let _ = indicator.len();
+    let frame_without_padding = &data[..min(indicator.content_len(), data.len())];
+
+    // TCP options
+    if let Some(tcp) = indicator.tcp() {
+        let _ = tcp.mss();
+        let _ = tcp.wscale();
+        let _ = tcp.sack();
+        let _ = tcp.ts();
+        let _ = tcp.ts_ecr();
+        let _ = tcp.is_sack_perm();
+        let _ = tcp.md5_sig();
+    }
+
+    // ICMPv4 payload
+    if let Some(icmpv4) = indicator.icmpv4() {
+        let _ = icmpv4.src();
+        let _ = icmpv4.dst();
+    }
+
+    // Fragmentation
+    if let Some(ipv4) = indicator.ipv4() {
+        if ipv4.is_fragment() {
+            let mut defrag = Defraggler::new();
+            if let Some(frag) = defrag.add(&indicator, frame_without_padding) {
+                let _ = frag.concatenate();
+            }
+        }
+    }
+});
@@ -0,0 +1,28 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use std::cmp::min;
+use std::net::SocketAddrV4;
+
+use pcap2socks::tcp::TcpRxState;
+
+fuzz_target!(|data: &[u8]| {
+    let src: SocketAddrV4 = "10.0.0.2:10000".parse().unwrap();
+    let dst: SocketAddrV4 = "1.1.1.1:80".parse().unwrap();
+    // Start near the wrap point of the sequence
+    let mut state = TcpRxState::new(src, dst, u32::MAX - 1024, 0, true);
+
+    // Each segment is in the format of sequence (4 Bytes), size (2 Bytes) and payload
+    let mut data = data;
+    while data.len() >= 6 {
+        let sequence = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+        let size = min(
+            u16::from_be_bytes([data[4], data[5]]) as usize,
+            data.len() - 6,
+        );
+
+        let _ = state.append_cache(sequence, &data[6..6 + size]);
+        let _ = state.cache().filled();
+
+        data = &data[6 + size..];
+    }
+});
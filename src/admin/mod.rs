@@ -0,0 +1,256 @@
+//! Support for keeping the recent log events in memory, and serving them from an admin HTTP
+//! endpoint.
+
+use log::Level;
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::flowlog::timestamp;
+
+/// Represents the count of shards of the recent log events, so concurrent writers seldom contend
+/// for the same lock.
+const RECENT_SHARDS: usize = 8;
+
+/// Represents the maximum size of the header of a request to the admin endpoint.
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// Represents a log event.
+#[derive(Clone, Debug)]
+pub struct Event {
+    sequence: u64,
+    time: SystemTime,
+    level: Level,
+    target: String,
+    message: String,
+}
+
+impl Event {
+    /// Returns the level of the event.
+    pub fn level(&self) -> Level {
+        self.level
+    }
+
+    /// Returns the message of the event.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    fn to_json(&self) -> String {
+        format!(
+            "{{\"seq\":{},\"time\":{},\"level\":\"{}\",\"target\":\"{}\",\"message\":\"{}\"}}",
+            self.sequence,
+            timestamp(self.time),
+            self.level,
+            escape(&self.target),
+            escape(&self.message)
+        )
+    }
+}
+
+/// Represents the recent log events in a bounded ring buffer. Events are written into shards in
+/// turn, and a write skips instead of waiting if its shard is being accessed, so logging never
+/// blocks the capture loop. The oldest events are dropped when the buffer is full.
+#[derive(Debug)]
+pub struct RecentEvents {
+    shards: Vec<Mutex<VecDeque<Event>>>,
+    sequence: AtomicU64,
+    shard_capacity: usize,
+}
+
+impl RecentEvents {
+    /// Creates a new `RecentEvents` holding at most about the capacity of events.
+    pub fn new(capacity: usize) -> RecentEvents {
+        let shard_capacity = (capacity + RECENT_SHARDS - 1) / RECENT_SHARDS;
+        RecentEvents {
+            shards: (0..RECENT_SHARDS)
+                .map(|_| Mutex::new(VecDeque::with_capacity(shard_capacity)))
+                .collect(),
+            sequence: AtomicU64::new(0),
+            shard_capacity,
+        }
+    }
+
+    /// Appends an event.
+    pub fn push(&self, level: Level, target: &str, message: String) {
+        if self.shard_capacity == 0 {
+            return;
+        }
+
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+        let shard = &self.shards[(sequence % RECENT_SHARDS as u64) as usize];
+        // Skip instead of blocking if the shard is being accessed
+        if let Ok(mut events) = shard.try_lock() {
+            if events.len() >= self.shard_capacity {
+                events.pop_front();
+            }
+            events.push_back(Event {
+                sequence,
+                time: SystemTime::now(),
+                level,
+                target: target.to_string(),
+                message,
+            });
+        }
+    }
+
+    /// Returns the events from the oldest to the newest.
+    pub fn events(&self) -> Vec<Event> {
+        let mut events = Vec::new();
+        for shard in &self.shards {
+            events.extend(shard.lock().unwrap().iter().cloned());
+        }
+        events.sort_by_key(|event| event.sequence);
+
+        events
+    }
+
+    /// Returns the events as a JSON array.
+    pub fn to_json(&self) -> String {
+        let events = self
+            .events()
+            .iter()
+            .map(|event| event.to_json())
+            .collect::<Vec<_>>();
+
+        format!("[{}]", events.join(","))
+    }
+}
+
+/// Escapes the string in a JSON string.
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Listens on the address for the admin HTTP endpoint in the background, which serves the
+/// recent log events as JSON at `/recent`, and returns the local address listened on.
+pub async fn listen(addr: SocketAddr, recent: Arc<RecentEvents>) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr).await?;
+    let local_addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(_) => continue,
+            };
+            let recent = Arc::clone(&recent);
+            tokio::spawn(async move {
+                let _ = handle(stream, &recent).await;
+            });
+        }
+    });
+
+    Ok(local_addr)
+}
+
+async fn handle(mut stream: TcpStream, recent: &RecentEvents) -> io::Result<()> {
+    // Read the header of the request
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 1024];
+    while !buffer.windows(4).any(|w| w == b"\r\n\r\n") {
+        if buffer.len() >= MAX_REQUEST_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request too large",
+            ));
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        buffer.extend_from_slice(&chunk[..n]);
+    }
+
+    let request = String::from_utf8_lossy(&buffer);
+    let mut fields = request.lines().next().unwrap_or_default().split(' ');
+    let (status, body) = match (fields.next(), fields.next()) {
+        (Some("GET"), Some("/recent")) => ("200 OK", recent.to_json()),
+        (Some("GET"), Some(_)) => ("404 Not Found", "{\"error\":\"not found\"}".to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            "{\"error\":\"method not allowed\"}".to_string(),
+        ),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[test]
+fn recent_events_bounded() {
+    let recent = RecentEvents::new(16);
+    for i in 0..100 {
+        recent.push(Level::Trace, "test", format!("event {}", i));
+    }
+
+    let events = recent.events();
+    assert_eq!(events.len(), 16);
+    assert_eq!(events[0].message(), "event 84");
+    assert_eq!(events[15].message(), "event 99");
+    assert_eq!(events[15].level(), Level::Trace);
+}
+
+#[test]
+fn recent_events_to_json() {
+    let recent = RecentEvents::new(16);
+    assert_eq!(recent.to_json(), "[]");
+
+    recent.push(Level::Debug, "pcap2socks", "say \"hi\"\n".to_string());
+    let json = recent.to_json();
+    assert!(json.starts_with("[{\"seq\":0,\"time\":"));
+    assert!(json.ends_with(
+        ",\"level\":\"DEBUG\",\"target\":\"pcap2socks\",\"message\":\"say \\\"hi\\\"\\n\"}]"
+    ));
+}
+
+#[tokio::test]
+async fn admin_listen() {
+    let recent = Arc::new(RecentEvents::new(16));
+    recent.push(Level::Info, "pcap2socks", "hello".to_string());
+    let addr = listen("127.0.0.1:0".parse().unwrap(), Arc::clone(&recent))
+        .await
+        .unwrap();
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(b"GET /recent HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("Content-Type: application/json\r\n"));
+    assert!(response.ends_with(&recent.to_json()));
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(b"GET /metrics HTTP/1.1\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+}
@@ -0,0 +1,266 @@
+//! Support for handing off captured frames between threads.
+
+use std::collections::VecDeque;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Represents the policy on a frame arriving when the queue is full.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum OverflowPolicy {
+    /// Represents the oldest frame in the queue is dropped to make room for the new frame.
+    DropOldest,
+    /// Represents the new frame is dropped.
+    DropNewest,
+    /// Represents the capture is blocked until there is room in the queue, so frames are dropped
+    /// by the pcap device instead once its buffer is full.
+    Block,
+}
+
+impl Display for OverflowPolicy {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            OverflowPolicy::DropOldest => write!(f, "drop-oldest"),
+            OverflowPolicy::DropNewest => write!(f, "drop-newest"),
+            OverflowPolicy::Block => write!(f, "block"),
+        }
+    }
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop-oldest" => Ok(OverflowPolicy::DropOldest),
+            "drop-newest" => Ok(OverflowPolicy::DropNewest),
+            "block" => Ok(OverflowPolicy::Block),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown overflow policy {}", s),
+            )),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    frames: VecDeque<Vec<u8>>,
+    is_closed: bool,
+    error: Option<io::Error>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    state: Mutex<State>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    capacity: usize,
+    policy: OverflowPolicy,
+    dropped: AtomicUsize,
+}
+
+/// Represents a bounded multi-producer multi-consumer queue of frames between the capture thread
+/// and the processing threads. Clones of a `FrameQueue` share the same queue.
+#[derive(Clone, Debug)]
+pub struct FrameQueue {
+    inner: Arc<Inner>,
+}
+
+impl FrameQueue {
+    /// Creates a new `FrameQueue` holding at most the count of frames, with the policy on
+    /// overflow.
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> io::Result<FrameQueue> {
+        if capacity == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "capture queue size cannot be 0",
+            ));
+        }
+
+        Ok(FrameQueue {
+            inner: Arc::new(Inner {
+                state: Mutex::new(State {
+                    frames: VecDeque::with_capacity(capacity),
+                    is_closed: false,
+                    error: None,
+                }),
+                not_empty: Condvar::new(),
+                not_full: Condvar::new(),
+                capacity,
+                policy,
+                dropped: AtomicUsize::new(0),
+            }),
+        })
+    }
+
+    /// Pushes a frame to the queue. Returns if the frame is queued, which is not if it is dropped
+    /// on overflow or the queue is closed.
+    pub fn push(&self, frame: Vec<u8>) -> bool {
+        let mut state = self.inner.state.lock().unwrap();
+        if state.frames.len() >= self.inner.capacity {
+            match self.inner.policy {
+                OverflowPolicy::DropOldest => {
+                    state.frames.pop_front();
+                    self.drop_frame();
+                }
+                OverflowPolicy::DropNewest => {
+                    self.drop_frame();
+
+                    return false;
+                }
+                OverflowPolicy::Block => {
+                    while state.frames.len() >= self.inner.capacity && !state.is_closed {
+                        state = self.inner.not_full.wait(state).unwrap();
+                    }
+                }
+            }
+        }
+        if state.is_closed {
+            return false;
+        }
+
+        state.frames.push_back(frame);
+        self.inner.not_empty.notify_one();
+
+        true
+    }
+
+    /// Pops a frame from the queue, waiting in the timeout if the queue is empty, or forever if
+    /// the timeout is `None`. Returns a `TimedOut` error if no frame arrives in the timeout, or
+    /// the error closing the queue once the queue is drained.
+    pub fn pop(&self, timeout: Option<Duration>) -> io::Result<Vec<u8>> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut state = self.inner.state.lock().unwrap();
+        loop {
+            if let Some(frame) = state.frames.pop_front() {
+                self.inner.not_full.notify_one();
+
+                return Ok(frame);
+            }
+            if state.is_closed {
+                return Err(match state.error {
+                    Some(ref e) => io::Error::new(e.kind(), e.to_string()),
+                    None => io::Error::new(io::ErrorKind::BrokenPipe, "capture queue closed"),
+                });
+            }
+
+            state = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, "timed out"));
+                    }
+                    self.inner
+                        .not_empty
+                        .wait_timeout(state, deadline - now)
+                        .unwrap()
+                        .0
+                }
+                None => self.inner.not_empty.wait(state).unwrap(),
+            };
+        }
+    }
+
+    /// Closes the queue. Frames pushed later are dropped, and consumers get the error, if any,
+    /// once the queue is drained. Producers blocked are woken up.
+    pub fn close(&self, error: Option<io::Error>) {
+        let mut state = self.inner.state.lock().unwrap();
+        if state.is_closed {
+            return;
+        }
+        state.is_closed = true;
+        state.error = error;
+
+        self.inner.not_empty.notify_all();
+        self.inner.not_full.notify_all();
+    }
+
+    /// Returns if the queue is closed.
+    pub fn is_closed(&self) -> bool {
+        self.inner.state.lock().unwrap().is_closed
+    }
+
+    fn drop_frame(&self) {
+        self.inner.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the count of frames in the queue.
+    pub fn len(&self) -> usize {
+        self.inner.state.lock().unwrap().frames.len()
+    }
+
+    /// Returns if the queue is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the maximum count of frames in the queue.
+    pub fn capacity(&self) -> usize {
+        self.inner.capacity
+    }
+
+    /// Returns the count of frames dropped on overflow.
+    pub fn dropped_count(&self) -> usize {
+        self.inner.dropped.load(Ordering::Relaxed)
+    }
+}
+
+#[test]
+fn frame_queue_overflow() {
+    use std::thread;
+
+    assert!(FrameQueue::new(0, OverflowPolicy::Block).is_err());
+
+    let timeout = Some(Duration::from_millis(0));
+
+    // Drop the oldest frame
+    let queue = FrameQueue::new(2, OverflowPolicy::DropOldest).unwrap();
+    assert!(queue.push(vec![1]));
+    assert!(queue.push(vec![2]));
+    assert!(queue.push(vec![3]));
+    assert_eq!(queue.len(), 2);
+    assert_eq!(queue.dropped_count(), 1);
+    assert_eq!(queue.pop(timeout).unwrap(), vec![2]);
+    assert_eq!(queue.pop(timeout).unwrap(), vec![3]);
+    assert_eq!(
+        queue.pop(timeout).unwrap_err().kind(),
+        io::ErrorKind::TimedOut
+    );
+
+    // Drop the newest frame
+    let queue = FrameQueue::new(2, OverflowPolicy::DropNewest).unwrap();
+    assert!(queue.push(vec![1]));
+    assert!(queue.push(vec![2]));
+    assert!(!queue.push(vec![3]));
+    assert_eq!(queue.dropped_count(), 1);
+    assert_eq!(queue.pop(timeout).unwrap(), vec![1]);
+    assert_eq!(queue.pop(timeout).unwrap(), vec![2]);
+
+    // Block the producer until a consumer makes room
+    let queue = FrameQueue::new(1, OverflowPolicy::Block).unwrap();
+    assert!(queue.push(vec![1]));
+    let producer = queue.clone();
+    let handle = thread::spawn(move || producer.push(vec![2]));
+    assert_eq!(queue.pop(None).unwrap(), vec![1]);
+    assert!(handle.join().unwrap());
+    assert_eq!(queue.pop(None).unwrap(), vec![2]);
+    assert_eq!(queue.dropped_count(), 0);
+
+    // Wake up the blocked producer on closing
+    assert!(queue.push(vec![3]));
+    let producer = queue.clone();
+    let handle = thread::spawn(move || producer.push(vec![4]));
+    queue.close(Some(io::Error::new(io::ErrorKind::Other, "device gone")));
+    assert!(!handle.join().unwrap());
+
+    // Drain the queue before the error
+    assert_eq!(queue.pop(None).unwrap(), vec![3]);
+    let e = queue.pop(None).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::Other);
+    assert_eq!(e.to_string(), "device gone");
+    assert!(!queue.push(vec![5]));
+}
@@ -0,0 +1,159 @@
+//! Support for computing digests of byte streams incrementally.
+
+use sha2::{Digest as _, Sha256};
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::str::FromStr;
+
+/// Represents the algorithm of a digest.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum DigestAlgorithm {
+    /// Represents SHA-256, which is suitable for proving the integrity of the byte stream.
+    Sha256,
+    /// Represents the 64-bit FNV-1a, which is much cheaper but not cryptographic, and only
+    /// suitable for detecting accidental differences.
+    Fnv1a64,
+}
+
+impl Display for DigestAlgorithm {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            DigestAlgorithm::Sha256 => write!(f, "sha256"),
+            DigestAlgorithm::Fnv1a64 => write!(f, "fnv1a64"),
+        }
+    }
+}
+
+impl FromStr for DigestAlgorithm {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sha256" => Ok(DigestAlgorithm::Sha256),
+            "fnv1a64" => Ok(DigestAlgorithm::Fnv1a64),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown digest algorithm {}", s),
+            )),
+        }
+    }
+}
+
+/// Represents the offset basis of the 64-bit FNV-1a.
+const FNV1A64_OFFSET: u64 = 0xcbf29ce484222325;
+/// Represents the prime of the 64-bit FNV-1a.
+const FNV1A64_PRIME: u64 = 0x100000001b3;
+
+#[derive(Clone, Debug)]
+enum DigestState {
+    Sha256(Box<Sha256>),
+    Fnv1a64(u64),
+}
+
+/// Represents a digest of a byte stream, which is updated incrementally as chunks of the stream
+/// arrive, so the stream is never held as a whole.
+#[derive(Clone, Debug)]
+pub struct Digest {
+    state: DigestState,
+}
+
+impl Digest {
+    /// Creates a new `Digest` of an empty stream.
+    pub fn new(algorithm: DigestAlgorithm) -> Digest {
+        let state = match algorithm {
+            DigestAlgorithm::Sha256 => DigestState::Sha256(Box::new(Sha256::new())),
+            DigestAlgorithm::Fnv1a64 => DigestState::Fnv1a64(FNV1A64_OFFSET),
+        };
+
+        Digest { state }
+    }
+
+    /// Updates the digest with the next chunk of the stream.
+    pub fn update(&mut self, data: &[u8]) {
+        match self.state {
+            DigestState::Sha256(ref mut sha256) => sha256.update(data),
+            DigestState::Fnv1a64(ref mut hash) => {
+                for b in data {
+                    *hash = (*hash ^ *b as u64).wrapping_mul(FNV1A64_PRIME);
+                }
+            }
+        }
+    }
+
+    /// Returns the algorithm of the digest.
+    pub fn algorithm(&self) -> DigestAlgorithm {
+        match self.state {
+            DigestState::Sha256(_) => DigestAlgorithm::Sha256,
+            DigestState::Fnv1a64(_) => DigestAlgorithm::Fnv1a64,
+        }
+    }
+
+    /// Returns the digest of the stream so far in hex. The digest can still be updated
+    /// afterward.
+    pub fn finish(&self) -> String {
+        let digest = match self.state {
+            DigestState::Sha256(ref sha256) => sha256.as_ref().clone().finalize().to_vec(),
+            DigestState::Fnv1a64(hash) => hash.to_be_bytes().to_vec(),
+        };
+
+        digest.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+impl Display for Digest {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm(), self.finish())
+    }
+}
+
+#[test]
+fn digest_sha256() {
+    let mut digest = Digest::new(DigestAlgorithm::Sha256);
+    assert_eq!(
+        digest.finish(),
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+    );
+
+    digest.update(b"abc");
+    assert_eq!(
+        digest.to_string(),
+        "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+    );
+
+    // Updated in chunks across blocks
+    let data = b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq";
+    let mut digest = Digest::new(DigestAlgorithm::Sha256);
+    for chunk in data.chunks(7) {
+        digest.update(chunk);
+    }
+    assert_eq!(
+        digest.finish(),
+        "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+    );
+
+    // Exactly a block, padded in another block
+    let mut digest = Digest::new(DigestAlgorithm::Sha256);
+    digest.update(&[b'a'; 64]);
+    assert_eq!(
+        digest.finish(),
+        "ffe054fe7ae0cb6dc65c3af9b61d5209f439851db43d0ba5997337df154668eb"
+    );
+
+    let mut digest = Digest::new(DigestAlgorithm::Sha256);
+    for _ in 0..1000 {
+        digest.update(&[b'a'; 1000]);
+    }
+    assert_eq!(
+        digest.finish(),
+        "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+    );
+}
+
+#[test]
+fn digest_fnv1a64() {
+    let mut digest = Digest::new(DigestAlgorithm::Fnv1a64);
+    assert_eq!(digest.finish(), "cbf29ce484222325");
+
+    digest.update(b"a");
+    assert_eq!(digest.to_string(), "fnv1a64:af63dc4c8601ec8c");
+}
@@ -0,0 +1,322 @@
+//! Support for decapsulating and encapsulating IPv4 packets tunneled in GRE or IP-in-IP.
+
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+/// Represents the IP protocol number of IP-in-IP.
+const PROTOCOL_IPIP: u8 = 4;
+/// Represents the IP protocol number of GRE.
+const PROTOCOL_GRE: u8 = 47;
+/// Represents the protocol type of IPv4 in GRE.
+const GRE_PROTOCOL_IPV4: u16 = 0x0800;
+/// Represents the flag of the checksum present in GRE.
+const GRE_FLAG_CHECKSUM: u16 = 0x8000;
+/// Represents the flag of the routing present in GRE, which is deprecated.
+const GRE_FLAG_ROUTING: u16 = 0x4000;
+/// Represents the flag of the key present in GRE.
+const GRE_FLAG_KEY: u16 = 0x2000;
+/// Represents the flag of the sequence number present in GRE.
+const GRE_FLAG_SEQUENCE: u16 = 0x1000;
+/// Represents the mask of the version in GRE.
+const GRE_VERSION_MASK: u16 = 0x0007;
+/// Represents the minimum length of an IPv4 header.
+const IPV4_MINIMUM_LEN: usize = 20;
+/// Represents the minimum length of a GRE header.
+const GRE_MINIMUM_LEN: usize = 4;
+/// Represents the TTL in the outer IPv4 header.
+const TTL: u8 = 128;
+
+/// Represents the type of encapsulation.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Encapsulation {
+    /// Represents the Generic Routing Encapsulation (RFC 2784).
+    Gre,
+    /// Represents the IP-in-IP encapsulation (RFC 2003).
+    Ipip,
+}
+
+impl Encapsulation {
+    fn protocol(&self) -> u8 {
+        match self {
+            Encapsulation::Gre => PROTOCOL_GRE,
+            Encapsulation::Ipip => PROTOCOL_IPIP,
+        }
+    }
+}
+
+impl Display for Encapsulation {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Encapsulation::Gre => write!(f, "gre"),
+            Encapsulation::Ipip => write!(f, "ipip"),
+        }
+    }
+}
+
+impl FromStr for Encapsulation {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gre" => Ok(Encapsulation::Gre),
+            "ipip" => Ok(Encapsulation::Ipip),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown encapsulation {}", s),
+            )),
+        }
+    }
+}
+
+/// Represents a tunnel learned from a decapsulated packet, which encapsulates packets sent back
+/// through it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Tunnel {
+    encapsulation: Encapsulation,
+    /// Represents the local endpoint, which is the destination of the outer header received.
+    local: Ipv4Addr,
+    /// Represents the remote endpoint, which is the source of the outer header received.
+    remote: Ipv4Addr,
+    /// Represents the GRE key.
+    key: Option<u32>,
+    /// Represents if the GRE checksum is present.
+    checksum: bool,
+}
+
+impl Tunnel {
+    /// Returns the size of the headers added in encapsulation.
+    pub fn overhead(&self) -> usize {
+        match self.encapsulation {
+            Encapsulation::Gre => {
+                IPV4_MINIMUM_LEN
+                    + GRE_MINIMUM_LEN
+                    + if self.checksum { 4 } else { 0 }
+                    + if self.key.is_some() { 4 } else { 0 }
+            }
+            Encapsulation::Ipip => IPV4_MINIMUM_LEN,
+        }
+    }
+
+    /// Encapsulates the inner IPv4 packet, and returns the outer IPv4 packet sent from the local
+    /// endpoint to the remote endpoint. The GRE key and checksum are present if they are present
+    /// in the packets received, and the GRE sequence number is never present.
+    pub fn encapsulate(&self, inner: &[u8]) -> Vec<u8> {
+        let total_length = self.overhead() + inner.len();
+        let mut packet = Vec::with_capacity(total_length);
+
+        // IPv4, which copies the DSCP, ECN and don't fragment flag of the inner header
+        let tos = inner.get(1).copied().unwrap_or(0);
+        let df = inner.get(6).copied().unwrap_or(0) & 0x40;
+        packet.extend_from_slice(&[0x45, tos]);
+        packet.extend_from_slice(&(total_length as u16).to_be_bytes());
+        packet.extend_from_slice(&[0, 0, df, 0, TTL, self.encapsulation.protocol(), 0, 0]);
+        packet.extend_from_slice(&self.local.octets());
+        packet.extend_from_slice(&self.remote.octets());
+        let header_checksum = checksum(&packet);
+        packet[10..12].copy_from_slice(&header_checksum.to_be_bytes());
+
+        // GRE
+        if self.encapsulation == Encapsulation::Gre {
+            let begin = packet.len();
+            let mut flags = 0;
+            if self.checksum {
+                flags |= GRE_FLAG_CHECKSUM;
+            }
+            if self.key.is_some() {
+                flags |= GRE_FLAG_KEY;
+            }
+            packet.extend_from_slice(&flags.to_be_bytes());
+            packet.extend_from_slice(&GRE_PROTOCOL_IPV4.to_be_bytes());
+            if self.checksum {
+                packet.extend_from_slice(&[0; 4]);
+            }
+            if let Some(key) = self.key {
+                packet.extend_from_slice(&key.to_be_bytes());
+            }
+            packet.extend_from_slice(inner);
+            if self.checksum {
+                let gre_checksum = checksum(&packet[begin..]);
+                packet[begin + 4..begin + 6].copy_from_slice(&gre_checksum.to_be_bytes());
+            }
+        } else {
+            packet.extend_from_slice(inner);
+        }
+
+        packet
+    }
+}
+
+/// Decapsulates the outer IPv4 packet in the encapsulation, and returns the tunnel and the inner
+/// IPv4 packet. Returns `None` if the packet is not in the encapsulation, is fragmented, or does
+/// not carry an IPv4 packet.
+pub fn decapsulate(encapsulation: Encapsulation, packet: &[u8]) -> Option<(Tunnel, &[u8])> {
+    if packet.len() < IPV4_MINIMUM_LEN || packet[0] >> 4 != 4 {
+        return None;
+    }
+    let header_len = ((packet[0] & 0x0f) as usize) * 4;
+    let total_length = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+    if header_len < IPV4_MINIMUM_LEN || total_length < header_len || packet.len() < total_length {
+        return None;
+    }
+    // Fragments of the outer packet are not reassembled
+    if packet[6] & 0x20 != 0 || u16::from_be_bytes([packet[6], packet[7]]) & 0x1fff != 0 {
+        return None;
+    }
+    if packet[9] != encapsulation.protocol() {
+        return None;
+    }
+    let remote = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+    let local = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+    let payload = &packet[header_len..total_length];
+
+    let (key, checksum, inner) = match encapsulation {
+        Encapsulation::Gre => {
+            if payload.len() < GRE_MINIMUM_LEN {
+                return None;
+            }
+            let flags = u16::from_be_bytes([payload[0], payload[1]]);
+            let protocol = u16::from_be_bytes([payload[2], payload[3]]);
+            if flags & (GRE_FLAG_ROUTING | GRE_VERSION_MASK) != 0 || protocol != GRE_PROTOCOL_IPV4 {
+                return None;
+            }
+            let checksum = flags & GRE_FLAG_CHECKSUM != 0;
+            let mut begin = GRE_MINIMUM_LEN;
+            if checksum {
+                begin += 4;
+            }
+            let key = match flags & GRE_FLAG_KEY != 0 {
+                true => {
+                    let key = payload.get(begin..begin + 4)?;
+                    begin += 4;
+
+                    Some(u32::from_be_bytes([key[0], key[1], key[2], key[3]]))
+                }
+                false => None,
+            };
+            if flags & GRE_FLAG_SEQUENCE != 0 {
+                begin += 4;
+            }
+
+            (key, checksum, payload.get(begin..)?)
+        }
+        Encapsulation::Ipip => (None, false, payload),
+    };
+    if inner.is_empty() || inner[0] >> 4 != 4 {
+        return None;
+    }
+
+    Some((
+        Tunnel {
+            encapsulation,
+            local,
+            remote,
+            key,
+            checksum,
+        },
+        inner,
+    ))
+}
+
+/// Returns the Internet checksum of the bytes.
+fn checksum(buffer: &[u8]) -> u16 {
+    let mut sum = 0u32;
+    for chunk in buffer.chunks(2) {
+        let word = match chunk.len() {
+            2 => u16::from_be_bytes([chunk[0], chunk[1]]),
+            _ => u16::from_be_bytes([chunk[0], 0]),
+        };
+        sum += word as u32;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+#[test]
+fn encap_gre_tcp_syn() {
+    // IPv4 and TCP SYN from 10.6.0.2:40000 to 1.1.1.1:443
+    let mut inner = vec![
+        0x45, 0x00, 0x00, 0x28, 0x00, 0x01, 0x40, 0x00, 0x40, 0x06, 0x00, 0x00, 10, 6, 0, 2, 1, 1,
+        1, 1,
+    ];
+    let checksum_inner = checksum(&inner);
+    inner[10..12].copy_from_slice(&checksum_inner.to_be_bytes());
+    inner.extend_from_slice(&[
+        0x9c, 0x40, 0x01, 0xbb, 0x00, 0x00, 0x03, 0xe8, 0x00, 0x00, 0x00, 0x00, 0x50, 0x02, 0xff,
+        0xff, 0x00, 0x00, 0x00, 0x00,
+    ]);
+
+    // GRE with the checksum and the key from the tunnel endpoint 192.0.2.1 to 192.0.2.2
+    let mut gre = vec![0xa0, 0x00, 0x08, 0x00, 0, 0, 0, 0, 0x00, 0x00, 0x00, 0x2a];
+    gre.extend_from_slice(&inner);
+    let checksum_gre = checksum(&gre);
+    gre[4..6].copy_from_slice(&checksum_gre.to_be_bytes());
+    let mut outer = vec![
+        0x45,
+        0x00,
+        0x00,
+        0x00,
+        0x00,
+        0x00,
+        0x00,
+        0x00,
+        0x40,
+        PROTOCOL_GRE,
+        0x00,
+        0x00,
+        192,
+        0,
+        2,
+        1,
+        192,
+        0,
+        2,
+        2,
+    ];
+    outer[2..4].copy_from_slice(&((IPV4_MINIMUM_LEN + gre.len()) as u16).to_be_bytes());
+    outer.extend_from_slice(&gre);
+
+    let (tunnel, packet) = decapsulate(Encapsulation::Gre, &outer).unwrap();
+    assert_eq!(packet, inner.as_slice());
+    assert_eq!(tunnel.local, Ipv4Addr::new(192, 0, 2, 2));
+    assert_eq!(tunnel.remote, Ipv4Addr::new(192, 0, 2, 1));
+    assert_eq!(tunnel.key, Some(42));
+    assert!(tunnel.checksum);
+    assert_eq!(tunnel.overhead(), 32);
+
+    // Re-encapsulate, the outer header is from the local endpoint to the remote endpoint
+    let reply = tunnel.encapsulate(&inner);
+    assert_eq!(reply.len(), tunnel.overhead() + inner.len());
+    assert_eq!(checksum(&reply[..IPV4_MINIMUM_LEN]), 0);
+    assert_eq!(checksum(&reply[IPV4_MINIMUM_LEN..]), 0);
+    assert_eq!(reply[6] & 0x40, 0x40);
+    let (reply_tunnel, packet) = decapsulate(Encapsulation::Gre, &reply).unwrap();
+    assert_eq!(packet, inner.as_slice());
+    assert_eq!(reply_tunnel.local, tunnel.remote);
+    assert_eq!(reply_tunnel.remote, tunnel.local);
+    assert_eq!(reply_tunnel.key, Some(42));
+
+    // Other encapsulations and fragments
+    assert!(decapsulate(Encapsulation::Ipip, &outer).is_none());
+    assert!(decapsulate(Encapsulation::Gre, &inner).is_none());
+    let mut fragment = outer.clone();
+    fragment[6] = 0x20;
+    assert!(decapsulate(Encapsulation::Gre, &fragment).is_none());
+
+    // IP-in-IP
+    let tunnel = Tunnel {
+        encapsulation: Encapsulation::Ipip,
+        local: Ipv4Addr::new(192, 0, 2, 2),
+        remote: Ipv4Addr::new(192, 0, 2, 1),
+        key: None,
+        checksum: false,
+    };
+    let outer = tunnel.encapsulate(&inner);
+    assert_eq!(outer.len(), IPV4_MINIMUM_LEN + inner.len());
+    let (_, packet) = decapsulate(Encapsulation::Ipip, &outer).unwrap();
+    assert_eq!(packet, inner.as_slice());
+}
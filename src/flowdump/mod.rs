@@ -0,0 +1,268 @@
+//! Support for dumping a snapshot of the table of open connections on demand.
+
+use log::{trace, warn};
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::io::{self, Write};
+use std::net::SocketAddrV4;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::flowlog;
+
+/// Represents the signal requesting a dump of the flow table.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum DumpSignal {
+    /// Represents the signal SIGUSR1.
+    Usr1,
+    /// Represents the signal SIGUSR2.
+    Usr2,
+}
+
+impl Display for DumpSignal {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            DumpSignal::Usr1 => write!(f, "usr1"),
+            DumpSignal::Usr2 => write!(f, "usr2"),
+        }
+    }
+}
+
+impl FromStr for DumpSignal {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "usr1" => Ok(DumpSignal::Usr1),
+            "usr2" => Ok(DumpSignal::Usr2),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown dump signal {}", s),
+            )),
+        }
+    }
+}
+
+/// Represents the state of a TCP connection in the flow table.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum FlowState {
+    /// Represents the connection to the proxy is deferred until payload arrives.
+    Deferred,
+    /// Represents the connection is open in both directions.
+    Established,
+    /// Represents the source closed its direction, but the proxy did not.
+    InClosed,
+    /// Represents the proxy closed its direction, but the source did not.
+    OutClosed,
+    /// Represents both directions are closed, and the connection is waiting for the last ACK.
+    Closing,
+}
+
+impl FlowState {
+    /// Returns the state of a connection to the proxy by whether it is closed for writing and
+    /// reading.
+    pub fn from_closed(is_tx_closed: bool, is_rx_closed: bool) -> FlowState {
+        match (is_tx_closed, is_rx_closed) {
+            (false, false) => FlowState::Established,
+            (true, false) => FlowState::InClosed,
+            (false, true) => FlowState::OutClosed,
+            (true, true) => FlowState::Closing,
+        }
+    }
+}
+
+impl Display for FlowState {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            FlowState::Deferred => write!(f, "deferred"),
+            FlowState::Established => write!(f, "established"),
+            FlowState::InClosed => write!(f, "in_closed"),
+            FlowState::OutClosed => write!(f, "out_closed"),
+            FlowState::Closing => write!(f, "closing"),
+        }
+    }
+}
+
+/// Represents the snapshot of a TCP connection in the flow table.
+#[derive(Clone, Debug)]
+pub struct FlowSnapshot {
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    state: FlowState,
+    /// Represents the size of payload sent from the source to the proxy.
+    bytes_in: usize,
+    /// Represents the size of payload sent from the proxy to the source, excluding
+    /// retransmissions.
+    bytes_out: usize,
+    recv_cache: usize,
+    send_cache: usize,
+    /// Represents the size of payload from the proxy queued but not sent yet.
+    send_queue: usize,
+    /// Represents the duration since payload last goes in either direction.
+    idle: Duration,
+}
+
+impl FlowSnapshot {
+    /// Creates a new `FlowSnapshot`.
+    pub fn new(
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        state: FlowState,
+        idle: Duration,
+    ) -> FlowSnapshot {
+        FlowSnapshot {
+            src,
+            dst,
+            state,
+            bytes_in: 0,
+            bytes_out: 0,
+            recv_cache: 0,
+            send_cache: 0,
+            send_queue: 0,
+            idle,
+        }
+    }
+
+    /// Sets the statistics of the receive side, which are the size of payload received and the
+    /// size of the receive cache.
+    pub fn set_recv_stats(&mut self, bytes_in: usize, cache: usize) {
+        self.bytes_in = bytes_in;
+        self.recv_cache = cache;
+    }
+
+    /// Sets the statistics of the send side, which are the size of payload sent, the size of the
+    /// send cache and the size of the send queue.
+    pub fn set_send_stats(&mut self, bytes_out: usize, cache: usize, queue: usize) {
+        self.bytes_out = bytes_out;
+        self.send_cache = cache;
+        self.send_queue = queue;
+    }
+
+    fn format(&self) -> String {
+        format!(
+            "{{\"src\":\"{}\",\"dst\":\"{}\",\"state\":\"{}\",\"bytes_in\":{},\"bytes_out\":{},\"recv_cache\":{},\"send_cache\":{},\"send_queue\":{},\"idle\":{}.{:03}}}",
+            self.src,
+            self.dst,
+            self.state,
+            self.bytes_in,
+            self.bytes_out,
+            self.recv_cache,
+            self.send_cache,
+            self.send_queue,
+            self.idle.as_secs(),
+            self.idle.subsec_millis()
+        )
+    }
+}
+
+/// Returns the flow table taken at the time in a JSON object.
+fn format(flows: &[FlowSnapshot], time: SystemTime) -> String {
+    format!(
+        "{{\"time\":{},\"flows\":[{}]}}",
+        flowlog::timestamp(time),
+        flows
+            .iter()
+            .map(|flow| flow.format())
+            .collect::<Vec<_>>()
+            .join(",")
+    )
+}
+
+/// Represents the dump of the flow table, which is written each time it is requested, like by a
+/// signal handler in another thread.
+#[derive(Debug)]
+pub struct FlowDump {
+    path: PathBuf,
+    requests: Arc<AtomicUsize>,
+    dumped: usize,
+}
+
+impl FlowDump {
+    /// Creates a new `FlowDump` writing to the file, or the standard error if the path is `-`,
+    /// each time the count of requests increases.
+    pub fn new(path: PathBuf, requests: Arc<AtomicUsize>) -> FlowDump {
+        let dumped = requests.load(Ordering::Relaxed);
+
+        FlowDump {
+            path,
+            requests,
+            dumped,
+        }
+    }
+
+    /// Returns if a dump is requested since the last call.
+    pub fn is_requested(&mut self) -> bool {
+        let requests = self.requests.load(Ordering::Relaxed);
+        if requests == self.dumped {
+            return false;
+        }
+        self.dumped = requests;
+
+        true
+    }
+
+    /// Writes the flow table in another thread, so the caller is not blocked by the I/O. The file
+    /// is written to a temporary file first and then renamed, so a reader will never see a
+    /// partial dump.
+    pub fn write(&self, flows: Vec<FlowSnapshot>) {
+        let path = self.path.clone();
+        thread::spawn(move || {
+            let s = format(&flows, SystemTime::now());
+            match write(&path, &s) {
+                Ok(_) => trace!("dump {} TCP flows to {}", flows.len(), path.display()),
+                Err(ref e) => warn!("dump flows to {}: {}", path.display(), e),
+            }
+        });
+    }
+}
+
+fn write(path: &Path, s: &str) -> io::Result<()> {
+    if path.to_str() == Some("-") {
+        return writeln!(io::stderr().lock(), "{}", s);
+    }
+
+    let mut temp = path.as_os_str().to_os_string();
+    temp.push(".tmp");
+    fs::write(&temp, format!("{}\n", s))?;
+
+    fs::rename(&temp, path)
+}
+
+#[test]
+fn flow_dump_format() {
+    use std::time::UNIX_EPOCH;
+
+    let mut snapshot = FlowSnapshot::new(
+        "10.6.0.2:40000".parse().unwrap(),
+        "1.1.1.1:443".parse().unwrap(),
+        FlowState::from_closed(true, false),
+        Duration::from_millis(1234),
+    );
+    snapshot.set_recv_stats(100, 20);
+    snapshot.set_send_stats(2000, 1460, 300);
+    let deferred = FlowSnapshot::new(
+        "10.6.0.2:40001".parse().unwrap(),
+        "1.1.1.1:80".parse().unwrap(),
+        FlowState::Deferred,
+        Duration::from_millis(5),
+    );
+    let time = UNIX_EPOCH + Duration::from_millis(1500);
+
+    assert_eq!(format(&[], time), "{\"time\":1.500,\"flows\":[]}");
+    assert_eq!(
+        format(&[snapshot, deferred], time),
+        "{\"time\":1.500,\"flows\":[{\"src\":\"10.6.0.2:40000\",\"dst\":\"1.1.1.1:443\",\"state\":\"in_closed\",\"bytes_in\":100,\"bytes_out\":2000,\"recv_cache\":20,\"send_cache\":1460,\"send_queue\":300,\"idle\":1.234},{\"src\":\"10.6.0.2:40001\",\"dst\":\"1.1.1.1:80\",\"state\":\"deferred\",\"bytes_in\":0,\"bytes_out\":0,\"recv_cache\":0,\"send_cache\":0,\"send_queue\":0,\"idle\":0.005}]}"
+    );
+
+    // Each request is served once
+    let requests = Arc::new(AtomicUsize::new(3));
+    let mut dump = FlowDump::new(PathBuf::from("-"), Arc::clone(&requests));
+    assert!(!dump.is_requested());
+    requests.fetch_add(2, Ordering::Relaxed);
+    assert!(dump.is_requested());
+    assert!(!dump.is_requested());
+}
@@ -0,0 +1,485 @@
+//! Support for logging a record of each connection when it closes, and accounting the bytes of
+//! each connection while it is open.
+
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::fs::OpenOptions;
+use std::io::{self, LineWriter, Write};
+use std::net::{SocketAddr, SocketAddrV4};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::digest::{Digest, DigestAlgorithm};
+
+/// Represents the reason of closing a connection.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum CloseReason {
+    /// Represents the connection is closed by FINs.
+    Fin,
+    /// Represents the connection is reset.
+    Rst,
+    /// Represents the connection is dropped or replaced by a new connection.
+    Reaped,
+    /// Represents the connection is abandoned after too many retransmissions or failures of
+    /// sending.
+    Abandoned,
+}
+
+impl Display for CloseReason {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            CloseReason::Fin => write!(f, "fin"),
+            CloseReason::Rst => write!(f, "rst"),
+            CloseReason::Reaped => write!(f, "reaped"),
+            CloseReason::Abandoned => write!(f, "abandoned"),
+        }
+    }
+}
+
+/// Represents the format of the flow log.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum FlowLogFormat {
+    /// Represents a JSON object in each line.
+    Json,
+    /// Represents space-separated key-value pairs in each line.
+    Text,
+}
+
+impl FromStr for FlowLogFormat {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(FlowLogFormat::Json),
+            "text" => Ok(FlowLogFormat::Text),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown flow log format {}", s),
+            )),
+        }
+    }
+}
+
+/// Represents the record of a TCP connection, which is written to the flow log when the
+/// connection closes.
+#[derive(Clone, Debug)]
+pub struct FlowRecord {
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    proxy: SocketAddrV4,
+    /// Represents the original source learned from the PROXY protocol header.
+    real_src: Option<SocketAddr>,
+    /// Represents the server name peeked from the TLS ClientHello.
+    server_name: Option<String>,
+    start: SystemTime,
+    /// Represents the size of payload sent from the source to the proxy.
+    bytes_in: usize,
+    /// Represents the size of payload sent from the proxy to the source, excluding
+    /// retransmissions.
+    bytes_out: usize,
+    peak_recv_cache: usize,
+    /// Represents the count of ranges merged in the receive cache.
+    recv_merges: usize,
+    /// Represents the peak count of discontinuous ranges in the receive cache.
+    peak_recv_ranges: usize,
+    peak_send_cache: usize,
+    retrans: usize,
+    /// Represents the count of retransmissions detected as spurious.
+    spurious_retrans: usize,
+    /// Represents the digest of payload sent from the source to the proxy.
+    digest_in: Option<Digest>,
+    /// Represents the digest of payload sent from the proxy to the source, excluding
+    /// retransmissions.
+    digest_out: Option<Digest>,
+}
+
+impl FlowRecord {
+    /// Creates a new `FlowRecord` starting now.
+    pub fn new(src: SocketAddrV4, dst: SocketAddrV4, proxy: SocketAddrV4) -> FlowRecord {
+        FlowRecord {
+            src,
+            dst,
+            proxy,
+            real_src: None,
+            server_name: None,
+            start: SystemTime::now(),
+            bytes_in: 0,
+            bytes_out: 0,
+            peak_recv_cache: 0,
+            recv_merges: 0,
+            peak_recv_ranges: 0,
+            peak_send_cache: 0,
+            retrans: 0,
+            spurious_retrans: 0,
+            digest_in: None,
+            digest_out: None,
+        }
+    }
+
+    /// Sets the algorithm of the digests of payload sent in both directions, and resets the
+    /// digest of payload sent from the source to the proxy.
+    pub fn set_digest(&mut self, algorithm: DigestAlgorithm) {
+        self.digest_in = Some(Digest::new(algorithm));
+    }
+
+    /// Updates the digest of payload sent from the source to the proxy with the payload.
+    pub fn update_digest_in(&mut self, payload: &[u8]) {
+        if let Some(ref mut digest) = self.digest_in {
+            digest.update(payload);
+        }
+    }
+
+    /// Sets the digest of payload sent from the proxy to the source.
+    pub fn set_digest_out(&mut self, digest: Option<Digest>) {
+        self.digest_out = digest;
+    }
+
+    /// Adds the size of payload sent from the source to the proxy.
+    pub fn add_bytes_in(&mut self, n: usize) {
+        self.bytes_in = self.bytes_in.checked_add(n).unwrap_or(usize::MAX);
+    }
+
+    /// Sets the original source learned from the PROXY protocol header.
+    pub fn set_real_src(&mut self, real_src: Option<SocketAddr>) {
+        self.real_src = real_src;
+    }
+
+    /// Sets the server name peeked from the TLS ClientHello.
+    pub fn set_server_name(&mut self, server_name: Option<String>) {
+        self.server_name = server_name;
+    }
+
+    /// Sets the statistics of the receive side, which are the peak size of the receive cache,
+    /// the count of ranges merged in the receive cache and the peak count of discontinuous ranges
+    /// in it.
+    pub fn set_recv_stats(&mut self, peak_cache: usize, merges: usize, peak_ranges: usize) {
+        self.peak_recv_cache = peak_cache;
+        self.recv_merges = merges;
+        self.peak_recv_ranges = peak_ranges;
+    }
+
+    /// Sets the statistics of the send side, which are the size of payload sent, the peak size
+    /// of the send cache, the count of retransmissions and the count of them detected as
+    /// spurious.
+    pub fn set_send_stats(
+        &mut self,
+        sent: usize,
+        peak_cache: usize,
+        retrans: usize,
+        spurious_retrans: usize,
+    ) {
+        self.bytes_out = sent;
+        self.peak_send_cache = peak_cache;
+        self.retrans = retrans;
+        self.spurious_retrans = spurious_retrans;
+    }
+
+    fn format(&self, format: FlowLogFormat, end: SystemTime, reason: CloseReason) -> String {
+        let start = timestamp(self.start);
+        let end = timestamp(end);
+        match format {
+            FlowLogFormat::Json => format!(
+                "{{\"src\":\"{}\",\"dst\":\"{}\",\"proxy\":\"{}\",{}{}\"start\":{},\"end\":{},\"bytes_in\":{},\"bytes_out\":{},\"peak_recv_cache\":{},\"recv_merges\":{},\"peak_recv_ranges\":{},\"peak_send_cache\":{},\"retrans\":{},\"spurious_retrans\":{},{}\"reason\":\"{}\"}}",
+                self.src,
+                self.dst,
+                self.proxy,
+                match self.real_src {
+                    Some(real_src) => format!("\"real_src\":\"{}\",", real_src),
+                    None => String::new(),
+                },
+                match self.server_name {
+                    Some(ref server_name) => format!("\"server_name\":\"{}\",", server_name),
+                    None => String::new(),
+                },
+                start,
+                end,
+                self.bytes_in,
+                self.bytes_out,
+                self.peak_recv_cache,
+                self.recv_merges,
+                self.peak_recv_ranges,
+                self.peak_send_cache,
+                self.retrans,
+                self.spurious_retrans,
+                match (&self.digest_in, &self.digest_out) {
+                    (Some(digest_in), Some(digest_out)) => format!(
+                        "\"digest_in\":\"{}\",\"digest_out\":\"{}\",",
+                        digest_in, digest_out
+                    ),
+                    _ => String::new(),
+                },
+                reason
+            ),
+            FlowLogFormat::Text => format!(
+                "src={} dst={} proxy={} {}{}start={} end={} bytes_in={} bytes_out={} peak_recv_cache={} recv_merges={} peak_recv_ranges={} peak_send_cache={} retrans={} spurious_retrans={} {}reason={}",
+                self.src,
+                self.dst,
+                self.proxy,
+                match self.real_src {
+                    Some(real_src) => format!("real_src={} ", real_src),
+                    None => String::new(),
+                },
+                match self.server_name {
+                    Some(ref server_name) => format!("server_name={} ", server_name),
+                    None => String::new(),
+                },
+                start,
+                end,
+                self.bytes_in,
+                self.bytes_out,
+                self.peak_recv_cache,
+                self.recv_merges,
+                self.peak_recv_ranges,
+                self.peak_send_cache,
+                self.retrans,
+                self.spurious_retrans,
+                match (&self.digest_in, &self.digest_out) {
+                    (Some(digest_in), Some(digest_out)) =>
+                        format!("digest_in={} digest_out={} ", digest_in, digest_out),
+                    _ => String::new(),
+                },
+                reason
+            ),
+        }
+    }
+}
+
+/// Returns the seconds since the UNIX epoch in milliseconds precision.
+pub(crate) fn timestamp(time: SystemTime) -> String {
+    let duration = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+
+    format!("{}.{:03}", duration.as_secs(), duration.subsec_millis())
+}
+
+/// Represents the flow log, which writes a record in a line for each connection when it closes.
+pub struct FlowLog {
+    writer: Box<dyn Write + Send>,
+    format: FlowLogFormat,
+}
+
+impl FlowLog {
+    /// Opens the flow log appending to the file, or writing to the standard output if the path
+    /// is `-`.
+    pub fn open(path: &Path, format: FlowLogFormat) -> io::Result<FlowLog> {
+        let writer: Box<dyn Write + Send> = match path.to_str() {
+            Some("-") => Box::new(io::stdout()),
+            _ => Box::new(LineWriter::new(
+                OpenOptions::new().create(true).append(true).open(path)?,
+            )),
+        };
+
+        Ok(FlowLog { writer, format })
+    }
+
+    /// Writes the record of a connection closed now for the reason.
+    pub fn write(&mut self, record: &FlowRecord, reason: CloseReason) -> io::Result<()> {
+        let line = record.format(self.format, SystemTime::now(), reason);
+
+        writeln!(self.writer, "{}", line)
+    }
+}
+
+/// Represents the byte counters of a TCP connection, which may be shared between threads.
+#[derive(Debug, Default)]
+pub struct ByteCounts {
+    /// Represents the size of payload sent from the source to the proxy.
+    bytes_in: AtomicU64,
+    /// Represents the size of payload sent from the proxy to the source, excluding
+    /// retransmissions.
+    bytes_out: AtomicU64,
+    closed: AtomicBool,
+}
+
+impl ByteCounts {
+    /// Creates a new `ByteCounts`.
+    pub fn new() -> ByteCounts {
+        ByteCounts::default()
+    }
+
+    /// Adds the size of payload sent from the source to the proxy.
+    pub fn add_in(&self, n: usize) {
+        self.bytes_in.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// Adds the size of payload sent from the proxy to the source.
+    pub fn add_out(&self, n: usize) {
+        self.bytes_out.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// Returns the size of payload sent in both directions since the last take, in the form of
+    /// (bytes in, bytes out), and zeroes the counters. Each counter is swapped atomically, so
+    /// bytes added concurrently are counted in either this take or the next one.
+    pub fn take_byte_counts(&self) -> (u64, u64) {
+        (
+            self.bytes_in.swap(0, Ordering::Relaxed),
+            self.bytes_out.swap(0, Ordering::Relaxed),
+        )
+    }
+
+    /// Returns if the connection is closed.
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+}
+
+/// Represents the byte counters of all the TCP connections, which can be cloned and polled from
+/// other threads, like a quota loop, while the connections are redirected.
+///
+/// The counters of a connection are kept after it closes until they are taken, so bytes sent
+/// between the last take and the close are not lost.
+#[derive(Clone, Debug, Default)]
+pub struct FlowAccounting {
+    flows: Arc<Mutex<HashMap<(SocketAddrV4, SocketAddrV4), Arc<ByteCounts>>>>,
+}
+
+impl FlowAccounting {
+    /// Creates a new `FlowAccounting`.
+    pub fn new() -> FlowAccounting {
+        FlowAccounting::default()
+    }
+
+    /// Opens the counters of a connection. The counters of a closed connection with the same
+    /// source and destination not taken yet are replaced.
+    pub fn open(&self, src: SocketAddrV4, dst: SocketAddrV4) -> Arc<ByteCounts> {
+        let counts = Arc::new(ByteCounts::new());
+        self.flows
+            .lock()
+            .unwrap()
+            .insert((src, dst), Arc::clone(&counts));
+
+        counts
+    }
+
+    /// Closes the counters of a connection.
+    pub fn close(&self, src: SocketAddrV4, dst: SocketAddrV4) {
+        if let Some(counts) = self.flows.lock().unwrap().get(&(src, dst)) {
+            counts.closed.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Takes the byte counts of a connection, in the form of (bytes in, bytes out). The counters
+    /// are removed if the connection is closed.
+    pub fn take_byte_counts(&self, src: SocketAddrV4, dst: SocketAddrV4) -> Option<(u64, u64)> {
+        let mut flows = self.flows.lock().unwrap();
+        let counts = flows.get(&(src, dst))?.take_byte_counts();
+        if flows[&(src, dst)].is_closed() {
+            flows.remove(&(src, dst));
+        }
+
+        Some(counts)
+    }
+
+    /// Takes the byte counts of all the connections, in the form of (source, destination, bytes
+    /// in, bytes out). The counters of closed connections are removed.
+    pub fn take_all(&self) -> Vec<(SocketAddrV4, SocketAddrV4, u64, u64)> {
+        let mut flows = self.flows.lock().unwrap();
+        let v = flows
+            .iter()
+            .map(|(&(src, dst), counts)| {
+                let (bytes_in, bytes_out) = counts.take_byte_counts();
+
+                (src, dst, bytes_in, bytes_out)
+            })
+            .collect();
+        flows.retain(|_, counts| !counts.is_closed());
+
+        v
+    }
+}
+
+#[test]
+fn flow_record_format() {
+    use std::time::Duration;
+
+    let mut record = FlowRecord::new(
+        "10.6.0.2:40000".parse().unwrap(),
+        "1.1.1.1:443".parse().unwrap(),
+        "127.0.0.1:1080".parse().unwrap(),
+    );
+    record.start = UNIX_EPOCH + Duration::from_millis(1000);
+    record.add_bytes_in(100);
+    record.set_recv_stats(50, 3, 2);
+    record.set_send_stats(2000, 1460, 2, 1);
+    let end = UNIX_EPOCH + Duration::from_millis(2500);
+
+    assert_eq!(
+        record.format(FlowLogFormat::Json, end, CloseReason::Fin),
+        "{\"src\":\"10.6.0.2:40000\",\"dst\":\"1.1.1.1:443\",\"proxy\":\"127.0.0.1:1080\",\"start\":1.000,\"end\":2.500,\"bytes_in\":100,\"bytes_out\":2000,\"peak_recv_cache\":50,\"recv_merges\":3,\"peak_recv_ranges\":2,\"peak_send_cache\":1460,\"retrans\":2,\"spurious_retrans\":1,\"reason\":\"fin\"}"
+    );
+    assert_eq!(
+        record.format(FlowLogFormat::Text, end, CloseReason::Abandoned),
+        "src=10.6.0.2:40000 dst=1.1.1.1:443 proxy=127.0.0.1:1080 start=1.000 end=2.500 bytes_in=100 bytes_out=2000 peak_recv_cache=50 recv_merges=3 peak_recv_ranges=2 peak_send_cache=1460 retrans=2 spurious_retrans=1 reason=abandoned"
+    );
+
+    // The original source learned from the PROXY protocol header
+    record.set_real_src(Some("192.0.2.1:50000".parse().unwrap()));
+    assert!(record
+        .format(FlowLogFormat::Json, end, CloseReason::Fin)
+        .contains("\"proxy\":\"127.0.0.1:1080\",\"real_src\":\"192.0.2.1:50000\",\"start\""));
+    assert!(record
+        .format(FlowLogFormat::Text, end, CloseReason::Fin)
+        .contains("proxy=127.0.0.1:1080 real_src=192.0.2.1:50000 start="));
+
+    // The server name peeked from the TLS ClientHello
+    record.set_server_name(Some("example.com".to_string()));
+    assert!(record
+        .format(FlowLogFormat::Json, end, CloseReason::Fin)
+        .contains("\"real_src\":\"192.0.2.1:50000\",\"server_name\":\"example.com\",\"start\""));
+    assert!(record
+        .format(FlowLogFormat::Text, end, CloseReason::Fin)
+        .contains("real_src=192.0.2.1:50000 server_name=example.com start="));
+
+    // The digests of payload sent in both directions
+    record.set_digest(DigestAlgorithm::Fnv1a64);
+    record.update_digest_in(b"a");
+    record.set_digest_out(Some(Digest::new(DigestAlgorithm::Fnv1a64)));
+    assert!(record
+        .format(FlowLogFormat::Json, end, CloseReason::Fin)
+        .contains("\"spurious_retrans\":1,\"digest_in\":\"fnv1a64:af63dc4c8601ec8c\",\"digest_out\":\"fnv1a64:cbf29ce484222325\",\"reason\""));
+    assert!(record
+        .format(FlowLogFormat::Text, end, CloseReason::Fin)
+        .contains(
+        "spurious_retrans=1 digest_in=fnv1a64:af63dc4c8601ec8c digest_out=fnv1a64:cbf29ce484222325 reason"
+    ));
+}
+
+#[test]
+fn flow_accounting_take() {
+    let src = "10.6.0.2:40000".parse().unwrap();
+    let dst = "1.1.1.1:443".parse().unwrap();
+    let accounting = FlowAccounting::new();
+    assert_eq!(accounting.take_byte_counts(src, dst), None);
+
+    let counts = accounting.open(src, dst);
+    counts.add_in(100);
+    counts.add_out(2000);
+    assert_eq!(accounting.take_byte_counts(src, dst), Some((100, 2000)));
+    assert_eq!(accounting.take_byte_counts(src, dst), Some((0, 0)));
+
+    // Counts after the last take are kept until taken after the close
+    counts.add_out(500);
+    accounting.close(src, dst);
+    assert_eq!(accounting.take_all(), vec![(src, dst, 0, 500)]);
+    assert!(accounting.take_all().is_empty());
+
+    // Concurrent increments are never lost
+    let counts = accounting.open(src, dst);
+    let handle = {
+        let counts = Arc::clone(&counts);
+        std::thread::spawn(move || {
+            for _ in 0..10000 {
+                counts.add_in(1);
+            }
+        })
+    };
+    let mut total = 0;
+    for _ in 0..100 {
+        total += accounting.take_byte_counts(src, dst).unwrap().0;
+    }
+    handle.join().unwrap();
+    total += accounting.take_byte_counts(src, dst).unwrap().0;
+    assert_eq!(total, 10000);
+}
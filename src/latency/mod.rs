@@ -0,0 +1,111 @@
+//! Support for measuring the latency added by the redirection.
+
+use std::cmp::min;
+use std::time::Duration;
+
+/// Represents the count of buckets of the histogram. The bucket `i` holds latencies in
+/// [2^i, 2^(i + 1)) microseconds, except the first bucket holds latencies below 2 microseconds
+/// and the last bucket holds all the latencies beyond.
+const BUCKETS: usize = 32;
+
+/// Represents a histogram of latencies. Latencies are recorded in buckets of powers of 2
+/// microseconds, so recording is cheap and percentiles are approximated by the upper bound of
+/// the bucket.
+#[derive(Clone, Debug)]
+pub struct LatencyHistogram {
+    buckets: [usize; BUCKETS],
+    count: usize,
+    max: Duration,
+}
+
+impl LatencyHistogram {
+    /// Creates a new empty `LatencyHistogram`.
+    pub fn new() -> LatencyHistogram {
+        LatencyHistogram {
+            buckets: [0; BUCKETS],
+            count: 0,
+            max: Duration::from_secs(0),
+        }
+    }
+
+    /// Records a latency.
+    pub fn record(&mut self, latency: Duration) {
+        let micros = min(latency.as_micros(), u64::MAX as u128) as u64;
+        let i = match micros {
+            0 => 0,
+            _ => min(63 - micros.leading_zeros() as usize, BUCKETS - 1),
+        };
+
+        self.buckets[i] = self.buckets[i].checked_add(1).unwrap_or(usize::MAX);
+        self.count = self.count.checked_add(1).unwrap_or(usize::MAX);
+        if latency > self.max {
+            self.max = latency;
+        }
+    }
+
+    /// Returns the count of latencies recorded.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Returns the maximum latency recorded.
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// Returns the approximate latency at the percentile in range 0 to 100, or `None` if no
+    /// latency is recorded. The latency returned is never over the maximum latency recorded.
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let rank = (self.count as f64 * percentile.max(0.0).min(100.0) / 100.0).ceil() as usize;
+        let rank = rank.max(1);
+        let mut cumulative = 0;
+        for (i, &n) in self.buckets.iter().enumerate() {
+            cumulative += n;
+            if cumulative >= rank {
+                let upper = Duration::from_micros(1 << (i + 1));
+
+                return Some(min(upper, self.max));
+            }
+        }
+
+        Some(self.max)
+    }
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        LatencyHistogram::new()
+    }
+}
+
+#[test]
+fn latency_histogram_percentile() {
+    let mut h = LatencyHistogram::new();
+    assert_eq!(h.percentile(50.0), None);
+
+    for _ in 0..90 {
+        h.record(Duration::from_micros(100));
+    }
+    for _ in 0..10 {
+        h.record(Duration::from_millis(10));
+    }
+    assert_eq!(h.count(), 100);
+    assert_eq!(h.max(), Duration::from_millis(10));
+
+    // 100 us falls in [64, 128) us
+    assert_eq!(h.percentile(0.0), Some(Duration::from_micros(128)));
+    assert_eq!(h.percentile(50.0), Some(Duration::from_micros(128)));
+    assert_eq!(h.percentile(90.0), Some(Duration::from_micros(128)));
+    // 10 ms falls in [8192, 16384) us, and is capped by the maximum
+    assert_eq!(h.percentile(91.0), Some(Duration::from_millis(10)));
+    assert_eq!(h.percentile(100.0), Some(Duration::from_millis(10)));
+
+    // Latencies below 1 us
+    let mut h = LatencyHistogram::new();
+    h.record(Duration::from_nanos(500));
+    assert_eq!(h.percentile(99.0), Some(Duration::from_nanos(500)));
+}
@@ -5,34 +5,63 @@
 use ipnetwork::Ipv4Network;
 use log::{debug, info, trace, warn};
 use lru::LruCache;
-use rand::{self, Rng};
+use rand::rngs::StdRng;
+use rand::{self, Rng, SeedableRng};
 use std::cmp::{max, min};
 use std::collections::{HashMap, HashSet};
+use std::hint;
 use std::net::{Ipv4Addr, Shutdown, SocketAddrV4};
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::io;
 
+pub mod admin;
+pub mod capture;
+pub mod digest;
+pub mod encap;
+pub mod flowdump;
+pub mod flowlog;
+pub mod latency;
+pub mod mapping;
+pub mod mirror;
 pub mod packet;
 pub mod pcap;
 pub mod proxy;
+pub mod shard;
+#[cfg(all(target_os = "linux", feature = "systemd"))]
+pub mod systemd;
 pub mod tcp;
 
+use self::proxy::sni;
 pub use self::proxy::ProxyConfig;
-use self::proxy::{DatagramWorker, ForwardDatagram, ForwardStream, StreamWorker};
+use self::proxy::{DatagramWorker, ForwardDatagram, ForwardStream, SocksReply, StreamWorker};
+use capture::FrameQueue;
+use digest::{Digest, DigestAlgorithm};
+use encap::{Encapsulation, Tunnel};
+use flowdump::{FlowDump, FlowSnapshot, FlowState};
+use flowlog::{ByteCounts, CloseReason, FlowAccounting, FlowLog, FlowRecord};
+use latency::LatencyHistogram;
+use mapping::{Mappings, PortStrategy};
+use mirror::{Mirror, MirrorDirection};
 use packet::layer::arp::Arp;
 use packet::layer::ethernet::Ethernet;
 use packet::layer::icmpv4::Icmpv4;
-use packet::layer::ipv4::Ipv4;
+use packet::layer::ipv4::{self, Ipv4};
+use packet::layer::loopback::Loopback;
+use packet::layer::sll::Sll;
 use packet::layer::tcp::Tcp;
 use packet::layer::udp::Udp;
 use packet::layer::{Layer, LayerKinds, Layers};
 use packet::{Defraggler, Indicator};
 use pcap::Interface;
-use pcap::{HardwareAddr, Receiver, Sender};
-use tcp::{TcpRxState, TcpTxState};
+use pcap::{HardwareAddr, LinkType, PollMode, Receiver, Sender};
+use tcp::pending::Pending;
+use tcp::scheduler::Scheduler;
+use tcp::seq::{seq_add, seq_sub};
+use tcp::{IsnStrategy, OverWindowPolicy, Pressure, TcpRxState, TcpTxState};
 
 /// Gets a list of available network interfaces for the current machine.
 pub fn interfaces() -> Vec<Interface> {
@@ -64,7 +93,7 @@ pub fn interface(name: Option<String>) -> Option<Interface> {
 /// Represents the max distance of `u32` values between packets in an `u32` window.
 const MAX_U32_WINDOW_SIZE: usize = 16 * 1024 * 1024;
 
-/// Represents the wait time after a `TimedOut` `IoError`.
+/// Represents the minimum interval between reads which time out.
 const TIMEDOUT_WAIT: u64 = 20;
 
 /// Represents if the receive-side silly window syndrome avoidance, Clark's algorithm, is enabled.
@@ -74,6 +103,8 @@ const ENABLE_SEND_SWS_AVOID: bool = true;
 
 /// Represents if the delayed ACK is enabled.
 const ENABLE_DELAYED_ACK: bool = true;
+/// Represents the maximum delay of an ACK allowed, which must be less than 0.5 seconds.
+const MAX_ACK_DELAY: u64 = 500;
 
 /// Represents if the TCP MSS option is enabled.
 const ENABLE_MSS: bool = true;
@@ -83,16 +114,92 @@ const ENABLE_MSS: bool = true;
 /// Exclude the 4 bytes used in FCS, the minimum frame size in pcap2socks is 60 Bytes.
 const MINIMUM_FRAME_SIZE: usize = 60;
 
+/// Represents the maximum count of retries of sending a frame after transient failures.
+const MAX_SEND_RETRIES: usize = 3;
+/// Represents the wait time before the first retry of sending a frame. The wait time is doubled in
+/// each retry.
+const SEND_RETRY_WAIT: u64 = 1;
+
+/// Represents the maximum count of consecutive retransmissions due to timeout before a TCP
+/// connection is abandoned.
+const MAX_RETRANS: usize = 15;
+
+/// Represents the time a path MTU learned from ICMPv4 is kept before it expires.
+const PMTU_TIMEOUT: u64 = 600000;
+
+/// Represents the destination ports of interactive TCP connections by default.
+const INTERACTIVE_PORTS: [u16; 4] = [22, 23, 53, 3389];
+/// Represents the minimum DSCP of interactive TCP connections by default.
+const INTERACTIVE_DSCP: u8 = 32;
+/// Represents the weight of interactive TCP connections in the scheduler by default.
+const INTERACTIVE_WEIGHT: usize = 4;
+
+/// Represents the synthetic impairment of frames sent to the source, which is used in testing
+/// the retransmission and the reassembly.
+struct Impairment {
+    /// Represents the loss rate in per mille.
+    loss: u16,
+    /// Represents the reorder rate in per mille.
+    reorder: u16,
+    rng: StdRng,
+    /// Represents the frame held for reordering and its brief.
+    held: Option<(Vec<u8>, String)>,
+}
+
 /// Represents a channel forward traffic to the source in pcap.
 pub struct Forwarder {
     tx: Sender,
     src_mtu_map: HashMap<Ipv4Addr, usize>,
+    /// Represents the path MTUs of sources learned from ICMPv4 fragmentation required, and the
+    /// instants they are learned.
+    pmtu_map: HashMap<Ipv4Addr, (usize, Instant)>,
     local_mtu: usize,
     src_hardware_addr_map: HashMap<Ipv4Addr, HardwareAddr>,
+    /// Represents the rules of destination hardware addresses of frames sent to sources, in the
+    /// form of (network, hardware address).
+    hardware_addr_rules: Vec<(Ipv4Network, HardwareAddr)>,
     local_hardware_addr: HardwareAddr,
     local_ip_addr: Ipv4Addr,
+    link_type: LinkType,
+    /// Represents the tunnels sources are behind, packets sent to these sources are encapsulated.
+    tunnels: HashMap<Ipv4Addr, Tunnel>,
     ipv4_identification_map: HashMap<(Ipv4Addr, Ipv4Addr), u16>,
     states: HashMap<(SocketAddrV4, SocketAddrV4), TcpTxState>,
+    /// Represents the scheduler of sending among TCP connections by weight, or `None` if
+    /// connections are sent in the order their payload arrives.
+    scheduler: Option<Scheduler<(SocketAddrV4, SocketAddrV4)>>,
+    /// Represents the rules of the weight of TCP connections in the scheduler by destination, in
+    /// the form of (network, port, weight).
+    weights: Vec<(Ipv4Network, Option<u16>, usize)>,
+    /// Represents the rules of the weight of TCP connections in the scheduler by DSCP, in the
+    /// form of (minimum DSCP, weight).
+    dscp_weights: Vec<(u8, usize)>,
+    /// Represents the TCP connection whose payload sent to the source is dumped.
+    hexdump: Option<(SocketAddrV4, SocketAddrV4)>,
+    /// Represents the DSCP overriding the DSCP of all the IPv4 packets sent to the source.
+    dscp: Option<u8>,
+    impairment: Option<Impairment>,
+    /// Represents the count of retransmissions of all the TCP connections.
+    retrans: usize,
+    /// Represents the count of duplicate ACKs suppressed of all the TCP connections.
+    suppressed_acks: usize,
+    /// Represents the maximum count of consecutive retransmissions due to timeout.
+    max_retrans: usize,
+    /// Represents the TCP connections abandoned but not yet cleaned up by the `Redirector`.
+    abandoned: Vec<(SocketAddrV4, SocketAddrV4)>,
+    /// Represents the count of TCP connections abandoned.
+    abandoned_count: usize,
+    /// Represents the count of frames failed to send.
+    send_failure: usize,
+    /// Represents the statistics of TCP connections cleaned up but not yet taken, in the form of
+    /// (payload sent, peak cache size, retransmissions, spurious retransmissions). Statistics are
+    /// kept only if it is set.
+    closed: Option<HashMap<(SocketAddrV4, SocketAddrV4), (usize, usize, usize, usize)>>,
+    /// Represents the digests of payload sent of TCP connections cleaned up but not yet taken.
+    closed_digests: HashMap<(SocketAddrV4, SocketAddrV4), Digest>,
+    /// Represents the latencies from TCP payload is read from the proxy to it is sent.
+    downstream_latency: LatencyHistogram,
+    byte_counts: HashMap<(SocketAddrV4, SocketAddrV4), Arc<ByteCounts>>,
     traffic: Option<Arc<AtomicUsize>>,
     count: Option<Arc<AtomicUsize>>,
 }
@@ -120,26 +227,158 @@ impl Forwarder {
         Forwarder {
             tx,
             src_mtu_map: HashMap::new(),
+            pmtu_map: HashMap::new(),
             local_mtu: mtu,
             src_hardware_addr_map: HashMap::new(),
+            hardware_addr_rules: Vec::new(),
             local_hardware_addr,
             local_ip_addr,
+            link_type: LinkType::Ethernet,
+            tunnels: HashMap::new(),
             ipv4_identification_map: HashMap::new(),
             states: HashMap::new(),
+            scheduler: Some(Scheduler::new()),
+            weights: INTERACTIVE_PORTS
+                .iter()
+                .map(|port| {
+                    (
+                        Ipv4Network::new(Ipv4Addr::UNSPECIFIED, 0).unwrap(),
+                        Some(*port),
+                        INTERACTIVE_WEIGHT,
+                    )
+                })
+                .collect(),
+            dscp_weights: vec![(INTERACTIVE_DSCP, INTERACTIVE_WEIGHT)],
+            hexdump: None,
+            dscp: None,
+            impairment: None,
+            retrans: 0,
+            suppressed_acks: 0,
+            max_retrans: MAX_RETRANS,
+            abandoned: Vec::new(),
+            abandoned_count: 0,
+            send_failure: 0,
+            closed: None,
+            closed_digests: HashMap::new(),
+            downstream_latency: LatencyHistogram::new(),
+            byte_counts: HashMap::new(),
             traffic,
             count,
         }
     }
 
-    /// Sets the source MTU.
+    /// Sets the TCP connection whose payload sent to the source is dumped in hex.
+    pub fn set_hexdump(&mut self, hexdump: Option<(SocketAddrV4, SocketAddrV4)>) {
+        self.hexdump = hexdump;
+    }
+
+    /// Sets the link-layer type of frames sent to the source. ARP packets are not sent in the
+    /// null/loopback link type.
+    pub fn set_link_type(&mut self, link_type: LinkType) {
+        self.link_type = link_type;
+    }
+
+    /// Sets if sending is scheduled among TCP connections by weight. If it is not set, the
+    /// payload of a TCP connection is sent as it arrives, as much as the window allows.
+    pub fn set_scheduler(&mut self, is_scheduled: bool) {
+        self.scheduler = match is_scheduled {
+            true => Some(Scheduler::new()),
+            false => None,
+        };
+    }
+
+    /// Sets the rules of the weight of TCP connections in the scheduler, by destination in the
+    /// form of (network, port, weight) and by DSCP in the form of (minimum DSCP, weight). A port
+    /// of `None` matches any port. The first matched rule of each kind applies, and the larger
+    /// weight of both is used. Connections matching no rule have a weight of 1.
+    pub fn set_weights(
+        &mut self,
+        weights: Vec<(Ipv4Network, Option<u16>, usize)>,
+        dscp_weights: Vec<(u8, usize)>,
+    ) -> io::Result<()> {
+        if weights.iter().any(|(_, _, weight)| *weight == 0)
+            || dscp_weights.iter().any(|(_, weight)| *weight == 0)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "weight 0 cannot be scheduled",
+            ));
+        }
+        if dscp_weights.iter().any(|(dscp, _)| *dscp > 63) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "DSCP out of range",
+            ));
+        }
+        self.weights = weights;
+        self.dscp_weights = dscp_weights;
+
+        Ok(())
+    }
+
+    /// Sets the DSCP of all the IPv4 packets sent to the source. If the DSCP is set, it overrides
+    /// the DSCP preserved from the source.
+    pub fn set_dscp(&mut self, dscp: Option<u8>) {
+        self.dscp = dscp;
+    }
+
+    /// Sets the synthetic impairment of frames sent to the source for testing. Frames are dropped
+    /// in the loss rate, and are swapped with the next frame in the reorder rate, both in per
+    /// mille. The random number generator is seeded for reproducibility if the seed is given.
+    pub fn set_impairment(&mut self, loss: u16, reorder: u16, seed: Option<u64>) {
+        self.impairment = match loss > 0 || reorder > 0 {
+            true => Some(Impairment {
+                loss,
+                reorder,
+                rng: match seed {
+                    Some(seed) => StdRng::seed_from_u64(seed),
+                    None => StdRng::from_entropy(),
+                },
+                held: None,
+            }),
+            false => None,
+        };
+    }
+
+    /// Sets the maximum count of consecutive retransmissions due to timeout before a TCP
+    /// connection is abandoned.
+    pub fn set_max_retrans(&mut self, max_retrans: usize) {
+        self.max_retrans = max_retrans;
+    }
+
+    /// Sets the tunnel the source is behind, and returns if the tunnel is changed. Packets sent to
+    /// the source are encapsulated in the tunnel, and the source MTU is reduced by the overhead
+    /// of the encapsulation.
+    pub fn set_tunnel(&mut self, src_ip_addr: Ipv4Addr, tunnel: Tunnel) -> bool {
+        if self.tunnels.get(&src_ip_addr) == Some(&tunnel) {
+            return false;
+        }
+        trace!("set tunnel of {} to {:?}", src_ip_addr, tunnel);
+        self.tunnels.insert(src_ip_addr, tunnel);
+
+        let mtu = self.get_src_mtu(src_ip_addr);
+        self.set_src_mtu(src_ip_addr, mtu);
+
+        true
+    }
+
+    /// Sets the source MTU, which is limited by the path MTU of the source if it is learned and
+    /// not expired, and by the overhead of the encapsulation if the source is behind a tunnel.
     pub fn set_src_mtu(&mut self, src_ip_addr: Ipv4Addr, mtu: usize) -> bool {
         let prev_mtu = *self
             .src_mtu_map
             .get(&src_ip_addr)
             .unwrap_or(&self.local_mtu);
+        let mtu = match self.get_src_pmtu(src_ip_addr) {
+            Some(pmtu) => min(mtu, pmtu),
+            None => mtu,
+        };
+        let local_mtu = match self.tunnels.get(&src_ip_addr) {
+            Some(tunnel) => self.local_mtu.saturating_sub(tunnel.overhead()),
+            None => self.local_mtu,
+        };
 
-        self.src_mtu_map
-            .insert(src_ip_addr, min(self.local_mtu, mtu));
+        self.src_mtu_map.insert(src_ip_addr, min(local_mtu, mtu));
         trace!("set source MTU of {} to {}", src_ip_addr, mtu);
 
         return *self
@@ -149,6 +388,18 @@ impl Forwarder {
             != prev_mtu;
     }
 
+    /// Sets the path MTU of the source learned from an ICMPv4 fragmentation required, and sets
+    /// the source MTU. The path MTU is cached until it expires, so new TCP connections of the
+    /// source start with the MSS limited by it instead of discovering it again.
+    pub fn set_src_pmtu(&mut self, src_ip_addr: Ipv4Addr, mtu: usize) -> bool {
+        self.pmtu_map
+            .retain(|_, (_, instant)| instant.elapsed().as_millis() < PMTU_TIMEOUT as u128);
+        self.pmtu_map.insert(src_ip_addr, (mtu, Instant::now()));
+        trace!("set source path MTU of {} to {}", src_ip_addr, mtu);
+
+        self.set_src_mtu(src_ip_addr, mtu)
+    }
+
     /// Sets the source hardware address.
     pub fn set_src_hardware_addr(&mut self, src_ip_addr: Ipv4Addr, hardware_addr: HardwareAddr) {
         self.src_hardware_addr_map
@@ -160,6 +411,14 @@ impl Forwarder {
         );
     }
 
+    /// Sets the rules of destination hardware addresses of IPv4 packets sent to sources, in the
+    /// form of (network, hardware address). The first matched rule applies, and overrides the
+    /// hardware address learned from frames and ARP, like the hardware address of the router for
+    /// sources in another segment. Sources matching no rule use the hardware address learned.
+    pub fn set_hardware_addr_rules(&mut self, rules: Vec<(Ipv4Network, HardwareAddr)>) {
+        self.hardware_addr_rules = rules;
+    }
+
     /// Sets the local IP address.
     pub fn set_local_ip_addr(&mut self, ip_addr: Ipv4Addr) {
         self.local_ip_addr = ip_addr;
@@ -191,7 +450,73 @@ impl Forwarder {
     pub fn clean_up(&mut self, dst: SocketAddrV4, src: SocketAddrV4) {
         let key = (src, dst);
 
-        self.states.remove(&key);
+        if let Some(state) = self.states.remove(&key) {
+            debug!(
+                "close TCP {} -> {} after {} retransmissions ({} spurious)",
+                dst,
+                src,
+                state.retrans(),
+                state.spurious_retrans()
+            );
+            if let Some(closed) = &mut self.closed {
+                closed.insert(
+                    key,
+                    (
+                        state.sent(),
+                        state.peak_cache(),
+                        state.retrans(),
+                        state.spurious_retrans(),
+                    ),
+                );
+                if let Some(digest) = state.digest() {
+                    self.closed_digests.insert(key, digest.clone());
+                }
+            }
+        }
+        self.unschedule_tcp(key);
+        self.byte_counts.remove(&key);
+    }
+
+    /// Sets the byte counters of the TCP connection, which count the payload sent.
+    pub fn set_byte_counts(
+        &mut self,
+        dst: SocketAddrV4,
+        src: SocketAddrV4,
+        byte_counts: Arc<ByteCounts>,
+    ) {
+        self.byte_counts.insert((src, dst), byte_counts);
+    }
+
+    /// Sets if the statistics of TCP connections are kept after they are cleaned up, until they
+    /// are taken.
+    pub fn set_keep_closed(&mut self, keep_closed: bool) {
+        self.closed = match keep_closed {
+            true => Some(HashMap::new()),
+            false => None,
+        };
+    }
+
+    /// Takes the statistics of the TCP connection cleaned up, in the form of (payload sent, peak
+    /// cache size, retransmissions, spurious retransmissions).
+    pub fn take_closed(
+        &mut self,
+        dst: SocketAddrV4,
+        src: SocketAddrV4,
+    ) -> Option<(usize, usize, usize, usize)> {
+        match &mut self.closed {
+            Some(closed) => closed.remove(&(src, dst)),
+            None => None,
+        }
+    }
+
+    /// Takes the digest of payload sent of the TCP connection cleaned up.
+    pub fn take_closed_digest(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> Option<Digest> {
+        self.closed_digests.remove(&(src, dst))
+    }
+
+    /// Returns the local hardware address.
+    pub fn get_local_hardware_addr(&self) -> HardwareAddr {
+        self.local_hardware_addr
     }
 
     /// Returns the source MTU.
@@ -202,6 +527,26 @@ impl Forwarder {
             .unwrap_or(&self.local_mtu)
     }
 
+    /// Returns the path MTU of the source if it is learned and not expired.
+    pub fn get_src_pmtu(&self, src_ip_addr: Ipv4Addr) -> Option<usize> {
+        match self.pmtu_map.get(&src_ip_addr) {
+            Some((mtu, instant)) if instant.elapsed().as_millis() < PMTU_TIMEOUT as u128 => {
+                Some(*mtu)
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the path MTUs of sources learned and not expired, in the form of (source, path
+    /// MTU, age).
+    pub fn get_src_pmtus(&self) -> Vec<(Ipv4Addr, usize, Duration)> {
+        self.pmtu_map
+            .iter()
+            .filter(|(_, (_, instant))| instant.elapsed().as_millis() < PMTU_TIMEOUT as u128)
+            .map(|(ip_addr, (mtu, instant))| (*ip_addr, *mtu, instant.elapsed()))
+            .collect()
+    }
+
     /// Returns the state of a TCP connection.
     pub fn get_state(&self, dst: SocketAddrV4, src: SocketAddrV4) -> Option<&TcpTxState> {
         let key = (src, dst);
@@ -265,6 +610,15 @@ impl Forwarder {
         self.send_ethernet(arp.dst_hardware_addr(), Layers::Arp(arp), None, None)
     }
 
+    /// Sends an ARP request packet resolving the hardware address of the source.
+    pub fn send_arp_request(&mut self, src_ip_addr: Ipv4Addr) -> io::Result<()> {
+        // ARP
+        let arp = Arp::new_request(self.local_hardware_addr, self.local_ip_addr, src_ip_addr);
+
+        // Send
+        self.send_ethernet(pcap::HARDWARE_ADDR_BROADCAST, Layers::Arp(arp), None, None)
+    }
+
     /// Sends an gratuitous ARP packet.
     pub fn send_gratuitous_arp(&mut self) -> io::Result<()> {
         // ARP
@@ -288,6 +642,16 @@ impl Forwarder {
         self.send_ipv4(dst_ip_addr, src_ip_addr, Layers::Icmpv4(icmpv4), None)
     }
 
+    /// Sends an ICMPv4 packet.
+    pub fn send_icmpv4(
+        &mut self,
+        dst_ip_addr: Ipv4Addr,
+        src_ip_addr: Ipv4Addr,
+        icmpv4: Icmpv4,
+    ) -> io::Result<()> {
+        self.send_ipv4(dst_ip_addr, src_ip_addr, Layers::Icmpv4(icmpv4), None)
+    }
+
     /// Sends an ICMPv4 destination host unreachable packet.
     pub fn send_icmpv4_destination_host_unreachable(
         &mut self,
@@ -327,30 +691,91 @@ impl Forwarder {
             .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
         state.append_queue(payload);
 
-        self.send_tcp(dst, src)
+        let dscp = state.dscp();
+        match self.scheduler {
+            Some(ref mut scheduler) => {
+                let weight = select_weight(&self.weights, &self.dscp_weights, dst, dscp);
+                scheduler.insert((src, dst), weight);
+
+                self.send_tcp_scheduled()
+            }
+            None => self.send_tcp(dst, src),
+        }
+    }
+
+    /// Sends TCP packets from the queues of all the scheduled TCP connections. Each time a
+    /// connection is scheduled, at most a segment will be sent, so a connection with a large
+    /// queue will not starve others.
+    fn send_tcp_scheduled(&mut self) -> io::Result<()> {
+        while let Some((src, dst)) = self
+            .scheduler
+            .as_mut()
+            .and_then(|scheduler| scheduler.schedule())
+        {
+            let mtu = *self.src_mtu_map.get(src.ip()).unwrap_or(&self.local_mtu);
+            let mss = mtu - (Ipv4::minimum_len() + Tcp::minimum_len());
+
+            match self.send_tcp_max(dst, src, mss) {
+                Ok(size) => {
+                    // Unschedule connections which can not send anymore, they will be resumed
+                    // on the arrival of ACKs
+                    if size == 0 {
+                        self.unschedule_tcp((src, dst));
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                    self.unschedule_tcp((src, dst));
+                }
+                Err(e) => {
+                    self.unschedule_tcp((src, dst));
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn unschedule_tcp(&mut self, key: (SocketAddrV4, SocketAddrV4)) {
+        if let Some(ref mut scheduler) = self.scheduler {
+            scheduler.remove(&key);
+        }
     }
 
     /// Retransmits TCP packets from the cache. This method is used for fast retransmission.
+    /// Ranges in the window not covered by the SACKs are retransmitted, or only the first
+    /// segment, which appears to be missing, if there is no SACK.
     pub fn retransmit_tcp(
         &mut self,
         dst: SocketAddrV4,
         src: SocketAddrV4,
         sacks: Option<Vec<(u32, u32)>>,
     ) -> io::Result<()> {
+        let mss = *self.src_mtu_map.get(src.ip()).unwrap_or(&self.local_mtu)
+            - (Ipv4::minimum_len() + Tcp::minimum_len());
         let state = self
             .get_state_mut(dst, src)
             .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
         let sequence = state.cache().sequence();
         let recv_next = state.cache().recv_next();
+        // The source window may be shrunk below the payload in flight
+        let size = min(state.cache().len(), max(state.src_window(), 1));
+        let size = match sacks {
+            Some(_) => size,
+            None => min(size, mss),
+        };
+        let window_next = seq_add(sequence, size as u32);
 
         // Congestion control
+        state.increase_retrans(false);
         if let Some(cc) = &mut state.cc_mut() {
             cc.fast_retransmission();
         }
+        self.retrans = self.retrans.checked_add(1).unwrap_or(usize::MAX);
 
         // Find all disjointed ranges
         let mut ranges = Vec::new();
-        ranges.push((sequence, recv_next));
+        ranges.push((sequence, window_next));
         if let Some(sacks) = sacks {
             for sack in sacks {
                 let mut temp_ranges = Vec::new();
@@ -368,13 +793,14 @@ impl Forwarder {
 
         // Retransmit
         for range in &ranges {
-            let size = range
-                .1
-                .checked_sub(range.0)
-                .unwrap_or_else(|| range.1 + (u32::MAX - range.0)) as usize;
+            let size = seq_sub(range.1, range.0) as usize;
             let state = self
                 .get_state(dst, src)
                 .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+            // Skip ranges which are no longer in the cache
+            if !state.cache().contains(range.0) {
+                continue;
+            }
             let payload = state.cache().get(range.0, size)?;
             if payload.len() > 0 {
                 if range.1 == recv_next && state.cache_fin().is_some() {
@@ -427,18 +853,31 @@ impl Forwarder {
         dst: SocketAddrV4,
         src: SocketAddrV4,
     ) -> io::Result<()> {
+        let max_retrans = self.max_retrans;
         let state = self
             .get_state_mut(dst, src)
             .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
         let next_rto = state.next_rto();
-        let payload = state.cache_mut().get_timed_out_and_update(next_rto);
+        // The source window may be shrunk below the payload in flight, retransmit in the window
+        // only, or a byte as a window probe if the window is closed
+        let window = max(state.src_window(), 1);
+        let payload = state
+            .cache_mut()
+            .get_timed_out_in_window_and_update(next_rto, window);
         let sequence = state.cache().sequence();
         let size = state.cache().len();
 
+        let mut is_retrans = false;
         if size > 0 {
             if payload.len() > 0 {
+                if state.timedout_retrans() >= max_retrans {
+                    return self.abandon_tcp(dst, src);
+                }
+
                 // Double RTO
+                state.increase_retrans(true);
                 state.double_rto();
+                is_retrans = true;
 
                 // Congestion control
                 if let Some(cc) = &mut state.cc_mut() {
@@ -477,8 +916,14 @@ impl Forwarder {
             // FIN
             if let Some(timer) = state.cache_fin() {
                 if timer.is_timedout() {
+                    if state.timedout_retrans() >= max_retrans {
+                        return self.abandon_tcp(dst, src);
+                    }
+
                     // Double RTO
+                    state.increase_retrans(true);
                     state.double_rto();
+                    is_retrans = true;
                     state.update_fin_timer();
                     trace!("retransmit TCP FIN {} -> {} due to timeout", dst, src);
 
@@ -487,12 +932,63 @@ impl Forwarder {
                 }
             }
         }
+        if is_retrans {
+            self.retrans = self.retrans.checked_add(1).unwrap_or(usize::MAX);
+        }
 
         // Delayed ACK0
         let state = self
             .get_state(dst, src)
             .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
-        if state.delayed_ack() {
+        if state.is_delayed_ack_timedout() {
+            self.send_tcp_ack_0(dst, src)?;
+        }
+
+        Ok(())
+    }
+
+    /// Abandons a TCP connection after too many retransmissions. An RST is sent to the source,
+    /// and the connection is cleaned up.
+    fn abandon_tcp(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()> {
+        let state = self
+            .get_state(dst, src)
+            .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+        warn!(
+            "abandon TCP {} -> {} after {} retransmissions",
+            dst,
+            src,
+            state.timedout_retrans()
+        );
+        let result = self.send_tcp_ack_rst(dst, src);
+
+        // Clean up
+        self.clean_up(dst, src);
+        self.abandoned.push((src, dst));
+        self.abandoned_count = self.abandoned_count.checked_add(1).unwrap_or(usize::MAX);
+
+        result
+    }
+
+    /// Takes the TCP connections abandoned but not yet cleaned up, in the form of (source,
+    /// destination).
+    pub fn take_abandoned(&mut self) -> Vec<(SocketAddrV4, SocketAddrV4)> {
+        std::mem::take(&mut self.abandoned)
+    }
+
+    /// Returns the count of TCP connections abandoned after too many retransmissions.
+    pub fn abandoned_count(&self) -> usize {
+        self.abandoned_count
+    }
+
+    /// Sends TCP delayed ACKs which are delayed for the maximum delay.
+    pub fn send_tcp_delayed_acks_timedout(&mut self) -> io::Result<()> {
+        let keys = self
+            .states
+            .iter()
+            .filter(|(_, state)| state.is_delayed_ack_timedout())
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+        for (src, dst) in keys {
             self.send_tcp_ack_0(dst, src)?;
         }
 
@@ -501,21 +997,38 @@ impl Forwarder {
 
     /// Sends TCP packets from the queue.
     pub fn send_tcp(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()> {
+        self.send_tcp_max(dst, src, usize::MAX)?;
+
+        Ok(())
+    }
+
+    /// Sends at most the given size of TCP payload from the queue, and returns the size of
+    /// payload sent.
+    fn send_tcp_max(
+        &mut self,
+        dst: SocketAddrV4,
+        src: SocketAddrV4,
+        max_size: usize,
+    ) -> io::Result<usize> {
         // Retransmit unhandled SYN
         let state = self
             .get_state(dst, src)
             .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
         if state.cache_syn().is_some() {
-            return self.send_tcp_ack_syn(dst, src);
+            self.send_tcp_ack_syn(dst, src)?;
+
+            return Ok(0);
         }
 
-        if state.src_window() > 0 {
+        let mut payload_size = 0;
+
+        if state.src_window() > 0 && !state.is_paused() {
             // TCP sequence
             let sent_size = state.cache().len();
             let remain_size = state.send_window().checked_sub(sent_size).unwrap_or(0);
             let remain_size = min(remain_size, u16::MAX as usize) as u16;
 
-            let mut size = min(remain_size as usize, state.queue().len());
+            let mut size = min(min(remain_size as usize, state.queue().len()), max_size);
             // Avoid SWS
             if ENABLE_SEND_SWS_AVOID {
                 let mtu = *self.src_mtu_map.get(src.ip()).unwrap_or(&self.local_mtu);
@@ -527,10 +1040,15 @@ impl Forwarder {
             }
             let size = size;
             if size > 0 {
+                let is_dumped = self.hexdump == Some((src, dst));
                 let state = self
                     .get_state_mut(dst, src)
                     .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
                 let payload = state.append_cache(size)?;
+                let queue_latency = state.queue_latency();
+                if is_dumped {
+                    hexdump("TCP queue", dst, src, state.sequence(), &payload);
+                }
 
                 // If the queue is empty and a FIN is in the queue, pop it
                 if state.queue().is_empty() && state.queue_fin() {
@@ -551,25 +1069,31 @@ impl Forwarder {
                     let sequence = state.sequence();
                     self.send_tcp_ack(dst, src, sequence, &payload, false)?;
                 }
+                if let Some(latency) = queue_latency {
+                    self.downstream_latency.record(latency);
+                }
+                if let Some(counts) = self.byte_counts.get(&(src, dst)) {
+                    counts.add_out(size);
+                }
+                payload_size = size;
             }
         }
 
-        // If the queue is empty and a FIN is in the queue, pop it
+        // If the queue and the cache are empty and a FIN is in the queue, pop it. The payload
+        // queued is held by a closed window or the size limit, so the FIN must wait for it
         // FIN
         let state = self
             .get_state_mut(dst, src)
             .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
-        if state.queue_fin() {
-            if state.cache().is_empty() {
-                // FIN
-                state.append_cache_fin();
+        if state.is_fin_ready() {
+            // FIN
+            state.append_cache_fin();
 
-                // Send
-                self.send_tcp_fin(dst, src)?;
-            }
+            // Send
+            self.send_tcp_fin(dst, src)?;
         }
 
-        Ok(())
+        Ok(payload_size)
     }
 
     fn send_tcp_ack(
@@ -590,12 +1114,8 @@ impl Forwarder {
                 .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
             let size = min(mss, payload.len() - i * mss);
             let payload = &payload[i * mss..i * mss + size];
-            let sequence = sequence
-                .checked_add((i * mss) as u32)
-                .unwrap_or_else(|| (i * mss) as u32 - (u32::MAX - sequence));
-            let mut recv_next = sequence
-                .checked_add(size as u32)
-                .unwrap_or_else(|| size as u32 - (u32::MAX - sequence));
+            let sequence = seq_add(sequence, (i * mss) as u32);
+            let mut recv_next = seq_add(sequence, size as u32);
 
             // TCP
             let tcp;
@@ -609,7 +1129,7 @@ impl Forwarder {
                     self.get_tcp_window(dst, src),
                     None,
                 );
-                recv_next = recv_next.checked_add(1).unwrap_or(0);
+                recv_next = seq_add(recv_next, 1);
             } else {
                 // ACK
                 tcp = Tcp::new_ack(
@@ -624,6 +1144,7 @@ impl Forwarder {
             }
 
             // Send
+            let window = tcp.window();
             self.send_ipv4(
                 dst.ip().clone(),
                 src.ip().clone(),
@@ -635,13 +1156,11 @@ impl Forwarder {
             let state = self
                 .get_state_mut(dst, src)
                 .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
-            state.clear_delayed_ack();
+            state.clear_delayed_ack(window);
 
             // Update TCP sequence
             let record_sequence = state.sequence();
-            let sub_sequence = recv_next
-                .checked_sub(record_sequence)
-                .unwrap_or_else(|| recv_next + (u32::MAX - record_sequence));
+            let sub_sequence = seq_sub(recv_next, record_sequence);
             if (sub_sequence as usize) <= MAX_U32_WINDOW_SIZE {
                 state.add_sequence(sub_sequence);
             }
@@ -652,17 +1171,18 @@ impl Forwarder {
         Ok(())
     }
 
-    /// Sends an TCP delayed ACK packet without payload.
+    /// Sends an TCP delayed ACK packet without payload. The ACK is delayed until enough
+    /// segments are received or the maximum delay elapses, but is sent at once for a window
+    /// update.
     pub fn send_tcp_delay_ack_0(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()> {
         if ENABLE_DELAYED_ACK {
+            let window = self.get_tcp_window(dst, src);
             let state = self
                 .get_state_mut(dst, src)
                 .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
 
-            if state.delayed_ack() {
+            if state.delay_ack(window) {
                 self.send_tcp_ack_0(dst, src)?;
-            } else {
-                state.set_delayed_ack();
             }
         } else {
             self.send_tcp_ack_0(dst, src)?;
@@ -671,18 +1191,35 @@ impl Forwarder {
         Ok(())
     }
 
+    /// Sends an TCP duplicate ACK packet without payload for a segment out of order or a
+    /// retransmission. The ACK is suppressed and coalesced into a delayed ACK if the duplicate
+    /// ACKs of the acknowledgement reach the maximum.
+    pub fn send_tcp_dup_ack_0(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()> {
+        let state = self
+            .get_state_mut(dst, src)
+            .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+        if !state.admit_dup_ack() {
+            self.suppressed_acks = self.suppressed_acks.checked_add(1).unwrap_or(usize::MAX);
+
+            return Ok(());
+        }
+
+        self.send_tcp_ack_0(dst, src)
+    }
+
     /// Sends an TCP ACK packet without payload.
     pub fn send_tcp_ack_0(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()> {
         // TCP
         let state = self
             .get_state(dst, src)
             .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+        let window = self.get_tcp_window(dst, src);
         let tcp = Tcp::new_ack(
             dst.port(),
             src.port(),
             state.sequence(),
             state.acknowledgement(),
-            self.get_tcp_window(dst, src),
+            window,
             state.sacks().clone(),
             None,
         );
@@ -694,7 +1231,7 @@ impl Forwarder {
         let state = self
             .get_state_mut(dst, src)
             .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
-        state.clear_delayed_ack();
+        state.clear_delayed_ack(window);
 
         Ok(())
     }
@@ -702,7 +1239,9 @@ impl Forwarder {
     fn send_tcp_ack_syn(&mut self, dst: SocketAddrV4, src: SocketAddrV4) -> io::Result<()> {
         let mss = match ENABLE_MSS {
             true => {
-                let mss = self.local_mtu - (Ipv4::minimum_len() + Tcp::minimum_len());
+                // Clamp by the path MTU of the source if it is known
+                let mtu = self.get_src_pmtu(*src.ip()).unwrap_or(self.local_mtu);
+                let mss = min(mtu, self.local_mtu) - (Ipv4::minimum_len() + Tcp::minimum_len());
                 let mss = if mss > u16::MAX as usize {
                     u16::MAX
                 } else {
@@ -723,7 +1262,7 @@ impl Forwarder {
             src.port(),
             state.sequence(),
             state.acknowledgement(),
-            self.get_tcp_window(dst, src),
+            state.syn_window(),
             mss,
             state.src_wscale(),
             state.sack_perm(),
@@ -731,13 +1270,14 @@ impl Forwarder {
         );
 
         // Send
+        let window = tcp.window();
         self.send_ipv4(dst.ip().clone(), src.ip().clone(), Layers::Tcp(tcp), None)?;
 
         // Clear TCP delayed ACK
         let state = self
             .get_state_mut(dst, src)
             .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
-        state.clear_delayed_ack();
+        state.clear_delayed_ack(window);
 
         Ok(())
     }
@@ -758,13 +1298,14 @@ impl Forwarder {
         );
 
         // Send
+        let window = tcp.window();
         self.send_ipv4(dst.ip().clone(), src.ip().clone(), Layers::Tcp(tcp), None)?;
 
         // Clear TCP delayed ACK
         let state = self
             .get_state_mut(dst, src)
             .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
-        state.clear_delayed_ack();
+        state.clear_delayed_ack(window);
 
         Ok(())
     }
@@ -834,12 +1375,50 @@ impl Forwarder {
     }
 
     fn send_ipv4(
+        &mut self,
+        dst_ip_addr: Ipv4Addr,
+        src_ip_addr: Ipv4Addr,
+        transport: Layers,
+        payload: Option<&[u8]>,
+    ) -> io::Result<()> {
+        let key = match transport {
+            Layers::Tcp(ref tcp) => Some((
+                SocketAddrV4::new(src_ip_addr, tcp.dst()),
+                SocketAddrV4::new(dst_ip_addr, tcp.src()),
+            )),
+            _ => None,
+        };
+
+        let result = self.try_send_ipv4(dst_ip_addr, src_ip_addr, transport, payload);
+        // Tear down the TCP connection if the injection keeps failing, the payload in the cache
+        // would be retransmitted endlessly otherwise
+        if let (Err(ref e), Some((src, dst))) = (&result, key) {
+            if is_transient_send_error(e) && self.states.contains_key(&(src, dst)) {
+                warn!(
+                    "tear down TCP {} -> {} after failing to send: {}",
+                    dst, src, e
+                );
+
+                // Clean up
+                self.clean_up(dst, src);
+                self.abandoned.push((src, dst));
+            }
+        }
+
+        result
+    }
+
+    fn try_send_ipv4(
         &mut self,
         dst_ip_addr: Ipv4Addr,
         src_ip_addr: Ipv4Addr,
         mut transport: Layers,
         payload: Option<&[u8]>,
     ) -> io::Result<()> {
+        // DSCP and ECN
+        let (dscp, ecn) = self.update_tcp_ecn(dst_ip_addr, src_ip_addr, &mut transport, payload);
+        let dscp = self.dscp.unwrap_or(dscp);
+
         // Fragmentation
         let size = &transport.len()
             + match payload {
@@ -853,7 +1432,7 @@ impl Forwarder {
             - Ipv4::minimum_len();
         if size <= mss {
             // IPv4
-            let ipv4 = Ipv4::new(
+            let mut ipv4 = Ipv4::new(
                 *self
                     .ipv4_identification_map
                     .get(&(src_ip_addr, dst_ip_addr))
@@ -863,6 +1442,8 @@ impl Forwarder {
                 src_ip_addr,
             )
             .unwrap();
+            ipv4.set_dscp(dscp);
+            ipv4.set_ecn(ecn);
 
             // Set IPv4 layer for checksum
             match transport {
@@ -873,10 +1454,7 @@ impl Forwarder {
 
             // Send
             self.send_ethernet(
-                *self
-                    .src_hardware_addr_map
-                    .get(&src_ip_addr)
-                    .unwrap_or(&pcap::HARDWARE_ADDR_UNSPECIFIED),
+                self.get_dst_hardware_addr(src_ip_addr),
                 Layers::Ipv4(ipv4),
                 Some(transport),
                 payload,
@@ -920,7 +1498,7 @@ impl Forwarder {
                 }
 
                 // IPv4
-                let ipv4 = if remain > 0 {
+                let mut ipv4 = if remain > 0 {
                     Ipv4::new_more_fragment(
                         *self
                             .ipv4_identification_map
@@ -945,13 +1523,12 @@ impl Forwarder {
                     )
                     .unwrap()
                 };
+                ipv4.set_dscp(dscp);
+                ipv4.set_ecn(ecn);
 
                 // Send
                 self.send_ethernet(
-                    *self
-                        .src_hardware_addr_map
-                        .get(&src_ip_addr)
-                        .unwrap_or(&pcap::HARDWARE_ADDR_UNSPECIFIED),
+                    self.get_dst_hardware_addr(src_ip_addr),
                     Layers::Ipv4(ipv4),
                     None,
                     Some(&buffer[n..n + length]),
@@ -967,6 +1544,53 @@ impl Forwarder {
         Ok(())
     }
 
+    fn update_tcp_ecn(
+        &mut self,
+        dst_ip_addr: Ipv4Addr,
+        src_ip_addr: Ipv4Addr,
+        transport: &mut Layers,
+        payload: Option<&[u8]>,
+    ) -> (u8, u8) {
+        let tcp = match transport {
+            Layers::Tcp(tcp) => tcp,
+            _ => return (0, 0),
+        };
+        let dst = SocketAddrV4::new(dst_ip_addr, tcp.src());
+        let src = SocketAddrV4::new(src_ip_addr, tcp.dst());
+        let state = match self.get_state_mut(dst, src) {
+            Some(state) => state,
+            None => return (0, 0),
+        };
+        if !state.ecn() || tcp.is_rst() {
+            return (state.dscp(), 0);
+        }
+
+        let is_payload = match payload {
+            Some(payload) => payload.len() > 0,
+            None => false,
+        };
+        if tcp.is_syn() || state.ece() {
+            tcp.set_ece();
+        }
+        if is_payload && state.cwr() {
+            tcp.set_cwr();
+            state.clear_cwr();
+        }
+
+        match is_payload {
+            true => (state.dscp(), ipv4::ECN_ECT0),
+            false => (state.dscp(), 0),
+        }
+    }
+
+    fn get_dst_hardware_addr(&self, src_ip_addr: Ipv4Addr) -> HardwareAddr {
+        select_hardware_addr(
+            &self.hardware_addr_rules,
+            &self.src_hardware_addr_map,
+            src_ip_addr,
+        )
+    }
+
     fn send_ethernet(
         &mut self,
         src_hardware_addr: HardwareAddr,
@@ -974,12 +1598,53 @@ impl Forwarder {
         transport: Option<Layers>,
         payload: Option<&[u8]>,
     ) -> io::Result<()> {
-        // Ethernet
-        let ethernet =
-            Ethernet::new(network.kind(), self.local_hardware_addr, src_hardware_addr).unwrap();
+        // Link
+        let link = match self.link_type {
+            // Frames captured in the Linux cooked link type are sent through the interface for
+            // output in Ethernet
+            LinkType::Ethernet | LinkType::LinuxSll => Layers::Ethernet(
+                Ethernet::new(network.kind(), self.local_hardware_addr, src_hardware_addr).unwrap(),
+            ),
+            LinkType::Null => match Loopback::new(network.kind()) {
+                Some(loopback) => Layers::Loopback(loopback),
+                // ARP is not available in the null/loopback link type
+                None => return Ok(()),
+            },
+        };
 
         // Indicator
-        let indicator = Indicator::new(Layers::Ethernet(ethernet), Some(network), transport);
+        let indicator = Indicator::new(link, Some(network), transport);
+
+        // Encapsulation and synthetic impairment
+        let tunnel = indicator
+            .ipv4()
+            .and_then(|ipv4| self.tunnels.get(&ipv4.dst()));
+        if tunnel.is_some() || self.impairment.is_some() {
+            let size = indicator.len();
+            let payload_size = payload.map_or(0, |payload| payload.len());
+            let mut frame = vec![0u8; max(size + payload_size, MINIMUM_FRAME_SIZE)];
+            match payload {
+                Some(payload) => {
+                    indicator.serialize_with_payload(&mut frame[..size + payload_size], payload)?
+                }
+                None => indicator.serialize(&mut frame[..size])?,
+            };
+            if let Some(tunnel) = tunnel {
+                let link_size = indicator.link().len();
+                let packet = tunnel.encapsulate(&frame[link_size..size + payload_size]);
+                frame.truncate(link_size);
+                frame.extend(packet);
+                if frame.len() < MINIMUM_FRAME_SIZE {
+                    frame.resize(MINIMUM_FRAME_SIZE, 0);
+                }
+            }
+
+            if self.impairment.is_some() {
+                return self.send_impaired(frame, indicator.brief());
+            }
+
+            return self.send_frame(&frame, &indicator.brief());
+        }
 
         // Send
         match payload {
@@ -988,16 +1653,114 @@ impl Forwarder {
         }
     }
 
+    fn send_impaired(&mut self, frame: Vec<u8>, brief: String) -> io::Result<()> {
+        let impairment = self.impairment.as_mut().unwrap();
+        if impairment
+            .rng
+            .gen_ratio(min(impairment.loss, 1000) as u32, 1000)
+        {
+            debug!("drop to pcap: {} (synthetic loss)", brief);
+
+            return Ok(());
+        }
+        let frames = match impairment.held.take() {
+            Some(held) => vec![(frame, brief), held],
+            None => {
+                if impairment
+                    .rng
+                    .gen_ratio(min(impairment.reorder, 1000) as u32, 1000)
+                {
+                    debug!("hold to pcap: {} (synthetic reorder)", brief);
+                    impairment.held = Some((frame, brief));
+
+                    return Ok(());
+                }
+
+                vec![(frame, brief)]
+            }
+        };
+
+        for (frame, brief) in frames {
+            self.send_frame(&frame, &brief)?;
+        }
+
+        Ok(())
+    }
+
+    fn send_frame(&mut self, frame: &[u8], brief: &str) -> io::Result<()> {
+        self.build_and_send(frame.len(), &mut |buffer| {
+            buffer.copy_from_slice(frame);
+        })?;
+        debug!("send to pcap: {} ({} Bytes)", brief, frame.len());
+
+        // Monitor
+        if let Some(traffic) = &self.traffic {
+            traffic.fetch_add(frame.len(), Ordering::Relaxed);
+        }
+        if let Some(count) = &self.count {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the count of retransmissions of all the TCP connections.
+    pub fn retrans_count(&self) -> usize {
+        self.retrans
+    }
+
+    /// Returns the count of duplicate ACKs suppressed of all the TCP connections.
+    pub fn suppressed_ack_count(&self) -> usize {
+        self.suppressed_acks
+    }
+
+    /// Builds a frame in the given size and sends it. Transient failures like a full buffer are
+    /// retried with backoff, and other failures return immediately.
+    fn build_and_send(&mut self, size: usize, func: &mut dyn FnMut(&mut [u8])) -> io::Result<()> {
+        let mut retries = 0;
+        let mut wait = SEND_RETRY_WAIT;
+        loop {
+            match self.tx.build_and_send(1, size, func).unwrap_or(Ok(())) {
+                Ok(_) => return Ok(()),
+                Err(e) => {
+                    if is_transient_send_error(&e) && retries < MAX_SEND_RETRIES {
+                        retries += 1;
+                        trace!("retry sending to pcap in {} ms: {}", wait, e);
+                        thread::sleep(Duration::from_millis(wait));
+                        wait = wait.checked_mul(2).unwrap_or(u64::MAX);
+                        continue;
+                    }
+
+                    self.send_failure = self.send_failure.checked_add(1).unwrap_or(usize::MAX);
+
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// Returns the count of frames failed to send, including frames failed after retries.
+    pub fn send_failure_count(&self) -> usize {
+        self.send_failure
+    }
+
+    /// Returns the approximate latency at the percentile in range 0 to 100 from TCP payload is
+    /// read from the proxy to it is sent to the source, or `None` if no payload is sent. The
+    /// latency includes the time the payload waits in the queue for the send window.
+    pub fn downstream_latency(&self, percentile: f64) -> Option<Duration> {
+        self.downstream_latency.percentile(percentile)
+    }
+
     fn send(&mut self, indicator: &Indicator) -> io::Result<()> {
         // Serialize and send
         let size = indicator.len();
         let buffer_size = max(size, MINIMUM_FRAME_SIZE);
         let mut result = None;
-        self.tx.build_and_send(1, buffer_size, &mut |buffer| {
+        self.build_and_send(buffer_size, &mut |buffer| {
             if let Err(e) = indicator.serialize(&mut buffer[..size]) {
                 result = Some(e);
             }
-        });
+        })?;
         match result {
             Some(e) => return Err(e),
             None => debug!("send to pcap: {} ({} Bytes)", indicator.brief(), size),
@@ -1019,15 +1782,13 @@ impl Forwarder {
         let size = indicator.len();
         let buffer_size = max(size + payload.len(), MINIMUM_FRAME_SIZE);
         let mut result = None;
-        self.tx
-            .build_and_send(1, buffer_size, &mut |buffer| {
-                if let Err(e) =
-                    indicator.serialize_with_payload(&mut buffer[..size + payload.len()], payload)
-                {
-                    result = Some(e);
-                }
-            })
-            .unwrap_or(Ok(()))?;
+        self.build_and_send(buffer_size, &mut |buffer| {
+            if let Err(e) =
+                indicator.serialize_with_payload(&mut buffer[..size + payload.len()], payload)
+            {
+                result = Some(e);
+            }
+        })?;
         match result {
             Some(e) => return Err(e),
             None => debug!(
@@ -1101,19 +1862,20 @@ impl ForwardDatagram for Forwarder {
     }
 }
 
+/// Returns if the error of sending to pcap is transient, like the buffer is full or the interface
+/// is busy. Other errors, like the interface is gone, are fatal.
+fn is_transient_send_error(e: &io::Error) -> bool {
+    match e.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted | io::ErrorKind::TimedOut => true,
+        // ENOBUFS in Linux, which is reported when the buffer of the packet socket is full
+        _ => cfg!(target_os = "linux") && e.raw_os_error() == Some(105),
+    }
+}
+
 fn disjoint_u32_range(main: (u32, u32), sub: (u32, u32)) -> Vec<(u32, u32)> {
-    let size_main = main
-        .1
-        .checked_sub(main.0)
-        .unwrap_or_else(|| main.1 + (u32::MAX - main.0)) as usize;
-    let diff_first = sub
-        .0
-        .checked_sub(main.0)
-        .unwrap_or_else(|| sub.0 + (u32::MAX - main.0)) as usize;
-    let diff_second = sub
-        .1
-        .checked_sub(main.1)
-        .unwrap_or_else(|| sub.1 + (u32::MAX - main.1)) as usize;
+    let size_main = seq_sub(main.1, main.0) as usize;
+    let diff_first = seq_sub(sub.0, main.0) as usize;
+    let diff_second = seq_sub(sub.1, main.1) as usize;
     let mut vector = Vec::with_capacity(2);
 
     if diff_first <= MAX_U32_WINDOW_SIZE {
@@ -1133,10 +1895,7 @@ fn disjoint_u32_range(main: (u32, u32), sub: (u32, u32)) -> Vec<(u32, u32)> {
     } else {
         if diff_second > MAX_U32_WINDOW_SIZE {
             // The distance between the main's left edge and the sub's right edge
-            let diff = sub
-                .1
-                .checked_sub(main.0)
-                .unwrap_or_else(|| sub.1 + (u32::MAX - main.0)) as usize;
+            let diff = seq_sub(sub.1, main.0) as usize;
             if diff > MAX_U32_WINDOW_SIZE {
                 // sub is in the left of the main
                 vector.push((main.0, main.1));
@@ -1152,11 +1911,51 @@ fn disjoint_u32_range(main: (u32, u32), sub: (u32, u32)) -> Vec<(u32, u32)> {
     vector
 }
 
+/// Represents the count of bytes in a line of hexdumps.
+const HEXDUMP_WIDTH: usize = 16;
+
+fn hexdump(name: &str, src: SocketAddrV4, dst: SocketAddrV4, sequence: u32, payload: &[u8]) {
+    debug!(
+        "dump {} of {} -> {} at {} ({} Bytes)",
+        name,
+        src,
+        dst,
+        sequence,
+        payload.len()
+    );
+    for (i, line) in payload.chunks(HEXDUMP_WIDTH).enumerate() {
+        let hex = line
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let ascii = line
+            .iter()
+            .map(|&b| match b {
+                0x20..=0x7e => b as char,
+                _ => '.',
+            })
+            .collect::<String>();
+        debug!(
+            "    {:08x}  {:<w$}  |{}|",
+            i * HEXDUMP_WIDTH,
+            hex,
+            ascii,
+            w = HEXDUMP_WIDTH * 3 - 1
+        );
+    }
+}
+
 /// Represents the threshold of TCP ACK duplicates before trigger a fast retransmission.
 const DUPLICATES_THRESHOLD: usize = 3;
 /// Represents the cool down time between 2 retransmissions.
 const RETRANS_COOL_DOWN: u128 = 200;
 
+/// Represents the ISN of TCP connections in the fixed ISN strategy.
+const FIXED_ISN: u32 = 0;
+/// Represents the step between ISNs of TCP connections in the sequential ISN strategy.
+const SEQUENTIAL_ISN_STEP: u32 = 64000;
+
 /// Represents if the TCP selective acknowledgment option is enabled.
 const ENABLE_SACK: bool = true;
 
@@ -1165,24 +1964,202 @@ const ENABLE_WSCALE: bool = true;
 /// Represents the max window scale of the receive window.
 const MAX_RECV_WSCALE: u8 = 8;
 
+/// Represents the maximum size of the receive caches of all the TCP connections. If the size is
+/// over the limitation, out of order payload will be dropped.
+const MAX_REASSEMBLY_SIZE: usize = 64 * 1024 * 1024;
+
+/// Represents the maximum count of consecutive failures of appending to the receive cache of a TCP
+/// connection for it is full before resetting the connection.
+const MAX_CACHE_FULL: usize = 8;
+
+/// Represents the interval of counting TCP segments out of the receive window of a TCP connection
+/// before resetting the connection.
+const OUT_OF_WINDOW_INTERVAL: u64 = 1000;
+
+/// Represents the minimum count of TCP segments out of the receive window of a TCP connection
+/// without progress before the connection is considered desynchronized.
+const DESYNC_OUT_OF_WINDOW: usize = 8;
+/// Represents the minimum time without progress of a TCP connection before the connection is
+/// considered desynchronized.
+const DESYNC_TIMEOUT: u64 = 3000;
+/// Represents the minimum duration of the head-of-line blocking of a TCP connection which is
+/// logged.
+const HOL_BLOCKED_THRESHOLD: u64 = 1000;
+
+/// Represents the maximum size of TCP payload held before the SYN of their connections arrives.
+const MAX_PENDING_SIZE: usize = 1024 * 1024;
+/// Represents the maximum time of TCP payload held before the SYN of their connections arrives.
+const PENDING_TIMEOUT: u64 = 200;
+
+/// Represents the maximum size of TCP payload held before connecting to the proxy in the deferred
+/// mode, which is a TLS record of the maximum size with its header.
+const MAX_DEFER_SIZE: usize = 16384 + 5;
+/// Represents the maximum time of TCP payload held before connecting to the proxy in the deferred
+/// mode.
+const DEFER_TIMEOUT: u64 = 1000;
+
+/// Represents the default maximum size of TCP payload not written to the proxy yet, beyond which
+/// delivering payload from the source pauses.
+const MAX_UNSENT_SIZE: usize = 1024 * 1024;
+/// Represents the percentage of the maximum size of TCP payload not written to the proxy yet,
+/// below which delivering payload from the source resumes.
+const UNSENT_RESUME_PERCENT: usize = 50;
+
+/// Represents the default initial congestion window in segments of the TCP connections.
+const INITIAL_WINDOW: usize = 1;
+
 /// Represents the max limit of UDP port for binding in local.
 const MAX_UDP_PORT: usize = 256;
+/// Represents the range of local UDP ports allocated in the lowest and hash port strategies.
+const UDP_PORT_RANGE: (u16, u16) = (61000, 65535);
+
+/// Represents the interval of saving the mappings of connections.
+const MAPPINGS_SAVE_INTERVAL: u64 = 5000;
+
+/// Represents the interval of ARP requests resolving the hardware addresses of sources.
+const ARP_RESOLVE_INTERVAL: u64 = 1000;
+/// Represents the timeout of resolving the hardware addresses of sources before falling back.
+const ARP_RESOLVE_TIMEOUT: u64 = 5000;
+/// Represents the interval of ARP requests refreshing the resolved hardware addresses of sources.
+const ARP_REFRESH_INTERVAL: u64 = 60000;
 
 /// Represents a channel redirect traffic to the proxy or loopback to the source in pcap.
 pub struct Redirector {
     tx: Arc<Mutex<Forwarder>>,
-    tx_src_hardware_addr_set_ip_addr_set: HashSet<Ipv4Addr>,
+    /// Represents the hardware addresses of sources set to the forwarder.
+    tx_src_hardware_addr_map: HashMap<Ipv4Addr, HardwareAddr>,
+    /// Represents the forwarders of the other shards of the interface, which the hardware
+    /// addresses of sources are also set to.
+    peers: Vec<Arc<Mutex<Forwarder>>>,
     src_ip_addr: Ipv4Network,
     local_ip_addr: Ipv4Addr,
     gw_ip_addr: Option<Ipv4Addr>,
     proxy: ProxyConfig,
     streams: HashMap<(SocketAddrV4, SocketAddrV4), StreamWorker>,
     states: HashMap<(SocketAddrV4, SocketAddrV4), TcpRxState>,
+    /// Represents the total allocated size of the receive caches.
+    reassembly_size: usize,
+    /// Represents the count of failures of appending to the receive caches for they are full.
+    cache_full: usize,
+    /// Represents the count of TCP segments ignored for they are out of the receive windows.
+    out_of_window: usize,
+    /// Represents the count of TCP segments out of the receive window of a TCP connection in the
+    /// interval before resetting the connection.
+    rst_out_of_window: Option<usize>,
+    /// Represents the count of TCP connections detected desynchronized.
+    desync: usize,
+    /// Represents the total duration of the head-of-line blocking of all the TCP connections, and
+    /// the count of the blocking over the threshold.
+    hol_blocked: (Duration, usize),
+    rst_desync: bool,
+    /// Represents the soft limit of the receive caches in percentage of the capacity.
+    cache_soft_limit: usize,
+    /// Represents if the buffers of the caches are allocated when connections are set up.
+    cache_alloc: bool,
+    strict_window: bool,
+    max_sacks: usize,
+    ack_segments: usize,
+    ack_delay: u64,
+    max_dup_acks: Option<usize>,
+    /// Represents the initial receive window in bytes of TCP connections.
+    initial_recv_window: Option<usize>,
+    unordered: bool,
+    /// Represents the time in milliseconds TCP payload after an unfilled range is held before the
+    /// range is skipped.
+    reassembly_deadline: Option<u64>,
+    /// Represents if TCP connections with the MD5 signature option are accepted.
+    md5_sig: bool,
+    /// Represents the destination ports of TCP connections prepended by a PROXY protocol header.
+    proxy_protocol: Vec<u16>,
+    /// Represents the first bytes of TCP connections buffered until the PROXY protocol header is
+    /// complete.
+    proxy_headers: HashMap<(SocketAddrV4, SocketAddrV4), Vec<u8>>,
+    /// Represents the destination ports of TCP connections connecting to the proxy after the first
+    /// payload arrives.
+    defer_connect: Vec<u16>,
+    /// Represents the TCP connections not connected to the proxy yet and the instants they are
+    /// established.
+    deferred: HashMap<(SocketAddrV4, SocketAddrV4), Instant>,
+    /// Represents the time in milliseconds a TCP connection without payload in either direction
+    /// is reaped after.
+    idle_timeout: Option<u64>,
+    /// Represents the maximum size of TCP payload not written to the proxy yet of a connection.
+    max_unsent: usize,
+    /// Represents the TCP connections paused for the proxy does not read as fast as the source
+    /// sends.
+    throttled: HashSet<(SocketAddrV4, SocketAddrV4)>,
+    isn: IsnStrategy,
+    port_strategy: PortStrategy,
+    /// Represents the ISN of the next TCP connection in the sequential ISN strategy.
+    next_isn: u32,
+    /// Represents the instant the frame being handled is captured.
+    captured: Instant,
+    /// Represents the latencies from TCP payload is captured to it is sent to the proxy.
+    upstream_latency: LatencyHistogram,
+    ecn: bool,
+    rst_unknown: bool,
+    /// Represents the initial congestion window in segments.
+    iw: usize,
+    /// Represents the TCP connection whose payload sent to the proxy is dumped.
+    hexdump: Option<(SocketAddrV4, SocketAddrV4)>,
+    reconnect: bool,
+    /// Represents the delay in milliseconds coalescing small payload sent to the proxy.
+    coalesce: Option<u64>,
+    snaplen: Option<usize>,
+    poll: PollMode,
+    /// Represents the encapsulation of traffic which is decapsulated before handled.
+    encapsulation: Option<Encapsulation>,
+    /// Represents the instant timers are fired last time.
+    timers_fired: Instant,
+    /// Represents the local hardware address of which frames are ignored.
+    inbound_only: Option<HardwareAddr>,
+    link_type: LinkType,
+    /// Represents the watchdog of the service manager notified in each wakeup.
+    #[cfg(all(target_os = "linux", feature = "systemd"))]
+    watchdog: Option<systemd::Watchdog>,
+    /// Represents the rules of destinations bypassing the proxy, in the form of (network, port).
+    bypass: Vec<(Ipv4Network, Option<u16>)>,
+    /// Represents the destination ports of TCP traffic proxied, empty represents all the ports.
+    ports: Vec<u16>,
+    /// Represents the rules of the cache size of TCP connections by destination, in the form of
+    /// (network, port, size).
+    cache_sizes: Vec<(Ipv4Network, Option<u16>, usize)>,
+    mirror: Option<Arc<Mirror>>,
+    /// Represents the rules of destinations of TCP connections mirrored, in the form of
+    /// (network, port).
+    mirror_rules: Vec<(Ipv4Network, Option<u16>)>,
+    /// Represents the mirrors of TCP connections mirrored.
+    mirrors: HashMap<(SocketAddrV4, SocketAddrV4), Arc<Mirror>>,
     datagrams: HashMap<u16, DatagramWorker>,
     /// Represents the map mapping a source port to a local port.
     datagram_map: HashMap<SocketAddrV4, u16>,
     /// Represents the LRU mapping a local port to a source port.
     udp_lru: LruCache<u16, SocketAddrV4>,
+    /// Represents the path of the file persisting the mappings of connections.
+    mappings_path: Option<PathBuf>,
+    /// Represents the mappings of connections restored from the last run.
+    restored: Mappings,
+    /// Represents the TCP segments of unknown connections held before their SYN arrives.
+    pending: Pending<(SocketAddrV4, SocketAddrV4), (Tcp, Vec<u8>)>,
+    mappings_saved: Instant,
+    /// Represents the sources whose hardware addresses are resolved via ARP.
+    resolve: Vec<Ipv4Addr>,
+    /// Represents the hardware address of sources which cannot be resolved in the timeout.
+    resolve_fallback: Option<HardwareAddr>,
+    resolve_started: Option<Instant>,
+    resolve_timedout: bool,
+    arp_requested: Option<Instant>,
+    flow_log: Option<Arc<Mutex<FlowLog>>>,
+    /// Represents the records of TCP connections written to the flow log when they close.
+    flows: HashMap<(SocketAddrV4, SocketAddrV4), FlowRecord>,
+    /// Represents the algorithm of the digests of payload of TCP connections in the flow log.
+    digest: Option<DigestAlgorithm>,
+    accounting: Option<FlowAccounting>,
+    byte_counts: HashMap<(SocketAddrV4, SocketAddrV4), Arc<ByteCounts>>,
+    flow_dump: Option<FlowDump>,
+    /// Represents the queue between the capture thread and the capture loop, and the count of
+    /// frames dropped on overflow of the queue last reported.
+    capture_queue: Option<(FrameQueue, usize)>,
     defrag: Defraggler,
 }
 
@@ -1197,16 +2174,83 @@ impl Redirector {
     ) -> Redirector {
         let redirector = Redirector {
             tx,
-            tx_src_hardware_addr_set_ip_addr_set: HashSet::new(),
+            tx_src_hardware_addr_map: HashMap::new(),
+            peers: Vec::new(),
             src_ip_addr,
             local_ip_addr,
             gw_ip_addr,
             proxy,
             streams: HashMap::new(),
             states: HashMap::new(),
+            reassembly_size: 0,
+            cache_full: 0,
+            out_of_window: 0,
+            rst_out_of_window: None,
+            desync: 0,
+            hol_blocked: (Duration::from_secs(0), 0),
+            rst_desync: false,
+            cache_soft_limit: tcp::SOFT_LIMIT_PERCENT,
+            cache_alloc: false,
+            strict_window: false,
+            max_sacks: tcp::MAX_SACKS,
+            ack_segments: tcp::ACK_SEGMENTS,
+            ack_delay: tcp::ACK_DELAY,
+            max_dup_acks: None,
+            initial_recv_window: None,
+            unordered: false,
+            reassembly_deadline: None,
+            md5_sig: false,
+            proxy_protocol: Vec::new(),
+            proxy_headers: HashMap::new(),
+            defer_connect: Vec::new(),
+            deferred: HashMap::new(),
+            idle_timeout: None,
+            max_unsent: MAX_UNSENT_SIZE,
+            throttled: HashSet::new(),
+            isn: IsnStrategy::Random,
+            port_strategy: PortStrategy::Ephemeral,
+            next_isn: FIXED_ISN,
+            captured: Instant::now(),
+            upstream_latency: LatencyHistogram::new(),
+            ecn: false,
+            rst_unknown: false,
+            iw: INITIAL_WINDOW,
+            hexdump: None,
+            reconnect: false,
+            coalesce: None,
+            snaplen: None,
+            poll: PollMode::Block,
+            encapsulation: None,
+            timers_fired: Instant::now(),
+            inbound_only: None,
+            link_type: LinkType::Ethernet,
+            #[cfg(all(target_os = "linux", feature = "systemd"))]
+            watchdog: systemd::Watchdog::new(),
+            bypass: Vec::new(),
+            ports: Vec::new(),
+            cache_sizes: Vec::new(),
+            mirror: None,
+            mirror_rules: Vec::new(),
+            mirrors: HashMap::new(),
             datagrams: HashMap::new(),
             datagram_map: HashMap::new(),
             udp_lru: LruCache::new(MAX_UDP_PORT),
+            mappings_path: None,
+            restored: Mappings::new(),
+            pending: Pending::new(MAX_PENDING_SIZE, PENDING_TIMEOUT),
+            mappings_saved: Instant::now(),
+            resolve: Vec::new(),
+            resolve_fallback: None,
+            resolve_started: None,
+            resolve_timedout: false,
+            arp_requested: None,
+            flow_log: None,
+            flows: HashMap::new(),
+            digest: None,
+            accounting: None,
+            byte_counts: HashMap::new(),
+            flow_dump: None,
+            capture_queue: None,
             defrag: Defraggler::new(),
         };
         if let Some(gw_ip_addr) = gw_ip_addr {
@@ -1216,6 +2260,474 @@ impl Redirector {
         redirector
     }
 
+    /// Sets if the ECN is negotiated in TCP connections.
+    pub fn set_ecn(&mut self, ecn: bool) {
+        self.ecn = ecn;
+    }
+
+    /// Sets if an RST is sent for TCP segments of unknown connections, so the source will fail
+    /// fast instead of retransmitting until timed out.
+    pub fn set_rst_unknown(&mut self, rst_unknown: bool) {
+        self.rst_unknown = rst_unknown;
+    }
+
+    /// Sets the initial congestion window in segments of TCP connections.
+    pub fn set_initial_window(&mut self, iw: usize) {
+        self.iw = iw;
+    }
+
+    /// Sets if TCP connections reconnect to the proxy and resume after the proxied streams are
+    /// reset.
+    pub fn set_reconnect(&mut self, reconnect: bool) {
+        self.reconnect = reconnect;
+    }
+
+    /// Sets the delay in milliseconds coalescing small payload of TCP connections before sending
+    /// to the proxy. The payload is sent when the delay elapses, the coalesced payload is large
+    /// enough or a TCP push arrives.
+    pub fn set_coalesce(&mut self, coalesce: Option<u64>) {
+        self.coalesce = coalesce;
+    }
+
+    /// Sets the snapshot length of frames. Frames are truncated to the length after captured, and
+    /// truncated frames will not be handled.
+    pub fn set_snaplen(&mut self, snaplen: Option<usize>) {
+        self.snaplen = snaplen;
+    }
+
+    /// Sets the mode of polling the capture, which must match the read timeout the capture is
+    /// opened with. In the busy mode, reads which time out are retried at once in a spin instead
+    /// of sleeping, and timers are fired at most once in the minimum interval between reads.
+    pub fn set_poll(&mut self, poll: PollMode) {
+        self.poll = poll;
+    }
+
+    /// Sets the encapsulation of traffic from sources behind tunnels. Frames carrying a packet in
+    /// the encapsulation are decapsulated before handled, so the inner traffic is redirected,
+    /// and packets sent back are encapsulated in the same tunnel. Other frames are handled as
+    /// usual.
+    pub fn set_encapsulation(&mut self, encapsulation: Option<Encapsulation>) {
+        self.encapsulation = encapsulation;
+    }
+
+    /// Sets the link-layer type of frames captured and sent. Frames of the null/loopback link type
+    /// carry no hardware address, so ARP is not available and only IPv4 is handled. Frames of the
+    /// Linux cooked link type are captured only, and frames are sent in Ethernet instead.
+    pub fn set_link_type(&mut self, link_type: LinkType) {
+        self.link_type = link_type;
+        self.tx.lock().unwrap().set_link_type(link_type);
+    }
+
+    /// Sets if only inbound frames are handled. The capture does not support filtering frames by
+    /// direction, so frames sent from the local hardware address, which are injected by
+    /// pcap2socks or sent by the OS, are ignored instead.
+    pub fn set_inbound_only(&mut self, inbound_only: bool) {
+        self.inbound_only = match inbound_only {
+            true => Some(self.tx.lock().unwrap().get_local_hardware_addr()),
+            false => None,
+        };
+    }
+
+    /// Sets the strategy of allocating local UDP ports bound for sources. Ports are ephemeral by
+    /// default. The lowest and hash strategies allocate ports in a fixed range, skipping ports
+    /// bound for other sources, so a source is likely bound to the same port across runs, which
+    /// eases testing and correlating logs. A port in use by another process falls back to an
+    /// ephemeral port. Local UDP ports restored from the mappings are still preferred.
+    pub fn set_port_strategy(&mut self, strategy: PortStrategy) {
+        self.port_strategy = strategy;
+    }
+
+    /// Sets the path of the file persisting the mappings of connections, which are restored from
+    /// the file if it exists. TCP connections restored will not be reset as unknown connections,
+    /// and local UDP ports restored are preferred in binding for their sources. The states of
+    /// the connections cannot be restored, so restored TCP connections are not resumed.
+    pub fn set_mappings_path(&mut self, path: Option<PathBuf>) -> io::Result<()> {
+        self.restored = match &path {
+            Some(path) => match Mappings::load(path) {
+                Ok(mappings) => mappings,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => Mappings::new(),
+                Err(e) => return Err(e),
+            },
+            None => Mappings::new(),
+        };
+        if let Some(path) = &path {
+            debug!(
+                "restore {} TCP and {} UDP mappings from {}",
+                self.restored.tcp_len(),
+                self.restored.udp_len(),
+                path.display()
+            );
+        }
+        self.mappings_path = path;
+
+        Ok(())
+    }
+
+    /// Sets the sources whose hardware addresses are resolved via ARP at startup, and the
+    /// hardware address used if they cannot be resolved in the timeout. Resolved hardware
+    /// addresses are refreshed periodically, and updated on ARP replies.
+    pub fn set_resolve(&mut self, resolve: Vec<Ipv4Addr>, fallback: Option<HardwareAddr>) {
+        self.resolve = resolve;
+        self.resolve_fallback = fallback;
+    }
+
+    /// Sets the forwarders of the other shards of the interface. Hardware addresses of sources
+    /// resolved or learned via ARP, which is handled by this redirector only, are also set to
+    /// these forwarders.
+    pub fn set_peers(&mut self, peers: Vec<Arc<Mutex<Forwarder>>>) {
+        self.peers = peers;
+    }
+
+    /// Sets the accounting, which counts the bytes of each TCP connection while it is open. The
+    /// accounting can be cloned and polled from other threads to take the byte counts of
+    /// long-lived connections periodically without tearing them down.
+    pub fn set_accounting(&mut self, accounting: Option<FlowAccounting>) {
+        self.accounting = accounting;
+    }
+
+    /// Sets the flow log, which writes a record of each TCP connection when it closes, containing
+    /// the statistics of the connection and the reason of closing. The flow log may be shared
+    /// between redirectors.
+    pub fn set_flow_log(&mut self, flow_log: Option<Arc<Mutex<FlowLog>>>) {
+        self.tx.lock().unwrap().set_keep_closed(flow_log.is_some());
+        self.flow_log = flow_log;
+    }
+
+    /// Sets the dump of the flow table, which writes a snapshot of all the open TCP connections
+    /// each time it is requested. The snapshot is taken in the capture loop on the next wakeup
+    /// and written in another thread.
+    pub fn set_flow_dump(&mut self, flow_dump: Option<FlowDump>) {
+        self.flow_dump = flow_dump;
+    }
+
+    /// Sets the queue between the capture thread and the capture loop, which the receive half
+    /// passed to `open` takes frames from, for monitoring. Frames dropped on overflow of the queue
+    /// are reported periodically.
+    pub fn set_capture_queue(&mut self, queue: Option<FrameQueue>) {
+        self.capture_queue = queue.map(|queue| {
+            let dropped = queue.dropped_count();
+            (queue, dropped)
+        });
+    }
+
+    /// Sets the algorithm of the digests of payload sent in both directions of TCP connections,
+    /// which are computed incrementally as payload is forwarded and written to the flow log when
+    /// the connections close. The digests are computed only if the flow log is set.
+    pub fn set_digest(&mut self, digest: Option<DigestAlgorithm>) {
+        self.digest = digest;
+    }
+
+    /// Sets the rules of destinations bypassing the proxy, in the form of (network, port). A port
+    /// of `None` matches any port. Traffic to the matched destinations will be ignored and left
+    /// to the OS, and will not be proxied.
+    pub fn set_bypass(&mut self, bypass: Vec<(Ipv4Network, Option<u16>)>) {
+        self.bypass = bypass;
+    }
+
+    /// Sets the destination ports of TCP traffic proxied. TCP traffic to other ports will be
+    /// ignored and left to the OS the same as bypassed traffic, and an empty list proxies all the
+    /// ports. Traffic matching the bypass rules is still bypassed even if its port is listed.
+    pub fn set_ports(&mut self, ports: Vec<u16>) -> io::Result<()> {
+        if ports.contains(&0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "port 0 cannot be proxied",
+            ));
+        }
+        self.ports = ports;
+
+        Ok(())
+    }
+
+    /// Sets the rules of the cache size of TCP connections by destination, in the form of
+    /// (network, port, size). A port of `None` matches any port, and the first matched rule
+    /// applies. The size overrides the default capacity of both the receive and the send caches
+    /// of a connection, but is still limited by the maximum receive window. Receive caches are
+    /// bounded by the reassembly size in total regardless of the rules.
+    pub fn set_cache_sizes(
+        &mut self,
+        cache_sizes: Vec<(Ipv4Network, Option<u16>, usize)>,
+    ) -> io::Result<()> {
+        if cache_sizes.iter().any(|(_, _, size)| *size == 0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cache size 0 cannot hold any payload",
+            ));
+        }
+        self.cache_sizes = cache_sizes;
+
+        Ok(())
+    }
+
+    /// Sets the mirror and the rules of destinations of TCP connections mirrored, in the form of
+    /// (network, port). A port of `None` matches any port. Payload of the matched connections is
+    /// teed to the mirror in both directions in addition to being forwarded, which doubles the
+    /// write load of these connections. The mirror may be shared between redirectors.
+    pub fn set_mirror(
+        &mut self,
+        mirror: Option<Arc<Mirror>>,
+        rules: Vec<(Ipv4Network, Option<u16>)>,
+    ) {
+        self.mirror = mirror;
+        self.mirror_rules = rules;
+    }
+
+    /// Sets the TCP connection whose payload is dumped in hex, in the form of (source,
+    /// destination). Both the payload sent to the proxy and the payload sent to the source are
+    /// dumped.
+    pub fn set_hexdump(&mut self, hexdump: Option<(SocketAddrV4, SocketAddrV4)>) {
+        self.hexdump = hexdump;
+        self.tx.lock().unwrap().set_hexdump(hexdump);
+    }
+
+    /// Sets the DSCP of all the IPv4 packets sent to the source, overriding the DSCP preserved
+    /// from the source.
+    pub fn set_dscp(&mut self, dscp: Option<u8>) {
+        self.tx.lock().unwrap().set_dscp(dscp);
+    }
+
+    /// Sets the maximum count of consecutive retransmissions due to timeout before a TCP
+    /// connection is abandoned.
+    pub fn set_max_retrans(&mut self, max_retrans: usize) {
+        self.tx.lock().unwrap().set_max_retrans(max_retrans);
+    }
+
+    /// Sets if sending is scheduled among TCP connections by weight.
+    pub fn set_scheduler(&mut self, is_scheduled: bool) {
+        self.tx.lock().unwrap().set_scheduler(is_scheduled);
+    }
+
+    /// Sets the rules of the weight of TCP connections in the scheduler, by destination in the
+    /// form of (network, port, weight) and by DSCP in the form of (minimum DSCP, weight).
+    pub fn set_weights(
+        &mut self,
+        weights: Vec<(Ipv4Network, Option<u16>, usize)>,
+        dscp_weights: Vec<(u8, usize)>,
+    ) -> io::Result<()> {
+        self.tx.lock().unwrap().set_weights(weights, dscp_weights)
+    }
+
+    /// Sets the soft limit of the receive caches of TCP connections in percentage of the
+    /// capacity. The advertised window shrinks once a receive cache reaches the soft limit.
+    pub fn set_cache_soft_limit(&mut self, percent: usize) {
+        self.cache_soft_limit = percent;
+    }
+
+    /// Sets if the buffers of the caches of TCP connections are allocated in the initial size
+    /// when connections are set up, instead of in the first appending. Allocating in advance
+    /// saves a reallocation of connections transferring bulk payload, but costs memory of short
+    /// connections.
+    pub fn set_cache_alloc(&mut self, alloc: bool) {
+        self.cache_alloc = alloc;
+    }
+
+    /// Sets if payload of TCP segments beyond the advertised window is dropped, instead of being
+    /// buffered up to the capacity of the receive cache.
+    pub fn set_strict_window(&mut self, strict_window: bool) {
+        self.strict_window = strict_window;
+    }
+
+    /// Sets the count of TCP segments out of the receive window of a TCP connection in an interval
+    /// before resetting the connection. A sustained stream of these segments is abnormal, and the
+    /// connection is likely broken or malicious. `None` represents these segments are always
+    /// ignored.
+    pub fn set_rst_out_of_window(&mut self, rst_out_of_window: Option<usize>) {
+        self.rst_out_of_window = rst_out_of_window;
+    }
+
+    /// Sets if TCP connections whose sequences are desynchronized from the source are reset. A
+    /// connection is considered desynchronized if it keeps receiving TCP segments out of the
+    /// receive window without progress, and would otherwise stall silently. The desynchronization
+    /// is always logged with the sequences of both sides.
+    pub fn set_rst_desync(&mut self, rst_desync: bool) {
+        self.rst_desync = rst_desync;
+    }
+
+    /// Sets the maximum count of SACK blocks reported in a TCP segment. The count is further
+    /// limited by the space of TCP options.
+    pub fn set_max_sacks(&mut self, max_sacks: usize) {
+        self.max_sacks = max_sacks;
+    }
+
+    /// Sets the ACK frequency of TCP connections. An ACK is sent after the maximum count of
+    /// segments received in order, or after the maximum delay in milliseconds since the first of
+    /// them. Window updates and segments out of order are still acknowledged at once.
+    pub fn set_ack_frequency(&mut self, segments: usize, delay: u64) -> io::Result<()> {
+        if segments == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "ACK segments must be at least 1",
+            ));
+        }
+        if delay >= MAX_ACK_DELAY {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("ACK delay must be less than {} ms", MAX_ACK_DELAY),
+            ));
+        }
+        self.ack_segments = segments;
+        self.ack_delay = delay;
+
+        Ok(())
+    }
+
+    /// Sets the maximum count of duplicate ACKs of an acknowledgement sent at once of TCP
+    /// connections. By default, each segment out of order or retransmitted is acknowledged at
+    /// once as RFC 5681 recommends, which floods the reverse path with an ACK per segment under
+    /// heavy reordering or loss. Beyond the maximum, duplicate ACKs of the same acknowledgement
+    /// are suppressed and coalesced into a delayed ACK carrying the latest SACK blocks. The
+    /// maximum cannot be less than 3, which the source needs for the fast retransmit. `None`
+    /// represents duplicate ACKs are never suppressed.
+    pub fn set_max_dup_acks(&mut self, max_dup_acks: Option<usize>) -> io::Result<()> {
+        if let Some(max_dup_acks) = max_dup_acks {
+            if max_dup_acks < DUPLICATES_THRESHOLD {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "maximum duplicate ACKs must be at least {}",
+                        DUPLICATES_THRESHOLD
+                    ),
+                ));
+            }
+        }
+        self.max_dup_acks = max_dup_acks;
+
+        Ok(())
+    }
+
+    /// Sets if payload of TCP connections is forwarded to the proxy in the order of arrival
+    /// instead of being reassembled. Payload after an unfilled range is forwarded at once with the
+    /// range skipped, and payload arriving late in the range is forwarded when it arrives, so the
+    /// byte stream is not guaranteed anymore.
+    pub fn set_unordered(&mut self, unordered: bool) {
+        self.unordered = unordered;
+    }
+
+    /// Sets the time in milliseconds TCP payload after an unfilled range is held before the range
+    /// is skipped and the payload is forwarded to the proxy. The payload missing in the range is
+    /// never forwarded, which breaks the reliability of TCP, but may suit latency-sensitive
+    /// streams. `None` represents the range is never skipped.
+    pub fn set_reassembly_deadline(&mut self, deadline: Option<u64>) {
+        self.reassembly_deadline = deadline;
+    }
+
+    /// Sets if TCP connections with the MD5 signature option are accepted instead of being
+    /// dropped. The signature cannot be recomputed without the key, and pcap2socks terminates the
+    /// connections and rewrites the addresses, so the signature is invalidated and not carried in
+    /// either direction. Accepted connections are only usable if the source does not enforce the
+    /// signature. The policy applies to every segment of the connections.
+    pub fn set_md5_sig(&mut self, md5_sig: bool) {
+        self.md5_sig = md5_sig;
+    }
+
+    /// Sets the destination ports of TCP connections whose first bytes are a PROXY protocol
+    /// header in v1 or v2 format, like those from a load balancer. The header is stripped before
+    /// the payload is forwarded to the proxy, and the original source in it is logged.
+    /// Connections without a valid header are reset.
+    pub fn set_proxy_protocol(&mut self, ports: Vec<u16>) {
+        self.proxy_protocol = ports;
+    }
+
+    /// Sets the destination ports of TCP connections which connect to the proxy after the first
+    /// payload arrives instead of at the SYN, for protocols where the source speaks first. The
+    /// first payload is held and peeked for the server name in a TLS ClientHello before
+    /// connecting, at the cost of a delay in setting up the connection.
+    pub fn set_defer_connect(&mut self, ports: Vec<u16>) {
+        self.defer_connect = ports;
+    }
+
+    /// Sets the time in milliseconds a TCP connection without payload in either direction is
+    /// reaped after. The time is reset by any payload delivered from the source or queued from
+    /// the proxy, however small, so connections kept alive by the application-layer, like HTTP/2
+    /// or WebSocket pings, are not reaped. `None` represents connections are never reaped for
+    /// idling.
+    pub fn set_idle_timeout(&mut self, timeout: Option<u64>) {
+        self.idle_timeout = timeout;
+    }
+
+    /// Sets the maximum size of payload of a TCP connection sent but not written to the proxy yet.
+    /// Writing to the proxy waits until its socket is writable, so when the proxy reads slower
+    /// than the source sends, payload piles up. Beyond the size, payload from the source is held
+    /// instead of being delivered, and the window advertised shrinks as the payload held grows,
+    /// which propagates the backpressure of the proxy to the source. Delivering resumes when the
+    /// size falls below half of it.
+    pub fn set_max_unsent(&mut self, size: usize) -> io::Result<()> {
+        if size == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "maximum unsent size cannot be 0",
+            ));
+        }
+        self.max_unsent = size;
+
+        Ok(())
+    }
+
+    /// Sets the strategy of generating ISNs of TCP connections. ISNs are random by default. The
+    /// fixed and sequential strategies make captures reproducible for testing and debugging, but
+    /// predictable ISNs allow an off-path attacker to spoof or inject segments into connections
+    /// as RFC 6528 warns, so they should never be used in untrusted networks.
+    pub fn set_isn_strategy(&mut self, strategy: IsnStrategy) {
+        self.isn = strategy;
+        self.next_isn = FIXED_ISN;
+    }
+
+    fn generate_isn(&mut self) -> u32 {
+        match self.isn {
+            // The thread-local generator is a CSPRNG seeded by the operating system
+            IsnStrategy::Random => rand::thread_rng().gen::<u32>(),
+            IsnStrategy::Fixed => FIXED_ISN,
+            IsnStrategy::Sequential => {
+                let isn = self.next_isn;
+                self.next_isn = seq_add(isn, SEQUENTIAL_ISN_STEP);
+
+                isn
+            }
+        }
+    }
+
+    /// Sets the maximum count of SOCKS handshakes in flight, including reconnections. A new TCP
+    /// connection waits for a slot before its SYN/ACK is sent, and is reset if no slot is freed
+    /// in time. `None` represents handshakes are not limited.
+    pub fn set_max_handshakes(&mut self, max_handshakes: Option<usize>) -> io::Result<()> {
+        if max_handshakes == Some(0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "maximum handshakes 0 cannot connect to the proxy",
+            ));
+        }
+        self.proxy.set_max_handshakes(max_handshakes);
+
+        Ok(())
+    }
+
+    /// Sets the initial receive window in bytes of TCP connections, which is advertised before
+    /// any payload is received, instead of the free space of the receive cache. The window is
+    /// further limited by the capacity of the receive cache of each connection.
+    pub fn set_initial_recv_window(&mut self, window: Option<usize>) -> io::Result<()> {
+        if let Some(window) = window {
+            let max_window = tcp::max_recv_window(MAX_RECV_WSCALE);
+            if window > max_window {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "initial receive window {} is over the maximum cache size {}",
+                        window, max_window
+                    ),
+                ));
+            }
+        }
+        self.initial_recv_window = window;
+
+        Ok(())
+    }
+
+    /// Sets the synthetic loss and reorder rates in per mille of frames sent to the source for
+    /// testing, with an optional seed of the random number generator for reproducibility.
+    pub fn set_impairment(&mut self, loss: u16, reorder: u16, seed: Option<u64>) {
+        self.tx.lock().unwrap().set_impairment(loss, reorder, seed);
+    }
+
     /// Opens an `Interface` for redirection.
     pub async fn open(&mut self, rx: &mut Receiver) -> io::Result<()> {
         self.open_monitored(rx, None, None, None).await
@@ -1238,12 +2750,62 @@ impl Redirector {
             // Monitor
             if let Some(is_running) = &is_running {
                 if !is_running.load(Ordering::Relaxed) {
+                    self.save_mappings();
+
                     return Ok(());
                 }
             }
+            // Fire due timers on each wakeup, whether a frame arrived or the capture timed out.
+            // Busy polling wakes up constantly, so timers are fired at most once in an interval
+            if self.poll == PollMode::Block
+                || self.timers_fired.elapsed() >= Duration::from_millis(TIMEDOUT_WAIT)
+            {
+                self.timers_fired = Instant::now();
+                self.handle_timers();
+                self.handle_deferred_timedout().await;
+            }
+            // Notify the watchdog the capture loop is alive
+            #[cfg(all(target_os = "linux", feature = "systemd"))]
+            {
+                if let Some(ref mut watchdog) = self.watchdog {
+                    if let Err(ref e) = watchdog.notify() {
+                        warn!("notify watchdog: {}", e);
+                    }
+                }
+            }
+
+            let instant = Instant::now();
             match rx.next() {
                 Ok(frame) => {
-                    if let Some(ref indicator) = Indicator::from(frame) {
+                    self.captured = Instant::now();
+                    let frame = match self.snaplen {
+                        Some(snaplen) => &frame[..min(frame.len(), snaplen)],
+                        None => frame,
+                    };
+                    let decapsulated;
+                    let frame = match self.decapsulate(frame) {
+                        Some(inner_frame) => {
+                            decapsulated = inner_frame;
+                            decapsulated.as_slice()
+                        }
+                        None => frame,
+                    };
+                    let indicator = match self.link_type {
+                        LinkType::Ethernet => Indicator::from(frame),
+                        LinkType::Null => Indicator::from_loopback(frame),
+                        LinkType::LinuxSll => Indicator::from_sll(frame),
+                    };
+                    if let Some(ref indicator) = indicator {
+                        // Ignore outbound frames, which are marked in the Linux cooked link type
+                        if let Some(hardware_addr) = self.inbound_only {
+                            if indicator
+                                .ethernet()
+                                .map_or(false, |ethernet| ethernet.src() == hardware_addr)
+                                || indicator.sll().map_or(false, |sll| sll.is_outgoing())
+                            {
+                                continue;
+                            }
+                        }
                         if let Some(t) = indicator.network_kind() {
                             let traffic = match &traffic {
                                 Some(traffic) => Some(Arc::clone(traffic)),
@@ -1273,7 +2835,17 @@ impl Redirector {
                 }
                 Err(e) => {
                     if e.kind() == io::ErrorKind::TimedOut {
-                        thread::sleep(Duration::from_millis(TIMEDOUT_WAIT));
+                        match self.poll {
+                            PollMode::Block => {
+                                // Avoid spinning if the capture times out too soon
+                                let elapsed = instant.elapsed();
+                                let wait = Duration::from_millis(TIMEDOUT_WAIT);
+                                if elapsed < wait {
+                                    thread::sleep(wait - elapsed);
+                                }
+                            }
+                            PollMode::Busy => hint::spin_loop(),
+                        }
                         continue;
                     }
                     return Err(e);
@@ -1282,12 +2854,377 @@ impl Redirector {
         }
     }
 
+    fn handle_timers(&mut self) {
+        // Resolve hardware addresses
+        if !self.resolve.is_empty() {
+            if let Err(ref e) = self.resolve_hardware_addrs() {
+                warn!("resolve hardware addresses: {}", e);
+            }
+        }
+
+        // Reset or drop TCP segments held before SYN too long
+        if !self.pending.is_empty() {
+            for (tcp, _) in self.pending.expire() {
+                let src = tcp.src_addr();
+                let dst = tcp.dst_addr();
+                if self.rst_unknown {
+                    // Send ACK/RST
+                    if let Err(ref e) = self.tx.lock().unwrap().send_tcp_ack_rst_untracked(
+                        dst,
+                        src,
+                        tcp.acknowledgement(),
+                    ) {
+                        warn!("reset TCP of {} -> {}: {}", src, dst, e);
+                    }
+                } else {
+                    trace!("drop TCP of unknown connection {} -> {}", src, dst);
+                }
+            }
+        }
+
+        // Send TCP delayed ACKs
+        if let Err(ref e) = self.tx.lock().unwrap().send_tcp_delayed_acks_timedout() {
+            warn!("send TCP delayed ACKs: {}", e);
+        }
+
+        // Clean up abandoned TCP connections
+        let abandoned = self.tx.lock().unwrap().take_abandoned();
+        for (src, dst) in abandoned {
+            self.clean_up(src, dst, CloseReason::Abandoned);
+        }
+
+        // Reap idle TCP connections
+        if let Some(timeout) = self.idle_timeout {
+            let timeout = Duration::from_millis(timeout);
+            let now = Instant::now();
+            let idle: Vec<_> = {
+                let tx_locked = self.tx.lock().unwrap();
+                self.states
+                    .iter()
+                    .filter(|(&(src, dst), state)| {
+                        state.idle_at(now) >= timeout
+                            && tx_locked
+                                .get_state(dst, src)
+                                .map_or(true, |tx_state| tx_state.idle_at(now) >= timeout)
+                    })
+                    .map(|(&key, _)| key)
+                    .collect()
+            };
+            for (src, dst) in idle {
+                debug!("reap idle TCP {} -> {}", src, dst);
+                self.drop_flow(src, dst);
+            }
+        }
+
+        // Deliver TCP payload held behind unfilled ranges reaching the reassembly deadline, since
+        // no more segments may arrive to trigger it while the source waits for the ranges
+        if self.reassembly_deadline.is_some() {
+            let timedout: Vec<_> = self
+                .states
+                .iter()
+                .filter(|(_, state)| state.is_reassembly_deadline_reached())
+                .map(|(&key, _)| key)
+                .collect();
+            for (src, dst) in timedout {
+                if let Err(ref e) = self.deliver_timedout(src, dst) {
+                    warn!("deliver TCP {} -> {}: {}", src, dst, e);
+                }
+            }
+        }
+
+        // Resume throttled TCP connections if the proxy catches up
+        if !self.throttled.is_empty() {
+            let resume_size = self.max_unsent * UNSENT_RESUME_PERCENT / 100;
+            let resumed: Vec<_> = self
+                .throttled
+                .iter()
+                .filter(|key| {
+                    self.streams
+                        .get(key)
+                        .map_or(true, |stream| stream.unsent() <= resume_size)
+                })
+                .copied()
+                .collect();
+            for (src, dst) in resumed {
+                self.throttled.remove(&(src, dst));
+                // Connections paused explicitly are left paused
+                let is_paused = self
+                    .tx
+                    .lock()
+                    .unwrap()
+                    .get_state(dst, src)
+                    .map_or(false, |tx_state| tx_state.is_paused());
+                if !is_paused {
+                    if let Err(ref e) = self.resume_flow(src, dst) {
+                        warn!("resume TCP {} -> {}: {}", src, dst, e);
+                    }
+                }
+            }
+        }
+
+        // Dump the flow table
+        if self
+            .flow_dump
+            .as_mut()
+            .map_or(false, |dump| dump.is_requested())
+        {
+            let flows = self.snapshot_flows();
+            if let Some(ref dump) = self.flow_dump {
+                dump.write(flows);
+            }
+        }
+
+        // Report frames dropped by the capture queue
+        if let Some((ref queue, ref mut reported)) = self.capture_queue {
+            let dropped = queue.dropped_count();
+            if dropped > *reported {
+                warn!(
+                    "drop {} frames for the capture queue is full",
+                    dropped - *reported
+                );
+                *reported = dropped;
+            }
+        }
+
+        // Persist mappings
+        if self.mappings_path.is_some()
+            && self.mappings_saved.elapsed().as_millis() >= MAPPINGS_SAVE_INTERVAL as u128
+        {
+            self.save_mappings();
+        }
+    }
+
+    /// Skips the unfilled ranges of a TCP connection, in the form of (source, destination),
+    /// reaching the reassembly deadline, and forwards the payload after them to the proxy.
+    fn deliver_timedout(&mut self, src: SocketAddrV4, dst: SocketAddrV4) -> io::Result<()> {
+        let key = (src, dst);
+        let state = match self.states.get_mut(&key) {
+            Some(state) => state,
+            None => return Ok(()),
+        };
+
+        while let Some((skipped_size, payload)) = state.advance_cache_timedout() {
+            let size = skipped_size + payload.len();
+            let delivered_size = payload.len();
+            if self.hexdump == Some(key) {
+                let sequence = seq_add(state.recv_next(), skipped_size as u32);
+                hexdump("TCP cache", src, dst, sequence, &payload);
+            }
+            if let Some(record) = self.flows.get_mut(&key) {
+                record.update_digest_in(&payload);
+            }
+            Redirector::mirror_in(&self.mirrors, src, dst, &payload);
+            // Measure the head-of-line blocking
+            if let Some(duration) = state.update_hol_blocked() {
+                self.hol_blocked.0 += duration;
+                if duration >= Duration::from_millis(HOL_BLOCKED_THRESHOLD) {
+                    self.hol_blocked.1 = self.hol_blocked.1.checked_add(1).unwrap_or(usize::MAX);
+                }
+            }
+            let captured = state.update_held(self.captured, true);
+
+            // Send
+            let payload = Redirector::strip_proxy_header(
+                &mut self.proxy_headers,
+                &mut self.flows,
+                key,
+                payload,
+            );
+            let (result, is_held) = match payload {
+                Ok(payload) if payload.is_empty() => (Ok(()), true),
+                Ok(payload) => match state.hold(payload) {
+                    Some(payload) => match self.streams.get_mut(&key) {
+                        Some(stream) => (stream.send(payload, true), false),
+                        None => (Err(io::Error::from(io::ErrorKind::NotFound)), false),
+                    },
+                    None => (Ok(()), true),
+                },
+                Err(e) => (Err(e), false),
+            };
+            if let Err(e) = result {
+                // Send ACK/RST
+                self.tx.lock().unwrap().send_tcp_ack_rst(dst, src)?;
+
+                // Clean up
+                self.clean_up(src, dst, CloseReason::Rst);
+
+                return Err(e);
+            }
+            // Hold payload from the source if the proxy does not read as fast
+            let max_unsent = self.max_unsent;
+            if !state.is_paused()
+                && self
+                    .streams
+                    .get(&key)
+                    .map_or(false, |stream| stream.unsent() >= max_unsent)
+            {
+                state.pause();
+                self.throttled.insert(key);
+                debug!("throttle TCP {} -> {}", src, dst);
+            }
+
+            let cache_remaining_size = state.advertise();
+
+            state.add_recv_next(size as u32);
+            state.add_delivered(delivered_size);
+            if let Some(record) = self.flows.get_mut(&key) {
+                record.add_bytes_in(delivered_size);
+            }
+            if let Some(counts) = self.byte_counts.get(&key) {
+                counts.add_in(delivered_size);
+            }
+            if let (Some(captured), false) = (captured, is_held) {
+                self.upstream_latency.record(captured.elapsed());
+            }
+
+            let mut tx_locked = self.tx.lock().unwrap();
+            let tx_state = tx_locked
+                .get_state_mut(dst, src)
+                .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+
+            // SACK
+            if state.sack_perm() {
+                let sacks = state.cache().filled();
+                tx_state.set_sacks(&sacks, state.recv_next());
+            }
+
+            // Update window size
+            tx_state.set_window(cache_remaining_size);
+
+            // Update TCP acknowledgement
+            tx_state.add_acknowledgement(size as u32);
+
+            // Send ACK0
+            tx_locked.send_tcp_ack_0(dst, src)?;
+        }
+
+        Ok(())
+    }
+
+    fn snapshot_flows(&self) -> Vec<FlowSnapshot> {
+        let tx_locked = self.tx.lock().unwrap();
+        self.states
+            .iter()
+            .map(|(&(src, dst), state)| {
+                let flow_state = match self.streams.get(&(src, dst)) {
+                    Some(stream) => {
+                        FlowState::from_closed(stream.is_tx_closed(), stream.is_rx_closed())
+                    }
+                    None => FlowState::Deferred,
+                };
+                let tx_state = tx_locked.get_state(dst, src);
+                let idle = match tx_state {
+                    Some(tx_state) => min(state.idle(), tx_state.idle()),
+                    None => state.idle(),
+                };
+
+                let mut snapshot = FlowSnapshot::new(src, dst, flow_state, idle);
+                snapshot.set_recv_stats(state.delivered(), state.cache().len());
+                if let Some(tx_state) = tx_state {
+                    snapshot.set_send_stats(
+                        tx_state.sent(),
+                        tx_state.cache().len(),
+                        tx_state.queue().len(),
+                    );
+                }
+
+                snapshot
+            })
+            .collect()
+    }
+
+    /// Decapsulates the frame in the encapsulation, and returns the frame of the same link-layer
+    /// carrying the inner packet. The tunnel is learned for sending back to the inner source.
+    fn decapsulate(&mut self, frame: &[u8]) -> Option<Vec<u8>> {
+        let encapsulation = self.encapsulation?;
+        let link_size = match self.link_type {
+            LinkType::Ethernet => {
+                let ethernet_size = Ethernet::minimum_len();
+                // IPv4 only, frames in VLANs are not decapsulated
+                if frame.get(ethernet_size - 2..ethernet_size)? != [0x08, 0x00] {
+                    return None;
+                }
+
+                ethernet_size
+            }
+            LinkType::Null => {
+                if !Loopback::parse(frame)?.is_ipv4() {
+                    return None;
+                }
+
+                Loopback::minimum_len()
+            }
+            LinkType::LinuxSll => {
+                if !Sll::parse(frame)?.is_ipv4() {
+                    return None;
+                }
+
+                Sll::minimum_len()
+            }
+        };
+        let (tunnel, packet) = encap::decapsulate(encapsulation, &frame[link_size..])?;
+
+        // Learn the tunnel of the inner source
+        let src = Ipv4Addr::new(
+            *packet.get(12)?,
+            *packet.get(13)?,
+            *packet.get(14)?,
+            *packet.get(15)?,
+        );
+        if src != self.local_ip_addr && self.src_ip_addr.contains(src) {
+            let mut tx_locked = self.tx.lock().unwrap();
+            if tx_locked.set_tunnel(src, tunnel) {
+                debug!(
+                    "decapsulate {} traffic of {}, update MTU to {}",
+                    encapsulation,
+                    src,
+                    tx_locked.get_src_mtu(src)
+                );
+            }
+        }
+
+        let mut inner_frame = Vec::with_capacity(link_size + packet.len());
+        inner_frame.extend_from_slice(&frame[..link_size]);
+        inner_frame.extend_from_slice(packet);
+
+        Some(inner_frame)
+    }
+
     fn handle_arp(
         &mut self,
         indicator: &Indicator,
         traffic: Option<Arc<AtomicUsize>>,
         count: Option<Arc<AtomicUsize>>,
     ) -> io::Result<()> {
+        // ARP reply resolving or refreshing the hardware address of the source
+        if let Some(arp) = indicator.arp() {
+            let src = arp.src();
+            if arp.is_reply()
+                && src != self.local_ip_addr
+                && self.src_ip_addr.contains(src)
+                && arp.dst() == self.local_ip_addr
+            {
+                debug!(
+                    "receive from pcap: {} ({} Bytes)",
+                    indicator.brief(),
+                    indicator.len()
+                );
+
+                // Set forwarder's hardware address
+                self.set_tx_hardware_addr(src, arp.src_hardware_addr());
+
+                // Monitor
+                if let Some(traffic) = traffic {
+                    traffic.fetch_add(indicator.content_len(), Ordering::Relaxed);
+                }
+                if let Some(count) = count {
+                    count.fetch_add(1, Ordering::Relaxed);
+                }
+
+                return Ok(());
+            }
+        }
+
         if let Some(gw_ip_addr) = self.gw_ip_addr {
             if let Some(arp) = indicator.arp() {
                 let src = arp.src();
@@ -1333,6 +3270,16 @@ impl Redirector {
             let src = ipv4.src();
             if src != self.local_ip_addr && self.src_ip_addr.contains(src) {
                 let src = ipv4.src();
+                if frame.len() < indicator.content_len() {
+                    debug!(
+                        "drop truncated frame {} ({} of {} Bytes)",
+                        indicator.brief(),
+                        frame.len(),
+                        indicator.content_len()
+                    );
+
+                    return Ok(());
+                }
                 debug!(
                     "receive from pcap: {} ({} + {} Bytes)",
                     indicator.brief(),
@@ -1340,7 +3287,13 @@ impl Redirector {
                     indicator.content_len() - indicator.len()
                 );
                 // Set forwarder's hardware address
-                self.set_tx_hardware_addr(src, indicator.ethernet().unwrap().src());
+                let src_hardware_addr = match indicator.sll() {
+                    Some(sll) => sll.src(),
+                    None => indicator.ethernet().map(|ethernet| ethernet.src()),
+                };
+                if let Some(src_hardware_addr) = src_hardware_addr {
+                    self.set_tx_hardware_addr(src, src_hardware_addr);
+                }
 
                 let frame_without_padding = &frame[..indicator.content_len()];
                 if ipv4.is_fragment() {
@@ -1352,20 +3305,34 @@ impl Redirector {
                     let (transport, payload) = frag.concatenate();
 
                     if let Some(transport) = transport {
+                        if self.is_bypassed(&transport) {
+                            trace!("bypass {}", transport);
+
+                            return Ok(());
+                        }
                         match transport {
                             Layers::Icmpv4(ref icmpv4) => self.handle_icmpv4(icmpv4)?,
-                            Layers::Tcp(ref tcp) => self.handle_tcp(tcp, &payload).await?,
+                            Layers::Tcp(ref tcp) => self.handle_tcp(ipv4, tcp, &payload).await?,
                             Layers::Udp(ref udp) => self.handle_udp(udp, &payload).await?,
                             _ => unreachable!(),
                         }
                     }
                 } else {
                     if let Some(transport) = indicator.transport() {
+                        if self.is_bypassed(transport) {
+                            trace!("bypass {}", transport);
+
+                            return Ok(());
+                        }
                         match transport {
                             Layers::Icmpv4(icmpv4) => self.handle_icmpv4(icmpv4)?,
                             Layers::Tcp(tcp) => {
-                                self.handle_tcp(tcp, &frame_without_padding[indicator.len()..])
-                                    .await?
+                                self.handle_tcp(
+                                    ipv4,
+                                    tcp,
+                                    &frame_without_padding[indicator.len()..],
+                                )
+                                .await?
                             }
                             Layers::Udp(udp) => {
                                 self.handle_udp(udp, &frame_without_padding[indicator.len()..])
@@ -1389,6 +3356,85 @@ impl Redirector {
         Ok(())
     }
 
+    fn get_mirror(&self, dst: SocketAddrV4) -> Option<Arc<Mirror>> {
+        let mirror = self.mirror.as_ref()?;
+        let is_mirrored = self.mirror_rules.iter().any(|(network, port)| {
+            network.contains(*dst.ip()) && port.map_or(true, |port| port == dst.port())
+        });
+
+        match is_mirrored {
+            true => Some(mirror.clone()),
+            false => None,
+        }
+    }
+
+    fn mirror_in(
+        mirrors: &HashMap<(SocketAddrV4, SocketAddrV4), Arc<Mirror>>,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        payload: &[u8],
+    ) {
+        if let Some(mirror) = mirrors.get(&(src, dst)) {
+            if let Err(ref e) = mirror.write(MirrorDirection::In, src, dst, payload) {
+                warn!("mirror TCP {} -> {}: {}", src, dst, e);
+            }
+        }
+    }
+
+    /// Strips the PROXY protocol header from the first bytes of a TCP connection, and returns the
+    /// payload after it, which is empty until the header is complete.
+    fn strip_proxy_header(
+        proxy_headers: &mut HashMap<(SocketAddrV4, SocketAddrV4), Vec<u8>>,
+        flows: &mut HashMap<(SocketAddrV4, SocketAddrV4), FlowRecord>,
+        key: (SocketAddrV4, SocketAddrV4),
+        payload: Vec<u8>,
+    ) -> io::Result<Vec<u8>> {
+        let buffer = match proxy_headers.get_mut(&key) {
+            Some(buffer) => buffer,
+            None => return Ok(payload),
+        };
+        buffer.extend(payload);
+        match proxy::header::parse(buffer)? {
+            Some((header, len)) => {
+                debug!("receive {} of {} -> {}", header, key.0, key.1);
+                if let Some(record) = flows.get_mut(&key) {
+                    record.set_real_src(header.src());
+                }
+                let payload = buffer.split_off(len);
+                proxy_headers.remove(&key);
+
+                Ok(payload)
+            }
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn get_cache_size(&self, dst: SocketAddrV4) -> Option<usize> {
+        self.cache_sizes
+            .iter()
+            .find(|(network, port, _)| {
+                network.contains(*dst.ip()) && port.map_or(true, |port| port == dst.port())
+            })
+            .map(|(_, _, size)| *size)
+    }
+
+    fn is_bypassed(&self, transport: &Layers) -> bool {
+        let dst = match transport.dst_addr() {
+            Some(dst) => dst,
+            None => return false,
+        };
+
+        if let Layers::Tcp(_) = transport {
+            if !self.ports.is_empty() && !self.ports.contains(&dst.port()) {
+                return true;
+            }
+        }
+
+        self.bypass.iter().any(|(network, port)| {
+            network.contains(*dst.ip()) && port.map_or(true, |port| port == dst.port())
+        })
+    }
+
     fn handle_icmpv4(&mut self, icmpv4: &Icmpv4) -> io::Result<()> {
         if icmpv4.is_destination_port_unreachable() {
             // Destination port unreachable
@@ -1410,7 +3456,7 @@ impl Redirector {
                 .tx
                 .lock()
                 .unwrap()
-                .set_src_mtu(icmpv4.dst_ip_addr().unwrap(), mtu as usize)
+                .set_src_pmtu(icmpv4.dst_ip_addr().unwrap(), mtu as usize)
             {
                 info!("Update MTU of {} to {}", icmpv4.dst_ip_addr().unwrap(), mtu);
             }
@@ -1419,14 +3465,52 @@ impl Redirector {
         Ok(())
     }
 
-    async fn handle_tcp(&mut self, tcp: &Tcp, payload: &[u8]) -> io::Result<()> {
+    async fn handle_tcp(&mut self, ipv4: &Ipv4, tcp: &Tcp, payload: &[u8]) -> io::Result<()> {
+        // Drop TCP MD5 signature, every segment of the connection is dropped the same as the SYN
+        if !self.md5_sig && tcp.is_md5_sig() {
+            trace!(
+                "drop TCP of {} -> {} with MD5 signature",
+                tcp.src_addr(),
+                tcp.dst_addr()
+            );
+
+            return Ok(());
+        }
+
+        // ECN
+        if !tcp.is_syn() {
+            let src = tcp.src_addr();
+            let dst = tcp.dst_addr();
+
+            let mut tx_locked = self.tx.lock().unwrap();
+            if let Some(tx_state) = tx_locked.get_state_mut(dst, src) {
+                if tx_state.ecn() {
+                    if tcp.is_cwr() {
+                        tx_state.set_ece(false);
+                    }
+                    if ipv4.ecn() == ipv4::ECN_CE {
+                        tx_state.set_ece(true);
+                    }
+                    if tcp.is_ece() && tcp.is_ack() {
+                        tx_state.admit_ece(tcp.acknowledgement());
+                    }
+                }
+            }
+        }
+
+        // Connect the deferred connection before its FIN, so the payload held is flushed
+        let key = (tcp.src_addr(), tcp.dst_addr());
+        if tcp.is_fin() && !tcp.is_rst() && self.deferred.contains_key(&key) {
+            self.connect_deferred(key.0, key.1).await?;
+        }
+
         if tcp.is_rst() {
             self.handle_tcp_rst(tcp);
         } else if tcp.is_ack() {
             self.handle_tcp_ack(tcp, payload)?;
         } else if tcp.is_syn() {
             // Pure TCP SYN
-            self.handle_tcp_syn(tcp).await?;
+            self.handle_tcp_syn(ipv4, tcp).await?;
         } else if tcp.is_fin() {
             // Pure TCP FIN
             self.handle_tcp_fin(tcp, payload)?;
@@ -1434,17 +3518,23 @@ impl Redirector {
             unreachable!();
         }
 
+        // Connect the deferred connection if its first payload is ready
+        if self.is_deferred_ready(key.0, key.1) {
+            self.connect_deferred(key.0, key.1).await?;
+        }
+
         Ok(())
     }
 
     fn handle_tcp_ack(&mut self, tcp: &Tcp, payload: &[u8]) -> io::Result<()> {
-        let src = SocketAddrV4::new(tcp.src_ip_addr(), tcp.src());
-        let dst = SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst());
+        let src = tcp.src_addr();
+        let dst = tcp.dst_addr();
         let key = (src, dst);
-        let is_exist = self.streams.get(&key).is_some();
+        let is_deferred = self.deferred.contains_key(&key);
+        let is_exist = self.streams.get(&key).is_some() || is_deferred;
         let is_writable = match self.streams.get(&key) {
             Some(stream) => !stream.is_tx_closed(),
-            None => false,
+            None => is_deferred,
         };
 
         if is_exist {
@@ -1467,15 +3557,217 @@ impl Redirector {
                     .get_state_mut(dst, src)
                     .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
 
+                // Duplicate ACK, which carries no payload and no window update while payload is
+                // in flight
+                let src_window = (tcp.window() as usize) << state.wscale() as usize;
+                let is_duplicate = payload.is_empty()
+                    && tx_state.cache().len() > 0
+                    && src_window == tx_state.src_window();
+
                 tx_state.acknowledge(tcp.acknowledgement());
-                tx_state.set_src_window((tcp.window() as usize) << state.wscale() as usize);
+                tx_state.set_src_window(src_window);
+
+                state.admit(tcp.acknowledgement(), is_duplicate);
             }
 
             if payload.len() > 0 {
                 // ACK
                 if is_writable {
-                    // Append to cache
-                    let cont_payload = state.append_cache(tcp.sequence(), payload)?;
+                    // Deliver payload arriving late in the unfilled ranges skipped
+                    let late_payload = match state.is_unordered() {
+                        true => state.take_skipped(tcp.sequence(), payload),
+                        false => None,
+                    };
+                    let is_late = match late_payload {
+                        Some(late_payload) => {
+                            let size = late_payload.len();
+                            if let Some(record) = self.flows.get_mut(&key) {
+                                record.update_digest_in(&late_payload);
+                            }
+                            Redirector::mirror_in(&self.mirrors, src, dst, &late_payload);
+                            let result = match state.hold(late_payload) {
+                                Some(late_payload) => self
+                                    .streams
+                                    .get_mut(&key)
+                                    .ok_or(io::Error::from(io::ErrorKind::NotFound))?
+                                    .send(late_payload, tcp.is_psh()),
+                                None => Ok(()),
+                            };
+                            if let Err(e) = result {
+                                // Send ACK/RST
+                                self.tx.lock().unwrap().send_tcp_ack_rst(dst, src)?;
+
+                                // Clean up
+                                self.clean_up(src, dst, CloseReason::Rst);
+
+                                return Err(e);
+                            }
+                            trace!(
+                                "deliver {} Bytes of TCP arriving late of {} -> {} at {}",
+                                size,
+                                src,
+                                dst,
+                                tcp.sequence()
+                            );
+                            state.add_delivered(size);
+                            if let Some(record) = self.flows.get_mut(&key) {
+                                record.add_bytes_in(size);
+                            }
+                            if let Some(counts) = self.byte_counts.get(&key) {
+                                counts.add_in(size);
+                            }
+
+                            size == payload.len()
+                        }
+                        None => false,
+                    };
+
+                    // The FIN follows the payload with it, so payload beyond the FIN is not
+                    // delivered even if the payload with the FIN fills a gap before it
+                    if tcp.is_fin() {
+                        state.set_fin_sequence(seq_add(tcp.sequence(), payload.len() as u32));
+                    }
+
+                    // Append to cache, or drop out of order payload if the reassembly is over budget
+                    let allocated = state.cache().allocated();
+                    let cont_payload = if is_late {
+                        None
+                    } else if tcp.sequence() != state.recv_next()
+                        && self.reassembly_size >= MAX_REASSEMBLY_SIZE
+                    {
+                        trace!(
+                            "drop TCP out of order of {} -> {} at {} over reassembly budget",
+                            src,
+                            dst,
+                            tcp.sequence()
+                        );
+
+                        None
+                    } else {
+                        let out_of_window = state.cache().out_of_window_count();
+                        match state.append_cache(tcp.sequence(), payload) {
+                            Ok(cont_payload) => {
+                                if state.cache().out_of_window_count() > out_of_window {
+                                    self.out_of_window =
+                                        self.out_of_window.checked_add(1).unwrap_or(usize::MAX);
+                                    trace!(
+                                        "ignore TCP out of window of {} -> {} at {}",
+                                        src,
+                                        dst,
+                                        tcp.sequence()
+                                    );
+
+                                    // Reset the connection flooded with segments out of window
+                                    if let Some(threshold) = self.rst_out_of_window {
+                                        let count =
+                                            state.out_of_window_in_interval(OUT_OF_WINDOW_INTERVAL);
+                                        if count >= threshold {
+                                            warn!(
+                                                "reset TCP of {} -> {} for {} segments out of window",
+                                                src, dst, count
+                                            );
+
+                                            // Send ACK/RST
+                                            self.tx.lock().unwrap().send_tcp_ack_rst(dst, src)?;
+
+                                            // Clean up
+                                            self.clean_up(src, dst, CloseReason::Rst);
+
+                                            return Ok(());
+                                        }
+                                    }
+
+                                    // Detect the desynchronization of sequences
+                                    if state.check_desync(DESYNC_OUT_OF_WINDOW, DESYNC_TIMEOUT) {
+                                        self.desync =
+                                            self.desync.checked_add(1).unwrap_or(usize::MAX);
+                                        let sequence = self
+                                            .tx
+                                            .lock()
+                                            .unwrap()
+                                            .get_state(dst, src)
+                                            .map_or(0, |tx_state| tx_state.sequence());
+                                        warn!(
+                                            "desynchronize TCP of {} -> {} at {}/{}, expect {}/{} for {} ms",
+                                            src,
+                                            dst,
+                                            tcp.sequence(),
+                                            tcp.acknowledgement(),
+                                            state.recv_next(),
+                                            sequence,
+                                            state.stalled().as_millis()
+                                        );
+
+                                        if self.rst_desync {
+                                            // Send ACK/RST
+                                            self.tx.lock().unwrap().send_tcp_ack_rst(dst, src)?;
+
+                                            // Clean up
+                                            self.clean_up(src, dst, CloseReason::Rst);
+
+                                            return Ok(());
+                                        }
+                                    }
+                                }
+
+                                cont_payload
+                            }
+                            Err(e) => {
+                                self.cache_full =
+                                    self.cache_full.checked_add(1).unwrap_or(usize::MAX);
+
+                                // Reset the connection instead of thrashing
+                                if state.cache_full() >= MAX_CACHE_FULL {
+                                    // Send ACK/RST
+                                    self.tx.lock().unwrap().send_tcp_ack_rst(dst, src)?;
+
+                                    // Clean up
+                                    self.clean_up(src, dst, CloseReason::Rst);
+                                }
+
+                                return Err(e);
+                            }
+                        }
+                    };
+                    // Deliver across the unfilled range after the reassembly deadline, or at once in
+                    // the unordered mode
+                    let (skipped_size, cont_payload) = match cont_payload {
+                        Some(payload) => (0, Some(payload)),
+                        None => match state
+                            .advance_cache_timedout()
+                            .or_else(|| state.advance_cache_unordered())
+                        {
+                            Some((skipped_size, payload)) => (skipped_size, Some(payload)),
+                            None => (0, None),
+                        },
+                    };
+                    if state.cache().allocated() != allocated {
+                        self.reassembly_size = (self.reassembly_size + state.cache().allocated())
+                            .checked_sub(allocated)
+                            .unwrap_or(0);
+                        trace!("set TCP reassembly size to {}", self.reassembly_size);
+                    }
+                    // Measure the head-of-line blocking
+                    if let Some(duration) = state.update_hol_blocked() {
+                        self.hol_blocked.0 += duration;
+                        if duration >= Duration::from_millis(HOL_BLOCKED_THRESHOLD) {
+                            self.hol_blocked.1 =
+                                self.hol_blocked.1.checked_add(1).unwrap_or(usize::MAX);
+                            debug!(
+                                "head-of-line block TCP of {} -> {} for {} ms",
+                                src,
+                                dst,
+                                duration.as_millis()
+                            );
+                        }
+                    }
+                    let captured = state.update_held(self.captured, cont_payload.is_some());
+                    // Acknowledge at once if payload is held out of order, or an unfilled range is
+                    // filled
+                    let is_gap = state.cache().range_count() > 0
+                        || cont_payload
+                            .as_ref()
+                            .map_or(false, |cont_payload| cont_payload.len() > payload.len());
 
                     // SACK
                     if state.sack_perm() {
@@ -1485,24 +3777,71 @@ impl Redirector {
                             .unwrap()
                             .get_state_mut(dst, src)
                             .ok_or(io::Error::from(io::ErrorKind::NotFound))?
-                            .set_sacks(&sacks);
+                            .set_sacks(&sacks, tcp.sequence());
                     }
 
                     match cont_payload {
                         Some(payload) => {
                             // Send
-                            let stream = self
-                                .streams
-                                .get_mut(&key)
-                                .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
-                            let size = payload.len();
-                            match stream.send(payload) {
+                            let size = skipped_size + payload.len();
+                            if self.hexdump == Some(key) {
+                                let sequence = seq_add(state.recv_next(), skipped_size as u32);
+                                hexdump("TCP cache", src, dst, sequence, &payload);
+                            }
+                            if let Some(record) = self.flows.get_mut(&key) {
+                                record.update_digest_in(&payload);
+                            }
+                            Redirector::mirror_in(&self.mirrors, src, dst, &payload);
+                            // Strip the PROXY protocol header
+                            let payload = Redirector::strip_proxy_header(
+                                &mut self.proxy_headers,
+                                &mut self.flows,
+                                key,
+                                payload,
+                            );
+                            // Hold the payload if paused
+                            let (result, is_held) = match payload {
+                                Ok(payload) if payload.is_empty() => (Ok(()), true),
+                                Ok(payload) => match state.hold(payload) {
+                                    Some(payload) => match self.streams.get_mut(&key) {
+                                        Some(stream) => (stream.send(payload, tcp.is_psh()), false),
+                                        None => {
+                                            (Err(io::Error::from(io::ErrorKind::NotFound)), false)
+                                        }
+                                    },
+                                    None => (Ok(()), true),
+                                },
+                                Err(e) => (Err(e), false),
+                            };
+                            match result {
                                 Ok(_) => {
-                                    let cache_remaining_size = (state.cache().remaining()
-                                        >> state.wscale() as usize)
-                                        as u16;
+                                    // Hold payload from the source if the proxy does not read as
+                                    // fast, the window shrinks as the payload held grows
+                                    let max_unsent = self.max_unsent;
+                                    if !state.is_paused()
+                                        && self
+                                            .streams
+                                            .get(&key)
+                                            .map_or(false, |stream| stream.unsent() >= max_unsent)
+                                    {
+                                        state.pause();
+                                        self.throttled.insert(key);
+                                        debug!("throttle TCP {} -> {}", src, dst);
+                                    }
+
+                                    let cache_remaining_size = state.advertise();
 
                                     state.add_recv_next(size as u32);
+                                    state.add_delivered(size - skipped_size);
+                                    if let Some(record) = self.flows.get_mut(&key) {
+                                        record.add_bytes_in(size - skipped_size);
+                                    }
+                                    if let Some(counts) = self.byte_counts.get(&key) {
+                                        counts.add_in(size - skipped_size);
+                                    }
+                                    if let (Some(captured), false) = (captured, is_held) {
+                                        self.upstream_latency.record(captured.elapsed());
+                                    }
 
                                     let mut tx_locked = self.tx.lock().unwrap();
                                     let tx_state = tx_locked
@@ -1517,14 +3856,18 @@ impl Redirector {
 
                                     // Send delayed ACK0
                                     // If there is a heavy traffic, the ACK reported may be inaccurate, which would results in retransmission
-                                    tx_locked.send_tcp_delay_ack_0(dst, src)?;
+                                    if is_gap {
+                                        tx_locked.send_tcp_ack_0(dst, src)?;
+                                    } else {
+                                        tx_locked.send_tcp_delay_ack_0(dst, src)?;
+                                    }
                                 }
                                 Err(e) => {
                                     // Send ACK/RST
                                     self.tx.lock().unwrap().send_tcp_ack_rst(dst, src)?;
 
                                     // Clean up
-                                    self.clean_up(src, dst);
+                                    self.clean_up(src, dst, CloseReason::Rst);
 
                                     return Err(e);
                                 }
@@ -1532,8 +3875,7 @@ impl Redirector {
                         }
                         None => {
                             // Retransmission or unordered
-                            let cache_remaining_size =
-                                (state.cache().remaining() >> state.wscale() as usize) as u16;
+                            let cache_remaining_size = state.advertise();
 
                             // Update window size
                             let mut tx_locked = self.tx.lock().unwrap();
@@ -1543,8 +3885,8 @@ impl Redirector {
 
                             tx_state.set_window(cache_remaining_size);
 
-                            // Send ACK0
-                            tx_locked.send_tcp_ack_0(dst, src)?;
+                            // Send duplicate ACK0
+                            tx_locked.send_tcp_dup_ack_0(dst, src)?;
                         }
                     }
                 } else {
@@ -1552,7 +3894,7 @@ impl Redirector {
                     self.tx.lock().unwrap().send_tcp_ack_rst(dst, src)?;
 
                     // Clean up
-                    self.clean_up(src, dst);
+                    self.clean_up(src, dst, CloseReason::Rst);
 
                     return Ok(());
                 }
@@ -1562,43 +3904,37 @@ impl Redirector {
                     if self.tx.lock().unwrap().get_cache_size(dst, src) == 0 {
                         // LAST_ACK
                         // Clean up
-                        self.clean_up(src, dst);
+                        self.clean_up(src, dst, CloseReason::Fin);
 
                         return Ok(());
                     }
                 } else {
                     // Duplicate ACK
-                    state.admit(tcp.acknowledgement());
-                    if state.duplicate() >= DUPLICATES_THRESHOLD {
-                        let is_cooled_down = match state.last_retrans() {
-                            Some(ref instant) => instant.elapsed().as_millis() < RETRANS_COOL_DOWN,
-                            None => false,
-                        };
-
-                        if !is_cooled_down && !tcp.is_zero_window() {
-                            // Fast retransmit
-                            let mut is_sr = false;
-                            if state.sack_perm() {
-                                if let Some(sacks) = tcp.sack() {
-                                    if sacks.len() > 0 {
-                                        // Selective retransmission
-                                        self.tx.lock().unwrap().retransmit_tcp(
-                                            dst,
-                                            src,
-                                            Some(sacks),
-                                        )?;
-                                        is_sr = true;
-                                    }
+                    if state.is_fast_retrans(DUPLICATES_THRESHOLD, RETRANS_COOL_DOWN)
+                        && !tcp.is_zero_window()
+                    {
+                        // Fast retransmit
+                        let mut is_sr = false;
+                        if state.sack_perm() {
+                            if let Some(sacks) = tcp.sack() {
+                                if sacks.len() > 0 {
+                                    // Selective retransmission
+                                    self.tx.lock().unwrap().retransmit_tcp(
+                                        dst,
+                                        src,
+                                        Some(sacks),
+                                    )?;
+                                    is_sr = true;
                                 }
                             }
+                        }
 
-                            if !is_sr {
-                                // Back N
-                                self.tx.lock().unwrap().retransmit_tcp(dst, src, None)?;
-                            }
-
-                            state.admit_retrans();
+                        if !is_sr {
+                            // The missing segment
+                            self.tx.lock().unwrap().retransmit_tcp(dst, src, None)?;
                         }
+
+                        state.admit_retrans();
                     }
                 }
             }
@@ -1610,27 +3946,69 @@ impl Redirector {
             if tcp.is_fin() || state.fin_sequence().is_some() {
                 self.handle_tcp_fin(tcp, payload)?;
             }
-        } else {
+        } else if self.restored.contains_tcp(src, dst) {
+            trace!("drop TCP of restored connection {} -> {}", src, dst);
+        } else if payload.len() > 0
+            && !tcp.is_fin()
+            && self
+                .pending
+                .hold(key, (tcp.clone(), payload.to_vec()), payload.len())
+        {
+            // Capture may reorder the payload before the SYN
+            trace!(
+                "hold TCP of unknown connection {} -> {} at {} before SYN",
+                src,
+                dst,
+                tcp.sequence()
+            );
+        } else if self.rst_unknown {
             // Send ACK/RST
             self.tx
                 .lock()
                 .unwrap()
                 .send_tcp_ack_rst_untracked(dst, src, tcp.acknowledgement())?;
+        } else {
+            trace!("drop TCP of unknown connection {} -> {}", src, dst);
         }
 
         Ok(())
     }
 
-    async fn handle_tcp_syn(&mut self, tcp: &Tcp) -> io::Result<()> {
-        let src = SocketAddrV4::new(tcp.src_ip_addr(), tcp.src());
-        let dst = SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst());
-        let key = (src, dst);
-        let is_exist = self.streams.get(&key).is_some();
+    async fn handle_tcp_syn(&mut self, ipv4: &Ipv4, tcp: &Tcp) -> io::Result<()> {
+        let src = tcp.src_addr();
+        // The original destination in the IP header is requested to the proxy verbatim, the
+        // source may address the frame to this host's hardware address as its gateway, or the
+        // destination may be in the source's network as well
+        let dst = tcp.dst_addr();
+        let key = (src, dst);
+        let is_exist = self.streams.get(&key).is_some() || self.deferred.contains_key(&key);
+
+        // Accept TCP MD5 signature, the signature is not carried in either direction
+        if tcp.is_md5_sig() {
+            debug!(
+                "accept TCP SYN of {} -> {} with MD5 signature invalidated",
+                src, dst
+            );
+        }
+
+        // Fall back from TCP Fast Open, the cookie is not replied and the payload in the SYN is
+        // not acknowledged, so the source will retransmit the payload after the handshake
+        if let Some(cookie) = tcp.fast_open_cookie() {
+            match cookie.len() {
+                0 => trace!("ignore TCP Fast Open cookie request of {} -> {}", src, dst),
+                _ => trace!(
+                    "ignore TCP Fast Open of {} -> {} with cookie of {} Bytes",
+                    src,
+                    dst,
+                    cookie.len()
+                ),
+            }
+        }
 
         // Connect if not connected, drop if established
         if !is_exist {
             // Clean up
-            self.clean_up(src, dst);
+            self.clean_up(src, dst, CloseReason::Reaped);
 
             // Admit SYN
             let wscale = match ENABLE_WSCALE {
@@ -1642,14 +4020,39 @@ impl Redirector {
                 None => None,
             };
             let sack_perm = ENABLE_SACK && tcp.is_sack_perm();
-            let state = TcpRxState::new(src, dst, tcp.sequence(), wscale.unwrap_or(0), sack_perm);
+            let cache_size = self.get_cache_size(dst);
+            let mirror = self.get_mirror(dst);
+            let is_deferred = self.defer_connect.contains(&dst.port());
+            let mut state =
+                TcpRxState::new(src, dst, tcp.sequence(), wscale.unwrap_or(0), sack_perm);
+            // Hold the first payload until connecting to the proxy
+            if is_deferred {
+                state.pause();
+            }
+            if let Some(size) = cache_size {
+                state.set_cache_capacity(size);
+            }
+            state.set_cache_alloc(self.cache_alloc);
+            state.set_cache_soft_limit_percent(self.cache_soft_limit);
+            if self.strict_window {
+                state.set_over_window_policy(OverWindowPolicy::Strict);
+            }
+            state.set_unordered(self.unordered);
+            state.set_reassembly_deadline(self.reassembly_deadline.map(Duration::from_millis));
+            let initial_recv_window = match self.initial_recv_window {
+                Some(window) => {
+                    state.set_initial_window(window);
 
+                    Some(min(window, state.cache().capacity()))
+                }
+                None => None,
+            };
+
+            let sequence = self.generate_isn();
             {
                 let mut tx_locked = self.tx.lock().unwrap();
 
-                let mut rng = rand::thread_rng();
-                let sequence = rng.gen::<u32>();
-                let acknowledgement = tcp.sequence().checked_add(1).unwrap_or(0);
+                let acknowledgement = seq_add(tcp.sequence(), 1);
                 if let Some(mss) = tcp.mss() {
                     let mtu = Ipv4::minimum_len() + Tcp::minimum_len() + mss as usize;
                     if tx_locked.set_src_mtu(tcp.src_ip_addr(), mtu) {
@@ -1657,7 +4060,7 @@ impl Redirector {
                     }
                 }
 
-                let tx_state = TcpTxState::new(
+                let mut tx_state = TcpTxState::new(
                     src,
                     dst,
                     sequence,
@@ -1668,45 +4071,181 @@ impl Redirector {
                     wscale,
                     tx_locked.get_src_mtu(tcp.src_ip_addr())
                         - (Ipv4::minimum_len() + Tcp::minimum_len()),
+                    self.iw,
                 );
+                if let Some(size) = cache_size {
+                    tx_state.set_cache_capacity(size);
+                }
+                tx_state.set_cache_alloc(self.cache_alloc);
+                tx_state.set_dscp(ipv4.dscp());
+                tx_state.set_max_sacks(self.max_sacks);
+                tx_state.set_ack_frequency(self.ack_segments, self.ack_delay);
+                tx_state.set_max_dup_acks(self.max_dup_acks);
+                if let Some(window) = initial_recv_window {
+                    tx_state.set_initial_window(window);
+                }
+                if self.ecn && tcp.is_ece() && tcp.is_cwr() {
+                    tx_state.enable_ecn();
+                }
+                if let (Some(algorithm), true) = (self.digest, self.flow_log.is_some()) {
+                    tx_state.enable_digest(algorithm);
+                }
+                if let Some(ref mirror) = mirror {
+                    tx_state.set_mirror(mirror.clone());
+                }
                 tx_locked.set_state(dst, src, tx_state);
             }
 
-            // Connect
-            let stream = StreamWorker::connect(self.get_tx(), src, dst, &self.proxy).await;
+            // Connect, or only open to the source if deferred
+            let stream = if is_deferred {
+                let result = self.tx.lock().unwrap().open(dst, src);
+
+                result.map(|_| None)
+            } else {
+                StreamWorker::connect(
+                    self.get_tx(),
+                    src,
+                    dst,
+                    &self.proxy,
+                    self.reconnect,
+                    self.coalesce,
+                )
+                .await
+                .map(Some)
+            };
 
             let stream = match stream {
                 Ok(stream) => stream,
                 Err(e) => {
-                    {
-                        let mut tx_locked = self.tx.lock().unwrap();
-                        let tx_state = tx_locked
-                            .get_state_mut(dst, src)
-                            .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+                    let reply = e
+                        .get_ref()
+                        .and_then(|e| e.downcast_ref::<SocksReply>())
+                        .copied();
+                    match reply {
+                        Some(reply) if reply.is_unreachable() => {
+                            // Original IPv4 header and the leading 8 bytes of the TCP header
+                            let ipv4 =
+                                Ipv4::new(0, LayerKinds::Tcp, src.ip().clone(), dst.ip().clone())
+                                    .unwrap();
+                            let mut payload = vec![0u8; Ipv4::minimum_len() + 8];
+                            ipv4.serialize(&mut payload, Ipv4::minimum_len() + Tcp::minimum_len())?;
+                            payload[Ipv4::minimum_len()..Ipv4::minimum_len() + 2]
+                                .copy_from_slice(&src.port().to_be_bytes());
+                            payload[Ipv4::minimum_len() + 2..Ipv4::minimum_len() + 4]
+                                .copy_from_slice(&dst.port().to_be_bytes());
+                            payload[Ipv4::minimum_len() + 4..]
+                                .copy_from_slice(&tcp.sequence().to_be_bytes());
+
+                            let icmpv4 = icmpv4_from_socks_reply(reply, &payload).unwrap();
+                            debug!(
+                                "translate SOCKS reply {} of {} -> {} to {}",
+                                reply, src, dst, icmpv4
+                            );
+
+                            // Send ICMPv4 destination unreachable or time exceeded
+                            self.tx.lock().unwrap().send_icmpv4(
+                                dst.ip().clone(),
+                                src.ip().clone(),
+                                icmpv4,
+                            )?;
+                        }
+                        _ => {
+                            if let Some(reply) = reply {
+                                debug!(
+                                    "translate SOCKS reply {} of {} -> {} to {}",
+                                    reply, src, dst, "TCP RST"
+                                );
+                            }
+
+                            let mut tx_locked = self.tx.lock().unwrap();
+                            let tx_state = tx_locked
+                                .get_state_mut(dst, src)
+                                .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
 
-                        tx_state.add_acknowledgement(1);
+                            tx_state.add_acknowledgement(1);
 
-                        // Send ACK/RST
-                        tx_locked.send_tcp_ack_rst(dst, src)?;
+                            // Send ACK/RST
+                            tx_locked.send_tcp_ack_rst(dst, src)?;
+                        }
                     }
 
                     // Clean up
-                    self.clean_up(src, dst);
+                    self.clean_up(src, dst, CloseReason::Rst);
 
                     return Err(e);
                 }
             };
 
-            self.states.insert(key, state);
-            self.streams.insert(key, stream);
+            // The cache may be allocated in advance
+            self.reassembly_size += state.cache().allocated();
+            if let Some(prev) = self.states.insert(key, state) {
+                self.reassembly_size = self
+                    .reassembly_size
+                    .checked_sub(prev.cache().allocated())
+                    .unwrap_or(0);
+            }
+
+            #[cfg(debug_assertions)]
+            self.check_reassembly_size();
+            match stream {
+                Some(stream) => {
+                    self.streams.insert(key, stream);
+                }
+                None => {
+                    trace!("defer connecting TCP {} -> {}", src, dst);
+                    self.deferred.insert(key, Instant::now());
+                }
+            }
+            if self.proxy_protocol.contains(&dst.port()) {
+                self.proxy_headers.insert(key, Vec::new());
+            }
+            if let Some(mirror) = mirror {
+                self.mirrors.insert(key, mirror);
+            }
+            if self.flow_log.is_some() {
+                let mut record = FlowRecord::new(src, dst, self.proxy.addr());
+                if let Some(algorithm) = self.digest {
+                    record.set_digest(algorithm);
+                }
+                self.flows.insert(key, record);
+            }
+            if let Some(ref accounting) = self.accounting {
+                let counts = accounting.open(src, dst);
+                self.tx
+                    .lock()
+                    .unwrap()
+                    .set_byte_counts(dst, src, Arc::clone(&counts));
+                self.byte_counts.insert(key, counts);
+            }
+
+            // Replay the payload held before the SYN
+            let pending = self.pending.take(&key);
+            if !pending.is_empty() {
+                // The acknowledgement of the payload cannot refer to the SYN, so it is replaced
+                // with one acknowledging nothing
+                let acknowledgement = match self.tx.lock().unwrap().get_state(dst, src) {
+                    Some(tx_state) => seq_sub(tx_state.sequence(), 1),
+                    None => return Ok(()),
+                };
+                for (mut tcp, payload) in pending {
+                    trace!(
+                        "replay TCP of {} -> {} at {} held before SYN",
+                        src,
+                        dst,
+                        tcp.sequence()
+                    );
+                    tcp.set_acknowledgement(acknowledgement);
+                    self.handle_tcp_ack(&tcp, &payload)?;
+                }
+            }
         }
 
         Ok(())
     }
 
     fn handle_tcp_rst(&mut self, tcp: &Tcp) {
-        let src = SocketAddrV4::new(tcp.src_ip_addr(), tcp.src());
-        let dst = SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst());
+        let src = tcp.src_addr();
+        let dst = tcp.dst_addr();
         let key = (src, dst);
 
         if tcp.is_ack() {
@@ -1716,28 +4255,29 @@ impl Redirector {
                     if tcp.sequence() == state.recv_next() {
                         // Admit RST
                         // Clean up
-                        self.clean_up(src, dst);
+                        self.clean_up(src, dst, CloseReason::Rst);
                     }
                 }
                 None => {
                     // Clean up
-                    self.clean_up(src, dst);
+                    self.clean_up(src, dst, CloseReason::Rst);
                 }
             }
         } else {
             // Clean up
-            self.clean_up(src, dst);
+            self.clean_up(src, dst, CloseReason::Rst);
         }
     }
 
     fn handle_tcp_fin(&mut self, tcp: &Tcp, payload: &[u8]) -> io::Result<()> {
-        let src = SocketAddrV4::new(tcp.src_ip_addr(), tcp.src());
-        let dst = SocketAddrV4::new(tcp.dst_ip_addr(), tcp.dst());
+        let src = tcp.src_addr();
+        let dst = tcp.dst_addr();
         let key = (src, dst);
-        let is_exist = self.streams.get(&key).is_some();
+        let is_deferred = self.deferred.contains_key(&key);
+        let is_exist = self.streams.get(&key).is_some() || is_deferred;
         let (is_writable, is_readable) = match self.streams.get(&key) {
             Some(stream) => (!stream.is_tx_closed(), !stream.is_rx_closed()),
-            None => (false, false),
+            None => (is_deferred, is_deferred),
         };
 
         if is_exist {
@@ -1748,19 +4288,23 @@ impl Redirector {
                     .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
                 if tcp.is_fin() {
                     // Update FIN sequence
-                    state.set_fin_sequence(
-                        tcp.sequence()
-                            .checked_add(payload.len() as u32)
-                            .unwrap_or_else(|| payload.len() as u32 - (u32::MAX - tcp.sequence())),
-                    );
+                    state.set_fin_sequence(seq_add(tcp.sequence(), payload.len() as u32));
                 }
 
                 // If the receive next is the same as the FIN sequence, the FIN should be popped
                 if let Some(fin_sequence) = state.fin_sequence() {
-                    if fin_sequence == state.recv_next() {
+                    if fin_sequence == state.recv_next() && state.is_paused() {
+                        // The FIN will be retransmitted by the source after the connection is
+                        // resumed
+                        trace!("defer TCP FIN of paused {} -> {}", src, dst);
+
+                        if payload.len() == 0 {
+                            // Send ACK0
+                            self.tx.lock().unwrap().send_tcp_ack_0(dst, src)?;
+                        }
+                    } else if fin_sequence == state.recv_next() {
                         // Admit FIN
                         state.admit_fin();
-                        state.add_recv_next(1);
 
                         {
                             let mut tx_locked = self.tx.lock().unwrap();
@@ -1783,7 +4327,7 @@ impl Redirector {
                         } else {
                             // Close by remote
                             // Clean up
-                            self.clean_up(src, dst);
+                            self.clean_up(src, dst, CloseReason::Fin);
                         }
                     } else {
                         trace!(
@@ -1804,28 +4348,302 @@ impl Redirector {
                 // Send ACK0
                 self.tx.lock().unwrap().send_tcp_ack_0(dst, src)?;
             }
-        } else {
+        } else if self.restored.contains_tcp(src, dst) {
+            trace!("drop TCP of restored connection {} -> {}", src, dst);
+        } else if self.rst_unknown {
             // Send ACK/RST
             self.tx
                 .lock()
                 .unwrap()
                 .send_tcp_ack_rst_untracked(dst, src, tcp.acknowledgement())?;
+        } else {
+            trace!("drop TCP of unknown connection {} -> {}", src, dst);
+        }
+
+        Ok(())
+    }
+
+    /// Returns if a TCP connection deferred is ready to connect to the proxy, which is when the
+    /// first payload held is a complete TLS ClientHello or not TLS, or the payload held or the
+    /// time waited reaches the bound.
+    fn is_deferred_ready(&self, src: SocketAddrV4, dst: SocketAddrV4) -> bool {
+        let key = (src, dst);
+        let (deferred, state) = match (self.deferred.get(&key), self.states.get(&key)) {
+            (Some(deferred), Some(state)) => (deferred, state),
+            _ => return false,
+        };
+        let payload = state.paused_payload();
+
+        match sni::parse(payload) {
+            Ok(None) => {
+                payload.len() >= MAX_DEFER_SIZE
+                    || deferred.elapsed() >= Duration::from_millis(DEFER_TIMEOUT)
+            }
+            _ => true,
+        }
+    }
+
+    /// Connects TCP connections deferred to the proxy after waiting too long for their first
+    /// payload.
+    async fn handle_deferred_timedout(&mut self) {
+        if self.deferred.is_empty() {
+            return;
+        }
+
+        let keys: Vec<_> = self
+            .deferred
+            .keys()
+            .copied()
+            .filter(|&(src, dst)| self.is_deferred_ready(src, dst))
+            .collect();
+        for (src, dst) in keys {
+            if let Err(ref e) = self.connect_deferred(src, dst).await {
+                warn!("connect TCP {} -> {}: {}", src, dst, e);
+            }
+        }
+    }
+
+    /// Connects a TCP connection deferred to the proxy, and sends the payload held to it.
+    async fn connect_deferred(&mut self, src: SocketAddrV4, dst: SocketAddrV4) -> io::Result<()> {
+        let key = (src, dst);
+        if self.deferred.remove(&key).is_none() {
+            return Ok(());
+        }
+
+        // Peek the server name
+        let server_name = self
+            .states
+            .get(&key)
+            .and_then(|state| sni::parse(state.paused_payload()).ok().flatten())
+            .and_then(|hello| hello.server_name().map(|name| name.to_string()));
+        match server_name {
+            Some(ref server_name) => {
+                debug!("peek server name {} of {} -> {}", server_name, src, dst)
+            }
+            None => trace!("peek no server name of {} -> {}", src, dst),
+        }
+        if let Some(record) = self.flows.get_mut(&key) {
+            record.set_server_name(server_name);
+        }
+
+        // Connect
+        let stream = StreamWorker::connect_opened(
+            self.get_tx(),
+            src,
+            dst,
+            &self.proxy,
+            self.reconnect,
+            self.coalesce,
+        )
+        .await;
+
+        match stream {
+            Ok(stream) => {
+                self.streams.insert(key, stream);
+            }
+            Err(e) => {
+                // Send ACK/RST
+                self.tx.lock().unwrap().send_tcp_ack_rst(dst, src)?;
+
+                // Clean up
+                self.clean_up(src, dst, CloseReason::Rst);
+
+                return Err(e);
+            }
+        }
+
+        // Send the payload held
+        self.resume_flow(src, dst)?;
+
+        Ok(())
+    }
+
+    /// Drains and closes a TCP connection. The payload not yet sent to the proxy will be flushed,
+    /// and the payload in the queue will be sent to the source within the send window followed by
+    /// a FIN, as if the proxy closed the connection. The connection is kept until the source
+    /// acknowledges the FIN, or abandoned after the maximum retransmissions, before it is cleaned
+    /// up. The receive window never holds contiguous payload, so only the payload held while the
+    /// connection is paused is delivered on its side.
+    pub fn drain_and_close(&mut self, src: SocketAddrV4, dst: SocketAddrV4) -> io::Result<()> {
+        let key = (src, dst);
+
+        if let Some(state) = self.states.get_mut(&key) {
+            let payload = state.resume();
+            if payload.len() > 0 {
+                if let Some(stream) = self.streams.get_mut(&key) {
+                    if let Err(ref e) = stream.send(payload, true) {
+                        warn!("drain TCP {} -> {}: {}", src, dst, e);
+                    }
+                }
+            }
+        }
+        {
+            let mut tx_locked = self.tx.lock().unwrap();
+            if let Some(tx_state) = tx_locked.get_state_mut(dst, src) {
+                tx_state.set_paused(false);
+            }
+        }
+        match self.streams.get_mut(&key) {
+            Some(stream) => {
+                // The stream queues the FIN after the payload received from the proxy, and the
+                // connection is cleaned up once the FIN is acknowledged
+                stream.shutdown(Shutdown::Both);
+                trace!("drain TCP {} -> {}", dst, src);
+            }
+            None => {
+                // Clean up
+                self.clean_up(src, dst, CloseReason::Fin);
+            }
         }
 
         Ok(())
     }
 
-    fn clean_up(&mut self, src: SocketAddrV4, dst: SocketAddrV4) {
+    /// Pauses forwarding a TCP connection, in the form of (source, destination). Payload from the
+    /// source is still acknowledged and buffered, but held instead of being sent to the proxy, so
+    /// the window advertised shrinks as the buffer fills, and payload from the proxy is queued
+    /// instead of being sent to the source. Returns if the connection exists.
+    pub fn pause_flow(&mut self, src: SocketAddrV4, dst: SocketAddrV4) -> bool {
+        let key = (src, dst);
+        let state = match self.states.get_mut(&key) {
+            Some(state) => state,
+            None => return false,
+        };
+        state.pause();
+
+        let mut tx_locked = self.tx.lock().unwrap();
+        if let Some(tx_state) = tx_locked.get_state_mut(dst, src) {
+            tx_state.set_paused(true);
+        }
+        debug!("pause TCP {} -> {}", src, dst);
+
+        true
+    }
+
+    /// Resumes forwarding a paused TCP connection, in the form of (source, destination). The
+    /// payload held is sent to the proxy, and the payload queued is sent to the source. Returns
+    /// if the connection exists.
+    pub fn resume_flow(&mut self, src: SocketAddrV4, dst: SocketAddrV4) -> io::Result<bool> {
+        let key = (src, dst);
+        let state = match self.states.get_mut(&key) {
+            Some(state) => state,
+            None => return Ok(false),
+        };
+        let payload = state.resume();
+        debug!("resume TCP {} -> {}", src, dst);
+
+        // Send
+        if payload.len() > 0 {
+            let stream = self
+                .streams
+                .get_mut(&key)
+                .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+            stream.send(payload, true)?;
+        }
+
+        let mut tx_locked = self.tx.lock().unwrap();
+        if let Some(tx_state) = tx_locked.get_state_mut(dst, src) {
+            tx_state.set_paused(false);
+
+            // Update window size
+            tx_state.set_window(state.advertise());
+
+            // Send ACK0 for the window update
+            tx_locked.send_tcp_ack_0(dst, src)?;
+            tx_locked.send_tcp(dst, src)?;
+        }
+
+        Ok(true)
+    }
+
+    /// Drops a TCP connection forcibly, in the form of (source, destination). An RST is sent to
+    /// the source, the proxied stream is closed, and the connection is cleaned up the same as a
+    /// normal teardown. Returns if the connection exists.
+    pub fn drop_flow(&mut self, src: SocketAddrV4, dst: SocketAddrV4) -> bool {
+        let key = (src, dst);
+        if self.states.get(&key).is_none() && self.streams.get(&key).is_none() {
+            return false;
+        }
+
+        if let Some(stream) = self.streams.get_mut(&key) {
+            stream.close();
+        }
+        {
+            let mut tx_locked = self.tx.lock().unwrap();
+            if tx_locked.get_state(dst, src).is_some() {
+                // Send ACK/RST
+                if let Err(ref e) = tx_locked.send_tcp_ack_rst(dst, src) {
+                    warn!("drop TCP {} -> {}: {}", src, dst, e);
+                }
+            }
+        }
+        debug!("drop TCP {} -> {}", src, dst);
+
+        // Clean up
+        self.clean_up(src, dst, CloseReason::Reaped);
+
+        true
+    }
+
+    fn clean_up(&mut self, src: SocketAddrV4, dst: SocketAddrV4, reason: CloseReason) {
         let key = (src, dst);
 
         self.streams.remove(&key);
-        self.states.remove(&key);
+        self.throttled.remove(&key);
+        self.proxy_headers.remove(&key);
+        self.deferred.remove(&key);
+        self.mirrors.remove(&key);
+        let state = self.states.remove(&key);
+        if let Some(ref state) = state {
+            self.reassembly_size = self
+                .reassembly_size
+                .checked_sub(state.cache().allocated())
+                .unwrap_or(0);
+
+            #[cfg(debug_assertions)]
+            self.check_reassembly_size();
+        }
+
+        let (closed, closed_digest) = {
+            let mut tx_locked = self.tx.lock().unwrap();
+            tx_locked.clean_up(dst, src);
+
+            (
+                tx_locked.take_closed(dst, src),
+                tx_locked.take_closed_digest(dst, src),
+            )
+        };
+
+        // Accounting
+        if self.byte_counts.remove(&key).is_some() {
+            if let Some(ref accounting) = self.accounting {
+                accounting.close(src, dst);
+            }
+        }
 
-        self.tx.lock().unwrap().clean_up(dst, src);
+        // Flow log
+        if let Some(mut record) = self.flows.remove(&key) {
+            if let Some(ref state) = state {
+                record.set_recv_stats(
+                    state.peak_cache(),
+                    state.cache().merge_count(),
+                    state.cache().peak_range_count(),
+                );
+            }
+            if let Some((sent, peak_cache, retrans, spurious_retrans)) = closed {
+                record.set_send_stats(sent, peak_cache, retrans, spurious_retrans);
+            }
+            record.set_digest_out(closed_digest);
+            if let Some(ref flow_log) = self.flow_log {
+                if let Err(ref e) = flow_log.lock().unwrap().write(&record, reason) {
+                    warn!("write flow log: {}", e);
+                }
+            }
+        }
     }
 
     async fn handle_udp(&mut self, udp: &Udp, payload: &[u8]) -> io::Result<()> {
-        let src = SocketAddrV4::new(udp.src_ip_addr(), udp.src());
+        let src = udp.src_addr();
 
         // Bind
         let port = self.bind_local_udp_port(src).await?;
@@ -1834,10 +4652,7 @@ impl Redirector {
         self.datagrams
             .get_mut(&port)
             .ok_or(io::Error::from(io::ErrorKind::NotFound))?
-            .send_to(
-                payload.to_vec(),
-                SocketAddrV4::new(udp.dst_ip_addr(), udp.dst()),
-            )?;
+            .send_to(payload.to_vec(), udp.dst_addr())?;
 
         Ok(())
     }
@@ -1853,7 +4668,18 @@ impl Redirector {
             }
             None => {
                 let bind_port = if self.udp_lru.len() < self.udp_lru.cap() {
-                    match DatagramWorker::bind(self.get_tx(), src, &self.proxy).await {
+                    let port = match self.restored.get_udp(src) {
+                        Some(port) => Some(port),
+                        None => {
+                            let datagrams = &self.datagrams;
+                            self.port_strategy.select(
+                                src,
+                                UDP_PORT_RANGE.0..=UDP_PORT_RANGE.1,
+                                |port| datagrams.contains_key(&port),
+                            )
+                        }
+                    };
+                    match DatagramWorker::bind(self.get_tx(), src, &self.proxy, port).await {
                         Ok((worker, port)) => {
                             self.datagrams.insert(port, worker);
 
@@ -1898,6 +4724,31 @@ impl Redirector {
         }
     }
 
+    fn save_mappings(&mut self) {
+        let path = match &self.mappings_path {
+            Some(path) => path,
+            None => return,
+        };
+
+        let mut mappings = Mappings::new();
+        for (src, dst) in self.streams.keys() {
+            mappings.insert_tcp(*src, *dst);
+        }
+        for (src, port) in &self.datagram_map {
+            mappings.insert_udp(*src, *port);
+        }
+        match mappings.save(path) {
+            Ok(_) => trace!(
+                "save {} TCP and {} UDP mappings to {}",
+                mappings.tcp_len(),
+                mappings.udp_len(),
+                path.display()
+            ),
+            Err(ref e) => warn!("save mappings to {}: {}", path.display(), e),
+        }
+        self.mappings_saved = Instant::now();
+    }
+
     fn unbind_local_udp_port(&mut self, src: SocketAddrV4) {
         let local_port = self.datagram_map.get(&src);
         match local_port {
@@ -1912,18 +4763,565 @@ impl Redirector {
         }
     }
 
+    /// Returns the total allocated size of the receive caches of all the TCP connections.
+    pub fn reassembly_size(&self) -> usize {
+        self.reassembly_size
+    }
+
+    /// Returns the maximum size of the receive caches of all the TCP connections.
+    pub fn max_reassembly_size(&self) -> usize {
+        MAX_REASSEMBLY_SIZE
+    }
+
+    /// Checks the total allocated size of the receive caches is accounted with the live states
+    /// and panics if it drifts. The check is performed when a connection is set up or cleaned up
+    /// in debug builds.
+    #[cfg(debug_assertions)]
+    fn check_reassembly_size(&self) {
+        let size = self
+            .states
+            .values()
+            .map(|state| state.cache().allocated())
+            .sum::<usize>();
+        assert_eq!(self.reassembly_size, size, "reassembly size drifted");
+    }
+
+    /// Returns the count of failures of appending to the receive caches of all the TCP
+    /// connections for they are full.
+    pub fn cache_full_count(&self) -> usize {
+        self.cache_full
+    }
+
+    /// Returns the count of TCP segments ignored for they are out of the receive windows of all
+    /// the TCP connections. A large count may indicate the receive window is too small for the
+    /// bandwidth-delay product of the path.
+    pub fn out_of_window_count(&self) -> usize {
+        self.out_of_window
+    }
+
+    /// Returns the total duration of the head-of-line blocking ended of all the TCP connections,
+    /// which is the time payload is buffered behind unfilled ranges waiting for the missing
+    /// payload before it can be delivered in order. A large duration relative to the lifetime of
+    /// the connections may indicate the unordered mode is worthwhile.
+    pub fn hol_blocked_duration(&self) -> Duration {
+        self.hol_blocked.0
+    }
+
+    /// Returns the count of the head-of-line blocking of TCP connections over the threshold.
+    pub fn hol_blocked_count(&self) -> usize {
+        self.hol_blocked.1
+    }
+
+    /// Returns the count of TCP connections detected desynchronized from the source.
+    pub fn desync_count(&self) -> usize {
+        self.desync
+    }
+
+    /// Returns the count of duplicate ACKs suppressed of all the TCP connections.
+    pub fn suppressed_ack_count(&self) -> usize {
+        self.tx.lock().unwrap().suppressed_ack_count()
+    }
+
+    /// Returns the approximate latency at the percentile in range 0 to 100 from TCP payload is
+    /// captured to it is sent to the proxy, or `None` if no payload is sent. The latency
+    /// includes the time the payload is held in the receive cache waiting for missing payload
+    /// before it, but excludes the network RTT.
+    pub fn upstream_latency(&self, percentile: f64) -> Option<Duration> {
+        self.upstream_latency.percentile(percentile)
+    }
+
+    /// Returns the approximate latency at the percentile in range 0 to 100 from TCP payload is
+    /// read from the proxy to it is sent to the source, or `None` if no payload is sent.
+    pub fn downstream_latency(&self, percentile: f64) -> Option<Duration> {
+        self.tx.lock().unwrap().downstream_latency(percentile)
+    }
+
+    /// Returns the count of TCP connections whose receive caches are under pressure, which are
+    /// reaching the soft limit or full.
+    pub fn under_pressure_count(&self) -> usize {
+        self.states
+            .values()
+            .filter(|state| state.cache().pressure() != Pressure::Low)
+            .count()
+    }
+
+    /// Returns the count of frames in the capture queue, or 0 if there is no capture queue.
+    pub fn capture_queue_len(&self) -> usize {
+        self.capture_queue
+            .as_ref()
+            .map_or(0, |(queue, _)| queue.len())
+    }
+
+    /// Returns the count of frames dropped on overflow of the capture queue.
+    pub fn capture_drop_count(&self) -> usize {
+        self.capture_queue
+            .as_ref()
+            .map_or(0, |(queue, _)| queue.dropped_count())
+    }
+
+    /// Returns the count of SOCKS handshakes in flight, excluding those waiting for a slot.
+    pub fn handshake_count(&self) -> usize {
+        self.proxy.handshake_count()
+    }
+
+    /// Returns the path MTUs of sources learned from ICMPv4 fragmentation required and not
+    /// expired, in the form of (source, path MTU, age), for debugging.
+    pub fn pmtu_cache(&self) -> Vec<(Ipv4Addr, usize, Duration)> {
+        self.tx.lock().unwrap().get_src_pmtus()
+    }
+
     fn get_tx(&self) -> Arc<Mutex<Forwarder>> {
         Arc::clone(&self.tx)
     }
 
     fn set_tx_hardware_addr(&mut self, ip_addr: Ipv4Addr, hardware_addr: HardwareAddr) {
-        if !self.tx_src_hardware_addr_set_ip_addr_set.contains(&ip_addr) {
+        let prev = self.tx_src_hardware_addr_map.get(&ip_addr).copied();
+        if prev != Some(hardware_addr) {
             self.tx
                 .lock()
                 .unwrap()
                 .set_src_hardware_addr(ip_addr, hardware_addr);
-            self.tx_src_hardware_addr_set_ip_addr_set.insert(ip_addr);
-            info!("Device {} ({}) joined the network", ip_addr, hardware_addr);
+            for peer in self.peers.iter() {
+                peer.lock()
+                    .unwrap()
+                    .set_src_hardware_addr(ip_addr, hardware_addr);
+            }
+            self.tx_src_hardware_addr_map.insert(ip_addr, hardware_addr);
+            match prev {
+                Some(_) => info!("Device {} changed to {}", ip_addr, hardware_addr),
+                None => info!("Device {} ({}) joined the network", ip_addr, hardware_addr),
+            }
+        }
+    }
+
+    fn resolve_hardware_addrs(&mut self) -> io::Result<()> {
+        let started = *self.resolve_started.get_or_insert_with(Instant::now);
+        let unresolved = self
+            .resolve
+            .iter()
+            .filter(|ip_addr| !self.tx_src_hardware_addr_map.contains_key(ip_addr))
+            .copied()
+            .collect::<Vec<_>>();
+
+        // Fall back after timeout
+        if !unresolved.is_empty()
+            && started.elapsed().as_millis() >= ARP_RESOLVE_TIMEOUT as u128
+            && !self.resolve_timedout
+        {
+            self.resolve_timedout = true;
+            match self.resolve_fallback {
+                Some(fallback) => {
+                    for ip_addr in unresolved.iter() {
+                        warn!(
+                            "Cannot resolve the hardware address of {}, fall back to {}",
+                            ip_addr, fallback
+                        );
+                        self.set_tx_hardware_addr(*ip_addr, fallback);
+                    }
+                }
+                None => {
+                    for ip_addr in unresolved.iter() {
+                        warn!("Cannot resolve the hardware address of {}", ip_addr);
+                    }
+                }
+            }
+        }
+
+        // Request unresolved addresses frequently, and refresh resolved addresses periodically
+        let interval = match unresolved.is_empty() {
+            true => ARP_REFRESH_INTERVAL,
+            false => ARP_RESOLVE_INTERVAL,
+        };
+        let is_due = match self.arp_requested {
+            Some(instant) => instant.elapsed().as_millis() >= interval as u128,
+            None => true,
+        };
+        if is_due {
+            self.arp_requested = Some(Instant::now());
+            let mut tx_locked = self.tx.lock().unwrap();
+            for ip_addr in self.resolve.iter() {
+                tx_locked.send_arp_request(*ip_addr)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the destination hardware address of frames sent to the source. The first rule matching
+/// the source applies, or the hardware address learned from the source is used.
+fn select_hardware_addr(
+    rules: &[(Ipv4Network, HardwareAddr)],
+    learned: &HashMap<Ipv4Addr, HardwareAddr>,
+    src_ip_addr: Ipv4Addr,
+) -> HardwareAddr {
+    match rules
+        .iter()
+        .find(|(network, _)| network.contains(src_ip_addr))
+    {
+        Some((_, hardware_addr)) => *hardware_addr,
+        None => *learned
+            .get(&src_ip_addr)
+            .unwrap_or(&pcap::HARDWARE_ADDR_UNSPECIFIED),
+    }
+}
+
+#[test]
+fn forwarder_select_hardware_addr() {
+    let client: HardwareAddr = "00:11:22:33:44:55".parse().unwrap();
+    let router: HardwareAddr = "66:77:88:99:aa:bb".parse().unwrap();
+    let mut learned = HashMap::new();
+    learned.insert(Ipv4Addr::new(10, 6, 0, 2), client);
+    learned.insert(Ipv4Addr::new(10, 7, 0, 2), client);
+
+    // Use the hardware address learned without rules
+    assert_eq!(
+        select_hardware_addr(&[], &learned, Ipv4Addr::new(10, 6, 0, 2)),
+        client
+    );
+    assert_eq!(
+        select_hardware_addr(&[], &learned, Ipv4Addr::new(10, 6, 0, 3)),
+        pcap::HARDWARE_ADDR_UNSPECIFIED
+    );
+
+    // Sources in another segment go through the router, and the first matched rule applies
+    let rules = vec![
+        ("10.7.0.0/16".parse().unwrap(), router),
+        ("10.0.0.0/8".parse().unwrap(), client),
+    ];
+    assert_eq!(
+        select_hardware_addr(&rules, &learned, Ipv4Addr::new(10, 7, 0, 2)),
+        router
+    );
+    assert_eq!(
+        select_hardware_addr(&rules, &learned, Ipv4Addr::new(10, 8, 0, 2)),
+        client
+    );
+    assert_eq!(
+        select_hardware_addr(&rules, &learned, Ipv4Addr::new(10, 6, 0, 2)),
+        client
+    );
+    assert_eq!(
+        select_hardware_addr(&rules, &learned, Ipv4Addr::new(192, 168, 0, 2)),
+        pcap::HARDWARE_ADDR_UNSPECIFIED
+    );
+}
+
+/// Returns the weight of a TCP connection in the scheduler. The first rule matching the
+/// destination and the first rule matching the DSCP apply, and the larger weight is used.
+fn select_weight(
+    weights: &[(Ipv4Network, Option<u16>, usize)],
+    dscp_weights: &[(u8, usize)],
+    dst: SocketAddrV4,
+    dscp: u8,
+) -> usize {
+    let weight = weights
+        .iter()
+        .find(|(network, port, _)| {
+            network.contains(*dst.ip()) && port.map_or(true, |port| port == dst.port())
+        })
+        .map_or(1, |(_, _, weight)| *weight);
+    let dscp_weight = dscp_weights
+        .iter()
+        .find(|(min_dscp, _)| dscp >= *min_dscp)
+        .map_or(1, |(_, weight)| *weight);
+
+    max(weight, dscp_weight)
+}
+
+#[test]
+fn forwarder_select_weight() {
+    let dst = |s: &str| s.parse::<SocketAddrV4>().unwrap();
+
+    // Connections matching no rule have a weight of 1
+    assert_eq!(select_weight(&[], &[], dst("10.0.0.1:80"), 46), 1);
+
+    let weights = vec![
+        ("10.0.0.0/8".parse().unwrap(), Some(22), 8),
+        ("0.0.0.0/0".parse().unwrap(), Some(22), 4),
+        ("192.168.0.0/16".parse().unwrap(), None, 2),
+    ];
+    let dscp_weights = vec![(46, 6), (32, 3)];
+    assert_eq!(
+        select_weight(&weights, &dscp_weights, dst("10.0.0.1:22"), 0),
+        8
+    );
+    assert_eq!(
+        select_weight(&weights, &dscp_weights, dst("172.16.0.1:22"), 0),
+        4
+    );
+    assert_eq!(
+        select_weight(&weights, &dscp_weights, dst("192.168.0.1:443"), 0),
+        2
+    );
+    // The first matched DSCP rule applies, and the larger weight is used
+    assert_eq!(
+        select_weight(&weights, &dscp_weights, dst("192.168.0.1:443"), 40),
+        3
+    );
+    assert_eq!(
+        select_weight(&weights, &dscp_weights, dst("10.0.0.1:22"), 46),
+        8
+    );
+    assert_eq!(
+        select_weight(&weights, &dscp_weights, dst("10.0.0.1:80"), 46),
+        6
+    );
+}
+
+/// Returns the ICMPv4 error translated from an unsuccessful SOCKS reply quoting the given payload,
+/// which is the original IPv4 header and the leading 8 bytes of the TCP header. `None` represents
+/// the reply is translated into a TCP RST.
+fn icmpv4_from_socks_reply(reply: SocksReply, payload: &[u8]) -> Option<Icmpv4> {
+    match reply {
+        SocksReply::NetworkUnreachable => {
+            Some(Icmpv4::new_destination_network_unreachable(payload))
         }
+        SocksReply::HostUnreachable => Some(Icmpv4::new_destination_host_unreachable(payload)),
+        SocksReply::TtlExpired => Some(Icmpv4::new_time_exceeded(payload)),
+        _ => None,
+    }
+}
+
+#[test]
+fn redirector_icmpv4_from_socks_reply() {
+    let payload = [0u8; 28];
+    let cases = [
+        (SocksReply::GeneralFailure, None),
+        (SocksReply::ConnectionNotAllowed, None),
+        (SocksReply::NetworkUnreachable, Some((3, 0))),
+        (SocksReply::HostUnreachable, Some((3, 1))),
+        (SocksReply::ConnectionRefused, None),
+        (SocksReply::TtlExpired, Some((11, 0))),
+        (SocksReply::CommandNotSupported, None),
+        (SocksReply::AddressTypeNotSupported, None),
+        (SocksReply::Unassigned(9), None),
+    ];
+    for (reply, expected) in cases.iter() {
+        let icmpv4 = icmpv4_from_socks_reply(*reply, &payload);
+        // Only unreachable replies are translated into ICMPv4
+        assert_eq!(icmpv4.is_some(), reply.is_unreachable());
+        let actual = icmpv4.map(|icmpv4| {
+            let mut buffer = vec![0u8; icmpv4.len()];
+            icmpv4.serialize(&mut buffer, icmpv4.len()).unwrap();
+            assert_eq!(&buffer[8..], &payload[..]);
+
+            (buffer[0], buffer[1])
+        });
+        assert_eq!(actual, *expected);
+    }
+}
+
+#[tokio::test]
+async fn redirector_arp_reply() {
+    use capture::OverflowPolicy;
+
+    let local_hardware_addr: HardwareAddr = "22:22:22:22:22:22".parse().unwrap();
+    let src_hardware_addr: HardwareAddr = "11:11:11:11:11:11".parse().unwrap();
+    let src_ip_addr: Ipv4Addr = "10.6.0.2".parse().unwrap();
+    let gw_ip_addr: Ipv4Addr = "10.6.0.254".parse().unwrap();
+
+    // Feed frames from a queue and collect frames sent to another queue instead of a pcap device
+    let rx_queue = FrameQueue::new(16, OverflowPolicy::Block).unwrap();
+    let tx_queue = FrameQueue::new(16, OverflowPolicy::Block).unwrap();
+    let forwarder = Forwarder::new(
+        pcap::sender(tx_queue.clone()),
+        1500,
+        local_hardware_addr,
+        "10.6.0.1".parse().unwrap(),
+    );
+    let mut redirector = Redirector::new(
+        Arc::new(Mutex::new(forwarder)),
+        "10.6.0.0/24".parse().unwrap(),
+        "10.6.0.1".parse().unwrap(),
+        Some(gw_ip_addr),
+        ProxyConfig::new_socks("127.0.0.1:1080".parse().unwrap(), false, false, None),
+    );
+    // A forwarder of another shard
+    let peer = Arc::new(Mutex::new(Forwarder::new(
+        pcap::sender(FrameQueue::new(16, OverflowPolicy::Block).unwrap()),
+        1500,
+        local_hardware_addr,
+        "10.6.0.1".parse().unwrap(),
+    )));
+    redirector.set_peers(vec![Arc::clone(&peer)]);
+
+    let request = Indicator::new(
+        Layers::Ethernet(
+            Ethernet::new(
+                LayerKinds::Arp,
+                src_hardware_addr,
+                pcap::HARDWARE_ADDR_BROADCAST,
+            )
+            .unwrap(),
+        ),
+        Some(Layers::Arp(Arp::new_request(
+            src_hardware_addr,
+            src_ip_addr,
+            gw_ip_addr,
+        ))),
+        None,
+    );
+    let mut frame = vec![0u8; request.len()];
+    request.serialize(frame.as_mut_slice()).unwrap();
+    assert!(rx_queue.push(frame));
+    rx_queue.close(None);
+
+    // The redirection stops once the frames fed are drained
+    let mut rx = pcap::receiver(rx_queue, Some(Duration::from_millis(0)));
+    let e = redirector.open(&mut rx).await.unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::BrokenPipe);
+
+    // The gratuitous ARP is followed by the ARP reply to the source
+    let frame = tx_queue.pop(Some(Duration::from_millis(0))).unwrap();
+    let indicator = Indicator::from(frame.as_slice()).unwrap();
+    assert_eq!(indicator.arp().unwrap().dst(), gw_ip_addr);
+    let frame = tx_queue.pop(Some(Duration::from_millis(0))).unwrap();
+    let indicator = Indicator::from(frame.as_slice()).unwrap();
+    let arp = indicator.arp().unwrap();
+    assert!(arp.is_reply());
+    assert_eq!(arp.src(), gw_ip_addr);
+    assert_eq!(arp.src_hardware_addr(), local_hardware_addr);
+    assert_eq!(arp.dst(), src_ip_addr);
+    assert_eq!(arp.dst_hardware_addr(), src_hardware_addr);
+    assert_eq!(indicator.ethernet().unwrap().dst(), src_hardware_addr);
+    assert!(tx_queue.is_empty());
+
+    // The hardware address learned is also set to the other shard
+    assert_eq!(
+        peer.lock().unwrap().src_hardware_addr_map.get(&src_ip_addr),
+        Some(&src_hardware_addr)
+    );
+}
+
+#[tokio::test]
+async fn redirector_tcp_fast_open() {
+    use capture::OverflowPolicy;
+    use pnet::packet::tcp::{TcpFlags, TcpPacket};
+    use std::net::SocketAddr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::time;
+
+    let local_hardware_addr: HardwareAddr = "22:22:22:22:22:22".parse().unwrap();
+    let src_hardware_addr: HardwareAddr = "11:11:11:11:11:11".parse().unwrap();
+    let src: SocketAddrV4 = "10.6.0.2:40000".parse().unwrap();
+    let dst: SocketAddrV4 = "1.1.1.1:80".parse().unwrap();
+    let request = b"GET / HTTP/1.1\r\nHost: 1.1.1.1\r\n\r\n";
+
+    // A SOCKS5 server collecting the payload forwarded
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let remote = match listener.local_addr().unwrap() {
+        SocketAddr::V4(addr) => addr,
+        SocketAddr::V6(_) => unreachable!(),
+    };
+    let server = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+
+        // VER, NMETHODS, METHODS
+        let mut buffer = [0u8; 2];
+        stream.read_exact(&mut buffer).await.unwrap();
+        let mut methods = vec![0u8; buffer[1] as usize];
+        stream.read_exact(&mut methods).await.unwrap();
+        stream.write_all(&[5, 0]).await.unwrap();
+        // VER, CMD, RSV, ATYP, DST.ADDR, DST.PORT
+        let mut request = [0u8; 10];
+        stream.read_exact(&mut request).await.unwrap();
+        stream
+            .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+
+        let mut received = vec![0u8; 64];
+        let size = stream.read(&mut received).await.unwrap();
+        received.truncate(size);
+
+        received
+    });
+
+    // Feed frames from a queue and collect frames sent to another queue instead of a pcap device
+    let rx_queue = FrameQueue::new(16, OverflowPolicy::Block).unwrap();
+    let tx_queue = FrameQueue::new(16, OverflowPolicy::Block).unwrap();
+    let forwarder = Forwarder::new(
+        pcap::sender(tx_queue.clone()),
+        1500,
+        local_hardware_addr,
+        "10.6.0.1".parse().unwrap(),
+    );
+    let mut redirector = Redirector::new(
+        Arc::new(Mutex::new(forwarder)),
+        "10.6.0.0/24".parse().unwrap(),
+        "10.6.0.1".parse().unwrap(),
+        None,
+        ProxyConfig::new_socks(remote, false, false, None),
+    );
+    redirector.set_isn_strategy(IsnStrategy::Fixed);
+
+    let frame =
+        |flags: u16, sequence: u32, acknowledgement: u32, options: &[u8], payload: &[u8]| {
+            let ipv4 = Ipv4::new(0, LayerKinds::Tcp, *src.ip(), *dst.ip()).unwrap();
+            // The header is built in bytes, so the options are parsed as captured
+            let mut b = vec![0u8; Tcp::minimum_len() + options.len()];
+            b[0..2].copy_from_slice(&src.port().to_be_bytes());
+            b[2..4].copy_from_slice(&dst.port().to_be_bytes());
+            b[4..8].copy_from_slice(&sequence.to_be_bytes());
+            b[8..12].copy_from_slice(&acknowledgement.to_be_bytes());
+            b[12] = ((b.len() / 4) as u8) << 4;
+            b[13] = flags as u8;
+            b[14..16].copy_from_slice(&65535u16.to_be_bytes());
+            b[20..].copy_from_slice(options);
+            let tcp = Tcp::parse(&TcpPacket::new(&b).unwrap(), &ipv4);
+            let indicator = Indicator::new(
+                Layers::Ethernet(
+                    Ethernet::new(LayerKinds::Ipv4, src_hardware_addr, local_hardware_addr)
+                        .unwrap(),
+                ),
+                Some(Layers::Ipv4(ipv4)),
+                Some(Layers::Tcp(tcp)),
+            );
+            let mut frame = vec![0u8; indicator.len() + payload.len()];
+            indicator
+                .serialize_with_payload(frame.as_mut_slice(), payload)
+                .unwrap();
+
+            frame
+        };
+
+    // A SYN carrying the request and a TCP Fast Open cookie, followed by the handshake completed
+    // and the request retransmitted after the SYN
+    let options = [
+        // MSS, 2 NOPs and the TCP Fast Open cookie
+        2, 4, 0x05, 0xb4, 1, 1, 34, 10, 0xc0, 0x0c, 0x1e, 0x00, 0xde, 0xad, 0xbe, 0xef,
+    ];
+    assert!(rx_queue.push(frame(TcpFlags::SYN, 1000, 0, &options, request)));
+    assert!(rx_queue.push(frame(TcpFlags::ACK, 1001, 1, &[], &[])));
+    assert!(rx_queue.push(frame(TcpFlags::ACK | TcpFlags::PSH, 1001, 1, &[], request)));
+    rx_queue.close(None);
+
+    // The redirection stops once the frames fed are drained
+    let mut rx = pcap::receiver(rx_queue, Some(Duration::from_millis(0)));
+    let e = redirector.open(&mut rx).await.unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::BrokenPipe);
+
+    // The payload in the SYN is not acknowledged, and the cookie is not echoed
+    let frame = tx_queue.pop(Some(Duration::from_millis(0))).unwrap();
+    let indicator = Indicator::from(frame.as_slice()).unwrap();
+    let syn_ack = indicator.tcp().unwrap();
+    assert!(syn_ack.is_syn() && syn_ack.is_ack());
+    assert_eq!(syn_ack.acknowledgement(), 1001);
+    assert!(!syn_ack.is_fast_open());
+    assert_eq!(syn_ack.fast_open_cookie(), None);
+    while let Ok(frame) = tx_queue.pop(Some(Duration::from_millis(0))) {
+        let indicator = Indicator::from(frame.as_slice()).unwrap();
+        let tcp = indicator.tcp().unwrap();
+        assert!(!tcp.is_rst());
+        assert!(seq_sub(tcp.acknowledgement(), 1001) as usize <= request.len());
     }
+
+    // The request retransmitted is delivered once from the sequence after the SYN
+    let received = time::timeout(Duration::from_secs(1), server)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(received, request.to_vec());
 }
@@ -1,135 +1,572 @@
 use env_logger::fmt::{Color, Formatter, Target};
-use ipnetwork::Ipv4Network;
+use ipnetwork::{IpNetworkError, Ipv4Network};
 use log::{error, info, warn, Level, LevelFilter, Log, Metadata, Record};
 use std::clone::Clone;
 use std::fmt::Display;
 use std::io::{self, Write};
-use std::net::{AddrParseError, IpAddr, Ipv4Addr, SocketAddrV4};
+use std::net::{AddrParseError, IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::num::ParseIntError;
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::atomic::AtomicUsize;
 use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 use structopt::StructOpt;
+use tokio::runtime::Runtime;
 
+use pcap2socks::admin::{self, RecentEvents};
+use pcap2socks::capture::{FrameQueue, OverflowPolicy};
+use pcap2socks::digest::DigestAlgorithm;
+use pcap2socks::encap::Encapsulation;
+use pcap2socks::flowdump::{DumpSignal, FlowDump};
+use pcap2socks::flowlog::{FlowLog, FlowLogFormat};
+use pcap2socks::mapping::PortStrategy;
+use pcap2socks::mirror::Mirror;
+use pcap2socks::packet::layer::ethernet::Ethernet;
+use pcap2socks::pcap::{self, HardwareAddr, LinkType, PollMode};
+use pcap2socks::shard::{self, Dispatcher};
+use pcap2socks::tcp::IsnStrategy;
 use pcap2socks::{self as lib, Forwarder, ProxyConfig, Redirector};
 
+/// Represents the default size of the queue of frames of each shard.
+const SHARD_QUEUE_SIZE: usize = 4096;
+
 #[tokio::main]
 async fn main() {
     // Parse arguments
     let flags = Flags::from_args();
 
     // Log
-    set_logger(flags.verbose);
+    let recent = set_logger(flags.verbose, flags.admin.is_some());
 
     // Interface
-    let inter = match lib::interface(flags.inter) {
-        Some(inter) => inter,
-        None => {
-            error!("Cannot determine the interface. Available interfaces are listed below, and please use -i <INTERFACE> to designate:");
-            for inter in lib::interfaces().iter() {
-                info!("    {}", inter);
-            }
-            return;
-        }
-    };
-    info!("Listen on {}", inter);
-
-    // MTU
-    let mtu = match flags.mtu {
-        Some(mtu) => mtu,
-        None => {
-            if inter.mtu() <= 0 {
-                error!("Cannot obtain the MTU. Please use --mtu <VALUE> to set");
+    let inters = match flags.inters.len() {
+        0 => match lib::interface(None) {
+            Some(inter) => vec![inter],
+            None => {
+                error!("Cannot determine the interface. Available interfaces are listed below, and please use -i <INTERFACE> to designate:");
+                for inter in lib::interfaces().iter() {
+                    info!("    {}", inter);
+                }
                 return;
             }
+        },
+        _ => {
+            let mut inters = Vec::new();
+            for name in flags.inters.iter() {
+                match lib::interface(Some(name.clone())) {
+                    Some(inter) => inters.push(inter),
+                    None => {
+                        error!(
+                            "Cannot find the interface {}. Available interfaces are listed below:",
+                            name
+                        );
+                        for inter in lib::interfaces().iter() {
+                            info!("    {}", inter);
+                        }
+                        return;
+                    }
+                }
+            }
 
-            inter.mtu()
+            inters
         }
     };
-    info!("Use MTU {}", mtu);
-
-    // Route
-    let src = match flags.preset {
-        Some(ref preset) => match preset.as_str() {
-            "t" | "tencent" => Ipv4Network::new(Ipv4Addr::new(10, 6, 0, 1), 32).unwrap(),
-            "n" | "netease" | "u" | "uu" => {
-                let mut ip_octets = inter.ip_addr().unwrap().octets();
-                ip_octets[0] = 172;
-                ip_octets[1] = 24;
-                ip_octets[2] = ip_octets[2].checked_add(1).unwrap_or(0);
-
-                Ipv4Network::new(Ipv4Addr::from(ip_octets), 32).unwrap()
+    if flags.any && inters.len() != 1 {
+        error!(
+            "Only one interface for output can be designated when capturing on all the interfaces"
+        );
+        return;
+    }
+    if let Some(dscp) = flags.dscp {
+        if dscp > 63 {
+            error!("The DSCP {} is out of range 0-63", dscp);
+            return;
+        }
+    }
+    if flags.shards == 0 {
+        error!("The count of shards must be at least 1");
+        return;
+    }
+    if flags.loss > 1000 || flags.reorder > 1000 {
+        error!("The synthetic loss and reorder rates must be in range 0-1000");
+        return;
+    }
+    if flags.src.len() > 1 && flags.src.len() != inters.len() {
+        error!("The count of sources must be 1 or the same with the count of interfaces");
+        return;
+    }
+    if flags.publish.len() > 1 && flags.publish.len() != inters.len() {
+        error!("The count of ARP publishing addresses must be 1 or the same with the count of interfaces");
+        return;
+    }
+
+    let mut routes = Vec::new();
+    for (i, inter) in inters.iter().enumerate() {
+        // MTU
+        let mtu = match flags.mtu {
+            Some(mtu) => mtu,
+            None => {
+                if inter.mtu() <= 0 {
+                    error!(
+                        "Cannot obtain the MTU of {}. Please use --mtu <VALUE> to set",
+                        inter.name()
+                    );
+                    return;
+                }
+
+                inter.mtu()
             }
-            _ => {
-                error!("The preset {} is not available", preset);
+        };
+
+        // Route
+        let src = match flags.preset {
+            Some(ref preset) => match preset.as_str() {
+                "t" | "tencent" => Ipv4Network::new(Ipv4Addr::new(10, 6, 0, 1), 32).unwrap(),
+                "n" | "netease" | "u" | "uu" => {
+                    let mut ip_octets = inter.ip_addr().unwrap().octets();
+                    ip_octets[0] = 172;
+                    ip_octets[1] = 24;
+                    ip_octets[2] = ip_octets[2].checked_add(1).unwrap_or(0);
+
+                    Ipv4Network::new(Ipv4Addr::from(ip_octets), 32).unwrap()
+                }
+                _ => {
+                    error!("The preset {} is not available", preset);
+                    return;
+                }
+            },
+            None => *flags.src.get(i).unwrap_or(&flags.src[0]),
+        };
+        let publish = match flags.preset {
+            Some(ref preset) => match preset.as_str() {
+                "t" | "tencent" => Some(Ipv4Addr::new(10, 6, 0, 2)),
+                "n" | "netease" | "u" | "uu" => {
+                    let mut ip_octets = inter.ip_addr().unwrap().octets();
+                    ip_octets[0] = 172;
+                    ip_octets[1] = 24;
+
+                    Some(Ipv4Addr::from(ip_octets))
+                }
+                _ => {
+                    error!("The preset {} is not available", preset);
+                    return;
+                }
+            },
+            None => flags
+                .publish
+                .get(i)
+                .or_else(|| flags.publish.first())
+                .cloned(),
+        };
+
+        // Gateway
+        let gw = publish.unwrap_or(inter.ip_addr().unwrap());
+
+        routes.push((mtu, src, publish, gw));
+    }
+
+    // Validate routes between interfaces
+    for (i, (_, src, _, _)) in routes.iter().enumerate() {
+        for (j, (_, other_src, _, other_gw)) in routes.iter().enumerate() {
+            if src.size() == 1 && src.network() == *other_gw {
+                error!("The source cannot be the same with the gateway (publish)");
                 return;
             }
-        },
-        None => flags.src.unwrap(),
-    };
-    let publish = match flags.preset {
-        Some(ref preset) => match preset.as_str() {
-            "t" | "tencent" => Some(Ipv4Addr::new(10, 6, 0, 2)),
-            "n" | "netease" | "u" | "uu" => {
-                let mut ip_octets = inter.ip_addr().unwrap().octets();
-                ip_octets[0] = 172;
-                ip_octets[1] = 24;
-
-                Some(Ipv4Addr::from(ip_octets))
-            }
-            _ => {
-                error!("The preset {} is not available", preset);
+            if i != j && (src.contains(other_src.network()) || other_src.contains(src.network())) {
+                error!(
+                    "The sources of {} and {} cannot overlap",
+                    inters[i].name(),
+                    inters[j].name()
+                );
                 return;
             }
-        },
-        None => flags.publish,
-    };
-
-    // Publish
-    if let Some(publish) = publish {
-        info!("Publish for {}", publish);
+        }
     }
 
-    // Gateway
-    let gw = publish.unwrap_or(inter.ip_addr().unwrap());
-    if src.size() == 1 && src.network() == gw {
-        error!("The source cannot be the same with the gateway (publish)");
-        return;
+    // Admin
+    if let Some(admin) = flags.admin {
+        match admin::listen(admin, Arc::clone(&recent)).await {
+            Ok(addr) => info!("Serve recent events at http://{}/recent", addr),
+            Err(ref e) => {
+                error!("{}", e);
+                return;
+            }
+        }
     }
 
-    // Instructions
-    show_info(src, gw, mtu);
-
     // Proxy
-    let (tx, mut rx) = match inter.open() {
-        Ok((tx, rx)) => (tx, rx),
-        Err(ref e) => {
+    let auth = match flags.username {
+        Some(ref username) => Some((username.clone(), flags.password.clone().unwrap())),
+        None => None,
+    };
+
+    // Flow dump
+    let dump_requests = Arc::new(AtomicUsize::new(0));
+    if flags.flow_dump.is_some() {
+        if let Err(ref e) = listen_dump_signal(flags.flow_dump_signal, Arc::clone(&dump_requests)) {
             error!("{}", e);
             return;
         }
+    }
+
+    // Flow log and mirror, which are shared by all the interfaces and shards
+    let flow_log = match flags.flow_log {
+        Some(ref path) => match FlowLog::open(path, flags.flow_log_format) {
+            Ok(flow_log) => Some(Arc::new(Mutex::new(flow_log))),
+            Err(ref e) => {
+                error!("{}", e);
+                return;
+            }
+        },
+        None => None,
     };
-    let forwarder = Forwarder::new(tx, mtu, inter.hardware_addr(), inter.ip_addr().unwrap());
-    let auth = match flags.username {
-        Some(ref username) => Some((username.clone(), flags.password.unwrap())),
+    let mirror = match flags.mirror_to {
+        Some(ref target) => match Mirror::open(target) {
+            Ok(mirror) => Some(Arc::new(mirror)),
+            Err(ref e) => {
+                error!("{}", e);
+                return;
+            }
+        },
         None => None,
     };
-    let mut redirector = Redirector::new(
-        Arc::new(Mutex::new(forwarder)),
-        src,
-        gw,
-        publish,
-        ProxyConfig::new_socks(
-            flags.dst.addr(),
-            flags.force_associate_dst,
-            flags.force_associate_bind_addr,
-            auth,
-        ),
-    );
-    match flags.username {
-        Some(username) => info!("Proxy {} to {}@{}", src, username, flags.dst),
-        None => info!("Proxy {} to {}", src, flags.dst),
+
+    let mut redirectors = Vec::new();
+    for (inter, (mtu, src, publish, gw)) in inters.iter().zip(routes.into_iter()) {
+        info!("Listen on {}", inter);
+        info!("Use MTU {}", mtu);
+        if inter.link_type() == LinkType::Null {
+            info!("Use the null/loopback link type, ARP is disabled");
+        }
+
+        // Publish
+        if let Some(publish) = publish {
+            info!("Publish for {}", publish);
+        }
+
+        // Instructions
+        show_info(src, gw, mtu);
+
+        // Snapshot length
+        if let Some(snaplen) = flags.snaplen {
+            if snaplen < Ethernet::minimum_len() + mtu {
+                warn!(
+                    "The snaplen {} is smaller than the frame size {}, frames may be truncated and dropped",
+                    snaplen,
+                    Ethernet::minimum_len() + mtu
+                );
+            }
+        }
+
+        // A capture timeout of 0 blocks until a frame arrives
+        let capture_timeout = match flags.capture_timeout {
+            0 => None,
+            capture_timeout => Some(Duration::from_millis(capture_timeout)),
+        };
+        let capture_timeout = flags.poll.read_timeout(capture_timeout);
+        let channel = match flags.any {
+            true => inter.open_any(capture_timeout),
+            false => inter.open(capture_timeout),
+        };
+        // Frames from all the interfaces are received in the Linux cooked link type
+        let link_type = match flags.any {
+            true => LinkType::LinuxSll,
+            false => inter.link_type(),
+        };
+        let (tx, rx) = match channel {
+            Ok((tx, rx)) => (tx, rx),
+            Err(ref e) => {
+                error!("{}", e);
+                return;
+            }
+        };
+        // Hand off frames from a capture thread
+        let (txs, rxs, capture_queues) = match flags.shards {
+            1 => {
+                let capture_queue = match flags.capture_queue {
+                    Some(size) => match FrameQueue::new(size, flags.capture_queue_overflow) {
+                        Ok(queue) => Some(queue),
+                        Err(ref e) => {
+                            error!("{}", e);
+                            return;
+                        }
+                    },
+                    None => None,
+                };
+                let rx = match capture_queue {
+                    Some(ref queue) => pcap::queued(rx, queue.clone(), capture_timeout),
+                    None => rx,
+                };
+
+                (vec![tx], vec![rx], vec![capture_queue])
+            }
+            shards => {
+                // Each shard takes frames from a queue of its own
+                let size = flags.capture_queue.unwrap_or(SHARD_QUEUE_SIZE);
+                let mut queues = Vec::new();
+                for _ in 0..shards {
+                    match FrameQueue::new(size, flags.capture_queue_overflow) {
+                        Ok(queue) => queues.push(queue),
+                        Err(ref e) => {
+                            error!("{}", e);
+                            return;
+                        }
+                    }
+                }
+                let rxs = match Dispatcher::new(shards, link_type).and_then(|dispatcher| {
+                    shard::dispatched(rx, dispatcher, queues.clone(), capture_timeout)
+                }) {
+                    Ok(rxs) => rxs,
+                    Err(ref e) => {
+                        error!("{}", e);
+                        return;
+                    }
+                };
+
+                (
+                    pcap::shared(tx, shards),
+                    rxs,
+                    queues.into_iter().map(Some).collect(),
+                )
+            }
+        };
+        let first = redirectors.len();
+        let mut forwarders = Vec::new();
+        let shards = txs.into_iter().zip(rxs).zip(capture_queues).enumerate();
+        for (shard, ((tx, rx), capture_queue)) in shards {
+            let mut forwarder =
+                Forwarder::new(tx, mtu, inter.hardware_addr(), inter.ip_addr().unwrap());
+            forwarder.set_hardware_addr_rules(
+                flags
+                    .rewrite_mac
+                    .iter()
+                    .filter(|rule| rule.is_applied(inter.name()))
+                    .map(|rule| rule.rule())
+                    .collect(),
+            );
+            let forwarder = Arc::new(Mutex::new(forwarder));
+            forwarders.push(Arc::clone(&forwarder));
+            let mut redirector = Redirector::new(
+                forwarder,
+                src,
+                gw,
+                publish,
+                ProxyConfig::new_socks(
+                    flags.dst.addr(),
+                    flags.force_associate_dst,
+                    flags.force_associate_bind_addr,
+                    auth.clone(),
+                ),
+            );
+            redirector.set_link_type(link_type);
+            redirector.set_capture_queue(capture_queue);
+            redirector.set_ecn(flags.ecn);
+            redirector.set_rst_unknown(flags.rst_unknown);
+            redirector.set_initial_window(flags.iw);
+            redirector.set_reconnect(flags.reconnect);
+            redirector.set_coalesce(flags.coalesce);
+            redirector.set_snaplen(flags.snaplen);
+            redirector.set_poll(flags.poll);
+            redirector.set_encapsulation(flags.encapsulation);
+            redirector.set_inbound_only(flags.inbound_only);
+            redirector.set_bypass(flags.bypass.iter().map(|rule| rule.rule()).collect());
+            if let Err(ref e) = redirector.set_ports(flags.ports.clone()) {
+                error!("{}", e);
+                return;
+            }
+            if let (Some(src), Some(dst)) = (flags.hexdump_src, flags.hexdump_dst) {
+                redirector.set_hexdump(Some((src, dst)));
+            }
+            redirector.set_dscp(flags.dscp);
+            redirector.set_max_retrans(flags.max_retrans);
+            redirector.set_cache_soft_limit(flags.cache_soft_limit);
+            redirector.set_cache_alloc(flags.eager_alloc);
+            redirector.set_scheduler(!flags.no_scheduler);
+            // Rules replace the default weights of interactive connections
+            if !flags.weight.is_empty() || !flags.dscp_weight.is_empty() {
+                if let Err(ref e) = redirector.set_weights(
+                    flags.weight.iter().map(|rule| rule.rule()).collect(),
+                    flags.dscp_weight.iter().map(|rule| rule.rule()).collect(),
+                ) {
+                    error!("{}", e);
+                    return;
+                }
+            }
+            redirector.set_strict_window(flags.strict_window);
+            redirector.set_unordered(flags.unordered);
+            redirector.set_reassembly_deadline(flags.reassembly_deadline);
+            redirector.set_md5_sig(flags.md5_sig);
+            redirector.set_proxy_protocol(flags.proxy_protocol.clone());
+            redirector.set_defer_connect(flags.defer_connect.clone());
+            redirector.set_idle_timeout(flags.idle_timeout);
+            redirector.set_isn_strategy(flags.isn);
+            redirector.set_port_strategy(flags.port_strategy);
+            redirector.set_max_sacks(flags.max_sacks);
+            redirector.set_rst_out_of_window(flags.rst_out_of_window);
+            redirector.set_rst_desync(flags.rst_desync);
+            if let Err(ref e) = redirector.set_max_dup_acks(flags.max_dup_acks) {
+                error!("{}", e);
+                return;
+            }
+            if let Err(ref e) = redirector.set_max_unsent(flags.max_unsent) {
+                error!("{}", e);
+                return;
+            }
+            if let Err(ref e) = redirector.set_max_handshakes(flags.max_handshakes) {
+                error!("{}", e);
+                return;
+            }
+            if let Err(ref e) = redirector.set_ack_frequency(flags.ack_segments, flags.ack_delay) {
+                error!("{}", e);
+                return;
+            }
+            if let Err(ref e) = redirector
+                .set_cache_sizes(flags.cache_size.iter().map(|rule| rule.rule()).collect())
+            {
+                error!("{}", e);
+                return;
+            }
+            if let Err(ref e) = redirector.set_initial_recv_window(flags.initial_recv_window) {
+                error!("{}", e);
+                return;
+            }
+            // Each interface and each shard persists mappings in its own file
+            let mappings_path = match flags.mappings {
+                Some(ref path) => {
+                    let mut path = path.clone().into_os_string();
+                    if inters.len() > 1 {
+                        path.push(format!(".{}", inter.name()));
+                    }
+                    if flags.shards > 1 {
+                        path.push(format!(".{}", shard));
+                    }
+
+                    Some(PathBuf::from(path))
+                }
+                None => None,
+            };
+            if let Err(ref e) = redirector.set_mappings_path(mappings_path) {
+                error!("{}", e);
+                return;
+            }
+            redirector.set_impairment(flags.loss, flags.reorder, flags.seed);
+            if let Some(ref flow_log) = flow_log {
+                redirector.set_flow_log(Some(Arc::clone(flow_log)));
+                redirector.set_digest(flags.flow_digest);
+            }
+            // Each interface and each shard dumps flows in its own file
+            let flow_dump_path = match flags.flow_dump {
+                Some(ref path) if path.to_str() != Some("-") => {
+                    let mut path = path.clone().into_os_string();
+                    if inters.len() > 1 {
+                        path.push(format!(".{}", inter.name()));
+                    }
+                    if flags.shards > 1 {
+                        path.push(format!(".{}", shard));
+                    }
+
+                    Some(PathBuf::from(path))
+                }
+                Some(ref path) => Some(path.clone()),
+                None => None,
+            };
+            redirector.set_flow_dump(
+                flow_dump_path.map(|path| FlowDump::new(path, Arc::clone(&dump_requests))),
+            );
+            if let Some(ref mirror) = mirror {
+                redirector.set_mirror(
+                    Some(Arc::clone(mirror)),
+                    flags.mirror.iter().map(|rule| rule.rule()).collect(),
+                );
+            }
+            // Each interface resolves only the sources in its own network, and ARP is handled by
+            // the first shard only
+            if shard == 0 {
+                redirector.set_resolve(
+                    flags
+                        .resolve
+                        .iter()
+                        .filter(|ip_addr| src.contains(**ip_addr))
+                        .copied()
+                        .collect(),
+                    flags.resolve_fallback,
+                );
+            }
+
+            let name = match flags.shards {
+                1 => inter.name().clone(),
+                _ => format!("{}#{}", inter.name(), shard),
+            };
+            redirectors.push((name, redirector, rx));
+        }
+        // The first shard sets the hardware addresses resolved via ARP to the other shards
+        redirectors[first].1.set_peers(forwarders.split_off(1));
+        match flags.username {
+            Some(ref username) => info!("Proxy {} to {}@{}", src, username, flags.dst),
+            None => info!("Proxy {} to {}", src, flags.dst),
+        }
+    }
+
+    // Interfaces other than the first one are redirected in their own threads
+    let (_, mut redirector, mut rx) = redirectors.remove(0);
+    for (name, mut redirector, mut rx) in redirectors.into_iter() {
+        thread::spawn(move || {
+            let rt = match Runtime::new() {
+                Ok(rt) => rt,
+                Err(ref e) => {
+                    error!("{}: {}", name, e);
+                    return;
+                }
+            };
+            if let Err(ref e) = rt.block_on(redirector.open(&mut rx)) {
+                error!("{}: {}", name, e);
+            }
+        });
+    }
+    // Notify the service manager the capture loops are up
+    #[cfg(all(target_os = "linux", feature = "systemd"))]
+    {
+        if let Err(ref e) = lib::systemd::notify("READY=1") {
+            warn!("notify service manager: {}", e);
+        }
     }
     if let Err(ref e) = redirector.open(&mut rx).await {
         error!("{}", e);
     }
+    #[cfg(all(target_os = "linux", feature = "systemd"))]
+    {
+        let _ = lib::systemd::notify("STOPPING=1");
+    }
+}
+
+/// Listens on the signal in the background, and counts each delivery in the requests.
+#[cfg(unix)]
+fn listen_dump_signal(signal: DumpSignal, requests: Arc<AtomicUsize>) -> io::Result<()> {
+    use std::sync::atomic::Ordering;
+    use tokio::signal::unix::{self, SignalKind};
+
+    let kind = match signal {
+        DumpSignal::Usr1 => SignalKind::user_defined1(),
+        DumpSignal::Usr2 => SignalKind::user_defined2(),
+    };
+    let mut stream = unix::signal(kind)?;
+    tokio::spawn(async move {
+        while stream.recv().await.is_some() {
+            requests.fetch_add(1, Ordering::Relaxed);
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn listen_dump_signal(signal: DumpSignal, _: Arc<AtomicUsize>) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        format!("signal {} is not supported on this platform", signal),
+    ))
 }
 
 fn show_info(src: Ipv4Network, gw: Ipv4Addr, mtu: usize) {
@@ -198,9 +635,22 @@ struct Flags {
         short,
         help = "Interface for listening",
         value_name = "INTERFACE",
+        number_of_values = 1,
         display_order(0)
     )]
-    pub inter: Option<String>,
+    pub inters: Vec<String>,
+    #[structopt(
+        long,
+        help = "Capture on all the interfaces (Linux only)",
+        display_order(1005)
+    )]
+    pub any: bool,
+    #[structopt(
+        long = "inbound-only",
+        help = "Handle inbound frames only",
+        display_order(1006)
+    )]
+    pub inbound_only: bool,
     #[structopt(long, help = "MTU", value_name = "VALUE", display_order(1))]
     pub mtu: Option<usize>,
     #[structopt(
@@ -217,17 +667,19 @@ struct Flags {
         help = "Source",
         value_name = "ADDRESS",
         required_unless("preset"),
+        number_of_values = 1,
         display_order(3)
     )]
-    pub src: Option<Ipv4Network>,
+    pub src: Vec<Ipv4Network>,
     #[structopt(
         long,
         short,
         help = "ARP publishing address",
         value_name = "ADDRESS",
+        number_of_values = 1,
         display_order(4)
     )]
-    pub publish: Option<Ipv4Addr>,
+    pub publish: Vec<Ipv4Addr>,
     #[structopt(
         long = "destination",
         short,
@@ -237,6 +689,22 @@ struct Flags {
         display_order(5)
     )]
     pub dst: ResolvableSocketAddrV4,
+    #[structopt(
+        long,
+        help = "Destinations bypassing the proxy",
+        value_name = "RULE",
+        number_of_values = 1,
+        display_order(6)
+    )]
+    pub bypass: Vec<BypassRule>,
+    #[structopt(
+        long,
+        help = "Destination ports of TCP traffic proxied",
+        value_name = "PORT",
+        number_of_values = 1,
+        display_order(27)
+    )]
+    pub ports: Vec<u16>,
     #[structopt(
         long = "force-associate-destination",
         help = "Force to associate with the destination",
@@ -250,6 +718,417 @@ struct Flags {
         display_order(1001)
     )]
     pub force_associate_bind_addr: bool,
+    #[structopt(long, help = "Negotiate ECN in TCP connections", display_order(1002))]
+    pub ecn: bool,
+    #[structopt(
+        long = "rst-unknown",
+        help = "Reset TCP segments of unknown connections",
+        display_order(1003)
+    )]
+    pub rst_unknown: bool,
+    #[structopt(
+        long,
+        help = "Reconnect to the proxy after TCP connections are reset",
+        display_order(1004)
+    )]
+    pub reconnect: bool,
+    #[structopt(
+        long = "strict-window",
+        help = "Drop TCP payload beyond the advertised window",
+        display_order(1007)
+    )]
+    pub strict_window: bool,
+    #[structopt(
+        long,
+        help = "Forward TCP payload in the order of arrival without reassembly",
+        display_order(1008)
+    )]
+    pub unordered: bool,
+    #[structopt(
+        long = "rst-desync",
+        help = "Reset TCP connections desynchronized from the source",
+        display_order(1009)
+    )]
+    pub rst_desync: bool,
+    #[structopt(
+        long = "initial-window",
+        help = "Initial congestion window in segments of TCP connections",
+        value_name = "VALUE",
+        default_value = "1",
+        display_order(7)
+    )]
+    pub iw: usize,
+    #[structopt(
+        long,
+        help = "Snapshot length of frames",
+        value_name = "VALUE",
+        display_order(8)
+    )]
+    pub snaplen: Option<usize>,
+    #[structopt(
+        long = "capture-timeout",
+        help = "Read timeout of the capture",
+        value_name = "MS",
+        default_value = "100",
+        display_order(18)
+    )]
+    pub capture_timeout: u64,
+    #[structopt(
+        long,
+        help = "Mode of polling the capture",
+        value_name = "MODE",
+        possible_values = &["block", "busy"],
+        default_value = "block",
+        display_order(37)
+    )]
+    pub poll: PollMode,
+    #[structopt(
+        long = "hexdump-source",
+        help = "Source of the TCP connection to dump in hex",
+        value_name = "ADDRESS",
+        requires("hexdump_dst"),
+        display_order(9)
+    )]
+    pub hexdump_src: Option<SocketAddrV4>,
+    #[structopt(
+        long = "hexdump-destination",
+        help = "Destination of the TCP connection to dump in hex",
+        value_name = "ADDRESS",
+        requires("hexdump_src"),
+        display_order(10)
+    )]
+    pub hexdump_dst: Option<SocketAddrV4>,
+    #[structopt(
+        long,
+        help = "DSCP of packets sent to the source",
+        value_name = "VALUE",
+        display_order(11)
+    )]
+    pub dscp: Option<u8>,
+    #[structopt(
+        long,
+        help = "Delay coalescing small payload sent to the proxy",
+        value_name = "MS",
+        display_order(12)
+    )]
+    pub coalesce: Option<u64>,
+    #[structopt(
+        long = "max-retrans",
+        help = "Maximum count of retransmissions of TCP connections",
+        value_name = "VALUE",
+        default_value = "15",
+        display_order(16)
+    )]
+    pub max_retrans: usize,
+    #[structopt(
+        long,
+        help = "File persisting mappings of connections",
+        value_name = "FILE",
+        display_order(17)
+    )]
+    pub mappings: Option<PathBuf>,
+    #[structopt(
+        long = "flow-log",
+        help = "File logging a record of each connection when it closes (\"-\" for stdout)",
+        value_name = "FILE",
+        display_order(19)
+    )]
+    pub flow_log: Option<PathBuf>,
+    #[structopt(
+        long = "flow-log-format",
+        help = "Format of the flow log",
+        value_name = "FORMAT",
+        possible_values = &["json", "text"],
+        default_value = "json",
+        display_order(20)
+    )]
+    pub flow_log_format: FlowLogFormat,
+    #[structopt(
+        long,
+        help = "Sources resolving the hardware address via ARP at startup",
+        value_name = "ADDRESS",
+        number_of_values = 1,
+        display_order(21)
+    )]
+    pub resolve: Vec<Ipv4Addr>,
+    #[structopt(
+        long = "resolve-fallback",
+        help = "Hardware address of sources which cannot be resolved",
+        value_name = "MAC",
+        requires = "resolve",
+        display_order(22)
+    )]
+    pub resolve_fallback: Option<HardwareAddr>,
+    #[structopt(
+        long = "proxy-protocol",
+        help = "Destination ports of TCP connections prepended by a PROXY protocol header",
+        value_name = "PORT",
+        number_of_values = 1,
+        display_order(26)
+    )]
+    pub proxy_protocol: Vec<u16>,
+    #[structopt(
+        long = "cache-soft-limit",
+        help = "Soft limit of receive caches in percentage, beyond which the window shrinks",
+        value_name = "PERCENT",
+        default_value = "80",
+        display_order(23)
+    )]
+    pub cache_soft_limit: usize,
+    #[structopt(
+        long = "max-sacks",
+        help = "Maximum count of SACK blocks reported in a TCP segment",
+        value_name = "VALUE",
+        default_value = "4",
+        display_order(24)
+    )]
+    pub max_sacks: usize,
+    #[structopt(
+        long = "initial-recv-window",
+        help = "Initial receive window of TCP connections",
+        value_name = "BYTES",
+        display_order(25)
+    )]
+    pub initial_recv_window: Option<usize>,
+    #[structopt(
+        long = "rst-out-of-window",
+        help = "Count of TCP segments out of window in a second before resetting the connection",
+        value_name = "COUNT",
+        display_order(28)
+    )]
+    pub rst_out_of_window: Option<usize>,
+    #[structopt(
+        long = "cache-size",
+        help = "Cache size of TCP connections to destinations",
+        value_name = "RULE=BYTES",
+        number_of_values = 1,
+        display_order(29)
+    )]
+    pub cache_size: Vec<CacheSizeRule>,
+    #[structopt(
+        long = "flow-digest",
+        help = "Algorithm of digests of payload in the flow log",
+        value_name = "ALGORITHM",
+        possible_values = &["sha256", "fnv1a64"],
+        requires = "flow_log",
+        display_order(30)
+    )]
+    pub flow_digest: Option<DigestAlgorithm>,
+    #[structopt(
+        long = "max-handshakes",
+        help = "Maximum count of SOCKS handshakes in flight",
+        value_name = "COUNT",
+        display_order(31)
+    )]
+    pub max_handshakes: Option<usize>,
+    #[structopt(
+        long = "ack-segments",
+        help = "Maximum count of TCP segments received in order before an ACK",
+        value_name = "COUNT",
+        default_value = "2",
+        display_order(32)
+    )]
+    pub ack_segments: usize,
+    #[structopt(
+        long = "ack-delay",
+        help = "Maximum delay of an ACK of TCP segments received in order",
+        value_name = "MS",
+        default_value = "200",
+        display_order(33)
+    )]
+    pub ack_delay: u64,
+    #[structopt(
+        long,
+        help = "Destinations of TCP connections mirrored",
+        value_name = "RULE",
+        number_of_values = 1,
+        requires = "mirror_to",
+        display_order(34)
+    )]
+    pub mirror: Vec<BypassRule>,
+    #[structopt(
+        long = "mirror-to",
+        help = "File or UDP collector mirroring payload of TCP connections",
+        value_name = "TARGET",
+        requires = "mirror",
+        display_order(35)
+    )]
+    pub mirror_to: Option<String>,
+    #[structopt(
+        long = "defer-connect",
+        help = "Destination ports of TCP connections connecting to the proxy after the first payload",
+        value_name = "PORT",
+        number_of_values = 1,
+        display_order(36)
+    )]
+    pub defer_connect: Vec<u16>,
+    #[structopt(
+        long = "idle-timeout",
+        help = "Time of TCP connections without payload before reaped",
+        value_name = "MS",
+        display_order(38)
+    )]
+    pub idle_timeout: Option<u64>,
+    #[structopt(
+        long,
+        help = "Strategy of generating initial sequence numbers of TCP connections",
+        value_name = "STRATEGY",
+        possible_values = &["random", "fixed", "sequential"],
+        default_value = "random",
+        display_order(39)
+    )]
+    pub isn: IsnStrategy,
+    #[structopt(
+        long = "port-strategy",
+        help = "Strategy of allocating local UDP ports bound for sources",
+        value_name = "STRATEGY",
+        possible_values = &["ephemeral", "lowest", "hash"],
+        default_value = "ephemeral",
+        display_order(48)
+    )]
+    pub port_strategy: PortStrategy,
+    #[structopt(
+        long,
+        help = "Encapsulation of traffic decapsulated before redirected",
+        value_name = "TYPE",
+        possible_values = &["gre", "ipip"],
+        display_order(40)
+    )]
+    pub encapsulation: Option<Encapsulation>,
+    #[structopt(
+        long = "flow-dump",
+        help = "File dumping the table of open connections on a signal (\"-\" for stderr)",
+        value_name = "FILE",
+        display_order(41)
+    )]
+    pub flow_dump: Option<PathBuf>,
+    #[structopt(
+        long = "flow-dump-signal",
+        help = "Signal requesting a dump of the table of open connections",
+        value_name = "SIGNAL",
+        possible_values = &["usr1", "usr2"],
+        default_value = "usr1",
+        display_order(42)
+    )]
+    pub flow_dump_signal: DumpSignal,
+    #[structopt(
+        long = "max-unsent",
+        help = "Maximum size of payload of TCP connections not written to the proxy before paused",
+        value_name = "SIZE",
+        default_value = "1048576",
+        display_order(43)
+    )]
+    pub max_unsent: usize,
+    #[structopt(
+        long = "max-dup-acks",
+        help = "Maximum count of duplicate ACKs of TCP connections sent at once",
+        value_name = "COUNT",
+        display_order(44)
+    )]
+    pub max_dup_acks: Option<usize>,
+    #[structopt(
+        long = "rewrite-mac",
+        help = "Destination MAC addresses of frames sent to sources",
+        value_name = "RULE=MAC",
+        number_of_values = 1,
+        display_order(45)
+    )]
+    pub rewrite_mac: Vec<MacRewriteRule>,
+    #[structopt(
+        long = "capture-queue",
+        help = "Size of the queue of frames handed off from a capture thread",
+        value_name = "FRAMES",
+        display_order(46)
+    )]
+    pub capture_queue: Option<usize>,
+    #[structopt(
+        long = "capture-queue-overflow",
+        help = "Policy on overflow of the capture queue",
+        value_name = "POLICY",
+        possible_values = &["drop-oldest", "drop-newest", "block"],
+        default_value = "drop-newest",
+        display_order(47)
+    )]
+    pub capture_queue_overflow: OverflowPolicy,
+    #[structopt(
+        long,
+        help = "Count of shards of the flow table of each interface",
+        value_name = "COUNT",
+        default_value = "1",
+        display_order(49)
+    )]
+    pub shards: usize,
+    #[structopt(
+        long,
+        help = "Address of the admin HTTP endpoint serving the recent log events",
+        value_name = "ADDRESS",
+        display_order(50)
+    )]
+    pub admin: Option<SocketAddr>,
+    #[structopt(
+        long = "reassembly-deadline",
+        help = "Time of TCP payload held behind an unfilled range before the range is skipped",
+        value_name = "MS",
+        display_order(51)
+    )]
+    pub reassembly_deadline: Option<u64>,
+    #[structopt(
+        long = "md5-sig",
+        help = "Accept TCP connections with the MD5 signature option, whose signature is invalidated for the addresses are rewritten",
+        display_order(52)
+    )]
+    pub md5_sig: bool,
+    #[structopt(
+        long = "eager-alloc",
+        help = "Allocate the buffers of the caches of TCP connections when they are set up",
+        display_order(53)
+    )]
+    pub eager_alloc: bool,
+    #[structopt(
+        long = "no-scheduler",
+        help = "Send payload of TCP connections as it arrives instead of scheduling by weight",
+        display_order(54)
+    )]
+    pub no_scheduler: bool,
+    #[structopt(
+        long,
+        help = "Weight of TCP connections to destinations in the scheduler",
+        value_name = "RULE=WEIGHT",
+        number_of_values = 1,
+        display_order(55)
+    )]
+    pub weight: Vec<WeightRule>,
+    #[structopt(
+        long = "dscp-weight",
+        help = "Weight of TCP connections with a DSCP of at least the value in the scheduler",
+        value_name = "DSCP=WEIGHT",
+        number_of_values = 1,
+        display_order(56)
+    )]
+    pub dscp_weight: Vec<DscpWeightRule>,
+    #[structopt(
+        long,
+        help = "Rate of synthetic loss of packets sent to the source in per mille for testing",
+        value_name = "PERMILLE",
+        default_value = "0",
+        display_order(13)
+    )]
+    pub loss: u16,
+    #[structopt(
+        long,
+        help = "Rate of synthetic reorder of packets sent to the source in per mille for testing",
+        value_name = "PERMILLE",
+        default_value = "0",
+        display_order(14)
+    )]
+    pub reorder: u16,
+    #[structopt(
+        long,
+        help = "Seed of the synthetic loss and reorder",
+        value_name = "VALUE",
+        display_order(15)
+    )]
+    pub seed: Option<u64>,
     #[structopt(
         long,
         help = "Username",
@@ -268,15 +1147,23 @@ struct Flags {
     pub password: Option<String>,
 }
 
+/// Represents the maximum count of the recent log events kept in memory.
+const MAX_RECENT: usize = 1024;
+
 /// Represents a logger.
 struct Logger {
     stderr_logger: env_logger::Logger,
     stdout_logger: env_logger::Logger,
+    /// Represents the recent log events, which are kept only if they are served.
+    recent: Option<Arc<RecentEvents>>,
+    /// Represents the maximum level of events logged, either output or kept.
+    max_level: LevelFilter,
 }
 
 impl Logger {
-    /// Initializes the global logger.
-    pub fn init(level: LevelFilter) {
+    /// Initializes the global logger and returns the recent log events. Events of all the levels
+    /// are kept in the recent log events only if `is_recent` is set.
+    pub fn init(level: LevelFilter, is_recent: bool) -> Arc<RecentEvents> {
         let fmt = |buf: &mut Formatter, record: &Record| {
             let mut style = buf.style();
 
@@ -303,28 +1190,46 @@ impl Logger {
             .format(fmt)
             .build();
 
+        let recent = Arc::new(RecentEvents::new(MAX_RECENT));
+        // Events below the level are formatted only if they are kept in the recent log events
+        let max_level = match is_recent {
+            true => LevelFilter::Trace,
+            false => level,
+        };
         let logger = Logger {
             stderr_logger,
             stdout_logger,
+            recent: match is_recent {
+                true => Some(Arc::clone(&recent)),
+                false => None,
+            },
+            max_level,
         };
 
-        // Set the logger
+        // Set the logger, and only the output is filtered in the level
         let r = log::set_boxed_logger(Box::new(logger));
         if r.is_ok() {
-            log::set_max_level(level);
+            log::set_max_level(max_level);
         }
+
+        recent
     }
 }
 
 impl Log for Logger {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        match metadata.level() {
-            Level::Error => self.stderr_logger.enabled(metadata),
-            _ => self.stdout_logger.enabled(metadata),
-        }
+        metadata.level() <= self.max_level
     }
 
     fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if let Some(ref recent) = self.recent {
+            recent.push(record.level(), record.target(), record.args().to_string());
+        }
+
+        // Loggers filter the output in the level
         match record.metadata().level() {
             Level::Error => self.stderr_logger.log(record),
             _ => self.stdout_logger.log(record),
@@ -334,13 +1239,13 @@ impl Log for Logger {
     fn flush(&self) {}
 }
 
-fn set_logger(verbose: usize) {
+fn set_logger(verbose: usize, is_recent: bool) -> Arc<RecentEvents> {
     let level = match verbose {
         0 => LevelFilter::Info,
         1 => LevelFilter::Debug,
         _ => LevelFilter::Trace,
     };
-    Logger::init(level);
+    Logger::init(level, is_recent)
 }
 
 #[derive(Debug)]
@@ -443,3 +1348,299 @@ impl FromStr for ResolvableSocketAddrV4 {
         Ok(ResolvableSocketAddrV4 { addr, alias })
     }
 }
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct BypassRule {
+    network: Ipv4Network,
+    port: Option<u16>,
+}
+
+impl BypassRule {
+    fn rule(&self) -> (Ipv4Network, Option<u16>) {
+        (self.network, self.port)
+    }
+}
+
+impl Display for BypassRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.port {
+            Some(port) => write!(f, "{}:{}", self.network, port),
+            None => write!(f, "{}", self.network),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum BypassRuleParseError {
+    NetworkParseError(IpNetworkError),
+    PortParseError(ParseIntError),
+}
+
+impl Display for BypassRuleParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BypassRuleParseError::NetworkParseError(e) => write!(f, "{}", e),
+            BypassRuleParseError::PortParseError(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl FromStr for BypassRule {
+    type Err = BypassRuleParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (network, port) = match s.rfind(':') {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None),
+        };
+
+        // An empty network represents any destination
+        let network = match network {
+            "" => Ipv4Network::new(Ipv4Addr::UNSPECIFIED, 0).unwrap(),
+            network => network
+                .parse()
+                .map_err(BypassRuleParseError::NetworkParseError)?,
+        };
+        let port = match port {
+            Some(port) => Some(port.parse().map_err(BypassRuleParseError::PortParseError)?),
+            None => None,
+        };
+
+        Ok(BypassRule { network, port })
+    }
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct MacRewriteRule {
+    network: Ipv4Network,
+    inter: Option<String>,
+    hardware_addr: HardwareAddr,
+}
+
+impl MacRewriteRule {
+    fn rule(&self) -> (Ipv4Network, HardwareAddr) {
+        (self.network, self.hardware_addr)
+    }
+
+    fn is_applied(&self, inter: &str) -> bool {
+        match self.inter {
+            Some(ref name) => name == inter,
+            None => true,
+        }
+    }
+}
+
+impl Display for MacRewriteRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.inter {
+            Some(ref inter) => write!(f, "{}%{}={}", self.network, inter, self.hardware_addr),
+            None => write!(f, "{}={}", self.network, self.hardware_addr),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum MacRewriteRuleParseError {
+    MissingHardwareAddrError,
+    NetworkParseError(IpNetworkError),
+    HardwareAddrParseError(String),
+}
+
+impl Display for MacRewriteRuleParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MacRewriteRuleParseError::MissingHardwareAddrError => {
+                write!(f, "missing MAC address")
+            }
+            MacRewriteRuleParseError::NetworkParseError(e) => write!(f, "{}", e),
+            MacRewriteRuleParseError::HardwareAddrParseError(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl FromStr for MacRewriteRule {
+    type Err = MacRewriteRuleParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let i = s
+            .rfind('=')
+            .ok_or(MacRewriteRuleParseError::MissingHardwareAddrError)?;
+        let (network, inter) = match s[..i].find('%') {
+            Some(j) => (&s[..j], Some(s[j + 1..i].to_string())),
+            None => (&s[..i], None),
+        };
+
+        // An empty network represents any source
+        let network = match network {
+            "" => Ipv4Network::new(Ipv4Addr::UNSPECIFIED, 0).unwrap(),
+            network => network
+                .parse()
+                .map_err(MacRewriteRuleParseError::NetworkParseError)?,
+        };
+        let hardware_addr = s[i + 1..]
+            .parse()
+            .map_err(|e: <HardwareAddr as FromStr>::Err| {
+                MacRewriteRuleParseError::HardwareAddrParseError(e.to_string())
+            })?;
+
+        Ok(MacRewriteRule {
+            network,
+            inter,
+            hardware_addr,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct CacheSizeRule {
+    rule: BypassRule,
+    size: usize,
+}
+
+impl CacheSizeRule {
+    fn rule(&self) -> (Ipv4Network, Option<u16>, usize) {
+        (self.rule.network, self.rule.port, self.size)
+    }
+}
+
+impl Display for CacheSizeRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.rule, self.size)
+    }
+}
+
+#[derive(Debug)]
+enum CacheSizeRuleParseError {
+    MissingSizeError,
+    RuleParseError(BypassRuleParseError),
+    SizeParseError(ParseIntError),
+}
+
+impl Display for CacheSizeRuleParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CacheSizeRuleParseError::MissingSizeError => write!(f, "missing cache size"),
+            CacheSizeRuleParseError::RuleParseError(e) => write!(f, "{}", e),
+            CacheSizeRuleParseError::SizeParseError(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl FromStr for CacheSizeRule {
+    type Err = CacheSizeRuleParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let i = s
+            .rfind('=')
+            .ok_or(CacheSizeRuleParseError::MissingSizeError)?;
+        let rule = s[..i]
+            .parse()
+            .map_err(CacheSizeRuleParseError::RuleParseError)?;
+        let size = s[i + 1..]
+            .parse()
+            .map_err(CacheSizeRuleParseError::SizeParseError)?;
+
+        Ok(CacheSizeRule { rule, size })
+    }
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct WeightRule {
+    rule: BypassRule,
+    weight: usize,
+}
+
+impl WeightRule {
+    fn rule(&self) -> (Ipv4Network, Option<u16>, usize) {
+        (self.rule.network, self.rule.port, self.weight)
+    }
+}
+
+impl Display for WeightRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.rule, self.weight)
+    }
+}
+
+#[derive(Debug)]
+enum WeightRuleParseError {
+    MissingWeightError,
+    RuleParseError(BypassRuleParseError),
+    WeightParseError(ParseIntError),
+}
+
+impl Display for WeightRuleParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WeightRuleParseError::MissingWeightError => write!(f, "missing weight"),
+            WeightRuleParseError::RuleParseError(e) => write!(f, "{}", e),
+            WeightRuleParseError::WeightParseError(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl FromStr for WeightRule {
+    type Err = WeightRuleParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let i = s
+            .rfind('=')
+            .ok_or(WeightRuleParseError::MissingWeightError)?;
+        let rule = s[..i]
+            .parse()
+            .map_err(WeightRuleParseError::RuleParseError)?;
+        let weight = s[i + 1..]
+            .parse()
+            .map_err(WeightRuleParseError::WeightParseError)?;
+
+        Ok(WeightRule { rule, weight })
+    }
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct DscpWeightRule {
+    dscp: u8,
+    weight: usize,
+}
+
+impl DscpWeightRule {
+    fn rule(&self) -> (u8, usize) {
+        (self.dscp, self.weight)
+    }
+}
+
+impl Display for DscpWeightRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.dscp, self.weight)
+    }
+}
+
+#[derive(Debug)]
+enum DscpWeightRuleParseError {
+    MissingWeightError,
+    DscpParseError(ParseIntError),
+    WeightParseError(ParseIntError),
+}
+
+impl Display for DscpWeightRuleParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DscpWeightRuleParseError::MissingWeightError => write!(f, "missing weight"),
+            DscpWeightRuleParseError::DscpParseError(e) => write!(f, "{}", e),
+            DscpWeightRuleParseError::WeightParseError(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl FromStr for DscpWeightRule {
+    type Err = DscpWeightRuleParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let i = s
+            .rfind('=')
+            .ok_or(DscpWeightRuleParseError::MissingWeightError)?;
+        let dscp = s[..i]
+            .parse()
+            .map_err(DscpWeightRuleParseError::DscpParseError)?;
+        let weight = s[i + 1..]
+            .parse()
+            .map_err(DscpWeightRuleParseError::WeightParseError)?;
+
+        Ok(DscpWeightRule { dscp, weight })
+    }
+}
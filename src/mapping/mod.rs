@@ -0,0 +1,261 @@
+//! Support for persisting mappings of connections across restarts.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::io;
+use std::net::SocketAddrV4;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Represents the strategy of allocating local UDP ports bound for sources.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum PortStrategy {
+    /// Represents an ephemeral port chosen by the operating system.
+    Ephemeral,
+    /// Represents the lowest port in the range not bound for other sources.
+    Lowest,
+    /// Represents the port in the range derived from the hash of the source, probing the next
+    /// port if it is bound for another source.
+    Hash,
+}
+
+impl PortStrategy {
+    /// Returns the port in the range allocated for the source, skipping ports in use, or `None`
+    /// if the port is left to the operating system or all the ports in the range are in use.
+    pub fn select<F: Fn(u16) -> bool>(
+        &self,
+        src: SocketAddrV4,
+        range: RangeInclusive<u16>,
+        is_used: F,
+    ) -> Option<u16> {
+        let start = *range.start() as u32;
+        let len = (*range.end() as u32 + 1).saturating_sub(start);
+        if len == 0 {
+            return None;
+        }
+        let offset = match self {
+            PortStrategy::Ephemeral => return None,
+            PortStrategy::Lowest => 0,
+            PortStrategy::Hash => hash(src) % len,
+        };
+
+        (0..len)
+            .map(|i| (start + (offset + i) % len) as u16)
+            .find(|&port| !is_used(port))
+    }
+}
+
+impl Display for PortStrategy {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            PortStrategy::Ephemeral => write!(f, "ephemeral"),
+            PortStrategy::Lowest => write!(f, "lowest"),
+            PortStrategy::Hash => write!(f, "hash"),
+        }
+    }
+}
+
+impl FromStr for PortStrategy {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ephemeral" => Ok(PortStrategy::Ephemeral),
+            "lowest" => Ok(PortStrategy::Lowest),
+            "hash" => Ok(PortStrategy::Hash),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown port strategy {}", s),
+            )),
+        }
+    }
+}
+
+/// Returns the FNV-1a hash of the source, which is stable across restarts and builds, unlike the
+/// hasher of the standard library.
+fn hash(src: SocketAddrV4) -> u32 {
+    src.ip()
+        .octets()
+        .iter()
+        .chain(src.port().to_be_bytes().iter())
+        .fold(0x811c_9dc5u32, |hash, &b| {
+            (hash ^ b as u32).wrapping_mul(0x0100_0193)
+        })
+}
+
+/// Represents the mappings of connections, which are the TCP connections and the local UDP ports
+/// bound for sources. The mappings can be persisted to a file and restored after a restart.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Mappings {
+    tcp: HashSet<(SocketAddrV4, SocketAddrV4)>,
+    udp: HashMap<SocketAddrV4, u16>,
+}
+
+impl Mappings {
+    /// Creates a new empty `Mappings`.
+    pub fn new() -> Mappings {
+        Mappings::default()
+    }
+
+    /// Loads the mappings from the file.
+    pub fn load(path: &Path) -> io::Result<Mappings> {
+        Mappings::parse(fs::read_to_string(path)?.as_str())
+    }
+
+    /// Saves the mappings to the file. The mappings are written to a temporary file first and
+    /// then renamed, so the file will not be truncated if pcap2socks exits while saving.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut temp = path.as_os_str().to_os_string();
+        temp.push(".tmp");
+        fs::write(&temp, self.to_string())?;
+
+        fs::rename(&temp, path)
+    }
+
+    fn parse(s: &str) -> io::Result<Mappings> {
+        let invalid = |line: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid mapping: {}", line),
+            )
+        };
+
+        let mut mappings = Mappings::new();
+        for line in s.lines() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            match fields.as_slice() {
+                ["tcp", src, dst] => {
+                    let src = src.parse().map_err(|_| invalid(line))?;
+                    let dst = dst.parse().map_err(|_| invalid(line))?;
+                    mappings.insert_tcp(src, dst);
+                }
+                ["udp", src, port] => {
+                    let src = src.parse().map_err(|_| invalid(line))?;
+                    let port = port.parse().map_err(|_| invalid(line))?;
+                    mappings.insert_udp(src, port);
+                }
+                _ => return Err(invalid(line)),
+            }
+        }
+
+        Ok(mappings)
+    }
+
+    /// Inserts a TCP connection in the form of (source, destination).
+    pub fn insert_tcp(&mut self, src: SocketAddrV4, dst: SocketAddrV4) {
+        self.tcp.insert((src, dst));
+    }
+
+    /// Inserts a local UDP port bound for the source.
+    pub fn insert_udp(&mut self, src: SocketAddrV4, port: u16) {
+        self.udp.insert(src, port);
+    }
+
+    /// Returns if the TCP connection in the form of (source, destination) is in the mappings.
+    pub fn contains_tcp(&self, src: SocketAddrV4, dst: SocketAddrV4) -> bool {
+        self.tcp.contains(&(src, dst))
+    }
+
+    /// Returns the local UDP port bound for the source.
+    pub fn get_udp(&self, src: SocketAddrV4) -> Option<u16> {
+        self.udp.get(&src).copied()
+    }
+
+    /// Returns the count of TCP connections in the mappings.
+    pub fn tcp_len(&self) -> usize {
+        self.tcp.len()
+    }
+
+    /// Returns the count of local UDP ports in the mappings.
+    pub fn udp_len(&self) -> usize {
+        self.udp.len()
+    }
+}
+
+impl Display for Mappings {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for (src, dst) in &self.tcp {
+            writeln!(f, "tcp {} {}", src, dst)?;
+        }
+        for (src, port) in &self.udp {
+            writeln!(f, "udp {} {}", src, port)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn mappings_parse() {
+    let mut mappings = Mappings::new();
+    mappings.insert_tcp(
+        "10.6.0.1:50000".parse().unwrap(),
+        "1.1.1.1:443".parse().unwrap(),
+    );
+    mappings.insert_udp("10.6.0.1:50001".parse().unwrap(), 40000);
+
+    let parsed = Mappings::parse(mappings.to_string().as_str()).unwrap();
+    assert_eq!(parsed, mappings);
+    assert!(parsed.contains_tcp(
+        "10.6.0.1:50000".parse().unwrap(),
+        "1.1.1.1:443".parse().unwrap()
+    ));
+    assert_eq!(
+        parsed.get_udp("10.6.0.1:50001".parse().unwrap()),
+        Some(40000)
+    );
+
+    let e = Mappings::parse("tcp 10.6.0.1:50000").unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn port_strategy_select() {
+    let src: SocketAddrV4 = "10.6.0.1:50000".parse().unwrap();
+    let other: SocketAddrV4 = "10.6.0.2:50000".parse().unwrap();
+    let range = 40000..=40015;
+
+    assert_eq!(
+        PortStrategy::Ephemeral.select(src, range.clone(), |_| false),
+        None
+    );
+
+    // The same source is always allocated the same port
+    let port = PortStrategy::Hash
+        .select(src, range.clone(), |_| false)
+        .unwrap();
+    assert!(range.contains(&port));
+    assert_eq!(
+        PortStrategy::Hash.select(src, range.clone(), |_| false),
+        Some(port)
+    );
+    assert_eq!(hash(src), hash("10.6.0.1:50000".parse().unwrap()));
+    assert_ne!(hash(src), hash(other));
+
+    // Ports in use are skipped
+    let next = if port == *range.end() {
+        *range.start()
+    } else {
+        port + 1
+    };
+    assert_eq!(
+        PortStrategy::Hash.select(src, range.clone(), |p| p == port),
+        Some(next)
+    );
+    assert_eq!(
+        PortStrategy::Lowest.select(src, range.clone(), |p| p < 40003),
+        Some(40003)
+    );
+    assert_eq!(
+        PortStrategy::Lowest.select(src, range.clone(), |_| true),
+        None
+    );
+    assert_eq!(PortStrategy::Hash.select(src, range, |_| true), None);
+}
@@ -0,0 +1,161 @@
+//! Support for mirroring payload of TCP connections to a secondary sink.
+
+use std::fmt::{self, Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::{SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::Mutex;
+
+/// Represents the maximum size of payload in a datagram sent to a UDP collector, which keeps
+/// datagrams from being fragmented on common paths.
+const MAX_DATAGRAM_PAYLOAD: usize = 1400;
+
+/// Represents the direction of payload mirrored.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum MirrorDirection {
+    /// Represents payload from the source to the proxy.
+    In,
+    /// Represents payload from the proxy to the source.
+    Out,
+}
+
+impl Display for MirrorDirection {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            MirrorDirection::In => write!(f, "in"),
+            MirrorDirection::Out => write!(f, "out"),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Sink {
+    File(File),
+    Udp(UdpSocket),
+}
+
+/// Represents a mirror, which tees payload of TCP connections to a file or a UDP collector. Each
+/// chunk is written as a record of a header line `<direction> <source> <destination> <length>`
+/// followed by the payload, so records of connections interleaved can be told apart. A chunk is
+/// split into multiple records if it does not fit in a datagram.
+#[derive(Debug)]
+pub struct Mirror {
+    sink: Mutex<Sink>,
+}
+
+impl Mirror {
+    /// Opens the mirror sending to the UDP collector if the target is like `udp://host:port`, or
+    /// appending to the file otherwise.
+    pub fn open(target: &str) -> io::Result<Mirror> {
+        let sink = match target.strip_prefix("udp://") {
+            Some(addr) => {
+                let addr = addr.parse::<SocketAddr>().map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("mirror collector {}: {}", addr, e),
+                    )
+                })?;
+                let local: SocketAddr = match addr {
+                    SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+                    SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+                };
+                let socket = UdpSocket::bind(local)?;
+                socket.connect(addr)?;
+
+                Sink::Udp(socket)
+            }
+            None => Sink::File(OpenOptions::new().create(true).append(true).open(target)?),
+        };
+
+        Ok(Mirror {
+            sink: Mutex::new(sink),
+        })
+    }
+
+    /// Writes a chunk of payload of the TCP connection in the direction.
+    pub fn write(
+        &self,
+        direction: MirrorDirection,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let mut sink = self.sink.lock().unwrap();
+        let size = match *sink {
+            Sink::File(_) => payload.len().max(1),
+            Sink::Udp(_) => MAX_DATAGRAM_PAYLOAD,
+        };
+
+        for chunk in payload.chunks(size) {
+            let record = record(direction, src, dst, chunk);
+            match *sink {
+                Sink::File(ref mut file) => file.write_all(&record)?,
+                Sink::Udp(ref socket) => {
+                    socket.send(&record)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn record(
+    direction: MirrorDirection,
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    chunk: &[u8],
+) -> Vec<u8> {
+    let mut record = format!("{} {} {} {}\n", direction, src, dst, chunk.len()).into_bytes();
+    record.extend_from_slice(chunk);
+
+    record
+}
+
+#[test]
+fn mirror_write() {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    let src = "1.1.1.1:1".parse().unwrap();
+    let dst = "2.2.2.2:2".parse().unwrap();
+
+    // File
+    let path = env::temp_dir().join(format!("pcap2socks-mirror-{}", process::id()));
+    let _ = fs::remove_file(&path);
+    let mirror = Mirror::open(path.to_str().unwrap()).unwrap();
+    mirror
+        .write(MirrorDirection::In, src, dst, b"GET /")
+        .unwrap();
+    mirror
+        .write(MirrorDirection::Out, src, dst, b"200")
+        .unwrap();
+    assert_eq!(
+        fs::read(&path).unwrap(),
+        b"in 1.1.1.1:1 2.2.2.2:2 5\nGET /out 1.1.1.1:1 2.2.2.2:2 3\n200".to_vec()
+    );
+    let _ = fs::remove_file(&path);
+
+    // UDP, split into datagrams
+    let collector = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let target = format!("udp://{}", collector.local_addr().unwrap());
+    let mirror = Mirror::open(&target).unwrap();
+    mirror
+        .write(
+            MirrorDirection::Out,
+            src,
+            dst,
+            &[0; MAX_DATAGRAM_PAYLOAD + 1],
+        )
+        .unwrap();
+    let mut buffer = [0u8; 2048];
+    let size = collector.recv(&mut buffer).unwrap();
+    let header = format!("out 1.1.1.1:1 2.2.2.2:2 {}\n", MAX_DATAGRAM_PAYLOAD);
+    assert_eq!(size, header.len() + MAX_DATAGRAM_PAYLOAD);
+    assert_eq!(&buffer[..header.len()], header.as_bytes());
+    let size = collector.recv(&mut buffer).unwrap();
+    assert_eq!(&buffer[..size], b"out 1.1.1.1:1 2.2.2.2:2 1\n\0");
+
+    assert!(Mirror::open("udp://localhost").is_err());
+}
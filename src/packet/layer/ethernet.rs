@@ -46,6 +46,11 @@ impl Ethernet {
         Ethernet::from(ethernet)
     }
 
+    /// Returns the minimum of the layer when converted into a byte-array.
+    pub fn minimum_len() -> usize {
+        14
+    }
+
     /// Returns the source of the layer.
     pub fn src(&self) -> MacAddr {
         self.layer.source
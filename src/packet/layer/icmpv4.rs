@@ -4,6 +4,7 @@ use super::{Layer, LayerKind, LayerKinds};
 use pnet::packet::icmp::destination_unreachable;
 use pnet::packet::icmp::echo_reply;
 use pnet::packet::icmp::echo_request;
+use pnet::packet::icmp::time_exceeded;
 use pnet::packet::icmp::{self, Icmp, IcmpPacket, IcmpTypes, MutableIcmpPacket};
 use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
 use pnet::packet::ipv4::Ipv4Packet;
@@ -41,6 +42,19 @@ impl Icmpv4 {
         Icmpv4::from(icmp)
     }
 
+    /// Creates a `Icmpv4` represents an ICMPv4 destination network unreachable.
+    pub fn new_destination_network_unreachable(payload: &[u8]) -> Icmpv4 {
+        let mut next_payload = vec![0u8; 4 + payload.len()];
+        next_payload[4..].copy_from_slice(payload);
+        let icmp = Icmp {
+            icmp_type: IcmpTypes::DestinationUnreachable,
+            icmp_code: destination_unreachable::IcmpCodes::DestinationNetworkUnreachable,
+            checksum: 0,
+            payload: next_payload,
+        };
+        Icmpv4::from(icmp)
+    }
+
     /// Creates a `Icmpv4` represents an ICMPv4 destination host unreachable.
     pub fn new_destination_host_unreachable(payload: &[u8]) -> Icmpv4 {
         let mut next_payload = vec![0u8; 4 + payload.len()];
@@ -67,6 +81,19 @@ impl Icmpv4 {
         Icmpv4::from(icmp)
     }
 
+    /// Creates a `Icmpv4` represents an ICMPv4 time to live exceeded in transit.
+    pub fn new_time_exceeded(payload: &[u8]) -> Icmpv4 {
+        let mut next_payload = vec![0u8; 4 + payload.len()];
+        next_payload[4..].copy_from_slice(payload);
+        let icmp = Icmp {
+            icmp_type: IcmpTypes::TimeExceeded,
+            icmp_code: time_exceeded::IcmpCodes::TimeToLiveExceededInTransit,
+            checksum: 0,
+            payload: next_payload,
+        };
+        Icmpv4::from(icmp)
+    }
+
     /// Creates an `Icmpv4` according to the given `Icmp`.
     pub fn from(icmp: Icmp) -> Icmpv4 {
         Icmpv4 { layer: icmp }
@@ -83,12 +110,16 @@ impl Icmpv4 {
     pub fn description(&self) -> String {
         if self.is_echo_reply() {
             String::from("Echo reply")
+        } else if self.is_destination_network_unreachable() {
+            String::from("Destination network unreachable")
         } else if self.is_destination_host_unreachable() {
             String::from("Destination host unreachable")
         } else if self.is_destination_port_unreachable() {
             String::from("Destination port unreachable")
         } else if self.is_fragmentation_required_and_df_flag_set() {
             String::from("Fragmentation required, and DF flag set")
+        } else if self.is_time_exceeded() {
+            String::from("Time to live exceeded in transit")
         } else if self.is_echo_request() {
             String::from("Echo request")
         } else {
@@ -264,6 +295,13 @@ impl Icmpv4 {
             && self.layer.icmp_code == echo_reply::IcmpCodes::NoCode
     }
 
+    /// Returns if the layer is an ICMPv4 destination network unreachable.
+    pub fn is_destination_network_unreachable(&self) -> bool {
+        self.layer.icmp_type == IcmpTypes::DestinationUnreachable
+            && self.layer.icmp_code
+                == destination_unreachable::IcmpCodes::DestinationNetworkUnreachable
+    }
+
     /// Returns if the layer is an ICMPv4 destination host unreachable.
     pub fn is_destination_host_unreachable(&self) -> bool {
         self.layer.icmp_type == IcmpTypes::DestinationUnreachable
@@ -285,6 +323,12 @@ impl Icmpv4 {
                 == destination_unreachable::IcmpCodes::FragmentationRequiredAndDFFlagSet
     }
 
+    /// Returns if the layer is an ICMPv4 time to live exceeded in transit.
+    pub fn is_time_exceeded(&self) -> bool {
+        self.layer.icmp_type == IcmpTypes::TimeExceeded
+            && self.layer.icmp_code == time_exceeded::IcmpCodes::TimeToLiveExceededInTransit
+    }
+
     /// Returns if the layer is an ICMPv4 echo request.
     pub fn is_echo_request(&self) -> bool {
         self.layer.icmp_type == IcmpTypes::EchoRequest
@@ -11,6 +11,11 @@ use std::net::Ipv4Addr;
 /// Represents the TTL in the sent packets.
 const TTL: u8 = 128;
 
+/// Represents the ECN codepoint ECT(0).
+pub const ECN_ECT0: u8 = 2;
+/// Represents the ECN codepoint CE.
+pub const ECN_CE: u8 = 3;
+
 /// Represents an IPv4 layer.
 #[derive(Clone, Debug)]
 pub struct Ipv4 {
@@ -113,6 +118,26 @@ impl Ipv4 {
         20
     }
 
+    /// Sets the DSCP of the layer.
+    pub fn set_dscp(&mut self, dscp: u8) {
+        self.layer.dscp = dscp;
+    }
+
+    /// Sets the ECN of the layer.
+    pub fn set_ecn(&mut self, ecn: u8) {
+        self.layer.ecn = ecn;
+    }
+
+    /// Returns the DSCP of the layer.
+    pub fn dscp(&self) -> u8 {
+        self.layer.dscp
+    }
+
+    /// Returns the ECN of the layer.
+    pub fn ecn(&self) -> u8 {
+        self.layer.ecn
+    }
+
     /// Returns the total length of the layer.
     pub fn total_length(&self) -> u16 {
         self.layer.total_length
@@ -0,0 +1,86 @@
+//! Support for serializing and deserializing the null/loopback layer.
+
+use super::{Layer, LayerKind, LayerKinds};
+use std::clone::Clone;
+use std::convert::TryInto;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+
+/// Represents the address family of IPv4, which is `2` on all the platforms.
+const AF_INET: u32 = 2;
+
+/// Represents a null/loopback layer, which is a 4-byte header of the address family of the
+/// payload in the host byte order, used by loopback interfaces of BSD and Windows.
+#[derive(Clone, Debug)]
+pub struct Loopback {
+    family: u32,
+}
+
+impl Loopback {
+    /// Creates a `Loopback`.
+    pub fn new(t: LayerKind) -> Option<Loopback> {
+        let family = match t {
+            LayerKinds::Ipv4 => AF_INET,
+            _ => return None,
+        };
+        Some(Loopback { family })
+    }
+
+    /// Creates a `Loopback` according to the given frame. The address family is accepted in
+    /// either byte order, since frames may be captured on a host of the other byte order.
+    pub fn parse(frame: &[u8]) -> Option<Loopback> {
+        let header: [u8; 4] = frame.get(..Loopback::minimum_len())?.try_into().ok()?;
+        let family = match u32::from_ne_bytes(header) {
+            AF_INET => AF_INET,
+            family if family.swap_bytes() == AF_INET => AF_INET,
+            family => family,
+        };
+
+        Some(Loopback { family })
+    }
+
+    /// Returns the minimum of the layer when converted into a byte-array.
+    pub fn minimum_len() -> usize {
+        4
+    }
+
+    /// Returns the address family of the layer.
+    pub fn family(&self) -> u32 {
+        self.family
+    }
+
+    /// Returns if the payload of the layer is IPv4.
+    pub fn is_ipv4(&self) -> bool {
+        self.family == AF_INET
+    }
+}
+
+impl Display for Loopback {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}: family {}", LayerKinds::Loopback, self.family)
+    }
+}
+
+impl Layer for Loopback {
+    fn kind(&self) -> LayerKind {
+        LayerKinds::Loopback
+    }
+
+    fn len(&self) -> usize {
+        Loopback::minimum_len()
+    }
+
+    fn serialize(&self, buffer: &mut [u8], _: usize) -> io::Result<usize> {
+        if buffer.len() < self.len() {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "buffer too small"));
+        }
+
+        buffer[..self.len()].copy_from_slice(&self.family.to_ne_bytes());
+
+        Ok(self.len())
+    }
+
+    fn serialize_with_payload(&self, buffer: &mut [u8], _: &[u8], n: usize) -> io::Result<usize> {
+        self.serialize(buffer, n)
+    }
+}
@@ -5,11 +5,14 @@ use std::cmp::{Eq, PartialEq};
 use std::fmt::{self, Display, Formatter};
 use std::hash::Hash;
 use std::io;
+use std::net::SocketAddrV4;
 
 pub mod arp;
 pub mod ethernet;
 pub mod icmpv4;
 pub mod ipv4;
+pub mod loopback;
+pub mod sll;
 pub mod tcp;
 pub mod udp;
 
@@ -29,6 +32,8 @@ impl Display for LayerKind {
                 LayerKinds::Icmpv4 => "ICMPv4",
                 LayerKinds::Tcp => "TCP",
                 LayerKinds::Udp => "UDP",
+                LayerKinds::Loopback => "Loopback",
+                LayerKinds::Sll => "SLL",
                 _ => "unknown",
             }
         )
@@ -53,6 +58,10 @@ pub mod LayerKinds {
     pub const Tcp: LayerKind = LayerKind(4);
     /// Represents the layer kind of UDP.
     pub const Udp: LayerKind = LayerKind(5);
+    /// Represents the layer kind of null/loopback.
+    pub const Loopback: LayerKind = LayerKind(6);
+    /// Represents the layer kind of Linux cooked (SLL).
+    pub const Sll: LayerKind = LayerKind(7);
 }
 
 /// Represents a layer.
@@ -90,6 +99,30 @@ pub enum Layers {
     Tcp(tcp::Tcp),
     /// Represents the UDP layer.
     Udp(udp::Udp),
+    /// Represents the null/loopback layer.
+    Loopback(loopback::Loopback),
+    /// Represents the Linux cooked (SLL) layer.
+    Sll(sll::Sll),
+}
+
+impl Layers {
+    /// Returns the source address of the layer if it is a TCP or UDP layer.
+    pub fn src_addr(&self) -> Option<SocketAddrV4> {
+        match self {
+            Layers::Tcp(ref layer) => Some(layer.src_addr()),
+            Layers::Udp(ref layer) => Some(layer.src_addr()),
+            _ => None,
+        }
+    }
+
+    /// Returns the original destination address of the layer if it is a TCP or UDP layer.
+    pub fn dst_addr(&self) -> Option<SocketAddrV4> {
+        match self {
+            Layers::Tcp(ref layer) => Some(layer.dst_addr()),
+            Layers::Udp(ref layer) => Some(layer.dst_addr()),
+            _ => None,
+        }
+    }
 }
 
 impl Display for Layers {
@@ -101,6 +134,8 @@ impl Display for Layers {
             Layers::Icmpv4(ref layer) => layer.fmt(f),
             Layers::Tcp(ref layer) => layer.fmt(f),
             Layers::Udp(ref layer) => layer.fmt(f),
+            Layers::Loopback(ref layer) => layer.fmt(f),
+            Layers::Sll(ref layer) => layer.fmt(f),
         }
     }
 }
@@ -114,6 +149,8 @@ impl Layer for Layers {
             Layers::Icmpv4(ref layer) => layer.kind(),
             Layers::Tcp(ref layer) => layer.kind(),
             Layers::Udp(ref layer) => layer.kind(),
+            Layers::Loopback(ref layer) => layer.kind(),
+            Layers::Sll(ref layer) => layer.kind(),
         }
     }
 
@@ -125,6 +162,8 @@ impl Layer for Layers {
             Layers::Icmpv4(ref layer) => layer.len(),
             Layers::Tcp(ref layer) => layer.len(),
             Layers::Udp(ref layer) => layer.len(),
+            Layers::Loopback(ref layer) => layer.len(),
+            Layers::Sll(ref layer) => layer.len(),
         }
     }
 
@@ -136,6 +175,8 @@ impl Layer for Layers {
             Layers::Icmpv4(ref layer) => layer.serialize(buffer, n),
             Layers::Tcp(ref layer) => layer.serialize(buffer, n),
             Layers::Udp(ref layer) => layer.serialize(buffer, n),
+            Layers::Loopback(ref layer) => layer.serialize(buffer, n),
+            Layers::Sll(ref layer) => layer.serialize(buffer, n),
         }
     }
 
@@ -152,6 +193,8 @@ impl Layer for Layers {
             Layers::Icmpv4(ref layer) => layer.serialize_with_payload(buffer, payload, n),
             Layers::Tcp(ref layer) => layer.serialize_with_payload(buffer, payload, n),
             Layers::Udp(ref layer) => layer.serialize_with_payload(buffer, payload, n),
+            Layers::Loopback(ref layer) => layer.serialize_with_payload(buffer, payload, n),
+            Layers::Sll(ref layer) => layer.serialize_with_payload(buffer, payload, n),
         }
     }
 }
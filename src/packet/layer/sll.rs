@@ -0,0 +1,149 @@
+//! Support for serializing and deserializing the Linux cooked (SLL) layer.
+
+use super::{Layer, LayerKind, LayerKinds};
+use crate::pcap::HardwareAddr;
+use std::clone::Clone;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+
+/// Represents the packet type of packets sent by the host.
+const PACKET_OUTGOING: u16 = 4;
+
+/// Represents the hardware type of Ethernet.
+const ARPHRD_ETHER: u16 = 1;
+
+/// Represents the protocol of IPv4.
+const ETH_P_IP: u16 = 0x0800;
+/// Represents the protocol of ARP.
+const ETH_P_ARP: u16 = 0x0806;
+
+/// Represents a Linux cooked (SLL) layer, which is a 16-byte header of the packet type, the
+/// hardware type, the source hardware address and the protocol of the payload, rebuilt by the
+/// capture of all the interfaces in Linux in place of the link-layer headers of their own.
+#[derive(Clone, Debug)]
+pub struct Sll {
+    packet_type: u16,
+    hardware_type: u16,
+    hardware_addr_len: u16,
+    hardware_addr: [u8; 8],
+    protocol: u16,
+}
+
+impl Sll {
+    /// Creates a `Sll` of a packet from an Ethernet interface to the host.
+    pub fn new(t: LayerKind, src: HardwareAddr) -> Option<Sll> {
+        let protocol = match t {
+            LayerKinds::Ipv4 => ETH_P_IP,
+            LayerKinds::Arp => ETH_P_ARP,
+            _ => return None,
+        };
+        let hardware_addr = [src.0, src.1, src.2, src.3, src.4, src.5, 0, 0];
+
+        Some(Sll {
+            packet_type: 0,
+            hardware_type: ARPHRD_ETHER,
+            hardware_addr_len: 6,
+            hardware_addr,
+            protocol,
+        })
+    }
+
+    /// Creates a `Sll` according to the given frame.
+    pub fn parse(frame: &[u8]) -> Option<Sll> {
+        let header = frame.get(..Sll::minimum_len())?;
+        let mut hardware_addr = [0u8; 8];
+        hardware_addr.copy_from_slice(&header[6..14]);
+
+        Some(Sll {
+            packet_type: u16::from_be_bytes([header[0], header[1]]),
+            hardware_type: u16::from_be_bytes([header[2], header[3]]),
+            hardware_addr_len: u16::from_be_bytes([header[4], header[5]]),
+            hardware_addr,
+            protocol: u16::from_be_bytes([header[14], header[15]]),
+        })
+    }
+
+    /// Returns the minimum of the layer when converted into a byte-array.
+    pub fn minimum_len() -> usize {
+        16
+    }
+
+    /// Returns the packet type of the layer.
+    pub fn packet_type(&self) -> u16 {
+        self.packet_type
+    }
+
+    /// Returns the source hardware address of the layer if the packet is from an Ethernet
+    /// interface.
+    pub fn src(&self) -> Option<HardwareAddr> {
+        if self.hardware_type != ARPHRD_ETHER || self.hardware_addr_len != 6 {
+            return None;
+        }
+        let a = &self.hardware_addr;
+
+        Some(HardwareAddr::new(a[0], a[1], a[2], a[3], a[4], a[5]))
+    }
+
+    /// Returns the protocol of the payload of the layer.
+    pub fn protocol(&self) -> u16 {
+        self.protocol
+    }
+
+    /// Returns if the packet is sent by the host.
+    pub fn is_outgoing(&self) -> bool {
+        self.packet_type == PACKET_OUTGOING
+    }
+
+    /// Returns if the payload of the layer is IPv4.
+    pub fn is_ipv4(&self) -> bool {
+        self.protocol == ETH_P_IP
+    }
+
+    /// Returns if the payload of the layer is ARP.
+    pub fn is_arp(&self) -> bool {
+        self.protocol == ETH_P_ARP
+    }
+}
+
+impl Display for Sll {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.src() {
+            Some(src) => write!(
+                f,
+                "{}: {}, protocol {:#06x}",
+                LayerKinds::Sll,
+                src,
+                self.protocol
+            ),
+            None => write!(f, "{}: protocol {:#06x}", LayerKinds::Sll, self.protocol),
+        }
+    }
+}
+
+impl Layer for Sll {
+    fn kind(&self) -> LayerKind {
+        LayerKinds::Sll
+    }
+
+    fn len(&self) -> usize {
+        Sll::minimum_len()
+    }
+
+    fn serialize(&self, buffer: &mut [u8], _: usize) -> io::Result<usize> {
+        if buffer.len() < self.len() {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "buffer too small"));
+        }
+
+        buffer[0..2].copy_from_slice(&self.packet_type.to_be_bytes());
+        buffer[2..4].copy_from_slice(&self.hardware_type.to_be_bytes());
+        buffer[4..6].copy_from_slice(&self.hardware_addr_len.to_be_bytes());
+        buffer[6..14].copy_from_slice(&self.hardware_addr);
+        buffer[14..16].copy_from_slice(&self.protocol.to_be_bytes());
+
+        Ok(self.len())
+    }
+
+    fn serialize_with_payload(&self, buffer: &mut [u8], _: &[u8], n: usize) -> io::Result<usize> {
+        self.serialize(buffer, n)
+    }
+}
@@ -6,11 +6,24 @@ use pnet::packet::tcp::{
     self, MutableTcpOptionPacket, MutableTcpPacket, TcpFlags, TcpOption, TcpOptionNumber,
     TcpOptionNumbers, TcpOptionPacket, TcpPacket,
 };
+use pnet::packet::FromPacket;
 use std::clone::Clone;
 use std::cmp::min;
 use std::fmt::{self, Display, Formatter};
 use std::io;
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+/// Represents the TCP MD5 signature option number.
+const TCP_OPTION_MD5_SIG: u8 = 19;
+/// Represents the TCP Fast Open cookie option number.
+const TCP_OPTION_FAST_OPEN: u8 = 34;
+
+/// Represents the maximum count of SACK blocks fitting in the 40 Bytes TCP options, which is 4
+/// with 2 NOPs (2 + 2 + 8 * 4 = 36 Bytes).
+pub const MAX_SACKS: usize = 4;
+/// Represents the maximum count of SACK blocks fitting in the 40 Bytes TCP options alongside the
+/// timestamp option, which is 3 (10 + 2 + 8 * 3 = 36 Bytes).
+pub const MAX_SACKS_WITH_TS: usize = 3;
 
 /// Represents a TCP packet.
 #[derive(Clone, Debug)]
@@ -57,7 +70,7 @@ impl Tcp {
             let sacks = sacks.unwrap();
 
             // Trim sacks
-            let size = min(3, sacks.len());
+            let size = min(MAX_SACKS_WITH_TS, sacks.len());
             let mut vector = Vec::with_capacity(size * 2);
             for i in 0..size {
                 vector.push(sacks[i].0);
@@ -80,7 +93,7 @@ impl Tcp {
             let sacks = sacks.unwrap();
 
             // Trim sacks
-            let size = min(4, sacks.len());
+            let size = min(MAX_SACKS, sacks.len());
             let mut vector = Vec::with_capacity(size * 2);
             for i in 0..size {
                 vector.push(sacks[i].0);
@@ -223,7 +236,7 @@ impl Tcp {
             window: packet.get_window(),
             checksum: packet.get_checksum(),
             urgent_ptr: packet.get_urgent_ptr(),
-            options: packet.get_options(),
+            options: parse_options(packet.get_options_raw()),
             payload: vec![],
         };
         let mut tcp = Tcp::from(d_tcp);
@@ -237,6 +250,21 @@ impl Tcp {
         20
     }
 
+    /// Sets the ECN echo flag of the layer.
+    pub fn set_ece(&mut self) {
+        self.layer.flags |= TcpFlags::ECE;
+    }
+
+    /// Sets the congestion window reduced flag of the layer.
+    pub fn set_cwr(&mut self) {
+        self.layer.flags |= TcpFlags::CWR;
+    }
+
+    /// Sets the acknowledgement of the layer.
+    pub fn set_acknowledgement(&mut self, acknowledgement: u32) {
+        self.layer.acknowledgement = acknowledgement;
+    }
+
     /// Sets the source and destination IP address for the layer with the given `Ipv4`.
     pub fn set_ipv4_layer(&mut self, ipv4: &Ipv4) {
         self.src = ipv4.src();
@@ -263,6 +291,19 @@ impl Tcp {
         self.layer.destination
     }
 
+    /// Returns the source address of the layer, which consists of the source IP address in the IP
+    /// header and the source port.
+    pub fn src_addr(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.src, self.layer.source)
+    }
+
+    /// Returns the original destination address of the layer, which consists of the destination
+    /// IP address in the IP header and the destination port. The address is the one the source
+    /// intended to reach, regardless of the hardware address the frame was sent to.
+    pub fn dst_addr(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.dst, self.layer.destination)
+    }
+
     /// Returns the sequence of the layer.
     pub fn sequence(&self) -> u32 {
         self.layer.sequence
@@ -288,6 +329,12 @@ impl Tcp {
         if self.is_ack() {
             flags = flags + ".";
         }
+        if self.is_ece() {
+            flags = flags + "E";
+        }
+        if self.is_cwr() {
+            flags = flags + "W";
+        }
         flags = flags + "]";
 
         flags
@@ -305,7 +352,7 @@ impl Tcp {
         for ref option in &self.layer.options {
             packet.populate(option);
             match packet.get_number() {
-                TcpOptionNumbers::MSS => {
+                TcpOptionNumbers::MSS if packet.get_length_raw() == [4] => {
                     let mss = bytes_to_u16(&buffer[2..4]);
 
                     return Some(mss);
@@ -324,7 +371,7 @@ impl Tcp {
         for ref option in &self.layer.options {
             packet.populate(option);
             match packet.get_number() {
-                TcpOptionNumbers::WSCALE => {
+                TcpOptionNumbers::WSCALE if packet.get_length_raw() == [3] => {
                     let wscale = *&buffer[2];
 
                     return Some(wscale);
@@ -370,7 +417,7 @@ impl Tcp {
         for ref option in &self.layer.options {
             packet.populate(option);
             match packet.get_number() {
-                TcpOptionNumbers::TIMESTAMPS => {
+                TcpOptionNumbers::TIMESTAMPS if packet.get_length_raw() == [10] => {
                     let ts = bytes_to_u32(&buffer[2..6]);
 
                     return Some(ts);
@@ -390,7 +437,7 @@ impl Tcp {
         for ref option in &self.layer.options {
             packet.populate(option);
             match packet.get_number() {
-                TcpOptionNumbers::TIMESTAMPS => {
+                TcpOptionNumbers::TIMESTAMPS if packet.get_length_raw() == [10] => {
                     let ts = bytes_to_u32(&buffer[6..10]);
 
                     return Some(ts);
@@ -402,6 +449,40 @@ impl Tcp {
         None
     }
 
+    /// Returns the MD5 signature of the layer. This function allocates space for serializing
+    /// options.
+    pub fn md5_sig(&self) -> Option<Vec<u8>> {
+        let mut buffer = vec![0u8; 40];
+        let mut packet = MutableTcpOptionPacket::new(buffer.as_mut_slice()).unwrap();
+        for ref option in &self.layer.options {
+            packet.populate(option);
+            if packet.get_number() == TcpOptionNumber::new(TCP_OPTION_MD5_SIG) {
+                let size = (buffer[1] as usize).checked_sub(2).unwrap_or(0);
+
+                return Some(Vec::from(&buffer[2..2 + min(size, 38)]));
+            }
+        }
+
+        None
+    }
+
+    /// Returns the TCP Fast Open cookie of the layer. An empty cookie indicates a cookie request.
+    /// This function allocates space for serializing options.
+    pub fn fast_open_cookie(&self) -> Option<Vec<u8>> {
+        let mut buffer = vec![0u8; 40];
+        let mut packet = MutableTcpOptionPacket::new(buffer.as_mut_slice()).unwrap();
+        for ref option in &self.layer.options {
+            packet.populate(option);
+            if packet.get_number() == TcpOptionNumber::new(TCP_OPTION_FAST_OPEN) {
+                let size = (buffer[1] as usize).checked_sub(2).unwrap_or(0);
+
+                return Some(Vec::from(&buffer[2..2 + min(size, 38)]));
+            }
+        }
+
+        None
+    }
+
     /// Returns if the layer is a TCP acknowledgement.
     pub fn is_ack(&self) -> bool {
         self.layer.flags & TcpFlags::ACK != 0
@@ -427,6 +508,21 @@ impl Tcp {
         self.layer.flags & TcpFlags::FIN != 0
     }
 
+    /// Returns if the layer is a TCP push.
+    pub fn is_psh(&self) -> bool {
+        self.layer.flags & TcpFlags::PSH != 0
+    }
+
+    /// Returns if the layer is a TCP ECN echo.
+    pub fn is_ece(&self) -> bool {
+        self.layer.flags & TcpFlags::ECE != 0
+    }
+
+    /// Returns if the layer is a TCP congestion window reduced.
+    pub fn is_cwr(&self) -> bool {
+        self.layer.flags & TcpFlags::CWR != 0
+    }
+
     /// Returns if the layer is a TCP reset or finish.
     pub fn is_rst_or_fin(&self) -> bool {
         self.is_rst() || self.is_fin()
@@ -449,6 +545,30 @@ impl Tcp {
 
         false
     }
+
+    /// Returns if the layer has the MD5 signature option. This function allocates space for
+    /// serializing options.
+    pub fn is_md5_sig(&self) -> bool {
+        for ref option in &self.layer.options {
+            if get_number_from_option(option) == TcpOptionNumber::new(TCP_OPTION_MD5_SIG) {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Returns if the layer has the TCP Fast Open cookie option. This function allocates space
+    /// for serializing options.
+    pub fn is_fast_open(&self) -> bool {
+        for ref option in &self.layer.options {
+            if get_number_from_option(option) == TcpOptionNumber::new(TCP_OPTION_FAST_OPEN) {
+                return true;
+            }
+        }
+
+        false
+    }
 }
 
 impl Display for Tcp {
@@ -537,6 +657,40 @@ impl Layer for Tcp {
     }
 }
 
+/// Parses TCP options from the raw bytes. NOPs are kept as padding, and options including unknown
+/// ones are walked over by their lengths. Parsing stops at the EOL or a malformed option whose
+/// length is invalid or beyond the bytes, and the remaining bytes are kept as EOLs, so the length
+/// of the header is preserved.
+fn parse_options(mut buffer: &[u8]) -> Vec<TcpOption> {
+    let mut options = Vec::new();
+    while let Some(&number) = buffer.first() {
+        let number = TcpOptionNumber::new(number);
+        let length = match number {
+            TcpOptionNumbers::EOL => break,
+            TcpOptionNumbers::NOP => 1,
+            _ => match buffer.get(1) {
+                Some(&length) if length >= 2 && length as usize <= buffer.len() => length as usize,
+                _ => break,
+            },
+        };
+
+        // The length is validated, so the option is parsed within its own bytes
+        options.push(
+            TcpOptionPacket::new(&buffer[..length])
+                .unwrap()
+                .from_packet(),
+        );
+        buffer = &buffer[length..];
+    }
+
+    // Padding
+    for _ in 0..buffer.len() {
+        options.push(TcpOptionPacket::new(&[0]).unwrap().from_packet());
+    }
+
+    options
+}
+
 fn bytes_to_u16(bytes: &[u8]) -> u16 {
     let mut result = 0;
     let size = bytes.len();
@@ -572,3 +726,158 @@ fn get_number_from_option(option: &TcpOption) -> TcpOptionNumber {
     }
     */
 }
+
+#[test]
+fn tcp_parse_md5_sig() {
+    let mut b = vec![0u8; 44];
+    // Source and destination
+    b[0..2].copy_from_slice(&1u16.to_be_bytes());
+    b[2..4].copy_from_slice(&2u16.to_be_bytes());
+    // Data offset and flags
+    b[12] = 11 << 4;
+    b[13] = TcpFlags::SYN as u8;
+    // MSS
+    b[20..24].copy_from_slice(&[2, 4, 0x05, 0xb4]);
+    // MD5 signature
+    b[24] = TCP_OPTION_MD5_SIG;
+    b[25] = 18;
+    for i in 0..16 {
+        b[26 + i] = i as u8;
+    }
+    // NOP
+    b[42] = 1;
+    b[43] = 1;
+
+    let ipv4 = Ipv4::new(
+        0,
+        LayerKinds::Tcp,
+        "1.1.1.1".parse().unwrap(),
+        "2.2.2.2".parse().unwrap(),
+    )
+    .unwrap();
+    let tcp = Tcp::parse(&TcpPacket::new(&b).unwrap(), &ipv4);
+
+    assert!(tcp.is_syn());
+    assert_eq!(tcp.mss(), Some(1460));
+    assert!(tcp.is_md5_sig());
+    assert_eq!(
+        tcp.md5_sig(),
+        Some((0..16).into_iter().collect::<Vec<u8>>())
+    );
+    assert_eq!(tcp.len(), 44);
+}
+
+#[test]
+fn tcp_parse_fast_open() {
+    use pnet::packet::Packet;
+
+    let mut b = vec![0u8; 40];
+    // Source and destination
+    b[0..2].copy_from_slice(&1u16.to_be_bytes());
+    b[2..4].copy_from_slice(&2u16.to_be_bytes());
+    // Sequence
+    b[4..8].copy_from_slice(&1000u32.to_be_bytes());
+    // Data offset of 36 Bytes and flags
+    b[12] = 9 << 4;
+    b[13] = TcpFlags::SYN as u8;
+    // MSS
+    b[20..24].copy_from_slice(&[2, 4, 0x05, 0xb4]);
+    // TCP Fast Open cookie
+    b[24] = TCP_OPTION_FAST_OPEN;
+    b[25] = 10;
+    for i in 0..8 {
+        b[26 + i] = i as u8;
+    }
+    // NOP
+    b[34] = 1;
+    b[35] = 1;
+    // Payload
+    b[36..40].copy_from_slice(b"GET ");
+
+    let ipv4 = Ipv4::new(
+        0,
+        LayerKinds::Tcp,
+        "1.1.1.1".parse().unwrap(),
+        "2.2.2.2".parse().unwrap(),
+    )
+    .unwrap();
+    let packet = TcpPacket::new(&b).unwrap();
+    let tcp = Tcp::parse(&packet, &ipv4);
+
+    assert!(tcp.is_syn());
+    assert_eq!(tcp.sequence(), 1000);
+    assert_eq!(tcp.mss(), Some(1460));
+    assert!(tcp.is_fast_open());
+    assert_eq!(
+        tcp.fast_open_cookie(),
+        Some((0..8).into_iter().collect::<Vec<u8>>())
+    );
+    assert_eq!(tcp.len(), 36);
+    assert_eq!(packet.payload(), b"GET ");
+}
+
+#[test]
+fn tcp_parse_syn_options() {
+    use pnet::packet::Packet;
+
+    // A SYN of Linux carrying data
+    let mut b = vec![0u8; 44];
+    b[0..2].copy_from_slice(&40000u16.to_be_bytes());
+    b[2..4].copy_from_slice(&443u16.to_be_bytes());
+    b[12] = 10 << 4;
+    b[13] = TcpFlags::SYN as u8;
+    // MSS, SACK permitted, timestamps, NOP and window scale
+    b[20..24].copy_from_slice(&[2, 4, 0x05, 0xb4]);
+    b[24..26].copy_from_slice(&[4, 2]);
+    b[26..28].copy_from_slice(&[8, 10]);
+    b[28..32].copy_from_slice(&1000u32.to_be_bytes());
+    b[32..36].copy_from_slice(&0u32.to_be_bytes());
+    b[36] = 1;
+    b[37..40].copy_from_slice(&[3, 3, 7]);
+    b[40..44].copy_from_slice(b"GET ");
+
+    let ipv4 = Ipv4::new(
+        0,
+        LayerKinds::Tcp,
+        "1.1.1.1".parse().unwrap(),
+        "2.2.2.2".parse().unwrap(),
+    )
+    .unwrap();
+    let packet = TcpPacket::new(&b).unwrap();
+    let tcp = Tcp::parse(&packet, &ipv4);
+
+    assert!(tcp.is_syn());
+    assert_eq!(tcp.mss(), Some(1460));
+    assert_eq!(tcp.wscale(), Some(7));
+    assert!(tcp.is_sack_perm());
+    assert_eq!(tcp.ts(), Some(1000));
+    assert_eq!(tcp.ts_ecr(), Some(0));
+    assert_eq!(tcp.sack(), None);
+    assert_eq!(tcp.len(), 40);
+    assert_eq!(packet.payload(), b"GET ");
+
+    // Unknown options are walked over, and bytes after the EOL are padding
+    let mut b = vec![0u8; 32];
+    b[12] = 8 << 4;
+    b[13] = TcpFlags::SYN as u8;
+    b[20..24].copy_from_slice(&[253, 4, 0xff, 0xff]);
+    b[24..27].copy_from_slice(&[3, 3, 7]);
+    b[27] = 0;
+    b[28..32].copy_from_slice(&[2, 4, 0x05, 0xb4]);
+    let tcp = Tcp::parse(&TcpPacket::new(&b).unwrap(), &ipv4);
+    assert_eq!(tcp.wscale(), Some(7));
+    assert_eq!(tcp.mss(), None);
+    assert_eq!(tcp.len(), 32);
+
+    // Malformed options
+    for options in [[2, 0, 1, 1], [1, 1, 3, 9], [2, 2, 1, 1], [1, 1, 1, 3]].iter() {
+        let mut b = vec![0u8; 24];
+        b[12] = 6 << 4;
+        b[13] = TcpFlags::SYN as u8;
+        b[20..24].copy_from_slice(options);
+        let tcp = Tcp::parse(&TcpPacket::new(&b).unwrap(), &ipv4);
+        assert_eq!(tcp.mss(), None);
+        assert_eq!(tcp.wscale(), None);
+        assert_eq!(tcp.len(), 24);
+    }
+}
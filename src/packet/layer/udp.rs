@@ -6,7 +6,7 @@ use pnet::packet::udp::{self, MutableUdpPacket, UdpPacket};
 use std::clone::Clone;
 use std::fmt::{self, Display, Formatter};
 use std::io;
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, SocketAddrV4};
 
 /// Represents an UDP packet.
 #[derive(Clone, Debug)]
@@ -84,6 +84,19 @@ impl Udp {
         self.layer.destination
     }
 
+    /// Returns the source address of the layer, which consists of the source IP address in the IP
+    /// header and the source port.
+    pub fn src_addr(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.src, self.layer.source)
+    }
+
+    /// Returns the original destination address of the layer, which consists of the destination
+    /// IP address in the IP header and the destination port. The address is the one the source
+    /// intended to reach, regardless of the hardware address the frame was sent to.
+    pub fn dst_addr(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.dst, self.layer.destination)
+    }
+
     /// Returns the length of the layer.
     pub fn length(&self) -> u16 {
         self.layer.length
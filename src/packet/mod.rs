@@ -19,6 +19,8 @@ use layer::arp::Arp;
 use layer::ethernet::Ethernet;
 use layer::icmpv4::Icmpv4;
 use layer::ipv4::Ipv4;
+use layer::loopback::Loopback;
+use layer::sll::Sll;
 use layer::tcp::Tcp;
 use layer::udp::Udp;
 use layer::{Layer, LayerKind, Layers};
@@ -43,53 +45,14 @@ impl Indicator {
 
     /// Creates a `Indicator` by the given Ethernet packet.
     pub fn parse(packet: &EthernetPacket) -> Indicator {
-        let mut transport = None;
-
         let link = Layers::Ethernet(Ethernet::parse(packet));
-        let network = match packet.get_ethertype() {
+        let (network, transport) = match packet.get_ethertype() {
             EtherTypes::Arp => match ArpPacket::new(packet.payload()) {
-                Some(ref arp_packet) => Some(Layers::Arp(Arp::parse(arp_packet))),
-                None => None,
+                Some(ref arp_packet) => (Some(Layers::Arp(Arp::parse(arp_packet))), None),
+                None => (None, None),
             },
-            EtherTypes::Ipv4 => match Ipv4Packet::new(packet.payload()) {
-                Some(ref ipv4_packet) => {
-                    let ipv4 = Ipv4::parse(ipv4_packet);
-                    // Fragment
-                    if !ipv4.is_fragment() {
-                        transport = match ipv4_packet.get_next_level_protocol() {
-                            IpNextHeaderProtocols::Icmp => {
-                                match IcmpPacket::new(ipv4_packet.payload()) {
-                                    Some(ref icmp_packet) => {
-                                        Some(Layers::Icmpv4(Icmpv4::parse(icmp_packet)))
-                                    }
-                                    None => None,
-                                }
-                            }
-                            IpNextHeaderProtocols::Tcp => {
-                                match TcpPacket::new(ipv4_packet.payload()) {
-                                    Some(ref tcp_packet) => {
-                                        Some(Layers::Tcp(Tcp::parse(tcp_packet, &ipv4)))
-                                    }
-                                    None => None,
-                                }
-                            }
-                            IpNextHeaderProtocols::Udp => {
-                                match UdpPacket::new(ipv4_packet.payload()) {
-                                    Some(ref udp_packet) => {
-                                        Some(Layers::Udp(Udp::parse(udp_packet, &ipv4)))
-                                    }
-                                    None => None,
-                                }
-                            }
-                            _ => None,
-                        };
-                    }
-
-                    Some(Layers::Ipv4(ipv4))
-                }
-                None => None,
-            },
-            _ => None,
+            EtherTypes::Ipv4 => parse_ipv4(packet.payload()),
+            _ => (None, None),
         };
 
         Indicator {
@@ -107,6 +70,49 @@ impl Indicator {
         }
     }
 
+    /// Creates a `Indicator` by the given frame of the null/loopback link type.
+    pub fn from_loopback(frame: &[u8]) -> Option<Indicator> {
+        let loopback = match Loopback::parse(frame) {
+            Some(loopback) => loopback,
+            None => return None,
+        };
+        let (network, transport) = match loopback.is_ipv4() {
+            true => parse_ipv4(&frame[Loopback::minimum_len()..]),
+            false => (None, None),
+        };
+
+        Some(Indicator {
+            link: Layers::Loopback(loopback),
+            network,
+            transport,
+        })
+    }
+
+    /// Creates a `Indicator` by the given frame of the Linux cooked (SLL) link type.
+    pub fn from_sll(frame: &[u8]) -> Option<Indicator> {
+        let sll = match Sll::parse(frame) {
+            Some(sll) => sll,
+            None => return None,
+        };
+        let payload = &frame[Sll::minimum_len()..];
+        let (network, transport) = if sll.is_ipv4() {
+            parse_ipv4(payload)
+        } else if sll.is_arp() {
+            match ArpPacket::new(payload) {
+                Some(ref arp_packet) => (Some(Layers::Arp(Arp::parse(arp_packet))), None),
+                None => (None, None),
+            }
+        } else {
+            (None, None)
+        };
+
+        Some(Indicator {
+            link: Layers::Sll(sll),
+            network,
+            transport,
+        })
+    }
+
     /// Returns the brief of the indicator.
     pub fn brief(&self) -> String {
         match self.network() {
@@ -147,6 +153,8 @@ impl Indicator {
             },
             None => match self.link() {
                 Layers::Ethernet(ethernet) => format!("{}", ethernet),
+                Layers::Loopback(loopback) => format!("{}", loopback),
+                Layers::Sll(sll) => format!("{}", sll),
                 _ => unreachable!(),
             },
         }
@@ -172,16 +180,14 @@ impl Indicator {
 
     /// Returns the content length of the indicator when converted into a byte-array.
     pub fn content_len(&self) -> usize {
-        match self.link() {
-            Layers::Ethernet(ethernet) => match self.network() {
-                Some(network) => match network {
-                    Layers::Arp(arp) => ethernet.len() + arp.len(),
-                    Layers::Ipv4(ipv4) => ethernet.len() + ipv4.total_length() as usize,
-                    _ => unreachable!(),
-                },
-                None => ethernet.len(),
+        let link_len = self.link().len();
+        match self.network() {
+            Some(network) => match network {
+                Layers::Arp(arp) => link_len + arp.len(),
+                Layers::Ipv4(ipv4) => link_len + ipv4.total_length() as usize,
+                _ => unreachable!(),
             },
-            _ => unreachable!(),
+            None => link_len,
         }
     }
 
@@ -254,6 +260,15 @@ impl Indicator {
         None
     }
 
+    /// Returns the Linux cooked (SLL) layer.
+    pub fn sll(&self) -> Option<&Sll> {
+        if let Layers::Sll(layer) = &self.link() {
+            return Some(layer);
+        }
+
+        None
+    }
+
     /// Returns the network layer.
     pub fn network(&self) -> Option<&Layers> {
         if let Some(layer) = &self.network {
@@ -366,13 +381,43 @@ impl Display for Indicator {
     }
 }
 
+/// Parses the IPv4 packet and returns the network layer and the transport layer.
+fn parse_ipv4(payload: &[u8]) -> (Option<Layers>, Option<Layers>) {
+    let ipv4_packet = match Ipv4Packet::new(payload) {
+        Some(ipv4_packet) => ipv4_packet,
+        None => return (None, None),
+    };
+    let ipv4 = Ipv4::parse(&ipv4_packet);
+    // Fragment
+    let transport = match ipv4.is_fragment() {
+        true => None,
+        false => match ipv4_packet.get_next_level_protocol() {
+            IpNextHeaderProtocols::Icmp => match IcmpPacket::new(ipv4_packet.payload()) {
+                Some(ref icmp_packet) => Some(Layers::Icmpv4(Icmpv4::parse(icmp_packet))),
+                None => None,
+            },
+            IpNextHeaderProtocols::Tcp => match TcpPacket::new(ipv4_packet.payload()) {
+                Some(ref tcp_packet) => Some(Layers::Tcp(Tcp::parse(tcp_packet, &ipv4))),
+                None => None,
+            },
+            IpNextHeaderProtocols::Udp => match UdpPacket::new(ipv4_packet.payload()) {
+                Some(ref udp_packet) => Some(Layers::Udp(Udp::parse(udp_packet, &ipv4))),
+                None => None,
+            },
+            _ => None,
+        },
+    };
+
+    (Some(Layers::Ipv4(ipv4)), transport)
+}
+
 /// Represents the expire time of each group of fragments.
 const EXPIRE_TIME: u128 = 10000;
 
 /// Represents a fragmentation.
 #[derive(Debug)]
 pub struct Fragmentation {
-    ethernet: Ethernet,
+    link: Layers,
     ipv4: Ipv4,
     buffer: Vec<u8>,
     length: usize,
@@ -383,17 +428,13 @@ pub struct Fragmentation {
 impl Fragmentation {
     /// Creates a `Fragmentation`.
     pub fn new(indicator: &Indicator) -> Option<Fragmentation> {
-        let ethernet = match indicator.ethernet() {
-            Some(ethernet) => ethernet,
-            None => return None,
-        };
         let ipv4 = match indicator.ipv4() {
             Some(ipv4) => ipv4,
             None => return None,
         };
 
         let frag = Fragmentation {
-            ethernet: ethernet.clone(),
+            link: indicator.link().clone(),
             ipv4: ipv4.clone(),
             buffer: vec![0; u16::MAX as usize],
             length: 0,
@@ -412,6 +453,9 @@ impl Fragmentation {
             None => return,
         };
         let offset = (ipv4.fragment_offset() as usize) * 8;
+        if offset + payload.len() > self.buffer.len() {
+            return;
+        }
         if !ipv4.is_more_fragment() {
             self.total_length = Some(offset + payload.len());
         }
@@ -480,6 +524,11 @@ impl Defraggler {
             None => return None,
         };
 
+        let header_size = indicator.link().len() + ipv4.len();
+        if frame.len() < header_size {
+            return None;
+        }
+
         let key = (ipv4.src(), ipv4.dst(), ipv4.kind(), ipv4.identification());
 
         let is_create = match self.frags.get(&key) {
@@ -497,7 +546,6 @@ impl Defraggler {
 
         // Add fragmentation
         let frag = self.frags.get_mut(&key).unwrap();
-        let header_size = indicator.ethernet().unwrap().len() + ipv4.len();
         frag.add(indicator, &frame[header_size..]);
         if frag.is_completed() {
             self.frags.remove(&key)
@@ -561,3 +609,200 @@ fn defraggler_add() {
 
     assert_eq!(p, &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]);
 }
+
+#[test]
+fn indicator_dst_addr_gateway() {
+    use layer::LayerKinds;
+    use std::net::SocketAddrV4;
+
+    // The source addresses the gateway's hardware address but a remote IP address
+    let ethernet = Ethernet::new(
+        LayerKinds::Ipv4,
+        "11:11:11:11:11:11".parse().unwrap(),
+        "22:22:22:22:22:22".parse().unwrap(),
+    )
+    .unwrap();
+    let ipv4 = Ipv4::new(
+        0,
+        LayerKinds::Tcp,
+        "10.6.0.2".parse().unwrap(),
+        "93.184.216.34".parse().unwrap(),
+    )
+    .unwrap();
+    let mut tcp = Tcp::new_ack(40000, 443, 0, 0, 65535, None, None);
+    tcp.set_ipv4_layer(&ipv4);
+    let i = Indicator::new(
+        Layers::Ethernet(ethernet),
+        Some(Layers::Ipv4(ipv4)),
+        Some(Layers::Tcp(tcp)),
+    );
+    let mut b = vec![0u8; i.len()];
+    i.serialize(b.as_mut_slice()).unwrap();
+
+    let i = Indicator::from(b.as_slice()).unwrap();
+    let transport = i.transport().unwrap();
+
+    assert_eq!(
+        transport.src_addr(),
+        Some("10.6.0.2:40000".parse::<SocketAddrV4>().unwrap())
+    );
+    assert_eq!(
+        transport.dst_addr(),
+        Some("93.184.216.34:443".parse::<SocketAddrV4>().unwrap())
+    );
+}
+
+#[test]
+fn indicator_dst_addr_in_src_network() {
+    use layer::LayerKinds;
+    use std::net::SocketAddrV4;
+
+    // The destination is in the same network as the source
+    let ethernet = Ethernet::new(
+        LayerKinds::Ipv4,
+        "11:11:11:11:11:11".parse().unwrap(),
+        "44:44:44:44:44:44".parse().unwrap(),
+    )
+    .unwrap();
+    let ipv4 = Ipv4::new(
+        0,
+        LayerKinds::Udp,
+        "10.6.0.2".parse().unwrap(),
+        "10.6.0.3".parse().unwrap(),
+    )
+    .unwrap();
+    let mut udp = Udp::new(40000, 53);
+    udp.set_ipv4_layer(&ipv4);
+    let i = Indicator::new(
+        Layers::Ethernet(ethernet),
+        Some(Layers::Ipv4(ipv4)),
+        Some(Layers::Udp(udp)),
+    );
+    let mut b = vec![0u8; i.len() + 4];
+    i.serialize_with_payload(b.as_mut_slice(), &[0, 1, 2, 3])
+        .unwrap();
+
+    let i = Indicator::from(b.as_slice()).unwrap();
+
+    assert_eq!(
+        i.udp().unwrap().dst_addr(),
+        "10.6.0.3:53".parse::<SocketAddrV4>().unwrap()
+    );
+    assert!(i.tcp().is_none());
+    assert!(i.network().unwrap().dst_addr().is_none());
+}
+
+#[test]
+fn indicator_from_loopback() {
+    use layer::LayerKinds;
+    use std::net::SocketAddrV4;
+
+    let loopback = Loopback::new(LayerKinds::Ipv4).unwrap();
+    let ipv4 = Ipv4::new(
+        0,
+        LayerKinds::Udp,
+        "127.0.0.1".parse().unwrap(),
+        "127.0.0.1".parse().unwrap(),
+    )
+    .unwrap();
+    let mut udp = Udp::new(40000, 53);
+    udp.set_ipv4_layer(&ipv4);
+    let i = Indicator::new(
+        Layers::Loopback(loopback),
+        Some(Layers::Ipv4(ipv4)),
+        Some(Layers::Udp(udp)),
+    );
+    let mut b = vec![0u8; i.len() + 4];
+    i.serialize_with_payload(b.as_mut_slice(), &[0, 1, 2, 3])
+        .unwrap();
+    assert_eq!(&b[..4], &2u32.to_ne_bytes());
+
+    let i = Indicator::from_loopback(b.as_slice()).unwrap();
+    assert_eq!(i.link_kind(), LayerKinds::Loopback);
+    assert!(i.ethernet().is_none());
+    assert_eq!(i.content_len(), b.len());
+    assert_eq!(
+        i.udp().unwrap().dst_addr(),
+        "127.0.0.1:53".parse::<SocketAddrV4>().unwrap()
+    );
+
+    // The address family in the other byte order
+    b[..4].copy_from_slice(&2u32.swap_bytes().to_ne_bytes());
+    assert!(Indicator::from_loopback(b.as_slice())
+        .unwrap()
+        .udp()
+        .is_some());
+
+    // Other address families
+    b[..4].copy_from_slice(&30u32.to_ne_bytes());
+    assert!(Indicator::from_loopback(b.as_slice())
+        .unwrap()
+        .network()
+        .is_none());
+    assert!(Indicator::from_loopback(&b[..3]).is_none());
+}
+
+#[test]
+fn indicator_from_sll() {
+    use crate::pcap::HardwareAddr;
+    use layer::LayerKinds;
+    use std::net::SocketAddrV4;
+
+    let src: HardwareAddr = "00:11:22:33:44:55".parse().unwrap();
+    let sll = Sll::new(LayerKinds::Ipv4, src).unwrap();
+    let ipv4 = Ipv4::new(
+        0,
+        LayerKinds::Udp,
+        "10.6.0.2".parse().unwrap(),
+        "1.1.1.1".parse().unwrap(),
+    )
+    .unwrap();
+    let mut udp = Udp::new(40000, 53);
+    udp.set_ipv4_layer(&ipv4);
+    let i = Indicator::new(
+        Layers::Sll(sll),
+        Some(Layers::Ipv4(ipv4)),
+        Some(Layers::Udp(udp)),
+    );
+    let mut b = vec![0u8; i.len() + 4];
+    i.serialize_with_payload(b.as_mut_slice(), &[0, 1, 2, 3])
+        .unwrap();
+    assert_eq!(
+        &b[..16],
+        &[0, 0, 0, 1, 0, 6, 0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0, 0, 0x08, 0x00]
+    );
+
+    let i = Indicator::from_sll(b.as_slice()).unwrap();
+    assert_eq!(i.link_kind(), LayerKinds::Sll);
+    assert!(i.ethernet().is_none());
+    assert_eq!(i.sll().unwrap().src(), Some(src));
+    assert!(!i.sll().unwrap().is_outgoing());
+    assert_eq!(i.content_len(), b.len());
+    assert_eq!(
+        i.udp().unwrap().dst_addr(),
+        "1.1.1.1:53".parse::<SocketAddrV4>().unwrap()
+    );
+
+    // Packets sent by the host
+    b[..2].copy_from_slice(&4u16.to_be_bytes());
+    assert!(Indicator::from_sll(b.as_slice())
+        .unwrap()
+        .sll()
+        .unwrap()
+        .is_outgoing());
+
+    // Packets from non-Ethernet interfaces, like a tunnel, carry no hardware address
+    b[2..4].copy_from_slice(&0xfffeu16.to_be_bytes());
+    b[4..6].copy_from_slice(&0u16.to_be_bytes());
+    let i = Indicator::from_sll(b.as_slice()).unwrap();
+    assert_eq!(i.sll().unwrap().src(), None);
+    assert!(i.udp().is_some());
+
+    // Other protocols
+    b[14..16].copy_from_slice(&0x86ddu16.to_be_bytes());
+    assert!(Indicator::from_sll(b.as_slice())
+        .unwrap()
+        .network()
+        .is_none());
+    assert!(Indicator::from_sll(&b[..15]).is_none());
+}
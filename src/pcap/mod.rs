@@ -1,10 +1,20 @@
 //! Support for handling pcap interfaces.
 
-use pnet::datalink::{self, Channel, Config, DataLinkReceiver, DataLinkSender, MacAddr};
+use pnet::datalink::{
+    self, Channel, Config, DataLinkReceiver, DataLinkSender, MacAddr, NetworkInterface,
+};
 use std::clone::Clone;
+#[cfg(target_os = "linux")]
+use std::cmp::min;
 use std::fmt::{self, Display, Formatter};
 use std::io;
+#[cfg(target_os = "linux")]
+use std::mem;
 use std::net::Ipv4Addr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
 #[cfg(windows)]
 use netifs;
@@ -12,6 +22,8 @@ use netifs;
 #[cfg(not(windows))]
 use interfaces as c_interfaces;
 
+use crate::capture::FrameQueue;
+
 /// Represents the hardware address MAC in an Ethernet network.
 pub type HardwareAddr = pnet::datalink::MacAddr;
 
@@ -20,6 +32,75 @@ pub const HARDWARE_ADDR_UNSPECIFIED: HardwareAddr = MacAddr(0, 0, 0, 0, 0, 0);
 /// Represents the broadcast hardware address `FF:FF:FF:FF:FF:FF` in an Ethernet network.
 pub const HARDWARE_ADDR_BROADCAST: HardwareAddr = MacAddr(0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF);
 
+/// Represents the link-layer type of a network interface.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum LinkType {
+    /// Represents Ethernet.
+    Ethernet,
+    /// Represents the null/loopback encapsulation, in which frames begin with a 4-byte header of
+    /// the address family instead of an Ethernet header.
+    Null,
+    /// Represents the Linux cooked (SLL) encapsulation, in which frames captured on all the
+    /// interfaces begin with a 16-byte header rebuilt from the link-layer headers of their own.
+    /// Frames cannot be sent in the encapsulation.
+    LinuxSll,
+}
+
+impl Display for LinkType {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            LinkType::Ethernet => write!(f, "Ethernet"),
+            LinkType::Null => write!(f, "Null/Loopback"),
+            LinkType::LinuxSll => write!(f, "Linux cooked"),
+        }
+    }
+}
+
+/// Represents the mode of polling a pcap device.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum PollMode {
+    /// Represents reading blocks until a frame arrives or the read times out.
+    Block,
+    /// Represents reading never blocks, and the capture loop spins on the device, which takes a
+    /// full core for the lowest latency.
+    Busy,
+}
+
+impl PollMode {
+    /// Returns the read timeout of the pcap device in the mode. Busy polling reads with a timeout
+    /// of 0, which returns at once if no frame arrives.
+    pub fn read_timeout(&self, read_timeout: Option<Duration>) -> Option<Duration> {
+        match self {
+            PollMode::Block => read_timeout,
+            PollMode::Busy => Some(Duration::from_millis(0)),
+        }
+    }
+}
+
+impl Display for PollMode {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            PollMode::Block => write!(f, "block"),
+            PollMode::Busy => write!(f, "busy"),
+        }
+    }
+}
+
+impl FromStr for PollMode {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(PollMode::Block),
+            "busy" => Ok(PollMode::Busy),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown poll mode {}", s),
+            )),
+        }
+    }
+}
+
 /// Represents the send half of a pcap device.
 pub type Sender = Box<dyn DataLinkSender>;
 /// Represents the receive half of a pcap device.
@@ -28,6 +109,30 @@ pub type Receiver = Box<dyn DataLinkReceiver>;
 /// Represents the buffer size of pcap channels.
 const BUFFER_SIZE: usize = 256 * 1024;
 
+/// Represents the name of the pseudo-interface of all the interfaces.
+pub const ANY: &str = "any";
+
+/// Represents the suffix of the name of the loopback adapter of Npcap in Windows, which is not
+/// flagged as a loopback interface.
+const NPCAP_LOOPBACK: &str = "NPF_Loopback";
+
+fn open_channel(
+    inter: &NetworkInterface,
+    read_timeout: Option<Duration>,
+) -> io::Result<(Sender, Receiver)> {
+    let mut config = Config::default();
+    config.write_buffer_size = BUFFER_SIZE;
+    config.read_buffer_size = BUFFER_SIZE;
+    config.read_timeout = read_timeout;
+    let channel = datalink::channel(inter, config)?;
+    let channel = match channel {
+        Channel::Ethernet(tx, rx) => (tx, rx),
+        _ => return Err(io::Error::new(io::ErrorKind::Other, "unknown link type")),
+    };
+
+    Ok(channel)
+}
+
 /// Represents a network interface and its associated addresses.
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub struct Interface {
@@ -54,8 +159,36 @@ impl Interface {
         }
     }
 
-    /// Opens the network interface for sending and receiving data.
-    pub fn open(&self) -> io::Result<(Sender, Receiver)> {
+    /// Opens the network interface for sending and receiving data. Receiving returns a `TimedOut`
+    /// error if no frame arrives in the read timeout, or blocks if the read timeout is `None`.
+    pub fn open(&self, read_timeout: Option<Duration>) -> io::Result<(Sender, Receiver)> {
+        let inter = self.datalink_interface()?;
+
+        open_channel(&inter, read_timeout)
+    }
+
+    /// Opens all the network interfaces for receiving data in the Linux cooked link type, and
+    /// the network interface for sending data in Ethernet. Only available on Linux.
+    #[cfg(target_os = "linux")]
+    pub fn open_any(&self, read_timeout: Option<Duration>) -> io::Result<(Sender, Receiver)> {
+        let inter = self.datalink_interface()?;
+        let (tx, _) = open_channel(&inter, read_timeout)?;
+        let rx = CookedReceiver::open(read_timeout)?;
+
+        Ok((tx, Box::new(rx)))
+    }
+
+    /// Opens all the network interfaces for receiving data in the Linux cooked link type, and
+    /// the network interface for sending data in Ethernet. Only available on Linux.
+    #[cfg(not(target_os = "linux"))]
+    pub fn open_any(&self, _: Option<Duration>) -> io::Result<(Sender, Receiver)> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "capturing on all the interfaces is not supported",
+        ))
+    }
+
+    fn datalink_interface(&self) -> io::Result<NetworkInterface> {
         let inters = datalink::interfaces();
         let inter = inters
             .into_iter()
@@ -66,16 +199,7 @@ impl Interface {
                 "interface not found",
             ))?;
 
-        let mut config = Config::default();
-        config.write_buffer_size = BUFFER_SIZE;
-        config.read_buffer_size = BUFFER_SIZE;
-        let channel = datalink::channel(&inter, config)?;
-        let channel = match channel {
-            Channel::Ethernet(tx, rx) => (tx, rx),
-            _ => return Err(io::Error::new(io::ErrorKind::Other, "unknown link type")),
-        };
-
-        Ok(channel)
+        Ok(inter)
     }
 
     /// Returns the name of the interface.
@@ -116,6 +240,17 @@ impl Interface {
     pub fn is_loopback(&self) -> bool {
         self.is_loopback
     }
+
+    /// Returns the link-layer type of frames captured on and sent to the interface. Loopback
+    /// interfaces use the null/loopback encapsulation on Windows. On Linux, the kernel presents
+    /// loopback frames with an Ethernet header of zero addresses, and on macOS and BSD, the BPF
+    /// backend replaces the null/loopback header with an Ethernet header, so they are Ethernet.
+    pub fn link_type(&self) -> LinkType {
+        match self.is_loopback && cfg!(windows) {
+            true => LinkType::Null,
+            false => LinkType::Ethernet,
+        }
+    }
 }
 
 impl Display for Interface {
@@ -162,8 +297,11 @@ pub fn interfaces() -> Vec<Interface> {
 
             let mut i = Interface::new();
             i.name = inter.name.clone();
+            i.is_loopback = inter.is_loopback() || inter.name.ends_with(NPCAP_LOOPBACK);
             i.hardware_addr = match inter.mac {
                 Some(mac) => mac,
+                // Loopback interfaces may have no hardware address
+                None if i.is_loopback => MacAddr::zero(),
                 None => return Err(()),
             };
             i.ip_addrs = inter
@@ -182,7 +320,6 @@ pub fn interfaces() -> Vec<Interface> {
             }
 
             i.is_up = inter.is_up();
-            i.is_loopback = inter.is_loopback();
 
             Ok(i)
         })
@@ -203,7 +340,7 @@ fn mark_interfaces(mut ifs: Vec<Interface>) -> Vec<Interface> {
                     i.alias = Some(inter.display_name.clone());
                     i.mtu = inter.mtu;
                     i.is_up = inter.is_up;
-                    i.is_loopback = inter.is_loopback;
+                    i.is_loopback = i.is_loopback || inter.is_loopback;
                 }
             }
         }
@@ -258,3 +395,298 @@ impl DataLinkSender for BlackHole {
         Some(Ok(()))
     }
 }
+
+/// Represents a receive half of all the interfaces in Linux, which receives frames in a packet
+/// socket bound to no interface like the `any` device of libpcap. The datagram socket strips the
+/// link-layer headers of the interfaces, so frames of different link-layer types are received in
+/// the same way, and the Linux cooked (SLL) header is rebuilt from the address of each frame.
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+struct CookedReceiver {
+    fd: libc::c_int,
+    flags: libc::c_int,
+    buffer: Vec<u8>,
+}
+
+#[cfg(target_os = "linux")]
+impl CookedReceiver {
+    fn open(read_timeout: Option<Duration>) -> io::Result<CookedReceiver> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_PACKET,
+                libc::SOCK_DGRAM,
+                (libc::ETH_P_ALL as u16).to_be() as libc::c_int,
+            )
+        };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut rx = CookedReceiver {
+            fd,
+            flags: 0,
+            buffer: vec![0u8; SLL_LEN + u16::MAX as usize],
+        };
+
+        // A timeout of 0 disables the timeout of the socket, so the socket is polled instead
+        match read_timeout {
+            Some(timeout) if timeout == Duration::from_millis(0) => rx.flags = libc::MSG_DONTWAIT,
+            Some(timeout) => {
+                let tv = libc::timeval {
+                    tv_sec: timeout.as_secs() as libc::time_t,
+                    tv_usec: timeout.subsec_micros() as libc::suseconds_t,
+                };
+                let r = unsafe {
+                    libc::setsockopt(
+                        fd,
+                        libc::SOL_SOCKET,
+                        libc::SO_RCVTIMEO,
+                        &tv as *const libc::timeval as *const libc::c_void,
+                        mem::size_of::<libc::timeval>() as libc::socklen_t,
+                    )
+                };
+                if r == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            None => {}
+        }
+
+        Ok(rx)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl DataLinkReceiver for CookedReceiver {
+    fn next(&mut self) -> io::Result<&[u8]> {
+        let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+        let mut addr_len = mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t;
+        let size = unsafe {
+            libc::recvfrom(
+                self.fd,
+                self.buffer[SLL_LEN..].as_mut_ptr() as *mut libc::c_void,
+                self.buffer.len() - SLL_LEN,
+                self.flags,
+                &mut addr as *mut libc::sockaddr_ll as *mut libc::sockaddr,
+                &mut addr_len,
+            )
+        };
+        if size == -1 {
+            let e = io::Error::last_os_error();
+            return match e.kind() {
+                io::ErrorKind::WouldBlock => {
+                    Err(io::Error::new(io::ErrorKind::TimedOut, "timed out"))
+                }
+                _ => Err(e),
+            };
+        }
+        self.buffer[..SLL_LEN].copy_from_slice(&sll_header(&addr));
+
+        Ok(&self.buffer[..SLL_LEN + size as usize])
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for CookedReceiver {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// Represents the length of the Linux cooked (SLL) header.
+#[cfg(target_os = "linux")]
+const SLL_LEN: usize = 16;
+
+/// Returns the Linux cooked (SLL) header of a frame received from the address, which is the
+/// packet type, the hardware type, the length of the hardware address, the hardware address
+/// padded to 8 bytes and the protocol, all in the network byte order.
+#[cfg(target_os = "linux")]
+fn sll_header(addr: &libc::sockaddr_ll) -> [u8; SLL_LEN] {
+    let mut header = [0u8; SLL_LEN];
+    header[0..2].copy_from_slice(&(addr.sll_pkttype as u16).to_be_bytes());
+    header[2..4].copy_from_slice(&addr.sll_hatype.to_be_bytes());
+    header[4..6].copy_from_slice(&(addr.sll_halen as u16).to_be_bytes());
+    let len = min(addr.sll_halen as usize, addr.sll_addr.len());
+    header[6..6 + len].copy_from_slice(&addr.sll_addr[..len]);
+    // The protocol is in the network byte order already
+    header[14..16].copy_from_slice(&addr.sll_protocol.to_ne_bytes());
+
+    header
+}
+
+/// Represents a receive half taking frames from a `FrameQueue`.
+#[derive(Debug)]
+struct QueuedReceiver {
+    queue: FrameQueue,
+    read_timeout: Option<Duration>,
+    frame: Vec<u8>,
+}
+
+impl DataLinkReceiver for QueuedReceiver {
+    fn next(&mut self) -> io::Result<&[u8]> {
+        self.frame = self.queue.pop(self.read_timeout)?;
+
+        Ok(&self.frame)
+    }
+}
+
+impl Drop for QueuedReceiver {
+    fn drop(&mut self) {
+        // Stop the producers, like the capture thread
+        self.queue.close(None);
+    }
+}
+
+/// Represents a send half pushing frames to a `FrameQueue`.
+#[derive(Debug)]
+struct QueuedSender {
+    queue: FrameQueue,
+}
+
+impl QueuedSender {
+    fn push(&mut self, frame: Vec<u8>) -> io::Result<()> {
+        if !self.queue.push(frame) && self.queue.is_closed() {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "frame queue closed",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl DataLinkSender for QueuedSender {
+    fn build_and_send(
+        &mut self,
+        num_packets: usize,
+        packet_size: usize,
+        func: &mut dyn FnMut(&mut [u8]),
+    ) -> Option<io::Result<()>> {
+        for _ in 0..num_packets {
+            let mut frame = vec![0u8; packet_size];
+            func(&mut frame);
+            if let Err(e) = self.push(frame) {
+                return Some(Err(e));
+            }
+        }
+
+        Some(Ok(()))
+    }
+
+    fn send_to(
+        &mut self,
+        packet: &[u8],
+        _: Option<datalink::NetworkInterface>,
+    ) -> Option<io::Result<()>> {
+        Some(self.push(packet.to_vec()))
+    }
+}
+
+/// Returns a virtual receive half taking frames from the queue, which times out the same as a
+/// pcap device with the read timeout, and fails with the error closing the queue after the
+/// frames queued. Frames can be fed by a capture thread, or by tests driving the redirection
+/// without a pcap device.
+pub fn receiver(queue: FrameQueue, read_timeout: Option<Duration>) -> Receiver {
+    Box::new(QueuedReceiver {
+        queue,
+        read_timeout,
+        frame: Vec::new(),
+    })
+}
+
+/// Returns a virtual send half pushing frames sent to the queue, so tests can assert on them.
+pub fn sender(queue: FrameQueue) -> Sender {
+    Box::new(QueuedSender { queue })
+}
+
+/// Represents a send half shared with other send halves, which send through the same send half
+/// in turn.
+struct SharedSender {
+    tx: Arc<Mutex<Sender>>,
+}
+
+impl DataLinkSender for SharedSender {
+    fn build_and_send(
+        &mut self,
+        num_packets: usize,
+        packet_size: usize,
+        func: &mut dyn FnMut(&mut [u8]),
+    ) -> Option<io::Result<()>> {
+        self.tx
+            .lock()
+            .unwrap()
+            .build_and_send(num_packets, packet_size, func)
+    }
+
+    fn send_to(
+        &mut self,
+        packet: &[u8],
+        dst: Option<datalink::NetworkInterface>,
+    ) -> Option<io::Result<()>> {
+        self.tx.lock().unwrap().send_to(packet, dst)
+    }
+}
+
+/// Returns the count of send halves sharing the send half, so each shard of the flow table can
+/// own a `Forwarder` of its own while sending through the same pcap device. The send half is
+/// locked only while a frame is sent.
+pub fn shared(tx: Sender, count: usize) -> Vec<Sender> {
+    let tx = Arc::new(Mutex::new(tx));
+
+    (0..count)
+        .map(|_| -> Sender {
+            Box::new(SharedSender {
+                tx: Arc::clone(&tx),
+            })
+        })
+        .collect()
+}
+
+/// Moves the capture of the receive half to a new thread handing off frames to the queue, and
+/// returns a receive half taking frames from the queue, which times out the same as a pcap
+/// device with the read timeout. Errors of the capture are returned by the receive half after
+/// the frames queued, and the capture stops when the receive half is dropped.
+pub fn queued(mut rx: Receiver, queue: FrameQueue, read_timeout: Option<Duration>) -> Receiver {
+    let producer = queue.clone();
+    thread::spawn(move || {
+        while !producer.is_closed() {
+            match rx.next() {
+                Ok(frame) => {
+                    producer.push(frame.to_vec());
+                }
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::TimedOut {
+                        continue;
+                    }
+                    producer.close(Some(e));
+                }
+            }
+        }
+    });
+
+    receiver(queue, read_timeout)
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn sll_header_ethernet() {
+    use crate::packet::layer::sll::Sll;
+
+    let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+    addr.sll_pkttype = 4;
+    addr.sll_hatype = 1;
+    addr.sll_halen = 6;
+    addr.sll_addr[..6].copy_from_slice(&[0x02, 0x00, 0x00, 0x00, 0x00, 0x01]);
+    addr.sll_protocol = 0x0800u16.to_be();
+
+    let header = sll_header(&addr);
+    assert_eq!(header[..6], [0x00, 0x04, 0x00, 0x01, 0x00, 0x06]);
+    assert_eq!(header[14..], [0x08, 0x00]);
+
+    let sll = Sll::parse(&header).unwrap();
+    assert!(sll.is_outgoing());
+    assert!(sll.is_ipv4());
+    assert_eq!(sll.src(), Some(HardwareAddr::new(0x02, 0, 0, 0, 0, 0x01)));
+}
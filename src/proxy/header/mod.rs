@@ -0,0 +1,247 @@
+//! Support for parsing the PROXY protocol header.
+
+use std::fmt::{self, Display, Formatter};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str;
+use tokio::io;
+
+/// Represents the signature of the PROXY protocol v1.
+const V1_SIGNATURE: &[u8] = b"PROXY ";
+/// Represents the maximum length of the PROXY protocol v1 header, including the CRLF.
+const V1_MAX_LEN: usize = 107;
+
+/// Represents the signature of the PROXY protocol v2.
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\x00\r\nQUIT\n";
+/// Represents the length of the fixed part of the PROXY protocol v2 header.
+const V2_MIN_LEN: usize = 16;
+
+/// Represents a PROXY protocol header prepended by a load balancer, which carries the original
+/// addresses of the connection.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ProxyHeader {
+    src: Option<SocketAddr>,
+    dst: Option<SocketAddr>,
+}
+
+impl ProxyHeader {
+    /// Returns the original source of the connection, or `None` if the header does not carry
+    /// the addresses, like a health check from the load balancer.
+    pub fn src(&self) -> Option<SocketAddr> {
+        self.src
+    }
+
+    /// Returns the original destination of the connection.
+    pub fn dst(&self) -> Option<SocketAddr> {
+        self.dst
+    }
+}
+
+impl Display for ProxyHeader {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match (self.src, self.dst) {
+            (Some(src), Some(dst)) => write!(f, "PROXY {} -> {}", src, dst),
+            _ => write!(f, "PROXY UNKNOWN"),
+        }
+    }
+}
+
+/// Parses the PROXY protocol header in v1 or v2 format from the first bytes of a connection.
+/// Returns the header and its length, or `None` if more bytes are required.
+pub fn parse(buffer: &[u8]) -> io::Result<Option<(ProxyHeader, usize)>> {
+    if is_prefix(buffer, V1_SIGNATURE) {
+        parse_v1(buffer)
+    } else if is_prefix(buffer, V2_SIGNATURE) {
+        parse_v2(buffer)
+    } else {
+        Err(invalid("missing PROXY protocol header"))
+    }
+}
+
+fn parse_v1(buffer: &[u8]) -> io::Result<Option<(ProxyHeader, usize)>> {
+    let len = match buffer.windows(2).position(|w| w == b"\r\n") {
+        Some(i) => i + 2,
+        None if buffer.len() >= V1_MAX_LEN => {
+            return Err(invalid("PROXY protocol v1 header too long"))
+        }
+        None => return Ok(None),
+    };
+    if len > V1_MAX_LEN {
+        return Err(invalid("PROXY protocol v1 header too long"));
+    }
+
+    let line = str::from_utf8(&buffer[..len - 2])
+        .map_err(|_| invalid("invalid PROXY protocol v1 header"))?;
+    let fields = line.split(' ').collect::<Vec<_>>();
+    let header = match fields.get(1) {
+        Some(&"UNKNOWN") => ProxyHeader {
+            src: None,
+            dst: None,
+        },
+        Some(&protocol) if protocol == "TCP4" || protocol == "TCP6" => {
+            if fields.len() != 6 {
+                return Err(invalid("invalid PROXY protocol v1 header"));
+            }
+            let parse_addr = |ip: &str, port: &str| -> io::Result<SocketAddr> {
+                let ip = ip
+                    .parse::<IpAddr>()
+                    .map_err(|_| invalid("invalid PROXY protocol v1 address"))?;
+                let port = port
+                    .parse::<u16>()
+                    .map_err(|_| invalid("invalid PROXY protocol v1 port"))?;
+                match (protocol, ip) {
+                    ("TCP4", IpAddr::V4(_)) | ("TCP6", IpAddr::V6(_)) => {
+                        Ok(SocketAddr::new(ip, port))
+                    }
+                    _ => Err(invalid("invalid PROXY protocol v1 address")),
+                }
+            };
+
+            ProxyHeader {
+                src: Some(parse_addr(fields[2], fields[4])?),
+                dst: Some(parse_addr(fields[3], fields[5])?),
+            }
+        }
+        _ => return Err(invalid("invalid PROXY protocol v1 header")),
+    };
+
+    Ok(Some((header, len)))
+}
+
+fn parse_v2(buffer: &[u8]) -> io::Result<Option<(ProxyHeader, usize)>> {
+    if buffer.len() < V2_MIN_LEN {
+        return Ok(None);
+    }
+    let version = buffer[12] >> 4;
+    let command = buffer[12] & 0x0f;
+    let family = buffer[13];
+    let len = V2_MIN_LEN + u16::from_be_bytes([buffer[14], buffer[15]]) as usize;
+    if version != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    if buffer.len() < len {
+        return Ok(None);
+    }
+    let addrs = &buffer[V2_MIN_LEN..len];
+
+    let header = match command {
+        // LOCAL
+        0x0 => ProxyHeader {
+            src: None,
+            dst: None,
+        },
+        // PROXY
+        0x1 => match family {
+            // TCP over IPv4
+            0x11 => {
+                if addrs.len() < 12 {
+                    return Err(invalid("invalid PROXY protocol v2 address"));
+                }
+                let ip = |b: &[u8]| IpAddr::V4(Ipv4Addr::new(b[0], b[1], b[2], b[3]));
+
+                ProxyHeader {
+                    src: Some(SocketAddr::new(ip(&addrs[0..4]), port(&addrs[8..10]))),
+                    dst: Some(SocketAddr::new(ip(&addrs[4..8]), port(&addrs[10..12]))),
+                }
+            }
+            // TCP over IPv6
+            0x21 => {
+                if addrs.len() < 36 {
+                    return Err(invalid("invalid PROXY protocol v2 address"));
+                }
+                let ip = |b: &[u8]| {
+                    let mut octets = [0u8; 16];
+                    octets.copy_from_slice(b);
+
+                    IpAddr::V6(Ipv6Addr::from(octets))
+                };
+
+                ProxyHeader {
+                    src: Some(SocketAddr::new(ip(&addrs[0..16]), port(&addrs[32..34]))),
+                    dst: Some(SocketAddr::new(ip(&addrs[16..32]), port(&addrs[34..36]))),
+                }
+            }
+            // Unspecified, UDP or UNIX, the addresses are ignored
+            _ => ProxyHeader {
+                src: None,
+                dst: None,
+            },
+        },
+        _ => return Err(invalid("unsupported PROXY protocol command")),
+    };
+
+    Ok(Some((header, len)))
+}
+
+fn port(b: &[u8]) -> u16 {
+    u16::from_be_bytes([b[0], b[1]])
+}
+
+/// Returns if the buffer matches the signature, or is a prefix of the signature.
+fn is_prefix(buffer: &[u8], signature: &[u8]) -> bool {
+    let len = buffer.len().min(signature.len());
+
+    len > 0 && buffer[..len] == signature[..len]
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[test]
+fn proxy_header_parse_v1() {
+    let b = b"PROXY TCP4 192.0.2.1 198.51.100.1 40000 443\r\nGET /";
+    let (header, len) = parse(b).unwrap().unwrap();
+    assert_eq!(header.src(), Some("192.0.2.1:40000".parse().unwrap()));
+    assert_eq!(header.dst(), Some("198.51.100.1:443".parse().unwrap()));
+    assert_eq!(&b[len..], b"GET /");
+
+    let b = b"PROXY TCP6 2001:db8::1 2001:db8::2 40000 443\r\n";
+    let (header, len) = parse(b).unwrap().unwrap();
+    assert_eq!(header.src(), Some("[2001:db8::1]:40000".parse().unwrap()));
+    assert_eq!(len, b.len());
+
+    let (header, _) = parse(b"PROXY UNKNOWN\r\n").unwrap().unwrap();
+    assert_eq!(header.src(), None);
+
+    // Incomplete
+    assert!(parse(b"PRO").unwrap().is_none());
+    assert!(parse(b"PROXY TCP4 192.0.2.1").unwrap().is_none());
+
+    // Invalid
+    assert!(parse(b"GET / HTTP/1.1\r\n").is_err());
+    assert!(parse(b"PROXY TCP4 2001:db8::1 198.51.100.1 40000 443\r\n").is_err());
+    assert!(parse(b"PROXY TCP4 192.0.2.1 198.51.100.1 40000\r\n").is_err());
+    assert!(parse(&[&b"PROXY "[..], &[b'0'; 120][..]].concat()).is_err());
+}
+
+#[test]
+fn proxy_header_parse_v2() {
+    let mut b = V2_SIGNATURE.to_vec();
+    // PROXY, TCP over IPv4
+    b.extend_from_slice(&[0x21, 0x11, 0, 12]);
+    b.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 1]);
+    b.extend_from_slice(&40000u16.to_be_bytes());
+    b.extend_from_slice(&443u16.to_be_bytes());
+    b.extend_from_slice(b"GET /");
+
+    let (header, len) = parse(&b).unwrap().unwrap();
+    assert_eq!(header.src(), Some("192.0.2.1:40000".parse().unwrap()));
+    assert_eq!(header.dst(), Some("198.51.100.1:443".parse().unwrap()));
+    assert_eq!(&b[len..], b"GET /");
+
+    // Incomplete
+    assert!(parse(&b[..8]).unwrap().is_none());
+    assert!(parse(&b[..20]).unwrap().is_none());
+
+    // LOCAL
+    let mut b = V2_SIGNATURE.to_vec();
+    b.extend_from_slice(&[0x20, 0x00, 0, 0]);
+    let (header, len) = parse(&b).unwrap().unwrap();
+    assert_eq!(header.src(), None);
+    assert_eq!(len, 16);
+
+    // Invalid version
+    let mut b = V2_SIGNATURE.to_vec();
+    b.extend_from_slice(&[0x11, 0x11, 0, 0]);
+    assert!(parse(&b).is_err());
+}
@@ -2,19 +2,24 @@
 
 use log::{debug, trace, warn};
 use std::net::{Ipv4Addr, Shutdown, SocketAddrV4};
-use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::TcpStream;
 use tokio::sync::mpsc::{self, Sender, UnboundedReceiver, UnboundedSender};
 use tokio::{self, io, time};
 
+pub mod header;
+pub mod sni;
 mod socks;
+pub use socks::SocksReply;
 use socks::SocksSendHalf;
 use socks::{SocksAuth, SocksOption};
 
 /// Represents the configuration of the proxy.
+#[derive(Clone, Debug)]
 pub enum ProxyConfig {
     /// Represents the SOCKS proxy configuration.
     Socks(SocketAddrV4, SocksOption),
@@ -40,6 +45,29 @@ impl ProxyConfig {
             ),
         )
     }
+
+    /// Returns the address of the proxy.
+    pub fn addr(&self) -> SocketAddrV4 {
+        match self {
+            ProxyConfig::Socks(remote, _) => *remote,
+        }
+    }
+
+    /// Sets the maximum count of handshakes in flight. Connecting waits for a slot, and fails
+    /// with `TimedOut` if no slot is freed in time. The limit is shared by clones of the
+    /// `ProxyConfig` made afterward.
+    pub fn set_max_handshakes(&mut self, max_handshakes: Option<usize>) {
+        match self {
+            ProxyConfig::Socks(_, options) => options.set_max_handshakes(max_handshakes),
+        }
+    }
+
+    /// Returns the count of handshakes in flight.
+    pub fn handshake_count(&self) -> usize {
+        match self {
+            ProxyConfig::Socks(_, options) => options.handshake_count(),
+        }
+    }
 }
 
 /// Trait for forwarding a stream.
@@ -73,10 +101,20 @@ const MAX_RECV_ZERO: usize = 3;
 /// Represents the interval of a tick.
 const TICK_INTERVAL: u64 = 500;
 
+/// Represents the maximum size of the coalesced payload before sending to the proxy.
+const MAX_COALESCE_SIZE: usize = 1460;
+
+/// Represents the maximum count of reconnecting to the proxy after the stream is reset.
+const MAX_RECONNECT: usize = 3;
+/// Represents the wait time before reconnecting to the proxy.
+const RECONNECT_WAIT: u64 = 1000;
+
 /// Represents a worker of a proxied TCP stream.
 pub struct StreamWorker {
     dst: SocketAddrV4,
-    tx_tx: UnboundedSender<Vec<u8>>,
+    tx_tx: UnboundedSender<(Vec<u8>, bool)>,
+    /// Represents the size of payload sent to the worker but not written to the proxy yet.
+    unsent: Arc<AtomicUsize>,
     is_tx_closed: Arc<AtomicBool>,
     is_rx_closed: Arc<AtomicBool>,
     tx_close_tx: Sender<()>,
@@ -84,72 +122,174 @@ pub struct StreamWorker {
 }
 
 impl StreamWorker {
-    /// Opens a new `StreamWorker`.
+    /// Opens a new `StreamWorker`. If `is_reconnect` is set, the worker will reconnect to the
+    /// proxy and resume the stream after the stream is reset. If `coalesce` is set, small payload
+    /// will be coalesced for at most the given milliseconds before sending to the proxy.
     pub async fn connect(
         tx: Arc<Mutex<dyn ForwardStream>>,
         src: SocketAddrV4,
         dst: SocketAddrV4,
         proxy: &ProxyConfig,
+        is_reconnect: bool,
+        coalesce: Option<u64>,
+    ) -> io::Result<StreamWorker> {
+        StreamWorker::connect_with(tx, src, dst, proxy, is_reconnect, coalesce, false).await
+    }
+
+    /// Opens a new `StreamWorker` of a stream connection which is already opened, like a
+    /// connection whose connecting to the proxy is deferred until the source sends payload.
+    pub async fn connect_opened(
+        tx: Arc<Mutex<dyn ForwardStream>>,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        proxy: &ProxyConfig,
+        is_reconnect: bool,
+        coalesce: Option<u64>,
+    ) -> io::Result<StreamWorker> {
+        StreamWorker::connect_with(tx, src, dst, proxy, is_reconnect, coalesce, true).await
+    }
+
+    async fn connect_with(
+        tx: Arc<Mutex<dyn ForwardStream>>,
+        src: SocketAddrV4,
+        dst: SocketAddrV4,
+        proxy: &ProxyConfig,
+        is_reconnect: bool,
+        coalesce: Option<u64>,
+        is_opened: bool,
     ) -> io::Result<StreamWorker> {
         let tx_cloned = Arc::clone(&tx);
+        let proxy = proxy.clone();
 
-        let stream = match proxy {
-            ProxyConfig::Socks(remote, options) => {
-                socks::connect(remote.clone(), dst, options).await?
-            }
-        };
-        let stream = stream.into_inner();
+        let stream = StreamWorker::open_stream(&proxy, dst).await?;
         let (mut stream_rx, mut stream_tx) = stream.into_split();
 
         // Open
-        tx.lock().unwrap().open(dst, src)?;
+        if !is_opened {
+            tx.lock().unwrap().open(dst, src)?;
+        }
 
-        let (tx_tx, mut tx_rx): (UnboundedSender<Vec<u8>>, UnboundedReceiver<Vec<u8>>) =
-            mpsc::unbounded_channel();
+        let (tx_tx, mut tx_rx): (
+            UnboundedSender<(Vec<u8>, bool)>,
+            UnboundedReceiver<(Vec<u8>, bool)>,
+        ) = mpsc::unbounded_channel();
+        let unsent = Arc::new(AtomicUsize::new(0));
+        let unsent_cloned = Arc::clone(&unsent);
         let is_tx_closed = Arc::new(AtomicBool::new(false));
         let is_tx_closed_cloned = Arc::clone(&is_tx_closed);
         let is_rx_closed = Arc::new(AtomicBool::new(false));
         let is_rx_closed_cloned = Arc::clone(&is_rx_closed);
         let (tx_close_tx, mut tx_close_rx) = mpsc::channel(1);
         let (rx_close_tx, mut rx_close_rx) = mpsc::channel(1);
+        let (stream_tx_tx, mut stream_tx_rx): (
+            UnboundedSender<OwnedWriteHalf>,
+            UnboundedReceiver<OwnedWriteHalf>,
+        ) = mpsc::unbounded_channel();
 
         // Send
         tokio::spawn(async move {
+            let mut is_closing = false;
+            let mut is_reconnect = is_reconnect;
+            // The payload failed to send before reconnecting
+            let mut pending: Option<Vec<u8>> = None;
             loop {
-                let is_close;
+                let mut is_close;
+                let mut received = None;
 
                 // Select
                 {
+                    let stream_tx_rx_fut = stream_tx_rx.recv();
                     let tx_rx_fut = tx_rx.recv();
                     let tx_close_rx_fut = tx_close_rx.recv();
 
-                    tokio::pin!(tx_rx_fut, tx_close_rx_fut);
+                    tokio::pin!(stream_tx_rx_fut, tx_rx_fut, tx_close_rx_fut);
 
+                    // Send all the pending payload before closing
                     tokio::select! {
-                        r = tx_rx_fut => match r {
-                            Some(payload) => {
-                                match stream_tx.write_all(payload.as_slice()).await {
-                                    Ok(_) => {
-                                        debug!(
-                                            "send to proxy: {}: {} -> {} ({} Bytes)",
-                                            "TCP", 0, dst, payload.len()
-                                        );
-
-                                        is_close = false
-                                    },
-                                    Err(ref e) => {
-                                        warn!("handle send: {}: {} -> {}: {}", "TCP", 0, dst, e);
-
-                                        is_close = true
+                        r = stream_tx_rx_fut, if is_reconnect => match r {
+                            Some(this_stream_tx) => {
+                                stream_tx = this_stream_tx;
+                                is_close = match pending.take() {
+                                    Some(payload) => {
+                                        match stream_tx.write_all(payload.as_slice()).await {
+                                            Ok(_) => {
+                                                debug!(
+                                                    "send to proxy: {}: {} -> {} ({} Bytes)",
+                                                    "TCP", 0, dst, payload.len()
+                                                );
+                                                unsent_cloned.fetch_sub(payload.len(), Ordering::Relaxed);
+
+                                                false
+                                            },
+                                            Err(ref e) => {
+                                                warn!("handle send: {}: {} -> {}: {}", "TCP", 0, dst, e);
+                                                pending = Some(payload);
+
+                                                false
+                                            }
+                                        }
                                     }
+                                    None => false
                                 };
                             }
+                            None => {
+                                // The stream will not be reconnected any more
+                                is_reconnect = false;
+                                is_close = pending.is_some();
+                            }
+                        },
+                        r = tx_rx_fut, if pending.is_none() => match r {
+                            Some(r) => {
+                                received = Some(r);
+                                is_close = false
+                            }
                             None => is_close = true
                         },
-                        _ = tx_close_rx_fut => is_close = true
+                        _ = tx_close_rx_fut, if !is_closing => {
+                            is_closing = true;
+                            is_close = false
+                        }
                     }
                 }
 
+                // Send the payload received, which is coalesced after the futures are dropped
+                if let Some((payload, is_push)) = received {
+                    let payload = match coalesce {
+                        Some(coalesce) if !is_push && payload.len() < MAX_COALESCE_SIZE => {
+                            StreamWorker::coalesce(&mut tx_rx, payload, coalesce).await
+                        }
+                        _ => payload,
+                    };
+                    // Writing waits until the socket is writable, and the payload behind is left
+                    // in the channel as unsent
+                    match stream_tx.write_all(payload.as_slice()).await {
+                        Ok(_) => {
+                            debug!(
+                                "send to proxy: {}: {} -> {} ({} Bytes)",
+                                "TCP",
+                                0,
+                                dst,
+                                payload.len()
+                            );
+                            unsent_cloned.fetch_sub(payload.len(), Ordering::Relaxed);
+                        }
+                        Err(ref e) => {
+                            warn!("handle send: {}: {} -> {}: {}", "TCP", 0, dst, e);
+
+                            // Wait for reconnecting
+                            if is_reconnect {
+                                pending = Some(payload);
+                            }
+                            is_close = !is_reconnect
+                        }
+                    };
+                }
+
+                if is_closing {
+                    // Stop receiving, and the payload left in the channel is still received
+                    tx_rx.close();
+                }
+
                 if is_close {
                     // Close
                     is_tx_closed_cloned.store(true, Ordering::Relaxed);
@@ -165,6 +305,7 @@ impl StreamWorker {
             let mut recv_zero: usize = 0;
             loop {
                 let size;
+                let mut is_reset = false;
 
                 // Select
                 {
@@ -200,12 +341,25 @@ impl StreamWorker {
                                 warn!("receive from proxy: {}: {} -> {}: {}", "TCP", dst, 0, e);
 
                                 size = 0;
+                                is_reset = true;
                             }
                         },
                         _ = rx_close_rx_fut => size = 0
                     }
                 }
 
+                // Reconnect
+                if is_reset && is_reconnect {
+                    if let Some(stream) = StreamWorker::reconnect(&proxy, dst).await {
+                        let (this_stream_rx, this_stream_tx) = stream.into_split();
+                        stream_rx = this_stream_rx;
+                        recv_zero = 0;
+                        if stream_tx_tx.send(this_stream_tx).is_ok() {
+                            continue;
+                        }
+                    }
+                }
+
                 if size > 0 {
                     // Loop until the data was transferred to the forwarder
                     let mut is_sent = false;
@@ -274,6 +428,7 @@ impl StreamWorker {
         Ok(StreamWorker {
             dst,
             tx_tx,
+            unsent,
             is_tx_closed,
             is_rx_closed,
             tx_close_tx,
@@ -281,10 +436,74 @@ impl StreamWorker {
         })
     }
 
-    /// Sends data on the proxied stream in TCP to the destination.
-    pub fn send(&mut self, payload: Vec<u8>) -> io::Result<()> {
+    /// Coalesces the payload with the following payload in the channel until the delay elapses,
+    /// the size reaches `MAX_COALESCE_SIZE` or a payload is pushed.
+    async fn coalesce(
+        rx: &mut UnboundedReceiver<(Vec<u8>, bool)>,
+        mut payload: Vec<u8>,
+        delay: u64,
+    ) -> Vec<u8> {
+        let deadline = time::Instant::now() + Duration::from_millis(delay);
+        while payload.len() < MAX_COALESCE_SIZE {
+            match time::timeout_at(deadline, rx.recv()).await {
+                Ok(Some((next_payload, is_push))) => {
+                    payload.extend_from_slice(next_payload.as_slice());
+                    if is_push {
+                        break;
+                    }
+                }
+                // Timed out or closed
+                _ => break,
+            }
+        }
+
+        payload
+    }
+
+    async fn open_stream(proxy: &ProxyConfig, dst: SocketAddrV4) -> io::Result<TcpStream> {
+        let stream = match proxy {
+            ProxyConfig::Socks(remote, options) => {
+                socks::connect(remote.clone(), dst, options).await?
+            }
+        };
+
+        Ok(stream.into_inner())
+    }
+
+    async fn reconnect(proxy: &ProxyConfig, dst: SocketAddrV4) -> Option<TcpStream> {
+        for i in 0..MAX_RECONNECT {
+            time::sleep(Duration::from_millis(RECONNECT_WAIT)).await;
+            match StreamWorker::open_stream(proxy, dst).await {
+                Ok(stream) => {
+                    debug!("reconnect stream {} -> {}", 0, dst);
+
+                    return Some(stream);
+                }
+                Err(ref e) => warn!(
+                    "reconnect to proxy ({}/{}): {}: {} -> {}: {}",
+                    i + 1,
+                    MAX_RECONNECT,
+                    "TCP",
+                    0,
+                    dst,
+                    e
+                ),
+            }
+        }
+
+        None
+    }
+
+    /// Sends data on the proxied stream in TCP to the destination. If `is_push` is set, the
+    /// data will be sent without coalescing.
+    pub fn send(&mut self, payload: Vec<u8>, is_push: bool) -> io::Result<()> {
+        // Count before sending, or the worker may write the payload before it is counted
+        let size = payload.len();
+        self.unsent.fetch_add(size, Ordering::Relaxed);
+
         // Send
-        if let Err(_) = self.tx_tx.send(payload) {
+        if let Err(_) = self.tx_tx.send((payload, is_push)) {
+            self.unsent.fetch_sub(size, Ordering::Relaxed);
             return Err(io::Error::from(io::ErrorKind::NotConnected));
         }
 
@@ -325,6 +544,12 @@ impl StreamWorker {
     pub fn is_rx_closed(&self) -> bool {
         self.is_rx_closed.load(Ordering::Relaxed)
     }
+
+    /// Returns the size of payload sent to the worker but not written to the proxy yet, which
+    /// grows when the proxy reads slower than the source sends.
+    pub fn unsent(&self) -> usize {
+        self.unsent.load(Ordering::Relaxed)
+    }
 }
 
 impl Drop for StreamWorker {
@@ -540,14 +765,17 @@ pub struct DatagramWorker {
 }
 
 impl DatagramWorker {
-    /// Creates a new `DatagramWorker`.
+    /// Creates a new `DatagramWorker`. If the port is given, it is preferred as the local port.
     pub async fn bind(
         tx: Arc<Mutex<dyn ForwardDatagram>>,
         src: SocketAddrV4,
         proxy: &ProxyConfig,
+        port: Option<u16>,
     ) -> io::Result<(DatagramWorker, u16)> {
         let (mut socks_rx, mut socks_tx, local_port) = match proxy {
-            ProxyConfig::Socks(remote, options) => socks::bind(remote.clone(), options).await?,
+            ProxyConfig::Socks(remote, options) => {
+                socks::bind(remote.clone(), options, port).await?
+            }
         };
 
         let (tx_tx, mut tx_rx): (
@@ -563,6 +791,7 @@ impl DatagramWorker {
 
         // Send
         tokio::spawn(async move {
+            let mut is_closing = false;
             loop {
                 let is_close;
 
@@ -573,6 +802,7 @@ impl DatagramWorker {
 
                     tokio::pin!(tx_rx_fut, close_rx_fut);
 
+                    // Send all the pending payload before closing
                     tokio::select! {
                         r = tx_rx_fut => match r {
                             Some((payload, dst)) => {
@@ -589,12 +819,20 @@ impl DatagramWorker {
                                 }
                                 is_close = false;
                             }
-                            None => is_close = false
+                            None => is_close = is_closing
                         },
-                        _ = close_rx_fut => is_close = true
+                        _ = close_rx_fut, if !is_closing => {
+                            is_closing = true;
+                            is_close = false
+                        }
                     }
                 }
 
+                if is_closing {
+                    // Stop receiving, and the payload left in the channel is still received
+                    tx_rx.close();
+                }
+
                 if is_close {
                     // Close
                     break;
@@ -745,7 +983,9 @@ impl DatagramWorker2 {
         proxy: &ProxyConfig,
     ) -> io::Result<(DatagramWorker2, u16)> {
         let (mut socks_rx, socks_tx, local_port) = match proxy {
-            ProxyConfig::Socks(remote, options) => socks::bind(remote.clone(), options).await?,
+            ProxyConfig::Socks(remote, options) => {
+                socks::bind(remote.clone(), options, None).await?
+            }
         };
 
         let a_src = Arc::new(AtomicU64::from(socket_addr_v4_to_u64(&src)));
@@ -891,3 +1131,100 @@ fn u64_to_socket_addr_v4(v: u64) -> SocketAddrV4 {
 
     SocketAddrV4::new(ip, port)
 }
+
+#[tokio::test]
+async fn stream_worker_unsent() {
+    use std::net::SocketAddr;
+    use tokio::net::TcpListener;
+
+    const SIZE: usize = 32 * 1024 * 1024;
+
+    struct Discard;
+
+    impl ForwardStream for Discard {
+        fn open(&mut self, _: SocketAddrV4, _: SocketAddrV4) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn forward(&mut self, _: SocketAddrV4, _: SocketAddrV4, _: &[u8]) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn tick(&mut self, _: SocketAddrV4, _: SocketAddrV4) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn close(&mut self, _: SocketAddrV4, _: SocketAddrV4) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn check(&self, _: SocketAddrV4, _: SocketAddrV4) -> io::Result<usize> {
+            Ok(usize::MAX)
+        }
+    }
+
+    // A SOCKS5 server which does not read the stream until released, and reads slowly then
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let remote = match listener.local_addr().unwrap() {
+        SocketAddr::V4(addr) => addr,
+        SocketAddr::V6(_) => unreachable!(),
+    };
+    let (release_tx, mut release_rx) = mpsc::channel::<()>(1);
+    let server = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+
+        // VER, NMETHODS, METHODS
+        let mut buffer = [0u8; 2];
+        stream.read_exact(&mut buffer).await.unwrap();
+        let mut methods = vec![0u8; buffer[1] as usize];
+        stream.read_exact(&mut methods).await.unwrap();
+        stream.write_all(&[5, 0]).await.unwrap();
+        // VER, CMD, RSV, ATYP, DST.ADDR, DST.PORT
+        let mut request = [0u8; 10];
+        stream.read_exact(&mut request).await.unwrap();
+        stream
+            .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+
+        release_rx.recv().await;
+        let mut received = Vec::new();
+        let mut buffer = vec![0u8; 65536];
+        while received.len() < SIZE {
+            let size = stream.read(&mut buffer).await.unwrap();
+            assert!(size > 0);
+            received.extend_from_slice(&buffer[..size]);
+            time::sleep(Duration::from_millis(1)).await;
+        }
+
+        received
+    });
+
+    let tx: Arc<Mutex<dyn ForwardStream>> = Arc::new(Mutex::new(Discard));
+    let proxy = ProxyConfig::new_socks(remote, false, false, None);
+    let mut worker = StreamWorker::connect(
+        tx,
+        "10.6.0.2:40000".parse().unwrap(),
+        "1.1.1.1:80".parse().unwrap(),
+        &proxy,
+        false,
+        None,
+    )
+    .await
+    .unwrap();
+    let payload = (0..SIZE).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    for chunk in payload.chunks(1460) {
+        worker.send(chunk.to_vec(), true).unwrap();
+    }
+
+    // The payload beyond the socket buffers is left unsent
+    time::sleep(Duration::from_millis(100)).await;
+    let unsent = worker.unsent();
+    assert!(unsent > 0 && unsent < SIZE);
+
+    // All the payload is written in order after the server catches up
+    release_tx.send(()).await.unwrap();
+    let received = server.await.unwrap();
+    assert!(received == payload);
+    assert_eq!(worker.unsent(), 0);
+}
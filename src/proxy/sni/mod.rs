@@ -0,0 +1,202 @@
+//! Support for peeking the server name indication in a TLS ClientHello.
+
+use std::str;
+use tokio::io;
+
+/// Represents the content type of the TLS handshake.
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+/// Represents the handshake type of the ClientHello.
+const HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 0x01;
+/// Represents the extension type of the server name indication.
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+/// Represents the name type of the host name in the server name indication.
+const NAME_TYPE_HOST_NAME: u8 = 0x00;
+/// Represents the length of the TLS record header.
+const RECORD_HEADER_LEN: usize = 5;
+
+/// Represents a TLS ClientHello peeked from the first bytes of a connection.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ClientHello {
+    server_name: Option<String>,
+}
+
+impl ClientHello {
+    /// Returns the host name in the server name indication, or `None` if the ClientHello does
+    /// not indicate one.
+    pub fn server_name(&self) -> Option<&str> {
+        self.server_name.as_deref()
+    }
+}
+
+/// Parses the TLS ClientHello from the first bytes of a connection. Returns `None` if more bytes
+/// are required. Only the first TLS record is parsed, extensions beyond it are ignored.
+pub fn parse(buffer: &[u8]) -> io::Result<Option<ClientHello>> {
+    if buffer.is_empty() {
+        return Ok(None);
+    }
+    if buffer[0] != CONTENT_TYPE_HANDSHAKE {
+        return Err(invalid("not a TLS handshake"));
+    }
+    if buffer.len() < RECORD_HEADER_LEN {
+        return Ok(None);
+    }
+    let len = u16::from_be_bytes([buffer[3], buffer[4]]) as usize;
+    if buffer.len() < RECORD_HEADER_LEN + len {
+        return Ok(None);
+    }
+    let mut reader = Reader::new(&buffer[RECORD_HEADER_LEN..RECORD_HEADER_LEN + len]);
+
+    // Handshake
+    if reader.u8()? != HANDSHAKE_TYPE_CLIENT_HELLO {
+        return Err(invalid("not a TLS ClientHello"));
+    }
+    reader.skip(3)?;
+    // Version and random
+    reader.skip(2 + 32)?;
+    // Session ID, cipher suites and compression methods
+    let n = reader.u8()? as usize;
+    reader.skip(n)?;
+    let n = reader.u16()? as usize;
+    reader.skip(n)?;
+    let n = reader.u8()? as usize;
+    reader.skip(n)?;
+
+    // Extensions, which may be truncated if the ClientHello spans multiple records
+    let mut server_name = None;
+    if let Ok(n) = reader.u16() {
+        let mut extensions = reader.take(n as usize).unwrap_or_else(|_| reader.rest());
+        while let (Ok(t), Ok(n)) = (extensions.u16(), extensions.u16()) {
+            let mut extension = match extensions.take(n as usize) {
+                Ok(extension) => extension,
+                Err(_) => break,
+            };
+            if t == EXTENSION_SERVER_NAME {
+                server_name = parse_server_name(&mut extension)?;
+                break;
+            }
+        }
+    }
+
+    Ok(Some(ClientHello { server_name }))
+}
+
+fn parse_server_name(reader: &mut Reader) -> io::Result<Option<String>> {
+    let n = reader.u16()? as usize;
+    let mut names = reader.take(n)?;
+    while let Ok(t) = names.u8() {
+        let n = names.u16()? as usize;
+        let name = names.bytes(n)?;
+        if t == NAME_TYPE_HOST_NAME {
+            // Host names are in ASCII, like `example.com`
+            let name = str::from_utf8(name).map_err(|_| invalid("invalid TLS server name"))?;
+            if !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_')
+            {
+                return Err(invalid("invalid TLS server name"));
+            }
+
+            return Ok(Some(name.to_string()));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Represents a reader over bytes, whose reads fail instead of panicking if the bytes are not
+/// enough.
+struct Reader<'a> {
+    buffer: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(buffer: &'a [u8]) -> Reader<'a> {
+        Reader { buffer }
+    }
+
+    fn bytes(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.buffer.len() < n {
+            return Err(invalid("truncated TLS ClientHello"));
+        }
+        let (bytes, rest) = self.buffer.split_at(n);
+        self.buffer = rest;
+
+        Ok(bytes)
+    }
+
+    fn take(&mut self, n: usize) -> io::Result<Reader<'a>> {
+        Ok(Reader::new(self.bytes(n)?))
+    }
+
+    fn rest(&mut self) -> Reader<'a> {
+        let buffer = self.buffer;
+        self.buffer = &[];
+
+        Reader::new(buffer)
+    }
+
+    fn skip(&mut self, n: usize) -> io::Result<()> {
+        self.bytes(n).map(|_| ())
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        let b = self.bytes(2)?;
+
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[test]
+fn sni_parse() {
+    fn client_hello(extensions: &[u8]) -> Vec<u8> {
+        let mut hello = vec![0x03, 0x03];
+        hello.extend_from_slice(&[0; 32]);
+        // Session ID, cipher suites and compression methods
+        hello.extend_from_slice(&[0, 0, 2, 0x13, 0x01, 1, 0]);
+        hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        hello.extend_from_slice(extensions);
+
+        let mut handshake = vec![HANDSHAKE_TYPE_CLIENT_HELLO, 0];
+        handshake.extend_from_slice(&(hello.len() as u16).to_be_bytes());
+        handshake.extend_from_slice(&hello);
+
+        let mut record = vec![CONTENT_TYPE_HANDSHAKE, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+
+        record
+    }
+
+    let name = b"example.com";
+    let mut extensions = vec![0x00, 0x17, 0, 0];
+    extensions.extend_from_slice(&[0, 0]);
+    extensions.extend_from_slice(&(name.len() as u16 + 5).to_be_bytes());
+    extensions.extend_from_slice(&(name.len() as u16 + 3).to_be_bytes());
+    extensions.push(NAME_TYPE_HOST_NAME);
+    extensions.extend_from_slice(&(name.len() as u16).to_be_bytes());
+    extensions.extend_from_slice(name);
+    let b = client_hello(&extensions);
+
+    let hello = parse(&b).unwrap().unwrap();
+    assert_eq!(hello.server_name(), Some("example.com"));
+
+    // Incomplete
+    assert!(parse(&[]).unwrap().is_none());
+    assert!(parse(&b[..3]).unwrap().is_none());
+    assert!(parse(&b[..b.len() - 1]).unwrap().is_none());
+
+    // Without the server name indication
+    let hello = parse(&client_hello(&[0x00, 0x17, 0, 0])).unwrap().unwrap();
+    assert_eq!(hello.server_name(), None);
+
+    // Not TLS
+    assert!(parse(b"GET / HTTP/1.1\r\n").is_err());
+}
@@ -1,9 +1,18 @@
 use async_socks5::{self, AddrKind, Auth};
 use log::trace;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::{self, BufStream};
 use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::Semaphore;
+use tokio::time;
+
+/// Represents the maximum time a SOCKS handshake waits for a slot before timing out.
+const HANDSHAKE_QUEUE_TIMEOUT: u64 = 5000;
 
 /// Represents the username and the password of the authentication connecting to a SOCKS5 server.
 #[derive(Clone, Debug)]
@@ -19,12 +28,22 @@ impl SocksAuth {
     }
 }
 
+/// Represents the SOCKS handshakes in flight, which are shared by all the clones of a
+/// `SocksOption`.
+#[derive(Debug, Default)]
+struct Handshakes {
+    /// Represents the slots of handshakes, `None` represents handshakes are not limited.
+    slots: Option<Semaphore>,
+    in_flight: AtomicUsize,
+}
+
 /// Represents the options connecting to a SOCKS5 server.
 #[derive(Clone, Debug)]
 pub struct SocksOption {
     force_associate_remote: bool,
     force_associate_bind_addr: bool,
     auth: Option<SocksAuth>,
+    handshakes: Arc<Handshakes>,
 }
 
 impl SocksOption {
@@ -38,9 +57,24 @@ impl SocksOption {
             force_associate_remote,
             force_associate_bind_addr: force_associate_bind_addr,
             auth,
+            handshakes: Arc::new(Handshakes::default()),
         }
     }
 
+    /// Sets the maximum count of SOCKS handshakes in flight, `None` represents handshakes are not
+    /// limited. Only clones of the `SocksOption` made afterward share the limit.
+    pub fn set_max_handshakes(&mut self, max_handshakes: Option<usize>) {
+        self.handshakes = Arc::new(Handshakes {
+            slots: max_handshakes.map(Semaphore::new),
+            in_flight: AtomicUsize::new(0),
+        });
+    }
+
+    /// Returns the count of SOCKS handshakes in flight, excluding those waiting for a slot.
+    pub fn handshake_count(&self) -> usize {
+        self.handshakes.in_flight.load(Ordering::Relaxed)
+    }
+
     fn auth(&self) -> Option<Auth> {
         match self.auth {
             Some(ref auth) => Some(Auth::new(auth.username.clone(), auth.password.clone())),
@@ -49,17 +83,139 @@ impl SocksOption {
     }
 }
 
+/// Represents an unsuccessful reply from a SOCKS5 server.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SocksReply {
+    GeneralFailure,
+    ConnectionNotAllowed,
+    NetworkUnreachable,
+    HostUnreachable,
+    ConnectionRefused,
+    TtlExpired,
+    CommandNotSupported,
+    AddressTypeNotSupported,
+    Unassigned(u8),
+}
+
+impl SocksReply {
+    /// Returns the reply code of the reply.
+    pub fn code(&self) -> u8 {
+        match self {
+            SocksReply::GeneralFailure => 1,
+            SocksReply::ConnectionNotAllowed => 2,
+            SocksReply::NetworkUnreachable => 3,
+            SocksReply::HostUnreachable => 4,
+            SocksReply::ConnectionRefused => 5,
+            SocksReply::TtlExpired => 6,
+            SocksReply::CommandNotSupported => 7,
+            SocksReply::AddressTypeNotSupported => 8,
+            SocksReply::Unassigned(code) => *code,
+        }
+    }
+
+    /// Returns if the reply indicates the target server is unreachable, which is translated into
+    /// an ICMPv4 error instead of a TCP RST.
+    pub fn is_unreachable(&self) -> bool {
+        matches!(
+            self,
+            SocksReply::NetworkUnreachable | SocksReply::HostUnreachable | SocksReply::TtlExpired
+        )
+    }
+
+    fn kind(&self) -> io::ErrorKind {
+        match self {
+            SocksReply::ConnectionNotAllowed => io::ErrorKind::PermissionDenied,
+            SocksReply::ConnectionRefused => io::ErrorKind::ConnectionRefused,
+            _ => io::ErrorKind::Other,
+        }
+    }
+}
+
+impl From<async_socks5::UnsuccessfulReply> for SocksReply {
+    fn from(reply: async_socks5::UnsuccessfulReply) -> Self {
+        match reply {
+            async_socks5::UnsuccessfulReply::GeneralFailure => SocksReply::GeneralFailure,
+            async_socks5::UnsuccessfulReply::ConnectionNotAllowedByRules => {
+                SocksReply::ConnectionNotAllowed
+            }
+            async_socks5::UnsuccessfulReply::NetworkUnreachable => SocksReply::NetworkUnreachable,
+            async_socks5::UnsuccessfulReply::HostUnreachable => SocksReply::HostUnreachable,
+            async_socks5::UnsuccessfulReply::ConnectionRefused => SocksReply::ConnectionRefused,
+            async_socks5::UnsuccessfulReply::TtlExpired => SocksReply::TtlExpired,
+            async_socks5::UnsuccessfulReply::CommandNotSupported => SocksReply::CommandNotSupported,
+            async_socks5::UnsuccessfulReply::AddressTypeNotSupported => {
+                SocksReply::AddressTypeNotSupported
+            }
+            async_socks5::UnsuccessfulReply::Unassigned(code) => SocksReply::Unassigned(code),
+        }
+    }
+}
+
+impl Display for SocksReply {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let s = match self {
+            SocksReply::GeneralFailure => "general SOCKS server failure",
+            SocksReply::ConnectionNotAllowed => "connection not allowed by ruleset",
+            SocksReply::NetworkUnreachable => "network unreachable",
+            SocksReply::HostUnreachable => "host unreachable",
+            SocksReply::ConnectionRefused => "connection refused",
+            SocksReply::TtlExpired => "TTL expired",
+            SocksReply::CommandNotSupported => "command not supported",
+            SocksReply::AddressTypeNotSupported => "address type not supported",
+            SocksReply::Unassigned(_) => "unassigned",
+        };
+
+        write!(f, "{} (0x{:02x})", s, self.code())
+    }
+}
+
+impl Error for SocksReply {}
+
 /// Connects to a target server through a SOCKS5 proxy.
 pub async fn connect(
     remote: SocketAddrV4,
     dst: SocketAddrV4,
     options: &SocksOption,
+) -> io::Result<BufStream<TcpStream>> {
+    // Wait for a slot if handshakes are limited, the slot is released after the handshake
+    let _permit = match options.handshakes.slots {
+        Some(ref slots) => {
+            let permit = time::timeout(
+                Duration::from_millis(HANDSHAKE_QUEUE_TIMEOUT),
+                slots.acquire(),
+            )
+            .await
+            .map_err(|_| {
+                io::Error::new(io::ErrorKind::TimedOut, "SOCKS handshake queue timed out")
+            })?
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+            Some(permit)
+        }
+        None => None,
+    };
+
+    options.handshakes.in_flight.fetch_add(1, Ordering::Relaxed);
+    let result = handshake(remote, dst, options).await;
+    options.handshakes.in_flight.fetch_sub(1, Ordering::Relaxed);
+
+    result
+}
+
+async fn handshake(
+    remote: SocketAddrV4,
+    dst: SocketAddrV4,
+    options: &SocksOption,
 ) -> io::Result<BufStream<TcpStream>> {
     let stream = TcpStream::connect(remote).await?;
     let mut stream = BufStream::new(stream);
     if let Err(e) = async_socks5::connect(&mut stream, dst, options.auth()).await {
         match e {
             async_socks5::Error::Io(e) => return Err(e),
+            async_socks5::Error::Response(reply) => {
+                let reply = SocksReply::from(reply);
+                return Err(io::Error::new(reply.kind(), reply));
+            }
             _ => return Err(io::Error::new(io::ErrorKind::Other, e)),
         }
     }
@@ -70,11 +226,151 @@ pub async fn connect(
 const RSV_SIZE: usize = 2;
 const FRAG_SIZE: usize = 1;
 const ATYP_SIZE: usize = 1;
-const DST_ADDR_SIZE: usize = 4;
 const DST_PORT_SIZE: usize = 2;
-const HEADER_SIZE: usize = RSV_SIZE + FRAG_SIZE + ATYP_SIZE + DST_ADDR_SIZE + DST_PORT_SIZE;
 
 const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+/// Represents the maximum length of a domain name in SOCKS5.
+const MAX_DOMAIN_LEN: usize = 255;
+
+/// Represents an address in a SOCKS5 request or reply, which is in the ATYP, DST.ADDR and
+/// DST.PORT fields.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SocksAddr {
+    Ipv4(SocketAddrV4),
+    Ipv6(SocketAddrV6),
+    Domain(String, u16),
+}
+
+impl SocksAddr {
+    /// Returns the length of the address.
+    pub fn len(&self) -> usize {
+        ATYP_SIZE
+            + match self {
+                SocksAddr::Ipv4(_) => 4,
+                SocksAddr::Ipv6(_) => 16,
+                SocksAddr::Domain(domain, _) => 1 + domain.len(),
+            }
+            + DST_PORT_SIZE
+    }
+
+    /// Serializes the address into the buffer and returns the length of the address.
+    pub fn serialize(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        let len = self.len();
+        if buffer.len() < len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "buffer too small",
+            ));
+        }
+
+        let port = match self {
+            SocksAddr::Ipv4(addr) => {
+                buffer[0] = ATYP_IPV4;
+                buffer[1..5].copy_from_slice(&addr.ip().octets());
+
+                addr.port()
+            }
+            SocksAddr::Ipv6(addr) => {
+                buffer[0] = ATYP_IPV6;
+                buffer[1..17].copy_from_slice(&addr.ip().octets());
+
+                addr.port()
+            }
+            SocksAddr::Domain(domain, port) => {
+                if domain.is_empty() || domain.len() > MAX_DOMAIN_LEN {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("domain name of {} Bytes", domain.len()),
+                    ));
+                }
+                buffer[0] = ATYP_DOMAIN;
+                buffer[1] = domain.len() as u8;
+                buffer[2..2 + domain.len()].copy_from_slice(domain.as_bytes());
+
+                *port
+            }
+        };
+        buffer[len - DST_PORT_SIZE..len].copy_from_slice(&port.to_be_bytes());
+
+        Ok(len)
+    }
+
+    /// Parses an address from the buffer and returns the address and its length.
+    pub fn parse(buffer: &[u8]) -> io::Result<(SocksAddr, usize)> {
+        let truncated = || io::Error::new(io::ErrorKind::UnexpectedEof, "address truncated");
+
+        let (addr_len, addr_offset) = match buffer.first() {
+            Some(&ATYP_IPV4) => (4, ATYP_SIZE),
+            Some(&ATYP_IPV6) => (16, ATYP_SIZE),
+            Some(&ATYP_DOMAIN) => (
+                *buffer.get(1).ok_or_else(truncated)? as usize,
+                ATYP_SIZE + 1,
+            ),
+            Some(atyp) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("address type 0x{:02x} not supported", atyp),
+                ))
+            }
+            None => return Err(truncated()),
+        };
+        let len = addr_offset + addr_len + DST_PORT_SIZE;
+        if buffer.len() < len {
+            return Err(truncated());
+        }
+
+        let addr = &buffer[addr_offset..addr_offset + addr_len];
+        let port = u16::from_be_bytes([buffer[len - 2], buffer[len - 1]]);
+        let addr = match buffer[0] {
+            ATYP_IPV4 => SocksAddr::Ipv4(SocketAddrV4::new(
+                Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]),
+                port,
+            )),
+            ATYP_IPV6 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(addr);
+
+                SocksAddr::Ipv6(SocketAddrV6::new(Ipv6Addr::from(octets), port, 0, 0))
+            }
+            _ => match String::from_utf8(addr.to_vec()) {
+                Ok(domain) if !domain.is_empty() => SocksAddr::Domain(domain, port),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "invalid domain name",
+                    ))
+                }
+            },
+        };
+
+        Ok((addr, len))
+    }
+}
+
+impl From<SocketAddrV4> for SocksAddr {
+    fn from(addr: SocketAddrV4) -> Self {
+        SocksAddr::Ipv4(addr)
+    }
+}
+
+impl From<SocketAddrV6> for SocksAddr {
+    fn from(addr: SocketAddrV6) -> Self {
+        SocksAddr::Ipv6(addr)
+    }
+}
+
+impl Display for SocksAddr {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            SocksAddr::Ipv4(addr) => write!(f, "{}", addr),
+            SocksAddr::Ipv6(addr) => write!(f, "{}", addr),
+            SocksAddr::Domain(domain, port) => write!(f, "{}:{}", domain, port),
+        }
+    }
+}
 
 /// Represents the send half of a SOCKS5 UDP client.
 #[derive(Debug)]
@@ -91,18 +387,16 @@ impl SocksSendHalf {
 
     /// Sends data on the socket to the given address.
     pub async fn send_to(&mut self, payload: &[u8], dst: SocketAddrV4) -> io::Result<usize> {
-        let mut buf = vec![0u8; HEADER_SIZE + payload.len()];
+        let dst = SocksAddr::from(dst);
+        let header_size = RSV_SIZE + FRAG_SIZE + dst.len();
+
+        let mut buf = vec![0u8; header_size + payload.len()];
         // RSV
         // FRAG
-        // ATYP
-        buf[3] = ATYP_IPV4;
-        // DST.ADDR
-        &buf[4..8].copy_from_slice(&dst.ip().octets());
-        // DST.PORT
-        buf[8] = (dst.port() / 256) as u8;
-        buf[9] = (dst.port() % 256) as u8;
+        // ATYP, DST.ADDR and DST.PORT
+        dst.serialize(&mut buf[RSV_SIZE + FRAG_SIZE..])?;
         // Data
-        &buf[10..].copy_from_slice(payload);
+        buf[header_size..].copy_from_slice(payload);
 
         self.socket.send(buf.as_slice()).await
     }
@@ -129,39 +423,52 @@ impl SocksRecvHalf {
     /// Receives a single datagram message on the socket.
     pub async fn recv_from(&mut self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddrV4)> {
         let n = self.socket.recv(&mut self.buffer).await?;
-        // ATYP and address
-        match self.buffer[3] {
-            ATYP_IPV4 => {}
-            _ => unreachable!(),
+        if n < RSV_SIZE + FRAG_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "datagram truncated",
+            ));
         }
-        let addr = SocketAddrV4::new(
-            Ipv4Addr::new(
-                self.buffer[4],
-                self.buffer[5],
-                self.buffer[6],
-                self.buffer[7],
-            ),
-            self.buffer[8] as u16 * 256 + self.buffer[9] as u16,
-        );
+        // ATYP, DST.ADDR and DST.PORT
+        let (addr, addr_len) = SocksAddr::parse(&self.buffer[RSV_SIZE + FRAG_SIZE..n])?;
+        let addr = match addr {
+            SocksAddr::Ipv4(addr) => addr,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("datagram from {} not supported", addr),
+                ))
+            }
+        };
         // Buffer
-        let size = n - HEADER_SIZE;
-        &buffer[..size].copy_from_slice(&self.buffer[HEADER_SIZE..n]);
+        let header_size = RSV_SIZE + FRAG_SIZE + addr_len;
+        let size = n - header_size;
+        buffer[..size].copy_from_slice(&self.buffer[header_size..n]);
 
         Ok((size, addr))
     }
 }
 
-/// Binds a local address to a target server through a SOCKS5 proxy.
+/// Binds a local address to a target server through a SOCKS5 proxy. If the port is given, it is
+/// preferred in binding, and an arbitrary port will be bound if it is unavailable.
 pub async fn bind(
     remote: SocketAddrV4,
     options: &SocksOption,
+    port: Option<u16>,
 ) -> io::Result<(SocksRecvHalf, SocksSendHalf, u16)> {
     // Connect
     let stream = TcpStream::connect(remote).await?;
     let stream = BufStream::new(stream);
 
-    let local = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
-    let socket = UdpSocket::bind(local).await?;
+    let local = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port.unwrap_or(0));
+    let socket = match UdpSocket::bind(local).await {
+        Ok(socket) => socket,
+        Err(_) if port.is_some() => {
+            let local = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
+            UdpSocket::bind(local).await?
+        }
+        Err(e) => return Err(e),
+    };
     let local_port = socket.local_addr().unwrap().port();
     let datagram = match async_socks5::SocksDatagram::associate::<SocketAddrV4>(
         stream,
@@ -174,6 +481,10 @@ pub async fn bind(
         Ok(datagram) => datagram,
         Err(e) => match e {
             async_socks5::Error::Io(e) => return Err(e),
+            async_socks5::Error::Response(reply) => {
+                let reply = SocksReply::from(reply);
+                return Err(io::Error::new(reply.kind(), reply));
+            }
             _ => return Err(io::Error::new(io::ErrorKind::Other, e)),
         },
     };
@@ -219,3 +530,114 @@ pub async fn bind(
         local_port,
     ))
 }
+
+#[test]
+fn socks_addr_connect_ipv6() {
+    let dst = SocketAddrV6::new("2001:db8::1".parse().unwrap(), 443, 0, 0);
+    let addr = SocksAddr::from(dst);
+
+    // VER, CMD, RSV
+    let mut request = vec![5u8, 1, 0];
+    request.resize(3 + addr.len(), 0);
+    assert_eq!(addr.serialize(&mut request[3..]).unwrap(), 19);
+    assert_eq!(
+        request,
+        vec![5, 1, 0, 4, 0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0x01, 0xbb]
+    );
+
+    let (parsed, len) = SocksAddr::parse(&request[3..]).unwrap();
+    assert_eq!(parsed, addr);
+    assert_eq!(len, 19);
+}
+
+#[test]
+fn socks_addr_domain_max_len() {
+    let domain = "a".repeat(MAX_DOMAIN_LEN);
+    let addr = SocksAddr::Domain(domain.clone(), 80);
+
+    let mut buffer = vec![0u8; addr.len()];
+    assert_eq!(addr.serialize(&mut buffer).unwrap(), 1 + 1 + 255 + 2);
+    assert_eq!(buffer[..2], [ATYP_DOMAIN, 255]);
+    assert_eq!(buffer[buffer.len() - 2..], [0, 80]);
+
+    let (parsed, len) = SocksAddr::parse(&buffer).unwrap();
+    assert_eq!(parsed, addr);
+    assert_eq!(len, buffer.len());
+
+    // Oversized domain names cannot be encoded
+    let addr = SocksAddr::Domain(domain + "a", 80);
+    let mut buffer = vec![0u8; addr.len()];
+    let e = addr.serialize(&mut buffer).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+
+    // Truncated addresses cannot be decoded
+    let e = SocksAddr::parse(&[ATYP_DOMAIN, 4, b'a']).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn socks_reply_from_unsuccessful_reply() {
+    use async_socks5::UnsuccessfulReply;
+
+    let cases = [
+        (
+            UnsuccessfulReply::GeneralFailure,
+            SocksReply::GeneralFailure,
+            1,
+            false,
+        ),
+        (
+            UnsuccessfulReply::ConnectionNotAllowedByRules,
+            SocksReply::ConnectionNotAllowed,
+            2,
+            false,
+        ),
+        (
+            UnsuccessfulReply::NetworkUnreachable,
+            SocksReply::NetworkUnreachable,
+            3,
+            true,
+        ),
+        (
+            UnsuccessfulReply::HostUnreachable,
+            SocksReply::HostUnreachable,
+            4,
+            true,
+        ),
+        (
+            UnsuccessfulReply::ConnectionRefused,
+            SocksReply::ConnectionRefused,
+            5,
+            false,
+        ),
+        (
+            UnsuccessfulReply::TtlExpired,
+            SocksReply::TtlExpired,
+            6,
+            true,
+        ),
+        (
+            UnsuccessfulReply::CommandNotSupported,
+            SocksReply::CommandNotSupported,
+            7,
+            false,
+        ),
+        (
+            UnsuccessfulReply::AddressTypeNotSupported,
+            SocksReply::AddressTypeNotSupported,
+            8,
+            false,
+        ),
+        (
+            UnsuccessfulReply::Unassigned(9),
+            SocksReply::Unassigned(9),
+            9,
+            false,
+        ),
+    ];
+    for (unsuccessful, reply, code, is_unreachable) in cases.iter().cloned() {
+        assert_eq!(SocksReply::from(unsuccessful), reply);
+        assert_eq!(reply.code(), code);
+        assert_eq!(reply.is_unreachable(), is_unreachable);
+    }
+}
@@ -0,0 +1,329 @@
+//! Support for dispatching frames to shards of the flow table.
+
+use lru::LruCache;
+use std::io;
+use std::net::Ipv4Addr;
+use std::thread;
+use std::time::Duration;
+
+use crate::capture::FrameQueue;
+use crate::pcap::{self, LinkType, Receiver};
+
+/// Represents the maximum count of fragmented IPv4 packets whose shards are remembered, so their
+/// following fragments are dispatched to the same shards as their first fragments.
+const MAX_FRAGMENTS: usize = 1024;
+
+const PROTOCOL_ICMPV4: u8 = 1;
+const PROTOCOL_TCP: u8 = 6;
+const PROTOCOL_UDP: u8 = 17;
+
+/// Represents the shards a frame is dispatched to.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Target {
+    /// Represents the frame is dispatched to the shard.
+    One(usize),
+    /// Represents the frame is dispatched to all the shards.
+    All,
+}
+
+/// Represents a dispatcher choosing the shard of the flow table each frame belongs to. A TCP
+/// segment is dispatched by the hash of its 5-tuple, and a UDP datagram by the hash of its
+/// source, because local UDP ports are bound per source. Frames other than IPv4, like ARP, are
+/// dispatched to the first shard, and ICMPv4 packets, which affect the path MTUs and the UDP
+/// ports of sources, are dispatched to all the shards. Following fragments of an IPv4 packet are
+/// dispatched to the shard its first fragment is dispatched to.
+#[derive(Debug)]
+pub struct Dispatcher {
+    shards: usize,
+    link_type: LinkType,
+    fragments: LruCache<(Ipv4Addr, Ipv4Addr, u8, u16), usize>,
+}
+
+impl Dispatcher {
+    /// Creates a new `Dispatcher` to the count of shards of frames in the link-layer type.
+    pub fn new(shards: usize, link_type: LinkType) -> io::Result<Dispatcher> {
+        if shards == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "count of shards cannot be 0",
+            ));
+        }
+
+        Ok(Dispatcher {
+            shards,
+            link_type,
+            fragments: LruCache::new(MAX_FRAGMENTS),
+        })
+    }
+
+    /// Returns the shards the frame is dispatched to. The frame is dispatched in the header
+    /// fields read in place, since it is dispatched before parsed.
+    pub fn target(&mut self, frame: &[u8]) -> Target {
+        match self.ipv4(frame) {
+            Some(packet) => self.target_ipv4(packet),
+            None => Target::One(0),
+        }
+    }
+
+    fn ipv4<'a>(&self, frame: &'a [u8]) -> Option<&'a [u8]> {
+        let link_size = match self.link_type {
+            LinkType::Ethernet => {
+                // IPv4 only, frames in VLANs are not dispatched
+                if frame.get(12..14)? != [0x08, 0x00] {
+                    return None;
+                }
+
+                14
+            }
+            LinkType::Null => {
+                let family = frame.get(..4)?;
+                let family = u32::from_ne_bytes([family[0], family[1], family[2], family[3]]);
+                if family != 2 && family.swap_bytes() != 2 {
+                    return None;
+                }
+
+                4
+            }
+            LinkType::LinuxSll => {
+                if frame.get(14..16)? != [0x08, 0x00] {
+                    return None;
+                }
+
+                16
+            }
+        };
+        let packet = &frame[link_size..];
+        if packet.len() < 20 || packet[0] >> 4 != 4 {
+            return None;
+        }
+
+        Some(packet)
+    }
+
+    fn target_ipv4(&mut self, packet: &[u8]) -> Target {
+        let src = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+        let dst = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+        let protocol = packet[9];
+        if protocol == PROTOCOL_ICMPV4 {
+            return Target::All;
+        }
+
+        // Fragmentation
+        let identification = u16::from_be_bytes([packet[4], packet[5]]);
+        let is_more_fragments = packet[6] & 0x20 != 0;
+        let offset = u16::from_be_bytes([packet[6] & 0x1f, packet[7]]);
+        let key = (src, dst, protocol, identification);
+        if offset > 0 {
+            let shard = match self.fragments.get(&key) {
+                Some(&shard) => shard,
+                // The first fragment is out of order
+                None => self.select(&[&src.octets(), &dst.octets(), &[protocol]]),
+            };
+
+            return Target::One(shard);
+        }
+
+        let header_size = ((packet[0] & 0x0f) as usize) * 4;
+        let ports = packet.get(header_size..header_size + 4);
+        let shard = match (protocol, ports) {
+            (PROTOCOL_TCP, Some(ports)) => self.select(&[
+                &src.octets(),
+                &ports[..2],
+                &dst.octets(),
+                &ports[2..],
+                &[protocol],
+            ]),
+            (PROTOCOL_UDP, Some(ports)) => self.select(&[&src.octets(), &ports[..2], &[protocol]]),
+            _ => self.select(&[&src.octets(), &dst.octets(), &[protocol]]),
+        };
+        if is_more_fragments {
+            self.fragments.put(key, shard);
+        }
+
+        Target::One(shard)
+    }
+
+    fn select(&self, fields: &[&[u8]]) -> usize {
+        (hash(fields) % self.shards as u32) as usize
+    }
+
+    /// Returns the count of shards.
+    pub fn shards(&self) -> usize {
+        self.shards
+    }
+}
+
+/// Returns the FNV-1a hash of the fields, which is stable across restarts and builds, so a flow
+/// is dispatched to the same shard after a restart with the same count of shards.
+fn hash(fields: &[&[u8]]) -> u32 {
+    fields
+        .iter()
+        .flat_map(|field| field.iter())
+        .fold(0x811c_9dc5u32, |hash, &b| {
+            (hash ^ b as u32).wrapping_mul(0x0100_0193)
+        })
+}
+
+/// Moves the capture of the receive half to a new thread dispatching frames to the queues of the
+/// shards, and returns the receive halves taking frames from each queue, which time out the same
+/// as a pcap device with the read timeout. Errors of the capture are returned by all the receive
+/// halves after the frames queued, and the capture stops when all the receive halves are
+/// dropped.
+pub fn dispatched(
+    mut rx: Receiver,
+    mut dispatcher: Dispatcher,
+    queues: Vec<FrameQueue>,
+    read_timeout: Option<Duration>,
+) -> io::Result<Vec<Receiver>> {
+    if queues.len() != dispatcher.shards() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "count of queues does not match the count of shards",
+        ));
+    }
+
+    let producers = queues.clone();
+    thread::spawn(move || {
+        while !producers.iter().all(|producer| producer.is_closed()) {
+            match rx.next() {
+                Ok(frame) => match dispatcher.target(frame) {
+                    Target::One(shard) => {
+                        producers[shard].push(frame.to_vec());
+                    }
+                    Target::All => {
+                        for producer in &producers {
+                            producer.push(frame.to_vec());
+                        }
+                    }
+                },
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::TimedOut {
+                        continue;
+                    }
+                    for producer in &producers {
+                        producer.close(Some(io::Error::new(e.kind(), e.to_string())));
+                    }
+                }
+            }
+        }
+    });
+
+    Ok(queues
+        .into_iter()
+        .map(|queue| pcap::receiver(queue, read_timeout))
+        .collect())
+}
+
+#[test]
+fn dispatcher_target() {
+    assert!(Dispatcher::new(0, LinkType::Ethernet).is_err());
+
+    fn frame(
+        src: [u8; 4],
+        dst: [u8; 4],
+        protocol: u8,
+        ports: [u8; 4],
+        fragment: [u8; 2],
+    ) -> Vec<u8> {
+        let mut frame = vec![0u8; 14];
+        frame[12..14].copy_from_slice(&[0x08, 0x00]);
+        frame.extend_from_slice(&[0x45, 0, 0, 24, 0x12, 0x34]);
+        frame.extend_from_slice(&fragment);
+        frame.extend_from_slice(&[64, protocol, 0, 0]);
+        frame.extend_from_slice(&src);
+        frame.extend_from_slice(&dst);
+        frame.extend_from_slice(&ports);
+
+        frame
+    }
+    let src = [10, 6, 0, 2];
+    let mut dispatcher = Dispatcher::new(16, LinkType::Ethernet).unwrap();
+
+    // ARP and ICMPv4
+    let mut arp = vec![0u8; 42];
+    arp[12..14].copy_from_slice(&[0x08, 0x06]);
+    assert_eq!(dispatcher.target(&arp), Target::One(0));
+    let icmpv4 = frame(src, [1, 1, 1, 1], PROTOCOL_ICMPV4, [0; 4], [0, 0]);
+    assert_eq!(dispatcher.target(&icmpv4), Target::All);
+
+    // TCP by the 5-tuple
+    let targets = (0..64u8)
+        .map(|port| {
+            dispatcher.target(&frame(
+                src,
+                [1, 1, 1, 1],
+                PROTOCOL_TCP,
+                [0x9c, port, 0, 80],
+                [0, 0],
+            ))
+        })
+        .collect::<Vec<_>>();
+    assert!(targets.iter().any(|target| *target != targets[0]));
+    let tcp = frame(
+        src,
+        [1, 1, 1, 1],
+        PROTOCOL_TCP,
+        [0x9c, 0x40, 1, 187],
+        [0, 0],
+    );
+    let other_tcp = frame(
+        src,
+        [8, 8, 8, 8],
+        PROTOCOL_TCP,
+        [0x9c, 0x40, 1, 187],
+        [0, 0],
+    );
+    assert_eq!(dispatcher.target(&tcp), dispatcher.target(&tcp));
+    assert_ne!(dispatcher.target(&tcp), dispatcher.target(&other_tcp));
+
+    // UDP by the source
+    let udp = frame(src, [1, 1, 1, 1], PROTOCOL_UDP, [0x9c, 0x40, 0, 53], [0, 0]);
+    let other_udp = frame(
+        src,
+        [8, 8, 8, 8],
+        PROTOCOL_UDP,
+        [0x9c, 0x40, 1, 187],
+        [0, 0],
+    );
+    assert_eq!(dispatcher.target(&udp), dispatcher.target(&other_udp));
+
+    // Following fragments to the shard of the first fragment
+    let first = frame(
+        src,
+        [8, 8, 8, 8],
+        PROTOCOL_UDP,
+        [0x9c, 0x40, 1, 187],
+        [0x20, 0],
+    );
+    let last = frame(src, [8, 8, 8, 8], PROTOCOL_UDP, [0; 4], [0, 1]);
+    assert_eq!(dispatcher.target(&first), dispatcher.target(&udp));
+    assert_eq!(dispatcher.target(&last), dispatcher.target(&udp));
+}
+
+#[test]
+fn dispatcher_target_sll() {
+    let mut ethernet = Dispatcher::new(16, LinkType::Ethernet).unwrap();
+    let mut sll = Dispatcher::new(16, LinkType::LinuxSll).unwrap();
+
+    // The same packets in the Linux cooked link type are dispatched the same as in Ethernet
+    let packet = |port: u8| {
+        let mut packet = vec![0x45, 0, 0, 24, 0x12, 0x34, 0, 0, 64, PROTOCOL_TCP, 0, 0];
+        packet.extend_from_slice(&[10, 6, 0, 2, 1, 1, 1, 1, 0x9c, port, 0, 80]);
+
+        packet
+    };
+    for port in 0..64u8 {
+        let mut ethernet_frame = vec![0u8; 14];
+        ethernet_frame[12..14].copy_from_slice(&[0x08, 0x00]);
+        ethernet_frame.extend_from_slice(&packet(port));
+        let mut sll_frame = vec![0u8; 16];
+        sll_frame[14..16].copy_from_slice(&[0x08, 0x00]);
+        sll_frame.extend_from_slice(&packet(port));
+        assert_eq!(sll.target(&sll_frame), ethernet.target(&ethernet_frame));
+    }
+
+    // ARP
+    let mut arp = vec![0u8; 44];
+    arp[14..16].copy_from_slice(&[0x08, 0x06]);
+    assert_eq!(sll.target(&arp), Target::One(0));
+}
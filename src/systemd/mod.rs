@@ -0,0 +1,172 @@
+//! Support for integrating with systemd as a notify service.
+
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::process;
+use std::time::{Duration, Instant};
+
+/// Sends a state to the service manager, like `READY=1`. Returns if the state is sent, a state
+/// is not sent if the service is not started by a service manager supporting notifications.
+pub fn notify(state: &str) -> io::Result<bool> {
+    match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => notify_to(&path, state).map(|_| true),
+        None => Ok(false),
+    }
+}
+
+/// Sends a state to the notify socket at the path.
+fn notify_to(path: &OsStr, state: &str) -> io::Result<()> {
+    let addr = parse_notify_socket(path)?;
+    let socket = UnixDatagram::unbound()?;
+    socket.send_to_addr(state.as_bytes(), &addr)?;
+
+    Ok(())
+}
+
+/// Parses the path of the notify socket in `NOTIFY_SOCKET`. A path beginning with `@` is a socket
+/// in the abstract namespace, and other paths are sockets in the file system.
+fn parse_notify_socket(path: &OsStr) -> io::Result<SocketAddr> {
+    let bytes = path.as_bytes();
+    match bytes.first() {
+        Some(b'@') => SocketAddr::from_abstract_name(&bytes[1..]),
+        Some(_) => SocketAddr::from_pathname(path),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "notify socket cannot be empty",
+        )),
+    }
+}
+
+/// Returns the interval the service manager expects keep-alive notifications in, or `None` if
+/// the watchdog is not enabled for this process.
+pub fn watchdog_interval() -> Option<Duration> {
+    let usec = env::var("WATCHDOG_USEC").ok();
+    let pid = env::var("WATCHDOG_PID").ok();
+
+    parse_watchdog_interval(usec.as_deref(), pid.as_deref(), process::id())
+}
+
+/// Parses the interval in `WATCHDOG_USEC` for the process of the ID, or returns `None` if the
+/// watchdog is not enabled, or is enabled for another process in `WATCHDOG_PID`.
+fn parse_watchdog_interval(usec: Option<&str>, pid: Option<&str>, id: u32) -> Option<Duration> {
+    if let Some(pid) = pid {
+        if pid.parse::<u32>().ok()? != id {
+            return None;
+        }
+    }
+
+    match usec?.parse::<u64>().ok()? {
+        0 => None,
+        usec => Some(Duration::from_micros(usec)),
+    }
+}
+
+/// Represents the watchdog of the service manager, which is notified periodically while the
+/// service is alive.
+#[derive(Debug)]
+pub struct Watchdog {
+    path: OsString,
+    interval: Duration,
+    notified: Option<Instant>,
+}
+
+impl Watchdog {
+    /// Creates a `Watchdog` if the watchdog is enabled for this process. Notifications are sent
+    /// at half of the interval the service manager expects, as systemd recommends.
+    pub fn new() -> Option<Watchdog> {
+        let path = env::var_os("NOTIFY_SOCKET")?;
+        let interval = watchdog_interval()?;
+
+        Some(Watchdog::with_interval(path, interval))
+    }
+
+    fn with_interval(path: OsString, interval: Duration) -> Watchdog {
+        Watchdog {
+            path,
+            interval: interval / 2,
+            notified: None,
+        }
+    }
+
+    /// Notifies the watchdog if the half interval elapses since the last notification.
+    pub fn notify(&mut self) -> io::Result<()> {
+        if let Some(notified) = self.notified {
+            if notified.elapsed() < self.interval {
+                return Ok(());
+            }
+        }
+        self.notified = Some(Instant::now());
+        notify_to(&self.path, "WATCHDOG=1")?;
+
+        Ok(())
+    }
+
+    /// Returns the interval of notifications.
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+}
+
+#[test]
+fn systemd_parse_watchdog_interval() {
+    assert_eq!(
+        parse_watchdog_interval(Some("2000000"), None, 100),
+        Some(Duration::from_secs(2))
+    );
+    assert_eq!(
+        parse_watchdog_interval(Some("2000000"), Some("100"), 100),
+        Some(Duration::from_secs(2))
+    );
+    // The watchdog of another process
+    assert_eq!(
+        parse_watchdog_interval(Some("2000000"), Some("1"), 100),
+        None
+    );
+    // Disabled or malformed
+    assert_eq!(parse_watchdog_interval(None, Some("100"), 100), None);
+    assert_eq!(parse_watchdog_interval(Some("0"), None, 100), None);
+    assert_eq!(parse_watchdog_interval(Some("2s"), None, 100), None);
+    assert_eq!(
+        parse_watchdog_interval(Some("2000000"), Some("pid"), 100),
+        None
+    );
+}
+
+#[test]
+fn systemd_notify() {
+    use std::fs;
+
+    let path = env::temp_dir().join(format!("pcap2socks-notify-{}", process::id()));
+    let _ = fs::remove_file(&path);
+    let server = UnixDatagram::bind(&path).unwrap();
+
+    notify_to(path.as_os_str(), "READY=1").unwrap();
+    let mut buffer = [0u8; 64];
+    let size = server.recv(&mut buffer).unwrap();
+    assert_eq!(&buffer[..size], b"READY=1");
+
+    let mut watchdog = Watchdog::with_interval(path.clone().into(), Duration::from_secs(2));
+    assert_eq!(watchdog.interval(), Duration::from_secs(1));
+    watchdog.notify().unwrap();
+    let size = server.recv(&mut buffer).unwrap();
+    assert_eq!(&buffer[..size], b"WATCHDOG=1");
+    // Not notified again before the interval elapses
+    server.set_nonblocking(true).unwrap();
+    watchdog.notify().unwrap();
+    assert!(server.recv(&mut buffer).is_err());
+    let _ = fs::remove_file(&path);
+
+    // Abstract namespace
+    let name = format!("pcap2socks-notify-{}", process::id());
+    let addr = SocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+    let server = UnixDatagram::bind_addr(&addr).unwrap();
+    notify_to(OsStr::new(&format!("@{}", name)), "STOPPING=1").unwrap();
+    let size = server.recv(&mut buffer).unwrap();
+    assert_eq!(&buffer[..size], b"STOPPING=1");
+
+    assert!(notify_to(OsStr::new(""), "READY=1").is_err());
+}
@@ -3,18 +3,67 @@
 use std::cmp::{max, min};
 use std::collections::{BTreeMap, HashSet, VecDeque};
 use std::fmt::{self, Display};
-use std::io::{Error, ErrorKind, Result};
+use std::io::{Error, ErrorKind, Read, Result, Write};
 use std::ops::Bound::Included;
 use std::time::Duration;
 
+use super::seq::{seq_add, seq_sub};
 use super::Timer;
 
 /// Represents the max distance of u32 values between packets in an u32 window.
 const MAX_U32_WINDOW_SIZE: usize = 16 * 1024 * 1024;
 
+/// Represents the size of the space of sequences, by which the keys of ranges wrapped around the
+/// end of the space are offset in a window.
+const SEQUENCE_SPACE: u64 = 1 << 32;
+
 /// Represents if the buffer should be allocated in the initial constructor of caches.
 const ALLOC_IN_INITIAL: bool = false;
 
+/// Represents the size below which the buffer of a queue is not shrunk, so a queue drained on
+/// every acknowledgement does not release and reallocate its buffer repeatedly.
+pub const INITIAL_CACHE_SIZE: usize = 4096;
+
+/// Represents the default soft limit of windows in percentage of the capacity.
+pub const SOFT_LIMIT_PERCENT: usize = 80;
+
+/// Represents the pressure of a cache.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Pressure {
+    /// Represents the cache is below the soft limit.
+    Low,
+    /// Represents the cache reaches the soft limit but is not full.
+    High,
+    /// Represents the cache is full.
+    Full,
+}
+
+/// Represents the policy of handling bytes beyond the advertised window.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum OverWindowPolicy {
+    /// Represents bytes beyond the advertised window are dropped and counted.
+    Strict,
+    /// Represents bytes beyond the advertised window are buffered up to the capacity.
+    Lenient,
+}
+
+fn check_initial(initial: usize, capacity: usize) -> Result<()> {
+    if initial == 0 || capacity == 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "cache size cannot be 0",
+        ));
+    }
+    if initial > capacity {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "initial cache size is over the capacity",
+        ));
+    }
+
+    Ok(())
+}
+
 /// Represents a queue cache. The `Queue` can hold continuos bytes constantly unless they are
 /// invalidated. The `Queue` can be used as a send window of a TCP connection.
 #[derive(Debug)]
@@ -26,6 +75,8 @@ pub struct Queue {
     size: usize,
     clocks: VecDeque<(u32, Timer)>,
     retrans: Option<u32>,
+    /// Represents the size below which the buffer is not shrunk.
+    initial: usize,
 }
 
 impl Queue {
@@ -37,32 +88,81 @@ impl Queue {
 
     /// Creates a new `Queue` with the specified capacity.
     pub fn with_capacity(capacity: usize, sequence: u32) -> Queue {
-        Queue {
-            buffer: match ALLOC_IN_INITIAL {
-                true => Vec::with_capacity(capacity),
-                false => Vec::new(),
-            },
+        Queue::with_capacity_alloc(capacity, sequence, ALLOC_IN_INITIAL)
+    }
+
+    /// Creates a new `Queue` with the specified capacity. If `alloc` is set, the buffer is
+    /// allocated in the initial size in the constructor, otherwise it is allocated lazily in
+    /// appending.
+    pub fn with_capacity_alloc(capacity: usize, sequence: u32, alloc: bool) -> Queue {
+        let mut queue = Queue {
+            buffer: Vec::new(),
             capacity,
             sequence,
             head: 0,
             size: 0,
-            clocks: match ALLOC_IN_INITIAL {
-                true => VecDeque::with_capacity(capacity),
-                false => VecDeque::new(),
-            },
+            clocks: VecDeque::new(),
             retrans: None,
+            initial: min(INITIAL_CACHE_SIZE, capacity),
+        };
+        if alloc {
+            queue.reserve(queue.initial);
+        }
+
+        queue
+    }
+
+    /// Creates a new `Queue` with the specified capacity whose buffer is allocated in the initial
+    /// size in the constructor. The buffer grows up to the capacity in appending, and is never
+    /// shrunk below the initial size. Fails if either size is 0 or the initial size is over the
+    /// capacity.
+    pub fn with_initial(initial: usize, capacity: usize, sequence: u32) -> Result<Queue> {
+        check_initial(initial, capacity)?;
+
+        let mut queue = Queue::with_capacity_alloc(capacity, sequence, false);
+        queue.initial = initial;
+        queue.reserve(initial);
+
+        Ok(queue)
+    }
+
+    /// Creates a new `Queue` with the specified capacity holding the bytes from the sequence. The
+    /// buffer is allocated in the size of the bytes, and the bytes are timed out at once, so they
+    /// are the first to be retransmitted.
+    pub fn from_bytes(capacity: usize, sequence: u32, data: &[u8]) -> Result<Queue> {
+        if data.len() > capacity {
+            return Err(Error::new(ErrorKind::Other, "queue is full"));
+        }
+
+        let mut clocks = VecDeque::new();
+        if !data.is_empty() {
+            clocks.push_back((sequence, Timer::new(0)));
         }
+        let queue = Queue {
+            buffer: data.to_vec(),
+            capacity,
+            sequence,
+            head: 0,
+            size: data.len(),
+            clocks,
+            retrans: None,
+            initial: min(INITIAL_CACHE_SIZE, capacity),
+        };
+
+        #[cfg(debug_assertions)]
+        queue.check_invariants();
+
+        Ok(queue)
     }
 
-    /// Appends some bytes to the end of the queue.
+    /// Appends some bytes to the end of the queue. Returns an error of `ErrorKind::WouldBlock` if
+    /// the queue is full, so the caller can wait for the bytes to be invalidated.
     pub fn append(&mut self, payload: &[u8], rto: u64) -> Result<()> {
         if payload.len() > self.remaining() {
-            return Err(Error::new(ErrorKind::Other, "queue is full"));
+            return Err(Error::new(ErrorKind::WouldBlock, "queue is full"));
         }
         if payload.len() > self.buffer.len() - self.size {
             // Extend the buffer
-            let prev_len = self.buffer.len();
-            let prev_tail = self.tail();
             let new_len = min(
                 self.capacity,
                 max(
@@ -73,27 +173,11 @@ impl Queue {
                     self.size + payload.len(),
                 ),
             );
-            self.buffer.resize(new_len, 0);
-
-            // From the begin of the buffer to the tail
-            if prev_tail <= self.head {
-                // From the begin to the mid of the buffer
-                let len_a = min(prev_tail, new_len - prev_len);
-                self.buffer.copy_within(..len_a, prev_len);
-
-                // From the mid to the tail of the buffer
-                let len_b = prev_tail - len_a;
-                if len_b > 0 {
-                    self.buffer.copy_within(len_a..len_a + len_b, 0);
-                }
-            }
+            self.extend(new_len);
         }
 
         // Sequence and clock
-        let sequence = self
-            .sequence
-            .checked_add(self.size as u32)
-            .unwrap_or_else(|| self.size as u32 - (u32::MAX - self.sequence));
+        let sequence = seq_add(self.sequence, self.size as u32);
         self.clocks.push_back((sequence, Timer::new(rto)));
 
         // From the tail to the end of the buffer
@@ -109,14 +193,120 @@ impl Queue {
 
         self.size += payload.len();
 
+        #[cfg(debug_assertions)]
+        self.check_invariants();
+
+        Ok(())
+    }
+
+    /// Reserves the buffer for at least the additional bytes to be appended in one step, so
+    /// appending them later does not reallocate the buffer. The buffer is limited by the
+    /// capacity.
+    pub fn reserve(&mut self, additional: usize) {
+        let new_len = min(
+            self.capacity,
+            self.size.checked_add(additional).unwrap_or(usize::MAX),
+        );
+        if new_len > self.buffer.len() {
+            self.extend(new_len);
+
+            #[cfg(debug_assertions)]
+            self.check_invariants();
+        }
+    }
+
+    /// Sets the capacity of the queue, so a new limit takes effect on the queue in use. Raising
+    /// the capacity only allows the buffer to grow further. Lowering the capacity below the
+    /// allocated buffer moves the bytes to the beginning of the buffer and shrinks the buffer,
+    /// and fails if the bytes in the queue do not fit in the capacity.
+    pub fn set_capacity(&mut self, capacity: usize) -> Result<()> {
+        if capacity < self.size {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "queue is over the capacity",
+            ));
+        }
+
+        if capacity < self.buffer.len() {
+            self.buffer.rotate_left(self.head);
+            self.buffer.truncate(capacity);
+            self.buffer.shrink_to_fit();
+            self.head = 0;
+        }
+        self.capacity = capacity;
+
+        #[cfg(debug_assertions)]
+        self.check_invariants();
+
         Ok(())
     }
 
+    /// Shrinks the buffer to the next power of two of the size of the queue once the size is
+    /// below a quarter of the buffer, so the memory taken by a burst is reclaimed after the bytes
+    /// are invalidated. The bytes are moved to the beginning of the new buffer. The buffer is not
+    /// shrunk below `INITIAL_CACHE_SIZE`, or the initial size of a queue created by
+    /// `with_initial`, limited by the capacity.
+    pub fn shrink_to_fit(&mut self) {
+        if self.size >= self.buffer.len() / 4 {
+            return;
+        }
+        let new_len = min(self.size.next_power_of_two(), self.capacity);
+        let new_len = max(new_len, min(self.initial, self.capacity));
+        if new_len >= self.buffer.len() {
+            return;
+        }
+
+        let mut buffer = vec![0u8; new_len];
+
+        // From the head to the end of the buffer
+        let len_a = min(self.size, self.buffer.len() - self.head);
+        buffer[..len_a].copy_from_slice(&self.buffer[self.head..self.head + len_a]);
+
+        // From the begin of the buffer to the tail
+        let len_b = self.size - len_a;
+        if len_b > 0 {
+            buffer[len_a..self.size].copy_from_slice(&self.buffer[..len_b]);
+        }
+
+        self.buffer = buffer;
+        self.head = 0;
+
+        #[cfg(debug_assertions)]
+        self.check_invariants();
+    }
+
+    fn extend(&mut self, new_len: usize) {
+        let prev_len = self.buffer.len();
+        let prev_tail = self.tail();
+        self.buffer.resize(new_len, 0);
+
+        // From the begin of the buffer to the tail
+        if prev_tail <= self.head {
+            // From the begin to the mid of the buffer
+            let len_a = min(prev_tail, new_len - prev_len);
+            self.buffer.copy_within(..len_a, prev_len);
+
+            // From the mid to the tail of the buffer
+            let len_b = prev_tail - len_a;
+            if len_b > 0 {
+                self.buffer.copy_within(len_a..len_a + len_b, 0);
+            }
+        }
+    }
+
+    /// Appends some bytes to the end of the queue at the certain sequence. The sequence must be
+    /// the same as the receive next of the queue.
+    pub fn append_at(&mut self, sequence: u32, payload: &[u8], rto: u64) -> Result<()> {
+        if sequence != self.recv_next() {
+            return Err(Error::new(ErrorKind::InvalidInput, "sequence gap"));
+        }
+
+        self.append(payload, rto)
+    }
+
     /// Invalidates queue to the certain sequence and returns the RTT.
     pub fn invalidate_to(&mut self, sequence: u32) -> Option<Duration> {
-        let size = sequence
-            .checked_sub(self.sequence)
-            .unwrap_or_else(|| u32::MAX - self.sequence + sequence) as usize;
+        let size = seq_sub(sequence, self.sequence) as usize;
 
         if size <= MAX_U32_WINDOW_SIZE as usize {
             self.sequence = sequence;
@@ -131,18 +321,12 @@ impl Queue {
 
             // Pop clocks
             while !self.clocks.is_empty() {
-                let dist = sequence
-                    .checked_sub(self.clocks[0].0)
-                    .unwrap_or_else(|| sequence + (u32::MAX - self.clocks[0].0))
-                    as usize;
+                let dist = seq_sub(sequence, self.clocks[0].0) as usize;
                 let recv_next = match self.clocks.len() {
                     1 => self.recv_next(),
                     _ => self.clocks[1].0,
                 };
-                let dist_next = sequence
-                    .checked_sub(recv_next)
-                    .unwrap_or_else(|| sequence + (u32::MAX - recv_next))
-                    as usize;
+                let dist_next = seq_sub(sequence, recv_next) as usize;
 
                 if dist <= MAX_U32_WINDOW_SIZE as usize && dist_next <= MAX_U32_WINDOW_SIZE as usize
                 {
@@ -155,12 +339,7 @@ impl Queue {
 
                             // Rollback on retransmission
                             if let Some(retrans) = self.retrans {
-                                if retrans
-                                    .checked_sub(sequence)
-                                    .unwrap_or_else(|| retrans + (u32::MAX - sequence))
-                                    as usize
-                                    <= MAX_U32_WINDOW_SIZE
-                                {
+                                if seq_sub(retrans, sequence) as usize <= MAX_U32_WINDOW_SIZE {
                                     // Karn's algorithm
                                     rtt = None;
                                 }
@@ -179,32 +358,29 @@ impl Queue {
 
             // Retransmission
             if let Some(retrans) = self.retrans {
-                if self
-                    .sequence
-                    .checked_sub(retrans)
-                    .unwrap_or_else(|| self.sequence + (u32::MAX - retrans))
-                    as usize
-                    <= MAX_U32_WINDOW_SIZE
-                {
+                if seq_sub(self.sequence, retrans) as usize <= MAX_U32_WINDOW_SIZE {
                     self.retrans = None;
                 }
             }
 
+            // Reclaim the buffer after a burst
+            self.shrink_to_fit();
+
+            #[cfg(debug_assertions)]
+            self.check_invariants();
+
             return rtt;
         }
 
         None
     }
 
-    /// Returns the payload from the certain sequence of the queue in the given size.
-    pub fn get(&self, sequence: u32, size: usize) -> Result<Vec<u8>> {
-        if size == 0 {
-            return Ok(Vec::new());
-        }
-        let distance = sequence
-            .checked_sub(self.sequence)
-            .unwrap_or_else(|| sequence + (u32::MAX - self.sequence))
-            as usize;
+    /// Returns the payload from the certain sequence of the queue in the given size as two slices
+    /// of the buffer without copying, which are the bytes from the certain sequence to the end of
+    /// the buffer and the bytes wrapped to the begin of the buffer. The second slice is empty if the
+    /// payload does not wrap.
+    pub fn slices(&self, sequence: u32, size: usize) -> Result<(&[u8], &[u8])> {
+        let distance = seq_sub(sequence, self.sequence) as usize;
         if distance > self.size {
             return Err(Error::new(
                 ErrorKind::InvalidInput,
@@ -214,24 +390,45 @@ impl Queue {
         if self.size - distance < size {
             return Err(Error::new(ErrorKind::InvalidInput, "request size too big"));
         }
-
-        let mut payload = vec![0u8; size];
+        if size == 0 {
+            return Ok((&[], &[]));
+        }
 
         // From the head to the end of the buffer
         let head = self.head + distance;
         let head = head.checked_sub(self.buffer.len()).unwrap_or(head);
         let length_a = min(size, self.buffer.len() - head);
-        payload[..length_a].copy_from_slice(&self.buffer[head..head + length_a]);
 
         // From the begin of the buffer to the tail
         let length_b = size - length_a;
-        if length_b > 0 {
-            payload[length_a..].copy_from_slice(&self.buffer[..length_b]);
-        }
+
+        Ok((
+            &self.buffer[head..head + length_a],
+            &self.buffer[..length_b],
+        ))
+    }
+
+    /// Returns the payload from the certain sequence of the queue in the given size, which can be
+    /// in the middle of the queue like a range to be retransmitted. Fails if the sequence is
+    /// before the sequence of the queue or the range is beyond the end of the queue.
+    pub fn get(&self, sequence: u32, size: usize) -> Result<Vec<u8>> {
+        let (a, b) = self.slices(sequence, size)?;
+
+        let mut payload = Vec::with_capacity(size);
+        payload.extend_from_slice(a);
+        payload.extend_from_slice(b);
 
         Ok(payload)
     }
 
+    /// Returns if the queue contains the byte at the certain sequence, which means the sequence is
+    /// in [sequence, sequence + len) of the queue.
+    pub fn contains(&self, sequence: u32) -> bool {
+        let distance = seq_sub(sequence, self.sequence) as usize;
+
+        distance < self.size
+    }
+
     /// Returns all the payload of the queue.
     pub fn get_all(&self) -> Vec<u8> {
         self.get(self.sequence, self.size).unwrap()
@@ -251,10 +448,7 @@ impl Queue {
 
         match recv_next {
             Some(recv_next) => {
-                let size = recv_next
-                    .checked_sub(self.sequence)
-                    .unwrap_or_else(|| recv_next + (u32::MAX - self.sequence))
-                    as usize;
+                let size = seq_sub(recv_next, self.sequence) as usize;
 
                 self.get(self.sequence, size).unwrap()
             }
@@ -276,20 +470,12 @@ impl Queue {
 
         match recv_next {
             Some(recv_next) => {
-                let size = recv_next
-                    .checked_sub(self.sequence)
-                    .unwrap_or_else(|| recv_next + (u32::MAX - self.sequence))
-                    as usize;
+                let size = seq_sub(recv_next, self.sequence) as usize;
 
                 // Update clock
                 while !self.clocks.is_empty() {
                     let next_sequence = self.clocks.front().unwrap().0;
-                    if recv_next
-                        .checked_sub(next_sequence)
-                        .unwrap_or_else(|| recv_next + (u32::MAX - next_sequence))
-                        as usize
-                        <= MAX_U32_WINDOW_SIZE
-                    {
+                    if seq_sub(recv_next, next_sequence) as usize <= MAX_U32_WINDOW_SIZE {
                         self.clocks.pop_front();
                     } else {
                         self.clocks.push_front((self.sequence, Timer::new(rto)));
@@ -312,11 +498,58 @@ impl Queue {
         }
     }
 
+    /// Returns the payload which is timed out from the begin to the first byte which is not timed out
+    /// in the given window and update their timeout timer. The window may be shrunk below the
+    /// payload in flight, and the payload out of the window is held in the queue until the window
+    /// is reopened. Only the timeout timer of the payload returned is updated, and the payload
+    /// after keeps its timer.
+    pub fn get_timed_out_in_window_and_update(&mut self, rto: u64, window: usize) -> Vec<u8> {
+        let mut size = self.size;
+        for clock in &self.clocks {
+            let timer = clock.1;
+            if !timer.is_timedout() {
+                size = seq_sub(clock.0, self.sequence) as usize;
+                break;
+            }
+        }
+        let size = min(size, window);
+        if size == 0 {
+            return Vec::new();
+        }
+        let recv_next = seq_add(self.sequence, size as u32);
+
+        // Update clock
+        let mut last = None;
+        while let Some(&(sequence, timer)) = self.clocks.front() {
+            let distance = seq_sub(recv_next, sequence) as usize;
+            if distance == 0 || distance > MAX_U32_WINDOW_SIZE {
+                break;
+            }
+            self.clocks.pop_front();
+            last = Some(timer);
+        }
+        // The payload after in the same clock keeps its timer
+        if let Some(timer) = last {
+            if size < self.size && self.clocks.front().map(|clock| clock.0) != Some(recv_next) {
+                self.clocks.push_front((recv_next, timer));
+            }
+        }
+        self.clocks.push_front((self.sequence, Timer::new(rto)));
+        self.retrans = Some(recv_next);
+
+        self.get(self.sequence, size).unwrap()
+    }
+
     /// Returns the capacity of the queue.
     pub fn capacity(&self) -> usize {
         self.capacity
     }
 
+    /// Returns the allocated size of the buffer of the queue.
+    pub fn allocated(&self) -> usize {
+        self.buffer.len()
+    }
+
     /// Returns the sequence of the queue.
     pub fn sequence(&self) -> u32 {
         self.sequence
@@ -342,15 +575,24 @@ impl Queue {
 
     /// Returns the receive next of the queue.
     pub fn recv_next(&self) -> u32 {
-        self.sequence
-            .checked_add(self.size as u32)
-            .unwrap_or_else(|| self.size as u32 - (u32::MAX - self.sequence))
+        seq_add(self.sequence, self.size as u32)
     }
 
     /// Returns if the queue is empty.
     pub fn is_empty(&self) -> bool {
         self.size == 0
     }
+
+    /// Checks the invariants of the queue and panics if any of them is broken. The check is
+    /// performed after each mutation in debug builds.
+    pub fn check_invariants(&self) {
+        assert!(self.buffer.len() <= self.capacity, "buffer over capacity");
+        assert!(self.size <= self.buffer.len(), "size over buffer");
+        assert!(
+            self.head < self.buffer.len() || (self.buffer.is_empty() && self.head == 0),
+            "head out of buffer"
+        );
+    }
 }
 
 impl Display for Queue {
@@ -386,6 +628,25 @@ fn queue_new() {
     assert_eq!(q.to_string(), "[]");
 }
 
+#[test]
+fn queue_from_bytes() {
+    let v = (0..8).into_iter().collect::<Vec<_>>();
+
+    let mut q = Queue::from_bytes(8, 100, &v[..6]).unwrap();
+    assert_eq!(q.to_string(), "[<0, 1, 2, 3, 4, 5>]");
+    assert_eq!(q.sequence(), 100);
+    assert_eq!(q.recv_next(), 106);
+    assert_eq!(q.get(102, 2).unwrap(), vec![2, 3]);
+    assert_eq!(q.get_timed_out(), v[..6].to_vec());
+
+    q.append(&v[6..], 0).unwrap();
+    assert_eq!(q.get_all(), v);
+    assert!(q.append(&[8], 0).is_err());
+
+    assert!(Queue::from_bytes(8, 0, &[]).unwrap().is_empty());
+    assert!(Queue::from_bytes(4, 0, &v).is_err());
+}
+
 #[test]
 fn queue_append_overflow() {
     let mut q = Queue::with_capacity(9, 0);
@@ -404,6 +665,141 @@ fn queue_append_overflow() {
     q.append(v.as_slice(), 0).unwrap();
 
     assert_eq!(q.to_string(), "[9, 10, 11, 12, 13, 14>, <6, 7, 8]");
+    q.check_invariants();
+}
+
+#[test]
+fn queue_append_full() {
+    let mut q = Queue::with_capacity(1, 0);
+    q.append(&[0], 0).unwrap();
+    assert_eq!(q.append(&[1], 0).unwrap_err().kind(), ErrorKind::WouldBlock);
+    q.invalidate_to(1);
+    q.append(&[1], 0).unwrap();
+}
+
+#[test]
+fn queue_append_at_capacity() {
+    let mut q = Queue::with_capacity(1000, 0);
+    let v = (0..2000).into_iter().map(|i| i as u8).collect::<Vec<_>>();
+
+    // Fill to exactly the capacity across the growth of the buffer
+    q.append(&v[..300], 0).unwrap();
+    q.append(&v[300..700], 0).unwrap();
+    q.append(&v[700..1000], 0).unwrap();
+    assert_eq!(q.len(), 1000);
+    assert_eq!(q.remaining(), 0);
+
+    // Appending no bytes succeeds, while appending 1 or many bytes fails
+    q.append(&[], 0).unwrap();
+    assert!(q.append(&v[1000..1001], 0).is_err());
+    assert!(q.append(&v[1000..1500], 0).is_err());
+    assert_eq!(q.len(), 1000);
+    assert_eq!(q.get_all(), &v[..1000]);
+
+    // Fill to exactly the capacity again across the end of the buffer
+    q.invalidate_to(600);
+    q.append(&v[1000..1599], 0).unwrap();
+    q.append(&v[1599..1600], 0).unwrap();
+    assert_eq!(q.remaining(), 0);
+    assert!(q.append(&v[1600..1601], 0).is_err());
+    assert_eq!(q.get_all(), &v[600..1600]);
+    assert_eq!(q.get(1599, 1).unwrap(), &v[1599..1600]);
+    q.check_invariants();
+}
+
+#[test]
+fn queue_reserve() {
+    let mut q = Queue::with_capacity(64, 0);
+    let v = (0..64).into_iter().collect::<Vec<_>>();
+
+    // Wrapped around the end of the buffer
+    q.append(&v[..8], 0).unwrap();
+    q.invalidate_to(6);
+    q.append(&v[8..12], 0).unwrap();
+    assert_eq!(q.to_string(), "[8, 9, 10, 11>, 4, 5, <6, 7]");
+
+    q.reserve(32);
+    assert_eq!(q.buffer.len(), 38);
+    assert_eq!(q.get_all(), &v[6..12]);
+    q.append(&v[12..44], 0).unwrap();
+    assert_eq!(q.buffer.len(), 38);
+    assert_eq!(q.get_all(), &v[6..44]);
+
+    // Limited by the capacity
+    q.reserve(usize::MAX);
+    assert_eq!(q.buffer.len(), 64);
+    assert_eq!(q.get_all(), &v[6..44]);
+    q.check_invariants();
+}
+
+#[test]
+fn queue_with_initial() {
+    assert!(Queue::with_initial(0, 64, 0).is_err());
+    assert!(Queue::with_initial(16, 0, 0).is_err());
+    assert!(Queue::with_initial(128, 64, 0).is_err());
+
+    let mut q = Queue::with_initial(16, 64, 0).unwrap();
+    let v = (0..64).into_iter().collect::<Vec<_>>();
+    assert_eq!(q.buffer.len(), 16);
+
+    // Grown up to the capacity
+    q.append(&v[..48], 0).unwrap();
+    q.append(&v[48..], 0).unwrap();
+    assert_eq!(q.buffer.len(), 64);
+    assert!(q.append(&v[..1], 0).is_err());
+
+    // Not shrunk below the initial size
+    q.invalidate_to(64);
+    assert_eq!(q.buffer.len(), 16);
+    q.check_invariants();
+}
+
+#[test]
+fn queue_with_capacity_alloc() {
+    let q = Queue::with_capacity_alloc(65536, 0, true);
+    assert_eq!(q.allocated(), INITIAL_CACHE_SIZE);
+
+    let q = Queue::with_capacity_alloc(65536, 0, false);
+    assert_eq!(q.allocated(), 0);
+}
+
+#[test]
+fn queue_set_capacity() {
+    let mut q = Queue::with_capacity(8, 0);
+    let v = (0..16).into_iter().collect::<Vec<_>>();
+
+    // Raise
+    q.append(&v[..8], 0).unwrap();
+    assert!(q.append(&v[8..9], 0).is_err());
+    q.set_capacity(12).unwrap();
+    assert_eq!(q.capacity(), 12);
+    q.append(&v[8..12], 0).unwrap();
+    assert_eq!(q.remaining(), 0);
+    assert_eq!(q.get_all(), &v[..12]);
+
+    // Lower below the buffer, the bytes wrapped around the end of the buffer are moved
+    q.invalidate_to(8);
+    q.append(&v[12..14], 0).unwrap();
+    assert_eq!(q.to_string(), "[12, 13>, 2, 3, 4, 5, 6, 7, <8, 9, 10, 11]");
+    q.set_capacity(6).unwrap();
+    assert_eq!(q.to_string(), "[<8, 9, 10, 11, 12, 13>]");
+    assert_eq!(q.sequence(), 8);
+    assert_eq!(q.get_all(), &v[8..14]);
+    assert!(q.append(&v[14..15], 0).is_err());
+
+    // Lower below the bytes in the queue
+    let e = q.set_capacity(5).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    assert_eq!(q.capacity(), 6);
+    assert_eq!(q.get_all(), &v[8..14]);
+
+    // Lower and grow again
+    q.invalidate_to(12);
+    q.set_capacity(2).unwrap();
+    q.set_capacity(4).unwrap();
+    q.append(&v[14..16], 0).unwrap();
+    assert_eq!(q.get_all(), &v[12..16]);
+    q.check_invariants();
 }
 
 #[test]
@@ -444,6 +840,279 @@ fn queue_append_overflow_overlapped_2() {
     assert_eq!(q.to_string(), "[9, 10, 11, 12, 13, 14>, <6, 7, 8]");
 }
 
+#[test]
+fn queue_window_shrink() {
+    let payload = (0..255).collect::<Vec<u8>>();
+
+    let mut queue = Queue::with_capacity(65536, 0);
+    queue.append(&payload[..100], 0).unwrap();
+    queue.append(&payload[100..200], 0).unwrap();
+    queue.append(&payload[200..], 0).unwrap();
+    std::thread::sleep(Duration::from_millis(1));
+
+    // Acknowledge the first segment and shrink the window below the payload in flight
+    queue.invalidate_to(100);
+    let retrans = queue.get_timed_out_in_window_and_update(0, 50);
+    assert_eq!(retrans, payload[100..150].to_vec());
+    assert_eq!(queue.sequence(), 100);
+    assert_eq!(queue.len(), 155);
+
+    // A stale acknowledgement should not move the queue backward
+    assert_eq!(queue.invalidate_to(50), None);
+    assert_eq!(queue.sequence(), 100);
+    assert_eq!(queue.get_all(), payload[100..].to_vec());
+    queue.check_invariants();
+
+    // Reopen the window
+    std::thread::sleep(Duration::from_millis(1));
+    let retrans = queue.get_timed_out_in_window_and_update(0, 65535);
+    assert_eq!(retrans, payload[100..].to_vec());
+    queue.invalidate_to(255);
+    assert!(queue.is_empty());
+}
+
+#[test]
+fn queue_window_shrink_clock() {
+    let payload = (0..255).collect::<Vec<u8>>();
+
+    let mut queue = Queue::with_capacity(65536, 0);
+    queue.append(&payload[..100], 0).unwrap();
+    queue.append(&payload[100..200], 0).unwrap();
+    queue.append(&payload[200..], 0).unwrap();
+    std::thread::sleep(Duration::from_millis(1));
+
+    // Only the clock of the payload in the window is updated
+    let retrans = queue.get_timed_out_in_window_and_update(60000, 150);
+    assert_eq!(retrans, payload[..150].to_vec());
+    let clocks = queue
+        .clocks
+        .iter()
+        .map(|(sequence, timer)| (*sequence, timer.is_timedout()))
+        .collect::<Vec<_>>();
+    assert_eq!(clocks, vec![(0, false), (150, true), (200, true)]);
+    queue.check_invariants();
+
+    // The payload after is not retransmitted until the payload before is timed out again
+    assert!(queue
+        .get_timed_out_in_window_and_update(60000, 65535)
+        .is_empty());
+
+    // The payload after is retransmitted once the payload before is acknowledged
+    queue.invalidate_to(150);
+    let retrans = queue.get_timed_out_in_window_and_update(0, 65535);
+    assert_eq!(retrans, payload[150..].to_vec());
+    queue.invalidate_to(255);
+    assert!(queue.is_empty());
+}
+
+#[test]
+fn queue_append_at() {
+    let mut q = Queue::with_capacity(9, 0);
+
+    let v = (0..4).into_iter().collect::<Vec<_>>();
+    q.append_at(0, v.as_slice(), 0).unwrap();
+
+    let v = (4..8).into_iter().collect::<Vec<_>>();
+    let e = q.append_at(5, v.as_slice(), 0).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidInput);
+
+    q.append_at(4, v.as_slice(), 0).unwrap();
+
+    assert_eq!(q.to_string(), "[<0, 1, 2, 3, 4, 5, 6, 7>]");
+}
+
+#[test]
+fn queue_invalidate_to_fin() {
+    let mut q = Queue::with_capacity(9, 0);
+
+    let v = (0..8).into_iter().collect::<Vec<_>>();
+    q.append(v.as_slice(), 0).unwrap();
+
+    // Payload is acknowledged, the FIN at the receive next is not
+    q.invalidate_to(8);
+    assert_eq!(q.sequence(), 8);
+    assert_eq!(q.recv_next(), 8);
+
+    // The FIN consumes a sequence
+    q.invalidate_to(9);
+    assert_eq!(q.sequence(), 9);
+    assert_eq!(q.recv_next(), 9);
+    assert!(q.is_empty());
+
+    // A stale acknowledgement of the payload only should not move the queue backward
+    assert_eq!(q.invalidate_to(8), None);
+    assert_eq!(q.sequence(), 9);
+    q.check_invariants();
+}
+
+#[test]
+fn queue_contains() {
+    let mut q = Queue::with_capacity(16, 0);
+    assert!(!q.contains(0));
+
+    let v = (0..8).into_iter().collect::<Vec<_>>();
+    q.append(v.as_slice(), 0).unwrap();
+    assert!(q.contains(0));
+    assert!(q.contains(7));
+    assert!(!q.contains(8));
+    assert!(!q.contains(u32::MAX));
+
+    q.invalidate_to(4);
+    assert!(!q.contains(3));
+    assert!(q.contains(4));
+}
+
+#[test]
+fn queue_contains_wrap() {
+    let mut q = Queue::with_capacity(16, u32::MAX - 4);
+
+    let v = (0..8).into_iter().collect::<Vec<_>>();
+    q.append(v.as_slice(), 0).unwrap();
+    assert!(!q.contains(u32::MAX - 5));
+    assert!(q.contains(u32::MAX - 4));
+    assert!(q.contains(u32::MAX));
+    assert!(q.contains(q.recv_next() - 1));
+    assert!(!q.contains(q.recv_next()));
+    assert!(!q.contains(u32::MAX / 2));
+
+    // Consistent with getting
+    for sequence in [u32::MAX - 5, u32::MAX - 4, u32::MAX, 0, 1, q.recv_next()].iter() {
+        assert_eq!(q.contains(*sequence), q.get(*sequence, 1).is_ok());
+    }
+}
+
+#[test]
+fn queue_invalidate_wrap() {
+    let v = (0..16).into_iter().collect::<Vec<_>>();
+
+    // Start at each sequence around the wrap boundary
+    for i in 0..16 {
+        let sequence = seq_add(u32::MAX - 7, i);
+        let mut q = Queue::with_capacity(16, sequence);
+        q.append(&v, 0).unwrap();
+        assert_eq!(q.recv_next(), seq_add(sequence, 16));
+        assert_eq!(q.get(seq_add(sequence, 8), 8).unwrap(), &v[8..]);
+
+        q.invalidate_to(seq_add(sequence, 5));
+        assert_eq!(q.sequence(), seq_add(sequence, 5));
+        assert_eq!(q.get_all(), &v[5..]);
+        assert!(!q.contains(seq_add(sequence, 4)));
+        assert!(q.contains(seq_add(sequence, 15)));
+        assert!(!q.contains(seq_add(sequence, 16)));
+
+        q.invalidate_to(seq_add(sequence, 16));
+        assert!(q.is_empty());
+        q.check_invariants();
+    }
+}
+
+#[test]
+fn queue_slices() {
+    let mut q = Queue::with_capacity(8, 0);
+    assert_eq!(q.slices(0, 0).unwrap(), (&[][..], &[][..]));
+    assert_eq!(q.slices(0, 1).unwrap_err().kind(), ErrorKind::InvalidInput);
+
+    // Not wrapped
+    q.append(&[0, 1, 2, 3, 4, 5, 6, 7], 0).unwrap();
+    assert_eq!(q.slices(2, 4).unwrap(), (&[2, 3, 4, 5][..], &[][..]));
+
+    // Wrapped
+    q.invalidate_to(6);
+    q.append(&[8, 9, 10], 0).unwrap();
+    assert_eq!(q.slices(6, 5).unwrap(), (&[6, 7][..], &[8, 9, 10][..]));
+    assert_eq!(q.slices(9, 2).unwrap(), (&[9, 10][..], &[][..]));
+    assert_eq!(q.get(7, 3).unwrap(), vec![7, 8, 9]);
+    assert_eq!(q.slices(7, 5).unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert_eq!(q.slices(12, 1).unwrap_err().kind(), ErrorKind::InvalidInput);
+}
+
+#[test]
+fn queue_get_range() {
+    let mut q = Queue::with_capacity(16, u32::MAX - 7);
+    let v = (0..16).into_iter().collect::<Vec<_>>();
+    q.append(&v, 0).unwrap();
+
+    // In the middle across the wrap of the sequence
+    assert_eq!(q.get(u32::MAX - 1, 4).unwrap(), &v[6..10]);
+    assert_eq!(q.get(4, 4).unwrap(), &v[12..16]);
+    assert_eq!(q.get(8, 0).unwrap(), vec![]);
+
+    // Before the begin or beyond the end
+    assert_eq!(
+        q.get(u32::MAX - 8, 1).unwrap_err().kind(),
+        ErrorKind::InvalidInput
+    );
+    assert_eq!(
+        q.get(u32::MAX - 8, 0).unwrap_err().kind(),
+        ErrorKind::InvalidInput
+    );
+    assert_eq!(q.get(4, 5).unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert_eq!(q.get(9, 0).unwrap_err().kind(), ErrorKind::InvalidInput);
+}
+
+#[test]
+fn queue_shrink_to_fit() {
+    let mut q = Queue::with_capacity(1024 * 1024, 0);
+    let v = (0..200 * 1024).map(|x| x as u8).collect::<Vec<_>>();
+
+    // Reclaimed after the burst is invalidated
+    for chunk in v.chunks(1460) {
+        q.append(chunk, 0).unwrap();
+    }
+    assert!(q.buffer.len() >= v.len());
+    q.invalidate_to(v.len() as u32);
+    assert!(q.is_empty());
+    assert_eq!(q.buffer.len(), INITIAL_CACHE_SIZE);
+
+    // Not shrunk until the size is below a quarter of the buffer
+    let n = INITIAL_CACHE_SIZE;
+    let sequence = v.len() as u32;
+    q.append(&v[..4 * n], 0).unwrap();
+    assert_eq!(q.buffer.len(), 4 * n);
+    q.invalidate_to(seq_add(sequence, 3 * n as u32));
+    assert_eq!(q.buffer.len(), 4 * n);
+
+    // The bytes wrapped around the end of the buffer are moved to the beginning
+    q.append(&v[4 * n..4 * n + n / 2], 0).unwrap();
+    q.invalidate_to(seq_add(sequence, (3 * n + 3 * n / 4) as u32));
+    assert_eq!(q.len(), 3 * n / 4);
+    assert_eq!(q.buffer.len(), n);
+    assert_eq!(q.head, 0);
+    assert_eq!(q.get_all(), &v[3 * n + 3 * n / 4..4 * n + n / 2]);
+    q.append(&v[4 * n + n / 2..4 * n + n / 2 + 2], 0).unwrap();
+    assert_eq!(q.get_all(), &v[3 * n + 3 * n / 4..4 * n + n / 2 + 2]);
+    q.check_invariants();
+}
+
+#[test]
+fn queue_pure_acks() {
+    let mut q = Queue::with_capacity(4 * 1024 * 1024, u32::MAX - 1000);
+
+    let v = vec![0u8; 4 * 1024 * 1024];
+    q.append(v.as_slice(), 0).unwrap();
+    assert_eq!(q.remaining(), 0);
+
+    // A stream of pure ACKs, each acknowledges a segment and opens the window for it
+    let mut acknowledged = 0;
+    while acknowledged < v.len() {
+        let sequence = seq_add(q.sequence(), 1460);
+        let sequence = match q.len() < 1460 {
+            true => q.recv_next(),
+            false => sequence,
+        };
+        q.invalidate_to(sequence);
+        acknowledged = min(acknowledged + 1460, v.len());
+        assert_eq!(q.remaining(), acknowledged);
+        assert_eq!(q.len(), v.len() - acknowledged);
+
+        // Duplicate pure ACKs acknowledge nothing
+        q.invalidate_to(sequence);
+        assert_eq!(q.remaining(), acknowledged);
+    }
+    assert!(q.is_empty());
+    assert_eq!(q.remaining(), q.capacity());
+}
+
 /// Represents a window cache. The `Window` can hold discontinuous bytes and pop out them when
 /// they are completed. The `Window` can be used as a receive window of a TCP connection.
 #[derive(Debug)]
@@ -456,6 +1125,20 @@ pub struct Window {
     size: usize,
     /// Represents edges of filled values. Use an u64 instead of an u32 because the sequence is used as a ring.
     edges: BTreeMap<u64, usize>,
+    /// Represents the count of appending ignored for the bytes are out of the window.
+    out_of_window: usize,
+    /// Represents the count of appending ignored for the bytes are delivered or filled already.
+    duplicates: usize,
+    /// Represents the size beyond which the window is under pressure.
+    soft_limit: usize,
+    policy: OverWindowPolicy,
+    /// Represents the furthest right edge of the window advertised. `None` represents the full
+    /// capacity is advertised.
+    advertised: Option<u32>,
+    /// Represents the count of ranges merged in appending.
+    merges: usize,
+    /// Represents the peak count of ranges.
+    peak_ranges: usize,
 }
 
 impl Window {
@@ -467,128 +1150,276 @@ impl Window {
 
     /// Creates a new `Window` with the specified capacity.
     pub fn with_capacity(capacity: usize, sequence: u32) -> Window {
-        Window {
-            buffer: match ALLOC_IN_INITIAL {
-                true => Vec::with_capacity(capacity),
-                false => Vec::new(),
-            },
+        Window::with_capacity_alloc(capacity, sequence, ALLOC_IN_INITIAL)
+    }
+
+    /// Creates a new `Window` with the specified capacity. If `alloc` is set, the buffer is
+    /// allocated in the initial size in the constructor, otherwise it is allocated lazily in
+    /// appending.
+    pub fn with_capacity_alloc(capacity: usize, sequence: u32, alloc: bool) -> Window {
+        let mut window = Window {
+            buffer: Vec::new(),
             capacity,
             sequence,
             head: 0,
             size: 0,
             edges: BTreeMap::new(),
+            out_of_window: 0,
+            duplicates: 0,
+            soft_limit: percent_of(capacity, SOFT_LIMIT_PERCENT),
+            policy: OverWindowPolicy::Lenient,
+            advertised: None,
+            merges: 0,
+            peak_ranges: 0,
+        };
+        if alloc {
+            window.reserve(min(INITIAL_CACHE_SIZE, capacity));
         }
+
+        window
     }
 
-    /// Appends some bytes to the window and returns continuous bytes from the beginning.
-    pub fn append(&mut self, sequence: u32, payload: &[u8]) -> Result<Option<Vec<u8>>> {
-        let sub_sequence = sequence
-            .checked_sub(self.sequence)
-            .unwrap_or_else(|| sequence + (u32::MAX - self.sequence))
-            as usize;
-        let (sequence, payload, sub_sequence) = if sub_sequence > MAX_U32_WINDOW_SIZE {
-            let recv_next = sequence
-                .checked_add(payload.len() as u32)
-                .unwrap_or_else(|| payload.len() as u32 - (u32::MAX - sequence));
-            let sub_recv_next_to_sequence = recv_next
-                .checked_sub(self.sequence)
-                .unwrap_or_else(|| sequence + (u32::MAX - recv_next));
-
-            if sub_recv_next_to_sequence as usize <= MAX_U32_WINDOW_SIZE {
-                let sub_sequence = self
-                    .sequence
-                    .checked_sub(sequence)
-                    .unwrap_or_else(|| self.sequence + (u32::MAX - sequence));
-                (self.sequence, &payload[sub_sequence as usize..], 0)
-            } else {
-                return Ok(None);
-            }
-        } else {
-            (sequence, payload, sub_sequence)
-        };
+    /// Creates a new `Window` with the specified capacity whose buffer is allocated in the initial
+    /// size in the constructor. The buffer grows up to the capacity in appending. Fails if either
+    /// size is 0 or the initial size is over the capacity.
+    pub fn with_initial(initial: usize, capacity: usize, sequence: u32) -> Result<Window> {
+        check_initial(initial, capacity)?;
 
-        let size = sub_sequence + payload.len();
-        if size > self.capacity {
+        let mut window = Window::with_capacity_alloc(capacity, sequence, false);
+        window.reserve(initial);
+
+        Ok(window)
+    }
+
+    /// Creates a new `Window` with the specified capacity holding the continuous bytes from the
+    /// sequence, which are not popped yet. The buffer is allocated in the size of the bytes. The
+    /// bytes are popped in the next appending, or can be consumed in place by
+    /// `drain_contiguous_with`.
+    pub fn from_contiguous(capacity: usize, sequence: u32, data: &[u8]) -> Result<Window> {
+        if data.len() > capacity {
             return Err(Error::new(ErrorKind::Other, "window is full"));
         }
-        if size > self.buffer.len() {
-            // Extend the buffer
-            let prev_len = self.buffer.len();
-            let new_len = min(
-                self.capacity,
-                max(
-                    self.buffer
-                        .len()
-                        .checked_add(self.buffer.len() / 2)
-                        .unwrap_or(usize::MAX),
-                    size,
-                ),
-            );
-            self.buffer.resize(new_len, 0);
 
-            // From the begin of the buffer to the tail
-            let ranges = self
-                .edges
-                .iter()
-                .map(|(sequence, &size)| {
-                    let sub_sequence = sequence
-                        .checked_sub(self.sequence as u64)
-                        .unwrap_or_else(|| sequence + (u32::MAX - self.sequence) as u64)
-                        as usize;
-                    let mut begin = self.get_tail(self.head, sub_sequence, prev_len);
-                    let end = self.get_tail(begin, size, prev_len);
-                    if end <= begin {
-                        begin = 0;
-                    }
+        let mut window = Window::with_capacity(capacity, sequence);
+        window.buffer = data.to_vec();
+        window.size = data.len();
+        if !data.is_empty() {
+            window.edges.insert(sequence as u64, data.len());
+        }
+        window.peak_ranges = window.edges.len();
 
-                    (begin, end)
-                })
-                .filter(|(begin, end)| *begin < self.head || *end <= self.head)
-                .collect::<Vec<_>>();
-            for (begin, end) in ranges {
-                // From the begin to the mid of the buffer
-                let len_a = min(
-                    (new_len - prev_len).checked_sub(begin).unwrap_or(0),
-                    end - begin,
-                );
-                if len_a > 0 {
-                    self.buffer
-                        .copy_within(begin..begin + len_a, prev_len + begin);
-                }
+        #[cfg(debug_assertions)]
+        window.check_invariants();
 
-                // From the mid to the tail of the buffer
-                let len_b = (end - begin) - len_a;
-                if len_b > 0 {
-                    self.buffer
-                        .copy_within(begin + len_a..end, begin + len_a - (new_len - prev_len));
-                }
-            }
-        }
+        Ok(window)
+    }
 
-        // TODO: copy valid bytes only
-        // TODO: copy bytes into empty ranges only
-        // To the end of the buffer
-        let tail = self.get_tail(self.head, sub_sequence, self.buffer.len());
-        let len_a = min(self.buffer.len() - tail, payload.len());
-        self.buffer[tail..tail + len_a].copy_from_slice(&payload[..len_a]);
+    /// Appends some bytes to the window and returns continuous bytes from the beginning.
+    /// Appending no bytes, e.g. the payload of a pure ACK, is a no-op. Returns an error of
+    /// `ErrorKind::WouldBlock` if the bytes are beyond the capacity of the window.
+    pub fn append(&mut self, sequence: u32, payload: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.insert(sequence, payload)?;
 
-        // From the begin of the buffer
-        let len_b = payload.len() - len_a;
-        if len_b > 0 {
-            self.buffer[..len_b].copy_from_slice(&payload[len_a..]);
-        }
+        // Pop if possible
+        let cont_payload = self.pop();
+        self.peak_ranges = max(self.peak_ranges, self.edges.len());
+
+        #[cfg(debug_assertions)]
+        self.check_invariants();
+
+        Ok(cont_payload)
+    }
+
+    /// Appends some bytes to the window before a FIN at the given sequence and returns continuous
+    /// bytes from the beginning. Bytes at and after the FIN are invalid and dropped, including
+    /// those filled before the FIN arrives, so the FIN follows the continuous bytes once they are
+    /// popped even if the bytes with the FIN fill a gap before them.
+    pub fn append_before_fin(
+        &mut self,
+        sequence: u32,
+        payload: &[u8],
+        fin_sequence: u32,
+    ) -> Result<Option<Vec<u8>>> {
+        self.insert(sequence, payload)?;
+        self.trim_to_window(seq_sub(fin_sequence, self.sequence));
+
+        // Pop if possible
+        let cont_payload = self.pop();
+        self.peak_ranges = max(self.peak_ranges, self.edges.len());
+
+        #[cfg(debug_assertions)]
+        self.check_invariants();
+
+        Ok(cont_payload)
+    }
+
+    /// Appends some bytes to the window without popping continuous bytes from the beginning, so
+    /// they can be consumed in place by `drain_contiguous_with` later.
+    pub fn fill(&mut self, sequence: u32, payload: &[u8]) -> Result<()> {
+        self.insert(sequence, payload)?;
+        self.peak_ranges = max(self.peak_ranges, self.edges.len());
+
+        #[cfg(debug_assertions)]
+        self.check_invariants();
+
+        Ok(())
+    }
+
+    /// Drains continuous bytes from the beginning of the window in place. The bytes are handed to
+    /// the function in at most 2 slices for the buffer is a ring, and the function returns the
+    /// size of bytes it consumes. Draining stops at the first slice not consumed entirely, and only
+    /// the bytes consumed are removed from the window. Returns the size of bytes drained.
+    pub fn drain_contiguous_with<F: FnMut(&[u8]) -> usize>(&mut self, mut f: F) -> usize {
+        let first_key = match self.edges.keys().next() {
+            Some(&key) => key,
+            None => return 0,
+        };
+        if first_key as u32 != self.sequence {
+            return 0;
+        }
+        let size = self.edges[&first_key];
+
+        // From the head to the end of the buffer
+        let len_a = min(size, self.buffer.len() - self.head);
+        let mut consumed = min(f(&self.buffer[self.head..self.head + len_a]), len_a);
+
+        // From the begin of the buffer to the tail
+        let len_b = size - len_a;
+        if consumed == len_a && len_b > 0 {
+            consumed += min(f(&self.buffer[..len_b]), len_b);
+        }
+        if consumed == 0 {
+            return 0;
+        }
+
+        self.edges.remove(&first_key);
+
+        // Shrink range sequence is possible
+        if ((u32::MAX - self.sequence) as usize) < consumed {
+            let keys = self.edges.keys().map(|x| *x).collect::<Vec<_>>();
+
+            for key in keys {
+                let value = self.edges.remove(&key).unwrap();
+                self.edges.insert(key - SEQUENCE_SPACE, value);
+            }
+        }
+
+        self.sequence = seq_add(self.sequence, consumed as u32);
+        self.head = (self.head + consumed) % self.buffer.len();
+        self.size -= consumed;
+        if consumed < size {
+            self.edges.insert(self.sequence as u64, size - consumed);
+        }
+
+        #[cfg(debug_assertions)]
+        self.check_invariants();
+
+        consumed
+    }
+
+    /// Delivers at most the given size of continuous bytes from the beginning of the window, so
+    /// a large run can be delivered in chunks as the receiver drains, relieving the window
+    /// gradually. Returns `None` if there are no continuous bytes or the size is 0.
+    pub fn deliver_up_to(&mut self, max: usize) -> Option<Vec<u8>> {
+        let mut payload = Vec::new();
+        self.drain_contiguous_with(|b| {
+            let size = min(b.len(), max - payload.len());
+            payload.extend_from_slice(&b[..size]);
+
+            size
+        });
+
+        match payload.len() {
+            0 => None,
+            _ => Some(payload),
+        }
+    }
+
+    fn insert(&mut self, sequence: u32, payload: &[u8]) -> Result<()> {
+        if payload.is_empty() {
+            return Ok(());
+        }
+
+        // Trim the prefix already delivered of a retransmission which straddles the beginning of
+        // the window, so only the tail not delivered yet is buffered
+        let (sequence, payload) = match self.trim_delivered(sequence, payload) {
+            Some(trimmed) => trimmed,
+            None => {
+                // A retransmission delivered entirely is a duplicate, only bytes beyond the limit
+                // of the window are out of the window
+                if seq_sub(self.sequence, sequence) as usize <= MAX_U32_WINDOW_SIZE {
+                    self.duplicates = self.duplicates.checked_add(1).unwrap_or(usize::MAX);
+                } else {
+                    self.out_of_window = self.out_of_window.checked_add(1).unwrap_or(usize::MAX);
+                }
+
+                return Ok(());
+            }
+        };
+        let sub_sequence = seq_sub(sequence, self.sequence) as usize;
+
+        // Drop bytes beyond the advertised window
+        let payload = match self.policy {
+            OverWindowPolicy::Strict => {
+                let limit = self.advertised_limit();
+                if sub_sequence + payload.len() > limit {
+                    self.out_of_window = self.out_of_window.checked_add(1).unwrap_or(usize::MAX);
+                    if sub_sequence >= limit {
+                        return Ok(());
+                    }
+
+                    &payload[..limit - sub_sequence]
+                } else {
+                    payload
+                }
+            }
+            OverWindowPolicy::Lenient => payload,
+        };
+
+        // Ignore a duplicate contained entirely in a filled range, so neither the size nor the
+        // ranges are touched
+        if self.is_filled(sequence, payload.len()) {
+            self.duplicates = self.duplicates.checked_add(1).unwrap_or(usize::MAX);
+
+            return Ok(());
+        }
+
+        let size = sub_sequence + payload.len();
+        if size > self.capacity {
+            return Err(Error::new(ErrorKind::WouldBlock, "window is full"));
+        }
+        if size > self.buffer.len() {
+            // Extend the buffer
+            let new_len = min(
+                self.capacity,
+                max(
+                    self.buffer
+                        .len()
+                        .checked_add(self.buffer.len() / 2)
+                        .unwrap_or(usize::MAX),
+                    size,
+                ),
+            );
+            self.extend(new_len);
+        }
+
+        // TODO: copy valid bytes only
+        // TODO: copy bytes into empty ranges only
+        // To the end of the buffer
+        let tail = self.get_tail(self.head, sub_sequence, self.buffer.len());
+        let len_a = min(self.buffer.len() - tail, payload.len());
+        self.buffer[tail..tail + len_a].copy_from_slice(&payload[..len_a]);
+
+        // From the begin of the buffer
+        let len_b = payload.len() - len_a;
+        if len_b > 0 {
+            self.buffer[..len_b].copy_from_slice(&payload[len_a..]);
+        }
 
         // Update size
-        let recv_next = sequence
-            .checked_add(payload.len() as u32)
-            .unwrap_or_else(|| payload.len() as u32 - (u32::MAX - sequence));
-        let record_recv_next = self
-            .sequence
-            .checked_add(self.size as u32)
-            .unwrap_or_else(|| self.size as u32 - (u32::MAX - self.sequence));
-        let sub_recv_next = recv_next
-            .checked_sub(record_recv_next)
-            .unwrap_or_else(|| recv_next + (u32::MAX - record_recv_next));
+        let recv_next = seq_add(sequence, payload.len() as u32);
+        let record_recv_next = seq_add(self.sequence, self.size as u32);
+        let sub_recv_next = seq_sub(recv_next, record_recv_next);
         if sub_recv_next as usize <= MAX_U32_WINDOW_SIZE {
             self.size += sub_recv_next as usize;
         }
@@ -597,7 +1428,7 @@ impl Window {
         {
             let mut sequence = sequence as u64;
             if (sequence as u32) < self.sequence {
-                sequence += u32::MAX as u64;
+                sequence += SEQUENCE_SPACE;
             }
 
             // Select ranges which can be merged in a loop
@@ -617,6 +1448,10 @@ impl Window {
                 }
 
                 // Pop
+                self.merges = self
+                    .merges
+                    .checked_add(pop_keys.len())
+                    .unwrap_or(usize::MAX);
                 for ref pop_key in pop_keys {
                     self.edges.remove(pop_key);
                 }
@@ -630,22 +1465,203 @@ impl Window {
                 }
             }
 
-            // Merge previous range
-            let mut size = end - sequence;
+            // Merge previous range, which may contain the bytes entirely
             if let Some(prev_key) = prev_key {
                 let prev_size = *self.edges.get(&prev_key).unwrap();
                 if prev_key + (prev_size as u64) >= sequence {
-                    size += sequence - prev_key;
+                    end = max(end, prev_key + prev_size as u64);
                     sequence = prev_key;
+                    self.merges = self.merges.checked_add(1).unwrap_or(usize::MAX);
                 }
             }
+            let size = end - sequence;
 
             // Insert range
             self.edges.insert(sequence, size as usize);
         }
 
-        // Pop if possible
-        let first_key = *self.edges.keys().next().unwrap();
+        Ok(())
+    }
+
+    /// Returns if the bytes in [sequence, sequence + size) are filled entirely in a range.
+    fn is_filled(&self, sequence: u32, size: usize) -> bool {
+        let mut sequence = sequence as u64;
+        if (sequence as u32) < self.sequence {
+            sequence += SEQUENCE_SPACE;
+        }
+
+        match self.edges.range(..=sequence).next_back() {
+            Some((&key, &value)) => key + value as u64 >= sequence + size as u64,
+            None => false,
+        }
+    }
+
+    /// Trims the prefix of the payload which is already delivered. Returns the sequence and the
+    /// payload remaining, or `None` if the payload is delivered entirely or is out of the window.
+    fn trim_delivered<'a>(&self, sequence: u32, payload: &'a [u8]) -> Option<(u32, &'a [u8])> {
+        let sub_sequence = seq_sub(sequence, self.sequence) as usize;
+        if sub_sequence <= MAX_U32_WINDOW_SIZE {
+            return Some((sequence, payload));
+        }
+
+        // The payload begins before the window
+        let sub_delivered = seq_sub(self.sequence, sequence) as usize;
+        if sub_delivered >= payload.len() {
+            return None;
+        }
+
+        Some((self.sequence, &payload[sub_delivered..]))
+    }
+
+    /// Reserves the buffer for at least the additional bytes to be appended beyond the expected
+    /// size in one step, so appending them later does not reallocate the buffer. The buffer is
+    /// limited by the capacity.
+    pub fn reserve(&mut self, additional: usize) {
+        let new_len = min(
+            self.capacity,
+            self.size.checked_add(additional).unwrap_or(usize::MAX),
+        );
+        if new_len > self.buffer.len() {
+            self.extend(new_len);
+
+            #[cfg(debug_assertions)]
+            self.check_invariants();
+        }
+    }
+
+    fn extend(&mut self, new_len: usize) {
+        let prev_len = self.buffer.len();
+        self.buffer.resize(new_len, 0);
+
+        // From the begin of the buffer to the tail
+        let ranges = self
+            .edges
+            .iter()
+            .map(|(sequence, &size)| {
+                let sub_sequence = seq_sub(*sequence as u32, self.sequence) as usize;
+                let mut begin = self.get_tail(self.head, sub_sequence, prev_len);
+                let end = self.get_tail(begin, size, prev_len);
+                if end <= begin {
+                    begin = 0;
+                }
+
+                (begin, end)
+            })
+            .filter(|(begin, end)| *begin < self.head || *end <= self.head)
+            .collect::<Vec<_>>();
+        for (begin, end) in ranges {
+            // From the begin to the mid of the buffer
+            let len_a = min(
+                (new_len - prev_len).checked_sub(begin).unwrap_or(0),
+                end - begin,
+            );
+            if len_a > 0 {
+                self.buffer
+                    .copy_within(begin..begin + len_a, prev_len + begin);
+            }
+
+            // From the mid to the tail of the buffer
+            let len_b = (end - begin) - len_a;
+            if len_b > 0 {
+                self.buffer
+                    .copy_within(begin + len_a..end, begin + len_a - (new_len - prev_len));
+            }
+        }
+    }
+
+    /// Skips the first unfilled range of the window and returns the size skipped and the
+    /// continuous bytes after it.
+    pub fn advance(&mut self) -> Option<(usize, Vec<u8>)> {
+        let first_key = match self.edges.keys().next() {
+            Some(&key) => key,
+            None => return None,
+        };
+        let size = seq_sub(first_key as u32, self.sequence) as usize;
+
+        // Shrink range sequence is possible
+        if ((u32::MAX - self.sequence) as usize) < size {
+            let keys = self.edges.keys().map(|x| *x).collect::<Vec<_>>();
+
+            for key in keys {
+                let value = self.edges.remove(&key).unwrap();
+                self.edges.insert(key - SEQUENCE_SPACE, value);
+            }
+        }
+
+        self.sequence = seq_add(self.sequence, size as u32);
+        self.head = (self.head + (size % self.buffer.len())) % self.buffer.len();
+        self.size -= size;
+
+        let cont_payload = self.pop();
+
+        #[cfg(debug_assertions)]
+        self.check_invariants();
+
+        match cont_payload {
+            Some(payload) => Some((size, payload)),
+            None => None,
+        }
+    }
+
+    /// Trims the window to the given size from the sequence of the window. Filled bytes beyond
+    /// the window are dropped and ranges straddling the edge of the window are truncated, so the
+    /// size of the window is released. Returns the size of filled bytes dropped.
+    pub fn trim_to_window(&mut self, window: u32) -> usize {
+        let window = window as usize;
+        if self.size <= window {
+            return 0;
+        }
+
+        let mut dropped = 0;
+        let keys = self.edges.keys().map(|x| *x).collect::<Vec<_>>();
+        for key in keys {
+            let sub_sequence = seq_sub(key as u32, self.sequence) as usize;
+            let size = self.edges[&key];
+            if sub_sequence >= window {
+                // Beyond the window
+                self.edges.remove(&key);
+                dropped += size;
+            } else if sub_sequence + size > window {
+                // Straddling the edge of the window
+                self.edges.insert(key, window - sub_sequence);
+                dropped += sub_sequence + size - window;
+            }
+        }
+
+        // The window ends at the last filled byte
+        self.size = match self.edges.iter().next_back() {
+            Some((&key, &size)) => seq_sub(key as u32, self.sequence) as usize + size,
+            None => 0,
+        };
+
+        #[cfg(debug_assertions)]
+        self.check_invariants();
+
+        dropped
+    }
+
+    /// Admits a FIN at the given sequence, which consumes a sequence after all the bytes before
+    /// it are popped. Bytes after the FIN are invalid and dropped. Returns if the FIN is admitted.
+    pub fn admit_fin(&mut self, sequence: u32) -> bool {
+        if sequence != self.sequence {
+            return false;
+        }
+
+        self.edges.clear();
+        self.size = 0;
+        self.sequence = seq_add(self.sequence, 1);
+
+        #[cfg(debug_assertions)]
+        self.check_invariants();
+
+        true
+    }
+
+    fn pop(&mut self) -> Option<Vec<u8>> {
+        let first_key = match self.edges.keys().next() {
+            Some(&key) => key,
+            None => return None,
+        };
         if first_key as u32 == self.sequence {
             let size = self.edges.remove(&first_key).unwrap();
 
@@ -655,7 +1671,7 @@ impl Window {
 
                 for key in keys {
                     let value = self.edges.remove(&key).unwrap();
-                    self.edges.insert(key - u32::MAX as u64, value);
+                    self.edges.insert(key - SEQUENCE_SPACE, value);
                 }
             }
 
@@ -672,17 +1688,14 @@ impl Window {
                 cont_payload[len_a..].copy_from_slice(&self.buffer[..len_b]);
             }
 
-            self.sequence = self
-                .sequence
-                .checked_add(size as u32)
-                .unwrap_or_else(|| size as u32 - (u32::MAX - self.sequence));
+            self.sequence = seq_add(self.sequence, size as u32);
             self.head = (self.head + (size % self.buffer.len())) % self.buffer.len();
             self.size -= cont_payload.len();
 
-            return Ok(Some(cont_payload));
+            return Some(cont_payload);
         }
 
-        Ok(None)
+        None
     }
 
     /// Returns the sequence of the window.
@@ -690,16 +1703,61 @@ impl Window {
         self.sequence
     }
 
+    /// Returns the sequence of the first byte which is neither received nor popped. For an empty
+    /// window, it equals the sequence of the window.
+    pub fn next_missing(&self) -> u32 {
+        match self.filled().first() {
+            Some(&(begin, end)) if begin == self.sequence => end,
+            _ => self.sequence,
+        }
+    }
+
     /// Returns the length of the window. Not all bytes in [sequence, sequence + len) are filled.
     pub fn len(&self) -> usize {
         self.size
     }
 
+    /// Returns the allocated size of the buffer of the window.
+    pub fn allocated(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Returns the count of appending ignored for the bytes are out of the window.
+    pub fn out_of_window_count(&self) -> usize {
+        self.out_of_window
+    }
+
+    /// Returns the count of appending ignored for the bytes are delivered or filled already, like
+    /// retransmissions.
+    pub fn duplicate_count(&self) -> usize {
+        self.duplicates
+    }
+
+    /// Returns the count of ranges merged in appending. A range is merged when the bytes appended
+    /// overlap or adjoin it.
+    pub fn merge_count(&self) -> usize {
+        self.merges
+    }
+
+    /// Returns the count of discontinuous ranges filled in the window, which indicates how
+    /// fragmented the window is.
+    pub fn range_count(&self) -> usize {
+        self.edges.len()
+    }
+
+    /// Returns the peak count of discontinuous ranges filled in the window.
+    pub fn peak_range_count(&self) -> usize {
+        self.peak_ranges
+    }
+
     /// Returns the receive next of the window.
     pub fn recv_next(&self) -> u32 {
-        self.sequence
-            .checked_add(self.size as u32)
-            .unwrap_or_else(|| self.size as u32 - (u32::MAX - self.sequence))
+        seq_add(self.sequence, self.size as u32)
+    }
+
+    /// Returns the capacity of the window.
+    pub fn capacity(&self) -> usize {
+        self.capacity
     }
 
     /// Returns the remaining size of the window.
@@ -707,6 +1765,89 @@ impl Window {
         self.capacity - self.size
     }
 
+    /// Sets the soft limit of the window in percentage of the capacity.
+    pub fn set_soft_limit_percent(&mut self, percent: usize) {
+        self.soft_limit = percent_of(self.capacity, min(percent, 100));
+    }
+
+    /// Returns the soft limit of the window. The window is under pressure once its length reaches
+    /// the soft limit.
+    pub fn soft_limit(&self) -> usize {
+        self.soft_limit
+    }
+
+    /// Returns the pressure of the window.
+    pub fn pressure(&self) -> Pressure {
+        if self.size >= self.capacity {
+            Pressure::Full
+        } else if self.size >= self.soft_limit {
+            Pressure::High
+        } else {
+            Pressure::Low
+        }
+    }
+
+    /// Sets the policy of handling bytes beyond the advertised window.
+    pub fn set_over_window_policy(&mut self, policy: OverWindowPolicy) {
+        self.policy = policy;
+    }
+
+    /// Returns the policy of handling bytes beyond the advertised window.
+    pub fn over_window_policy(&self) -> OverWindowPolicy {
+        self.policy
+    }
+
+    /// Sets the size of the window advertised from the sequence of the window. Once a window is
+    /// advertised, the right edge of the advertised window never moves backward, so bytes in a
+    /// window advertised before are still accepted after the window shrinks.
+    pub fn set_advertised(&mut self, window: usize) {
+        let window = min(window, self.capacity);
+        let window = match self.advertised {
+            Some(_) => max(window, self.advertised_limit()),
+            None => window,
+        };
+        let window = min(window, MAX_U32_WINDOW_SIZE) as u32;
+        self.advertised = Some(seq_add(self.sequence, window));
+    }
+
+    /// Sets the size of the window initially advertised from the sequence of the window, which
+    /// replaces the window advertised before regardless of its right edge.
+    pub fn set_initial_advertised(&mut self, window: usize) {
+        self.advertised = None;
+        self.set_advertised(window);
+    }
+
+    /// Returns the size from the sequence of the window to the right edge of the advertised
+    /// window.
+    pub fn advertised_limit(&self) -> usize {
+        match self.advertised {
+            Some(advertised) => {
+                let limit = seq_sub(advertised, self.sequence) as usize;
+                match limit > MAX_U32_WINDOW_SIZE {
+                    true => 0,
+                    false => min(limit, self.capacity),
+                }
+            }
+            None => self.capacity,
+        }
+    }
+
+    /// Returns the size of the window which should be advertised. The advertised size equals to
+    /// the remaining size below the soft limit, and shrinks quadratically from the soft limit
+    /// to 0 when the window is full, so the sender slows down before bytes are dropped.
+    pub fn advertised(&self) -> usize {
+        match self.pressure() {
+            Pressure::Low => self.remaining(),
+            Pressure::High => {
+                let remaining = self.remaining() as u128;
+                let range = (self.capacity - self.soft_limit) as u128;
+
+                (remaining * remaining / range) as usize
+            }
+            Pressure::Full => 0,
+        }
+    }
+
     fn tail(&self) -> usize {
         self.get_tail(self.head, self.size, self.buffer.len())
     }
@@ -723,20 +1864,73 @@ impl Window {
     pub fn filled(&self) -> Vec<(u32, u32)> {
         let mut v = Vec::new();
         for (&sequence, &size) in &self.edges {
-            let begin = sequence.checked_sub(u32::MAX as u64).unwrap_or(sequence) as u32;
-            let end = begin
-                .checked_add(size as u32)
-                .unwrap_or_else(|| size as u32 - (u32::MAX - begin));
+            let begin = sequence as u32;
+            let end = seq_add(begin, size as u32);
             v.push((begin, end));
         }
 
         v
     }
 
+    /// Returns at most the count of unfilled ranges of the window from the sequence of the window
+    /// to the last filled byte, in order.
+    pub fn gaps(&self, limit: usize) -> Vec<(u32, u32)> {
+        let mut v = Vec::new();
+        let mut begin = self.sequence as u64;
+        for (&sequence, &size) in &self.edges {
+            if v.len() >= limit {
+                break;
+            }
+            if sequence > begin {
+                v.push((begin as u32, sequence as u32));
+            }
+            begin = sequence + size as u64;
+        }
+
+        v
+    }
+
     /// Returns if the window is empty.
     pub fn is_empty(&self) -> bool {
         self.size == 0
     }
+
+    /// Checks the invariants of the window and panics if any of them is broken. The check is
+    /// performed after each mutation in debug builds.
+    pub fn check_invariants(&self) {
+        assert!(self.buffer.len() <= self.capacity, "buffer over capacity");
+        assert!(self.size <= self.buffer.len(), "size over buffer");
+        assert!(
+            self.head < self.buffer.len() || (self.buffer.is_empty() && self.head == 0),
+            "head out of buffer"
+        );
+
+        // Edges are sorted, disjoint and in the window
+        let mut filled = 0;
+        let mut prev_end = None;
+        for (&key, &size) in &self.edges {
+            let sub_sequence = seq_sub(key as u32, self.sequence) as usize;
+            assert!(sub_sequence + size <= self.size, "edge out of window");
+            if let Some(prev_end) = prev_end {
+                assert!(key > prev_end, "edges overlapped");
+            }
+            prev_end = Some(key + size as u64);
+            filled += size;
+        }
+        assert!(filled <= self.size, "filled over size");
+
+        // The size spans from the head to the end of the last range
+        let end = match self.edges.iter().next_back() {
+            Some((&key, &size)) => seq_sub(key as u32, self.sequence) as usize + size,
+            None => 0,
+        };
+        assert_eq!(self.size, end, "size not spanning to the last range");
+    }
+}
+
+/// Returns the percentage of the size.
+fn percent_of(size: usize, percent: usize) -> usize {
+    (size as u128 * percent as u128 / 100) as usize
 }
 
 impl Display for Window {
@@ -751,10 +1945,7 @@ impl Display for Window {
         let mut edge_begin_set = HashSet::new();
         let mut edge_end_set = HashSet::new();
         self.edges.iter().for_each(|(sequence, &size)| {
-            let sub_sequence = sequence
-                .checked_sub(self.sequence as u64)
-                .unwrap_or_else(|| sequence + (u32::MAX - self.sequence) as u64)
-                as usize;
+            let sub_sequence = seq_sub(*sequence as u32, self.sequence) as usize;
             let begin = self.get_tail(head, sub_sequence, self.buffer.len());
             let end = self
                 .get_tail(begin, size, self.buffer.len())
@@ -813,6 +2004,28 @@ fn window_append() {
     assert_eq!(w.to_string(), "[8, 9, 10, 11, 12, 13, <6, <15>>]");
 }
 
+#[test]
+fn window_append_alloc() {
+    // The buffer is allocated in the initial size in advance
+    let mut w = Window::with_capacity_alloc(65536, 0, true);
+    assert_eq!(w.allocated(), INITIAL_CACHE_SIZE);
+
+    // Appending in the initial size does not grow the buffer
+    let v = vec![0u8; INITIAL_CACHE_SIZE];
+    w.append(0, v.as_slice()).unwrap();
+    assert_eq!(w.allocated(), INITIAL_CACHE_SIZE);
+
+    // The buffer is limited by the capacity
+    let w = Window::with_capacity_alloc(9, 0, true);
+    assert_eq!(w.allocated(), 9);
+
+    // The buffer is allocated lazily in appending
+    let mut w = Window::with_capacity_alloc(65536, 0, false);
+    assert_eq!(w.allocated(), 0);
+    w.append(0, &[0u8; 10]).unwrap();
+    assert!(w.allocated() >= 10);
+}
+
 #[test]
 fn window_append_overflow() {
     let mut w = Window::with_capacity(9, 0);
@@ -832,6 +2045,145 @@ fn window_append_overflow() {
     assert_eq!(w.to_string(), "[9, 10, 11, 12, 13>>, <0, <6, 7, 8]");
 }
 
+#[test]
+fn window_append_full() {
+    let mut w = Window::with_capacity(1, 0);
+    assert_eq!(w.append(1, &[1]).unwrap_err().kind(), ErrorKind::WouldBlock);
+    assert_eq!(w.append(0, &[0]).unwrap(), Some(vec![0]));
+}
+
+#[test]
+fn window_append_at_capacity() {
+    let mut w = Window::with_capacity(1000, 0);
+    let v = (0..2000).into_iter().map(|i| i as u8).collect::<Vec<_>>();
+
+    // Fill to exactly the capacity across the growth of the buffer, out of order
+    w.append(700, &v[700..1000]).unwrap();
+    w.append(300, &v[300..600]).unwrap();
+    assert_eq!(w.len(), 1000);
+    assert_eq!(w.remaining(), 0);
+
+    // Appending no bytes succeeds, while appending 1 or many bytes beyond fails
+    assert_eq!(w.append(1000, &[]).unwrap(), None);
+    assert!(w.append(1000, &v[1000..1001]).is_err());
+    assert!(w.append(900, &v[900..1500]).is_err());
+    assert_eq!(w.filled(), vec![(300, 600), (700, 1000)]);
+
+    // Completed without corruption
+    assert_eq!(w.append(600, &v[600..700]).unwrap(), None);
+    assert_eq!(w.append(0, &v[..300]).unwrap(), Some(v[..1000].to_vec()));
+    assert!(w.is_empty());
+
+    // Fill to exactly the capacity again across the end of the buffer
+    assert_eq!(w.append(1999, &v[1999..2000]).unwrap(), None);
+    assert!(w.append(2000, &v[..1]).is_err());
+    assert_eq!(
+        w.append(1000, &v[1000..1999]).unwrap(),
+        Some(v[1000..2000].to_vec())
+    );
+    w.check_invariants();
+}
+
+#[test]
+fn window_reserve() {
+    let mut w = Window::with_capacity(64, 0);
+    let v = (0..64).into_iter().collect::<Vec<_>>();
+
+    // Wrapped around the end of the buffer
+    w.append(0, &v[..6]).unwrap();
+    w.append(8, &v[8..10]).unwrap();
+    w.append(12, &v[12..14]).unwrap();
+
+    w.reserve(32);
+    assert_eq!(w.allocated(), 40);
+    assert_eq!(w.filled(), vec![(8, 10), (12, 14)]);
+    w.append(14, &v[14..46]).unwrap();
+    assert_eq!(w.allocated(), 40);
+
+    // Limited by the capacity
+    w.reserve(usize::MAX);
+    assert_eq!(w.allocated(), 64);
+    w.append(10, &v[10..12]).unwrap();
+    assert_eq!(w.append(6, &v[6..8]).unwrap(), Some(v[6..46].to_vec()));
+    w.check_invariants();
+}
+
+#[test]
+fn window_with_initial() {
+    assert!(Window::with_initial(0, 64, 0).is_err());
+    assert!(Window::with_initial(16, 0, 0).is_err());
+    assert!(Window::with_initial(128, 64, 0).is_err());
+
+    let mut w = Window::with_initial(16, 64, 0).unwrap();
+    let v = (0..64).into_iter().collect::<Vec<_>>();
+    assert_eq!(w.allocated(), 16);
+
+    // Grown up to the capacity
+    w.append(8, &v[8..64]).unwrap();
+    assert_eq!(w.allocated(), 64);
+    assert!(w.append(64, &v[..1]).is_err());
+    assert_eq!(w.append(0, &v[..8]).unwrap(), Some(v.clone()));
+    w.check_invariants();
+}
+
+#[test]
+fn window_append_out_of_window() {
+    let mut w = Window::with_capacity(65536, 0);
+
+    let v = (0..4).into_iter().collect::<Vec<_>>();
+    w.append(0, v.as_slice()).unwrap();
+    w.append(5 + MAX_U32_WINDOW_SIZE as u32, v.as_slice())
+        .unwrap();
+    assert_eq!(w.out_of_window_count(), 1);
+
+    // Bytes delivered before the window are duplicates
+    assert_eq!(w.append(u32::MAX - 100, v.as_slice()).unwrap(), None);
+    assert_eq!(w.out_of_window_count(), 1);
+    assert_eq!(w.duplicate_count(), 1);
+
+    // Bytes beyond the limit before the window
+    assert_eq!(
+        w.append(u32::MAX - 100 - MAX_U32_WINDOW_SIZE as u32, v.as_slice())
+            .unwrap(),
+        None
+    );
+    assert_eq!(w.out_of_window_count(), 2);
+    assert_eq!(w.duplicate_count(), 1);
+
+    // Partially acknowledged bytes are trimmed instead
+    let v = (2..6).into_iter().collect::<Vec<_>>();
+    assert_eq!(w.append(2, v.as_slice()).unwrap(), Some(vec![4, 5]));
+    assert_eq!(w.out_of_window_count(), 2);
+    assert_eq!(w.duplicate_count(), 1);
+}
+
+#[test]
+fn window_trim_to_window() {
+    let mut w = Window::with_capacity(16, 0);
+
+    w.append(2, &[2, 3]).unwrap();
+    w.append(6, &[6, 7, 8, 9]).unwrap();
+    w.append(12, &[12, 13]).unwrap();
+    assert_eq!(w.len(), 14);
+
+    // Nothing to trim
+    assert_eq!(w.trim_to_window(14), 0);
+
+    // The range straddling the edge is truncated and the range beyond is dropped
+    assert_eq!(w.trim_to_window(8), 4);
+    assert_eq!(w.filled(), vec![(2, 4), (6, 8)]);
+    assert_eq!(w.len(), 8);
+    assert_eq!(w.remaining(), 8);
+    w.check_invariants();
+
+    assert_eq!(w.append(0, &[0, 1]).unwrap(), Some(vec![0, 1, 2, 3]));
+    assert_eq!(w.append(4, &[4, 5]).unwrap(), Some(vec![4, 5, 6, 7]));
+    assert!(w.is_empty());
+
+    // Dropped bytes can be received again
+    assert_eq!(w.append(8, &[8, 9]).unwrap(), Some(vec![8, 9]));
+}
+
 #[test]
 fn window_append_overflow_overlapped() {
     let mut w = Window::with_capacity(9, 0);
@@ -870,6 +2222,367 @@ fn window_append_overflow_overlapped_2() {
     assert_eq!(w.to_string(), "[9, 10, 11, 12, 13, 14>>, <0, <7, 8]");
 }
 
+#[test]
+fn window_append_straddling_retransmission() {
+    let mut w = Window::with_capacity(16, 0);
+
+    let v = (0..12).into_iter().collect::<Vec<_>>();
+    assert_eq!(w.append(0, &v[..4]).unwrap(), Some(v[..4].to_vec()));
+
+    // Retransmission of bytes 2 to 8, only bytes 4 to 8 are new
+    assert_eq!(w.append(2, &v[2..8]).unwrap(), Some(v[4..8].to_vec()));
+    assert_eq!(w.out_of_window_count(), 0);
+
+    // Retransmission ending exactly at the window is delivered entirely
+    assert_eq!(w.append(4, &v[4..8]).unwrap(), None);
+    assert_eq!(w.out_of_window_count(), 0);
+    assert_eq!(w.duplicate_count(), 1);
+    assert!(w.is_empty());
+
+    // Straddling retransmission completing a buffered range
+    w.append(10, &v[10..12]).unwrap();
+    assert_eq!(w.append(6, &v[6..10]).unwrap(), Some(v[8..12].to_vec()));
+    assert!(w.is_empty());
+
+    // Straddling retransmission across the overflow
+    let mut w = Window::with_capacity(16, u32::MAX - 1);
+    assert_eq!(
+        w.append(u32::MAX - 1, &v[..2]).unwrap(),
+        Some(v[..2].to_vec())
+    );
+    assert_eq!(
+        w.append(u32::MAX - 1, &v[..4]).unwrap(),
+        Some(v[2..4].to_vec())
+    );
+    assert_eq!(w.out_of_window_count(), 0);
+}
+
+#[test]
+fn window_drain_contiguous_with() {
+    let mut w = Window::with_capacity(8, 0);
+    let v = (0..12).into_iter().collect::<Vec<_>>();
+
+    // Nothing to drain before the beginning is filled
+    w.fill(2, &v[2..4]).unwrap();
+    assert_eq!(w.drain_contiguous_with(|_| unreachable!()), 0);
+
+    w.fill(0, &v[..2]).unwrap();
+    assert_eq!(w.len(), 4);
+
+    // Partial consumption leaves the rest in the window
+    let mut drained = Vec::new();
+    let n = w.drain_contiguous_with(|b| {
+        drained.extend_from_slice(&b[..1]);
+        1
+    });
+    assert_eq!(n, 1);
+    assert_eq!(w.sequence(), 1);
+    assert_eq!(w.len(), 3);
+    assert_eq!(w.filled(), vec![(1, 4)]);
+
+    // Across the end of the buffer, handed in 2 slices
+    w.fill(4, &v[4..9]).unwrap();
+    let mut slices = 0;
+    let n = w.drain_contiguous_with(|b| {
+        slices += 1;
+        drained.extend_from_slice(b);
+        b.len()
+    });
+    assert_eq!(n, 8);
+    assert_eq!(slices, 2);
+    assert_eq!(drained, v[..9].to_vec());
+    assert!(w.is_empty());
+
+    // Appending still pops
+    w.fill(10, &v[10..12]).unwrap();
+    assert_eq!(w.append(9, &v[9..10]).unwrap(), Some(v[9..12].to_vec()));
+    assert_eq!(w.drain_contiguous_with(|b| b.len()), 0);
+}
+
+#[test]
+fn window_deliver_up_to() {
+    let mut w = Window::with_capacity(8, 0);
+    let v = (0..16).into_iter().collect::<Vec<_>>();
+
+    // Nothing to deliver before the beginning is filled
+    w.fill(2, &v[2..6]).unwrap();
+    assert_eq!(w.deliver_up_to(4), None);
+
+    w.fill(0, &v[..2]).unwrap();
+    assert_eq!(w.deliver_up_to(0), None);
+    assert_eq!(w.deliver_up_to(4), Some(v[..4].to_vec()));
+    assert_eq!(w.sequence(), 4);
+    assert_eq!(w.filled(), vec![(4, 6)]);
+
+    // Across the end of the buffer, in chunks
+    w.fill(6, &v[6..12]).unwrap();
+    assert_eq!(w.deliver_up_to(3), Some(v[4..7].to_vec()));
+    w.fill(13, &v[13..14]).unwrap();
+    assert_eq!(w.deliver_up_to(3), Some(v[7..10].to_vec()));
+    assert_eq!(w.sequence(), 10);
+    assert_eq!(w.filled(), vec![(10, 12), (13, 14)]);
+
+    // Stops at the unfilled range
+    assert_eq!(w.deliver_up_to(8), Some(v[10..12].to_vec()));
+    assert_eq!(w.deliver_up_to(8), None);
+    assert_eq!(w.append(12, &v[12..13]).unwrap(), Some(v[12..14].to_vec()));
+    assert!(w.is_empty());
+}
+
+#[test]
+fn window_from_contiguous() {
+    let v = (0..8).into_iter().collect::<Vec<_>>();
+
+    let mut w = Window::from_contiguous(8, 100, &v[..4]).unwrap();
+    assert_eq!(w.len(), 4);
+    assert_eq!(w.allocated(), 4);
+    assert_eq!(w.filled(), vec![(100, 104)]);
+    assert_eq!(w.next_missing(), 104);
+
+    // The bytes are popped in the next appending
+    assert_eq!(w.append(106, &v[6..]).unwrap(), Some(v[..4].to_vec()));
+    assert_eq!(w.append(104, &v[4..6]).unwrap(), Some(v[4..].to_vec()));
+    assert!(w.is_empty());
+
+    let mut w = Window::from_contiguous(8, 100, &v[..4]).unwrap();
+    assert_eq!(w.drain_contiguous_with(|b| b.len()), 4);
+    assert_eq!(w.sequence(), 104);
+
+    assert!(Window::from_contiguous(8, 0, &[]).unwrap().is_empty());
+    assert!(Window::from_contiguous(4, 0, &v).is_err());
+}
+
+#[test]
+fn window_advance() {
+    let mut w = Window::with_capacity(9, 0);
+
+    let v = (4..6).into_iter().collect::<Vec<_>>();
+    w.append(4, v.as_slice()).unwrap();
+
+    let v = (8..9).into_iter().collect::<Vec<_>>();
+    w.append(8, v.as_slice()).unwrap();
+
+    let (size, payload) = w.advance().unwrap();
+    assert_eq!(size, 4);
+    assert_eq!(payload, vec![4, 5]);
+    assert_eq!(w.sequence(), 6);
+
+    let (size, payload) = w.advance().unwrap();
+    assert_eq!(size, 2);
+    assert_eq!(payload, vec![8]);
+    assert_eq!(w.sequence(), 9);
+
+    assert!(w.advance().is_none());
+    assert!(w.is_empty());
+}
+
+#[test]
+fn window_advance_overflow() {
+    let mut w = Window::with_capacity(16, u32::MAX - 4);
+
+    let v = (0..2).into_iter().collect::<Vec<_>>();
+    w.append(2, v.as_slice()).unwrap();
+
+    // The sequence wraps around to 0 after u32::MAX
+    let (size, payload) = w.advance().unwrap();
+    assert_eq!(size, 7);
+    assert_eq!(payload, vec![0, 1]);
+    assert_eq!(w.sequence(), 4);
+    assert!(w.is_empty());
+}
+
+#[test]
+fn window_append_wrap() {
+    let v = (0..16).into_iter().collect::<Vec<_>>();
+
+    // Start at each sequence around the wrap boundary, and fill the latter half first
+    for i in 0..16 {
+        let sequence = seq_add(u32::MAX - 7, i);
+        let mut w = Window::with_capacity(16, sequence);
+        assert_eq!(w.append(seq_add(sequence, 8), &v[8..]).unwrap(), None);
+        assert_eq!(
+            w.filled(),
+            vec![(seq_add(sequence, 8), seq_add(sequence, 16))]
+        );
+        assert_eq!(w.recv_next(), seq_add(sequence, 16));
+
+        assert_eq!(w.append(sequence, &v[..8]).unwrap(), Some(v.clone()));
+        assert_eq!(w.sequence(), seq_add(sequence, 16));
+        assert!(w.is_empty());
+        w.check_invariants();
+    }
+}
+
+#[test]
+fn window_next_missing() {
+    let mut w = Window::with_capacity(16, u32::MAX - 4);
+    assert_eq!(w.next_missing(), u32::MAX - 4);
+
+    let v = (0..2).into_iter().collect::<Vec<_>>();
+    w.append(u32::MAX - 2, v.as_slice()).unwrap();
+    assert_eq!(w.next_missing(), u32::MAX - 4);
+
+    let v = (0..2).into_iter().collect::<Vec<_>>();
+    w.append(u32::MAX - 4, v.as_slice()).unwrap();
+    assert_eq!(w.next_missing(), u32::MAX);
+    assert!(w.is_empty());
+
+    let v = (0..2).into_iter().collect::<Vec<_>>();
+    w.append(2, v.as_slice()).unwrap();
+    assert_eq!(w.next_missing(), u32::MAX);
+}
+
+#[test]
+fn window_gaps() {
+    let mut w = Window::with_capacity(64, u32::MAX - 15);
+    let v = (0..64).into_iter().collect::<Vec<_>>();
+    assert_eq!(w.gaps(3), vec![]);
+
+    // Three disjoint segments out of order across the wrap of the sequence
+    w.append(8, &v[24..32]).unwrap();
+    w.append(u32::MAX - 7, &v[8..12]).unwrap();
+    w.append(0, &v[16..20]).unwrap();
+    assert_eq!(
+        w.gaps(3),
+        vec![(u32::MAX - 15, u32::MAX - 7), (u32::MAX - 3, 0), (4, 8)]
+    );
+    assert_eq!(w.gaps(1), vec![(u32::MAX - 15, u32::MAX - 7)]);
+
+    // The gaps are closed
+    w.append(u32::MAX - 15, &v[..8]).unwrap();
+    assert_eq!(w.gaps(3), vec![(u32::MAX - 3, 0), (4, 8)]);
+    w.append(u32::MAX - 3, &v[12..16]).unwrap();
+    w.append(4, &v[20..24]).unwrap();
+    assert_eq!(w.gaps(3), vec![]);
+    assert!(w.is_empty());
+}
+
+#[test]
+fn window_append_empty() {
+    let mut w = Window::with_capacity(16, 0);
+
+    // Pure ACKs
+    assert_eq!(w.append(0, &[]).unwrap(), None);
+    assert_eq!(w.append(8, &[]).unwrap(), None);
+    assert!(w.is_empty());
+    assert!(w.filled().is_empty());
+    assert_eq!(w.allocated(), 0);
+    assert_eq!(w.out_of_window_count(), 0);
+
+    let v = (0..8).into_iter().collect::<Vec<_>>();
+    w.append(4, &v[4..]).unwrap();
+    assert_eq!(w.append(0, &[]).unwrap(), None);
+    assert_eq!(w.filled(), vec![(4, 8)]);
+    assert_eq!(w.append(0, &v[..4]).unwrap(), Some(v));
+}
+
+#[test]
+fn window_merge_counts() {
+    let mut w = Window::with_capacity(64, 0);
+    let v = (0..32).into_iter().collect::<Vec<_>>();
+
+    // In order
+    w.append(0, &v[..4]).unwrap();
+    assert_eq!(w.range_count(), 0);
+    assert_eq!(w.peak_range_count(), 0);
+
+    // Out of order
+    w.append(8, &v[8..12]).unwrap();
+    w.append(16, &v[16..20]).unwrap();
+    w.append(24, &v[24..28]).unwrap();
+    assert_eq!(w.range_count(), 3);
+    assert_eq!(w.merge_count(), 0);
+
+    // Adjoining the previous range
+    w.append(12, &v[12..14]).unwrap();
+    assert_eq!(w.range_count(), 3);
+    assert_eq!(w.merge_count(), 1);
+
+    // Bridging 2 ranges
+    w.append(14, &v[14..16]).unwrap();
+    assert_eq!(w.range_count(), 2);
+    assert_eq!(w.merge_count(), 3);
+    assert_eq!(w.peak_range_count(), 3);
+
+    // Filled
+    w.append(4, &v[4..8]).unwrap();
+    w.append(20, &v[20..24]).unwrap();
+    assert_eq!(w.range_count(), 0);
+    assert_eq!(w.peak_range_count(), 3);
+}
+
+#[test]
+fn window_append_contained() {
+    let mut w = Window::with_capacity(64, u32::MAX - 7);
+    let v = (0..32).into_iter().collect::<Vec<_>>();
+
+    // Across the wrap of the sequence
+    w.append(u32::MAX - 3, &v[4..20]).unwrap();
+    let (len, remaining) = (w.len(), w.remaining());
+
+    // A strict sub-slice of the range is a no-op
+    assert_eq!(w.append(2, &[0xff; 8]).unwrap(), None);
+    assert_eq!(w.append(u32::MAX - 3, &[0xff; 16]).unwrap(), None);
+    assert_eq!(w.len(), len);
+    assert_eq!(w.remaining(), remaining);
+    assert_eq!(w.filled(), vec![(u32::MAX - 3, 12)]);
+    assert_eq!(w.merge_count(), 0);
+
+    assert_eq!(
+        w.append(u32::MAX - 7, &v[..4]).unwrap(),
+        Some(v[..20].to_vec())
+    );
+    w.check_invariants();
+}
+
+#[test]
+fn window_append_overlapped() {
+    let mut w = Window::with_capacity(64, 0);
+    let v = (0..48).into_iter().collect::<Vec<_>>();
+    let mut delivered = Vec::new();
+    let mut tail = 0;
+
+    // Segments overlapping, containing or contained in the ranges before
+    for &(begin, end) in &[
+        (8, 16),
+        (10, 12),
+        (8, 16),
+        (6, 20),
+        (30, 40),
+        (32, 34),
+        (28, 42),
+        (35, 36),
+        (18, 31),
+        (4, 5),
+    ] {
+        if let Some(payload) = w.append(begin as u32, &v[begin..end]).unwrap() {
+            delivered.extend(payload);
+        }
+        w.check_invariants();
+        tail = max(tail, end);
+        assert_eq!(w.len(), tail);
+        assert_eq!(w.remaining(), 64 - tail);
+    }
+    assert_eq!(w.range_count(), 2);
+    assert_eq!(w.filled(), vec![(4, 5), (6, 42)]);
+    assert!(delivered.is_empty());
+
+    // Filled from the beginning
+    delivered.extend(w.append(0, &v[..7]).unwrap().unwrap());
+    assert_eq!(delivered, &v[..42]);
+    assert_eq!(w.len(), 0);
+    assert_eq!(w.remaining(), 64);
+
+    // Retransmissions overlapping the bytes delivered
+    w.append(44, &v[44..48]).unwrap();
+    w.append(45, &v[45..46]).unwrap();
+    assert_eq!(w.len(), 6);
+    delivered.extend(w.append(40, &v[40..45]).unwrap().unwrap());
+    assert_eq!(delivered, &v[..48]);
+    assert_eq!(w.len(), 0);
+    w.check_invariants();
+}
+
 #[test]
 fn window_append_prev_and_next() {
     let mut w = Window::with_capacity(8, 0);
@@ -891,4 +2604,349 @@ fn window_append_prev_and_next() {
     w.append(1, v.as_slice()).unwrap();
 
     assert_eq!(w.to_string(), "[0, 1, 2, <0, <4, 5>>]");
+    w.check_invariants();
+}
+
+#[test]
+fn window_admit_fin_after_payload() {
+    let mut w = Window::with_capacity(9, 0);
+
+    // Payload immediately followed by a FIN
+    let v = (0..8).into_iter().collect::<Vec<_>>();
+    assert_eq!(w.append(0, v.as_slice()).unwrap(), Some(v.clone()));
+    assert!(!w.admit_fin(7));
+    assert!(w.admit_fin(8));
+    assert_eq!(w.sequence(), 9);
+    assert!(w.is_empty());
+
+    // The retransmitted payload and FIN are duplicated
+    assert_eq!(w.append(0, v.as_slice()).unwrap(), None);
+    assert!(!w.admit_fin(8));
+    assert_eq!(w.sequence(), 9);
+    w.check_invariants();
+}
+
+#[test]
+fn window_admit_fin_after_gap() {
+    let mut w = Window::with_capacity(9, 0);
+
+    // The FIN arrives before the payload preceding it is filled
+    let v = (4..8).into_iter().collect::<Vec<_>>();
+    assert_eq!(w.append(4, v.as_slice()).unwrap(), None);
+    assert!(!w.admit_fin(8));
+    assert_eq!(w.sequence(), 0);
+
+    let v = (0..4).into_iter().collect::<Vec<_>>();
+    assert_eq!(
+        w.append(0, v.as_slice()).unwrap(),
+        Some((0..8).into_iter().collect::<Vec<_>>())
+    );
+    assert!(w.admit_fin(8));
+    assert_eq!(w.sequence(), 9);
+    w.check_invariants();
+}
+
+#[test]
+fn window_admit_fin_only() {
+    let mut w = Window::with_capacity(9, 100);
+
+    // A FIN without payload
+    assert!(!w.admit_fin(101));
+    assert!(w.admit_fin(100));
+    assert_eq!(w.sequence(), 101);
+    assert_eq!(w.recv_next(), 101);
+    assert!(w.is_empty());
+
+    // Bytes after the FIN are invalid
+    let v = (0..4).into_iter().collect::<Vec<_>>();
+    let mut w = Window::with_capacity(9, 100);
+    assert_eq!(w.append(104, v.as_slice()).unwrap(), None);
+    assert!(w.admit_fin(100));
+    assert!(w.filled().is_empty());
+    assert!(w.is_empty());
+    w.check_invariants();
+}
+
+#[test]
+fn window_pressure() {
+    let mut w = Window::with_capacity(100, 0);
+    assert_eq!(w.soft_limit(), 80);
+    assert_eq!(w.pressure(), Pressure::Low);
+    assert_eq!(w.advertised(), 100);
+
+    // Below the soft limit
+    w.append(10, &[0; 69]).unwrap();
+    assert_eq!(w.pressure(), Pressure::Low);
+    assert_eq!(w.advertised(), 21);
+
+    // Reach the soft limit
+    w.append(79, &[0; 1]).unwrap();
+    assert_eq!(w.pressure(), Pressure::High);
+    assert_eq!(w.advertised(), 20);
+
+    // Shrink ahead of being full
+    w.append(80, &[0; 10]).unwrap();
+    assert_eq!(w.pressure(), Pressure::High);
+    assert_eq!(w.advertised(), 5);
+
+    w.append(90, &[0; 10]).unwrap();
+    assert_eq!(w.pressure(), Pressure::Full);
+    assert_eq!(w.advertised(), 0);
+
+    // Relieve
+    w.append(0, &[0; 10]).unwrap();
+    assert_eq!(w.pressure(), Pressure::Low);
+    assert_eq!(w.advertised(), 100);
+
+    // Configure the soft limit
+    w.set_soft_limit_percent(50);
+    assert_eq!(w.soft_limit(), 50);
+    w.set_soft_limit_percent(200);
+    assert_eq!(w.soft_limit(), 100);
+}
+
+#[test]
+fn window_over_window_lenient() {
+    let mut w = Window::with_capacity(100, 0);
+    assert_eq!(w.over_window_policy(), OverWindowPolicy::Lenient);
+    w.set_advertised(20);
+
+    // Buffer beyond the advertised window
+    assert_eq!(w.append(10, &[1; 20]).unwrap(), None);
+    assert_eq!(w.len(), 30);
+    assert_eq!(w.out_of_window_count(), 0);
+
+    // Fail beyond the capacity
+    assert!(w.append(90, &[1; 20]).is_err());
+    assert_eq!(w.out_of_window_count(), 0);
+}
+
+#[test]
+fn window_over_window_strict() {
+    let mut w = Window::with_capacity(100, 0);
+    w.set_over_window_policy(OverWindowPolicy::Strict);
+    assert_eq!(w.advertised_limit(), 100);
+    w.set_advertised(20);
+    assert_eq!(w.advertised_limit(), 20);
+
+    // Deliver and advertise a window, whose right edge never moves backward
+    assert_eq!(w.append(0, &[1; 10]).unwrap(), Some(vec![1; 10]));
+    assert_eq!(w.advertised_limit(), 10);
+    w.set_advertised(20);
+    assert_eq!(w.advertised_limit(), 20);
+    assert_eq!(w.append(20, &[1; 10]).unwrap(), None);
+    w.set_advertised(5);
+    assert_eq!(w.advertised_limit(), 20);
+
+    // Drop beyond the advertised window
+    assert_eq!(w.append(40, &[1; 10]).unwrap(), None);
+    assert_eq!(w.len(), 20);
+    assert_eq!(w.out_of_window_count(), 1);
+
+    // Truncate straddling the advertised window
+    w.set_advertised(50);
+    assert_eq!(w.advertised_limit(), 50);
+    assert_eq!(w.append(55, &[1; 10]).unwrap(), None);
+    assert_eq!(w.len(), 50);
+    assert_eq!(w.filled(), vec![(20, 30), (55, 60)]);
+    assert_eq!(w.out_of_window_count(), 2);
+
+    // Within the advertised window
+    assert_eq!(w.append(10, &[1; 10]).unwrap(), Some(vec![1; 20]));
+    assert_eq!(w.out_of_window_count(), 2);
+}
+
+/// Represents a reader over a `Window`. Bytes appended to the `WindowReader` are reassembled
+/// in the window, and can be read from it once they are continuous.
+///
+/// The `WindowReader` never blocks: reading returns an error of `ErrorKind::WouldBlock` if no
+/// bytes are continuous, and returns `Ok(0)` only after a FIN is admitted and all the bytes
+/// before it are read. A read may return fewer bytes than the buffer can hold, and the remaining
+/// bytes are kept for the next read.
+#[derive(Debug)]
+pub struct WindowReader {
+    window: Window,
+    ready: VecDeque<u8>,
+    fin: bool,
+}
+
+impl WindowReader {
+    /// Creates a new `WindowReader` over the window.
+    pub fn new(window: Window) -> WindowReader {
+        WindowReader {
+            window,
+            ready: VecDeque::new(),
+            fin: false,
+        }
+    }
+
+    /// Appends some bytes to the window. Continuous bytes from the beginning become readable.
+    pub fn append(&mut self, sequence: u32, payload: &[u8]) -> Result<()> {
+        if self.fin {
+            return Err(Error::new(ErrorKind::InvalidInput, "window is finished"));
+        }
+        if let Some(payload) = self.window.append(sequence, payload)? {
+            self.ready.extend(payload);
+        }
+
+        Ok(())
+    }
+
+    /// Admits a FIN at the given sequence. Returns if the FIN is admitted.
+    pub fn admit_fin(&mut self, sequence: u32) -> bool {
+        if self.window.admit_fin(sequence) {
+            self.fin = true;
+        }
+
+        self.fin
+    }
+
+    /// Returns the size of bytes which can be read without blocking.
+    pub fn readable(&self) -> usize {
+        self.ready.len()
+    }
+
+    /// Returns the reference of the window.
+    pub fn get_ref(&self) -> &Window {
+        &self.window
+    }
+
+    /// Returns the mutable reference of the window.
+    pub fn get_mut(&mut self) -> &mut Window {
+        &mut self.window
+    }
+
+    /// Consumes the `WindowReader` and returns the window. Bytes not read yet are lost.
+    pub fn into_inner(self) -> Window {
+        self.window
+    }
+}
+
+impl Read for WindowReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.ready.is_empty() {
+            return match self.fin {
+                true => Ok(0),
+                false => Err(Error::from(ErrorKind::WouldBlock)),
+            };
+        }
+
+        let size = min(buf.len(), self.ready.len());
+        for (i, b) in self.ready.drain(..size).enumerate() {
+            buf[i] = b;
+        }
+
+        Ok(size)
+    }
+}
+
+/// Represents a writer over a `Queue`. Bytes written to the `QueueWriter` are appended to the
+/// queue with the given RTO.
+///
+/// The `QueueWriter` never blocks: writing returns an error of `ErrorKind::WouldBlock` if the
+/// queue is full. A write may accept fewer bytes than the buffer holds if the queue is nearly
+/// full, and the caller should write the remaining bytes after the queue is invalidated.
+#[derive(Debug)]
+pub struct QueueWriter {
+    queue: Queue,
+    rto: u64,
+}
+
+impl QueueWriter {
+    /// Creates a new `QueueWriter` over the queue.
+    pub fn new(queue: Queue, rto: u64) -> QueueWriter {
+        QueueWriter { queue, rto }
+    }
+
+    /// Sets the RTO of bytes written afterwards.
+    pub fn set_rto(&mut self, rto: u64) {
+        self.rto = rto;
+    }
+
+    /// Returns the size of bytes which can be written without blocking.
+    pub fn writable(&self) -> usize {
+        self.queue.remaining()
+    }
+
+    /// Returns the reference of the queue.
+    pub fn get_ref(&self) -> &Queue {
+        &self.queue
+    }
+
+    /// Returns the mutable reference of the queue.
+    pub fn get_mut(&mut self) -> &mut Queue {
+        &mut self.queue
+    }
+
+    /// Consumes the `QueueWriter` and returns the queue.
+    pub fn into_inner(self) -> Queue {
+        self.queue
+    }
+}
+
+impl Write for QueueWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let size = min(buf.len(), self.queue.remaining());
+        if size == 0 {
+            return Err(Error::from(ErrorKind::WouldBlock));
+        }
+        self.queue.append(&buf[..size], self.rto)?;
+
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn window_reader_read() {
+    let mut r = WindowReader::new(Window::with_capacity(16, 0));
+    let mut buf = [0; 4];
+
+    // Nothing continuous
+    r.append(4, &[4, 5, 6, 7]).unwrap();
+    assert_eq!(r.read(&mut buf).unwrap_err().kind(), ErrorKind::WouldBlock);
+
+    // Partial read
+    r.append(0, &[0, 1, 2, 3]).unwrap();
+    assert_eq!(r.readable(), 8);
+    let mut buf = [0; 6];
+    assert_eq!(r.read(&mut buf).unwrap(), 6);
+    assert_eq!(buf, [0, 1, 2, 3, 4, 5]);
+    assert_eq!(r.read(&mut buf).unwrap(), 2);
+    assert_eq!(buf[..2], [6, 7]);
+    assert_eq!(r.read(&mut buf).unwrap_err().kind(), ErrorKind::WouldBlock);
+
+    // End of file after FIN
+    r.append(8, &[8]).unwrap();
+    assert!(r.admit_fin(9));
+    assert!(r.append(9, &[9]).is_err());
+    assert_eq!(r.read(&mut buf).unwrap(), 1);
+    assert_eq!(r.read(&mut buf).unwrap(), 0);
+}
+
+#[test]
+fn queue_writer_write() {
+    let mut w = QueueWriter::new(Queue::with_capacity(8, 0), 1000);
+
+    // Partial write
+    assert_eq!(w.write(&[0, 1, 2, 3, 4, 5]).unwrap(), 6);
+    assert_eq!(w.write(&[6, 7, 8, 9]).unwrap(), 2);
+    assert_eq!(w.writable(), 0);
+    assert_eq!(w.write(&[8, 9]).unwrap_err().kind(), ErrorKind::WouldBlock);
+    assert_eq!(w.get_ref().get_all(), vec![0, 1, 2, 3, 4, 5, 6, 7]);
+
+    // Write after invalidating
+    w.get_mut().invalidate_to(4);
+    w.write_all(&[8, 9]).unwrap();
+    assert_eq!(w.into_inner().get_all(), vec![4, 5, 6, 7, 8, 9]);
 }
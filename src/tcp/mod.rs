@@ -1,16 +1,26 @@
 //! Support for tracking TCP connections.
 
-use log::trace;
+use log::{debug, trace, warn};
 use std::cmp::{max, min};
 use std::collections::VecDeque;
 use std::fmt::{self, Display};
 use std::net::SocketAddrV4;
+use std::str::FromStr;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::io;
 
-mod cache;
+pub mod cache;
+pub use cache::{OverWindowPolicy, Pressure, SOFT_LIMIT_PERCENT};
 use cache::{Queue, Window};
 
+use crate::digest::{Digest, DigestAlgorithm};
+use crate::mirror::{Mirror, MirrorDirection};
+pub mod pending;
+pub mod scheduler;
+pub mod seq;
+use seq::{seq_add, seq_sub};
+
 /// Represents a timer.
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct Timer {
@@ -52,6 +62,44 @@ pub enum TcpCcAlgorithms {
     Cubic,
 }
 
+/// Represents the strategy of generating initial sequence numbers of TCP connections.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum IsnStrategy {
+    /// Represents a random ISN for each connection, which is hard to predict as RFC 6528
+    /// requires.
+    Random,
+    /// Represents the same ISN for all the connections.
+    Fixed,
+    /// Represents the ISN increasing by a step for each connection.
+    Sequential,
+}
+
+impl Display for IsnStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IsnStrategy::Random => write!(f, "random"),
+            IsnStrategy::Fixed => write!(f, "fixed"),
+            IsnStrategy::Sequential => write!(f, "sequential"),
+        }
+    }
+}
+
+impl FromStr for IsnStrategy {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "random" => Ok(IsnStrategy::Random),
+            "fixed" => Ok(IsnStrategy::Fixed),
+            "sequential" => Ok(IsnStrategy::Sequential),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown ISN strategy {}", s),
+            )),
+        }
+    }
+}
+
 /// Represents the initial slow start threshold rate for congestion window in a TCP connection.
 const INITIAL_SSTHRESH_RATE: usize = 100;
 
@@ -69,8 +117,18 @@ pub trait TcpCc: Send + Sync {
     /// Indicates a TCP fast retransmission event.
     fn fast_retransmission(&mut self);
 
+    /// Indicates a TCP ECN echo event. The congestion window is reduced as a fast
+    /// retransmission.
+    fn congestion_experienced(&mut self) {
+        self.fast_retransmission();
+    }
+
     /// Returns the congestion window of the TCP connection.
     fn cwnd(&self) -> usize;
+
+    /// Returns a copy of the congestion control state, which is restored if a retransmission
+    /// turns out to be spurious.
+    fn clone_box(&self) -> Box<dyn TcpCc>;
 }
 
 /// Represents the TCP Tahoe congestion control state of a TCP connection.
@@ -85,13 +143,13 @@ pub struct TcpTahoeCcState {
 }
 
 impl TcpTahoeCcState {
-    /// Creates a new `TcpTahoeCcState`.
-    pub fn new(src: SocketAddrV4, dst: SocketAddrV4, mss: usize) -> TcpTahoeCcState {
+    /// Creates a new `TcpTahoeCcState` with the initial window in segments.
+    pub fn new(src: SocketAddrV4, dst: SocketAddrV4, mss: usize, iw: usize) -> TcpTahoeCcState {
         TcpTahoeCcState {
             src,
             dst,
             mss,
-            cwnd: mss,
+            cwnd: max(mss, mss.checked_mul(iw).unwrap_or(usize::MAX)),
             ssthresh: mss.checked_mul(INITIAL_SSTHRESH_RATE).unwrap_or(usize::MAX),
             cwnd_count: 0,
         }
@@ -182,6 +240,10 @@ impl TcpCc for TcpTahoeCcState {
     fn cwnd(&self) -> usize {
         self.cwnd
     }
+
+    fn clone_box(&self) -> Box<dyn TcpCc> {
+        Box::new(self.clone())
+    }
 }
 
 impl Display for TcpTahoeCcState {
@@ -206,13 +268,13 @@ pub struct TcpRenoCcState {
 }
 
 impl TcpRenoCcState {
-    /// Creates a new `TcpRenoCcState`.
-    pub fn new(src: SocketAddrV4, dst: SocketAddrV4, mss: usize) -> TcpRenoCcState {
+    /// Creates a new `TcpRenoCcState` with the initial window in segments.
+    pub fn new(src: SocketAddrV4, dst: SocketAddrV4, mss: usize, iw: usize) -> TcpRenoCcState {
         TcpRenoCcState {
             src,
             dst,
             mss,
-            cwnd: mss,
+            cwnd: max(mss, mss.checked_mul(iw).unwrap_or(usize::MAX)),
             ssthresh: mss.checked_mul(INITIAL_SSTHRESH_RATE).unwrap_or(usize::MAX),
             cwnd_count: 0,
         }
@@ -305,6 +367,10 @@ impl TcpCc for TcpRenoCcState {
     fn cwnd(&self) -> usize {
         self.cwnd
     }
+
+    fn clone_box(&self) -> Box<dyn TcpCc> {
+        Box::new(self.clone())
+    }
 }
 
 impl Display for TcpRenoCcState {
@@ -336,8 +402,8 @@ pub struct TcpCubicCcState {
 }
 
 impl TcpCubicCcState {
-    /// Creates a new `TcpCubicCcState`.
-    pub fn new(src: SocketAddrV4, dst: SocketAddrV4, mss: usize) -> TcpCubicCcState {
+    /// Creates a new `TcpCubicCcState` with the initial window in segments.
+    pub fn new(src: SocketAddrV4, dst: SocketAddrV4, mss: usize, iw: usize) -> TcpCubicCcState {
         TcpCubicCcState {
             src,
             dst,
@@ -346,7 +412,7 @@ impl TcpCubicCcState {
             k: 0.0,
             last_update: Instant::now(),
             mss,
-            cwnd: mss,
+            cwnd: max(mss, mss.checked_mul(iw).unwrap_or(usize::MAX)),
             ssthresh: mss.checked_mul(INITIAL_SSTHRESH_RATE).unwrap_or(usize::MAX),
             cwnd_count: 0,
         }
@@ -523,6 +589,10 @@ impl TcpCc for TcpCubicCcState {
     fn cwnd(&self) -> usize {
         self.cwnd
     }
+
+    fn clone_box(&self) -> Box<dyn TcpCc> {
+        Box::new(self.clone())
+    }
 }
 
 impl Display for TcpCubicCcState {
@@ -538,6 +608,21 @@ impl Display for TcpCubicCcState {
 /// Represents the receive window size.
 const RECV_WINDOW: u16 = u16::MAX;
 
+/// Returns the maximum receive window in bytes of a TCP connection with the window scale.
+pub fn max_recv_window(wscale: u8) -> usize {
+    (RECV_WINDOW as usize) << wscale as usize
+}
+
+/// Represents the default maximum count of SACK blocks reported in a TCP segment. The count is
+/// further limited by the space of TCP options.
+pub const MAX_SACKS: usize = 4;
+
+/// Represents the default maximum count of TCP segments received in order before an ACK is
+/// sent.
+pub const ACK_SEGMENTS: usize = 2;
+/// Represents the default maximum delay of an ACK of TCP segments received in order.
+pub const ACK_DELAY: u64 = 200;
+
 /// Represents the maximum size of extra cache in a TCP connection.
 const MAX_QUEUE: usize = 16777216;
 
@@ -554,11 +639,32 @@ const RTO_K: f64 = 4.0;
 const RTO_ALPHA: f64 = 1.0 / 8.0;
 const RTO_BETA: f64 = 1.0 / 4.0;
 
+/// Represents the rate of the SRTT within which an ACK acknowledging retransmitted payload is
+/// recognized as acknowledging the original transmission, which makes the retransmission
+/// spurious.
+const SPURIOUS_RTT_RATE: f64 = 0.5;
+
 /// Represents if the congestion control is enabled.
 const ENABLE_CC: bool = true;
 /// Represents the congestion control algorithm.
 const CC_ALGORITHM: TcpCcAlgorithms = TcpCcAlgorithms::Reno;
 
+/// Represents the maximum count of unfilled ranges skipped in the unordered mode which are
+/// tracked for payload arriving late.
+const MAX_SKIPPED_RANGES: usize = 64;
+
+/// Represents the retransmissions of a TCP connection not acknowledged yet.
+struct TcpRecovery {
+    /// Represents the instant of the latest retransmission.
+    instant: Instant,
+    /// Represents the count of retransmissions.
+    retrans: usize,
+    /// Represents the RTO before the first retransmission.
+    rto: u64,
+    /// Represents the congestion control state before the first retransmission.
+    cc: Option<Box<dyn TcpCc>>,
+}
+
 /// Represents the TX state of a TCP connection.
 pub struct TcpTxState {
     src: SocketAddrV4,
@@ -569,22 +675,66 @@ pub struct TcpTxState {
     sequence: u32,
     acknowledgement: u32,
     window: u16,
+    /// Represents the window advertised in the TCP SYN/ACK, which is never scaled.
+    syn_window: u16,
     sacks: Option<Vec<(u32, u32)>>,
-    delayed_ack: bool,
+    max_sacks: usize,
+    /// Represents the count of segments received in order which are not acknowledged yet.
+    delayed_ack: usize,
+    /// Represents the instant the first segment not acknowledged yet is received.
+    delayed_ack_since: Option<Instant>,
+    /// Represents the window advertised in the last ACK.
+    acked_window: u16,
+    ack_segments: usize,
+    ack_delay: u64,
+    /// Represents the acknowledgement of the last duplicate ACKs and their count.
+    dup_acks: (u32, usize),
+    /// Represents the maximum count of duplicate ACKs of an acknowledgement sent at once.
+    max_dup_acks: Option<usize>,
+    /// Represents the count of duplicate ACKs suppressed.
+    suppressed_acks: usize,
     cache: Queue,
+    /// Represents the count of consecutive failures of appending to the cache for it is full.
+    cache_full: usize,
+    /// Represents the peak size of the cache.
+    peak_cache: usize,
+    /// Represents the size of payload sent, excluding retransmissions.
+    sent: usize,
     cache_syn: Option<Instant>,
     cache_fin: Option<Timer>,
     cache_fin_retrans: bool,
+    /// Represents the count of retransmissions.
+    retrans: usize,
+    /// Represents the count of consecutive retransmissions due to timeout.
+    timedout_retrans: usize,
+    /// Represents the count of retransmissions detected as spurious.
+    spurious_retrans: usize,
+    recovery: Option<TcpRecovery>,
     queue: VecDeque<u8>,
+    /// Represents the sizes of payload in the queue and the instants they are queued.
+    queued: VecDeque<(usize, Instant)>,
+    /// Represents the time the payload last appended to the cache waited in the queue.
+    queue_latency: Option<Duration>,
     queue_fin: bool,
     rto: u64,
     srtt: Option<f64>,
     rttvar: Option<f64>,
     cc: Option<Box<dyn TcpCc>>,
+    dscp: u8,
+    ecn: bool,
+    ece: bool,
+    cwr: bool,
+    ecn_recover: Option<u32>,
+    paused: bool,
+    /// Represents the digest of payload sent, excluding retransmissions.
+    digest: Option<Digest>,
+    mirror: Option<Arc<Mirror>>,
+    /// Represents the instant payload from the proxy is last queued.
+    active: Instant,
 }
 
 impl TcpTxState {
-    /// Creates a new `TcpTxState`.
+    /// Creates a new `TcpTxState` with the initial window in segments.
     pub fn new(
         src: SocketAddrV4,
         dst: SocketAddrV4,
@@ -595,6 +745,7 @@ impl TcpTxState {
         sack_perm: bool,
         wscale: Option<u8>,
         mss: usize,
+        iw: usize,
     ) -> TcpTxState {
         TcpTxState {
             src,
@@ -605,28 +756,59 @@ impl TcpTxState {
             sequence,
             acknowledgement,
             window: RECV_WINDOW,
+            syn_window: RECV_WINDOW,
             sacks: None,
-            delayed_ack: false,
+            max_sacks: MAX_SACKS,
+            delayed_ack: 0,
+            delayed_ack_since: None,
+            acked_window: RECV_WINDOW,
+            ack_segments: ACK_SEGMENTS,
+            ack_delay: ACK_DELAY,
+            dup_acks: (0, 0),
+            max_dup_acks: None,
+            suppressed_acks: 0,
             cache: Queue::with_capacity(
                 (RECV_WINDOW as usize) << wscale.unwrap_or(0) as usize,
                 sequence,
             ),
+            cache_full: 0,
+            peak_cache: 0,
+            sent: 0,
             cache_syn: None,
             cache_fin: None,
             cache_fin_retrans: true,
+            retrans: 0,
+            timedout_retrans: 0,
+            spurious_retrans: 0,
+            recovery: None,
             queue: VecDeque::new(),
+            queued: VecDeque::new(),
+            queue_latency: None,
             queue_fin: false,
             rto: INITIAL_RTO,
             srtt: None,
             rttvar: None,
             cc: match ENABLE_CC {
                 true => match CC_ALGORITHM {
-                    TcpCcAlgorithms::Tahoe => Some(Box::new(TcpTahoeCcState::new(src, dst, mss))),
-                    TcpCcAlgorithms::Reno => Some(Box::new(TcpRenoCcState::new(src, dst, mss))),
-                    TcpCcAlgorithms::Cubic => Some(Box::new(TcpCubicCcState::new(src, dst, mss))),
+                    TcpCcAlgorithms::Tahoe => {
+                        Some(Box::new(TcpTahoeCcState::new(src, dst, mss, iw)))
+                    }
+                    TcpCcAlgorithms::Reno => Some(Box::new(TcpRenoCcState::new(src, dst, mss, iw))),
+                    TcpCcAlgorithms::Cubic => {
+                        Some(Box::new(TcpCubicCcState::new(src, dst, mss, iw)))
+                    }
                 },
                 false => None,
             },
+            dscp: 0,
+            ecn: false,
+            ece: false,
+            cwr: false,
+            ecn_recover: None,
+            paused: false,
+            digest: None,
+            mirror: None,
+            active: Instant::now(),
         }
     }
 
@@ -643,10 +825,7 @@ impl TcpTxState {
 
     /// Adds sequence to the TCP connection.
     pub fn add_sequence(&mut self, n: u32) {
-        self.sequence = self
-            .sequence
-            .checked_add(n)
-            .unwrap_or_else(|| n - (u32::MAX - self.sequence));
+        self.sequence = seq_add(self.sequence, n);
         trace!(
             "add TCP sequence of {} -> {} to {}",
             self.dst,
@@ -657,10 +836,7 @@ impl TcpTxState {
 
     /// Adds acknowledgement to the TCP connection.
     pub fn add_acknowledgement(&mut self, n: u32) {
-        self.acknowledgement = self
-            .acknowledgement
-            .checked_add(n)
-            .unwrap_or_else(|| n - (u32::MAX - self.acknowledgement));
+        self.acknowledgement = seq_add(self.acknowledgement, n);
         trace!(
             "add TCP acknowledgement of {} -> {} to {}",
             self.dst,
@@ -680,38 +856,166 @@ impl TcpTxState {
         );
     }
 
-    /// Sets the SACKs of the TCP connection.
-    pub fn set_sacks(&mut self, sacks: &Vec<(u32, u32)>) {
+    /// Sets the initial window in bytes of the TCP connection, which is advertised before any
+    /// payload is received.
+    pub fn set_initial_window(&mut self, window: usize) {
+        self.syn_window = scale_window(window, 0);
+        self.set_window(scale_window(window, self.src_wscale.unwrap_or(0)));
+    }
+
+    /// Sets the capacity of the cache of the TCP connection, which is limited by the default
+    /// capacity. The cache is recreated, so it takes effect only if the cache is empty.
+    pub fn set_cache_capacity(&mut self, capacity: usize) {
+        if !self.cache.is_empty() {
+            return;
+        }
+        let capacity = min(capacity, self.cache.capacity());
+        self.cache = Queue::with_capacity(capacity, self.cache.sequence());
+    }
+
+    /// Sets if the buffer of the cache of the TCP connection is allocated in advance instead of
+    /// in appending. The cache is recreated, so it takes effect only if the cache is empty.
+    pub fn set_cache_alloc(&mut self, alloc: bool) {
+        if !self.cache.is_empty() {
+            return;
+        }
+        self.cache =
+            Queue::with_capacity_alloc(self.cache.capacity(), self.cache.sequence(), alloc);
+    }
+
+    /// Sets the maximum count of SACK blocks reported of the TCP connection.
+    pub fn set_max_sacks(&mut self, max_sacks: usize) {
+        self.max_sacks = max_sacks;
+    }
+
+    /// Sets the ACK frequency of the TCP connection, which is the maximum count of segments
+    /// received in order before an ACK is sent, and the maximum delay in milliseconds of the ACK.
+    pub fn set_ack_frequency(&mut self, segments: usize, delay: u64) {
+        self.ack_segments = segments;
+        self.ack_delay = delay;
+    }
+
+    /// Sets the maximum count of duplicate ACKs of an acknowledgement sent at once of the TCP
+    /// connection. `None` represents each segment out of order is acknowledged at once.
+    pub fn set_max_dup_acks(&mut self, max_dup_acks: Option<usize>) {
+        self.max_dup_acks = max_dup_acks;
+    }
+
+    /// Sets the DSCP of the TCP connection.
+    pub fn set_dscp(&mut self, dscp: u8) {
+        self.dscp = dscp;
+        trace!("set TCP DSCP of {} -> {} to {}", self.dst, self.src, dscp);
+    }
+
+    /// Sets if sending payload from the queue of the TCP connection is paused. Payload keeps
+    /// being queued while it is paused.
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        trace!(
+            "set TCP paused of {} -> {} to {}",
+            self.dst,
+            self.src,
+            paused
+        );
+    }
+
+    /// Sets the mirror of the TCP connection, to which payload sent is teed, excluding
+    /// retransmissions.
+    pub fn set_mirror(&mut self, mirror: Arc<Mirror>) {
+        self.mirror = Some(mirror);
+    }
+
+    /// Enables the digest of payload sent of the TCP connection.
+    pub fn enable_digest(&mut self, algorithm: DigestAlgorithm) {
+        self.digest = Some(Digest::new(algorithm));
+    }
+
+    /// Enables the ECN of the TCP connection.
+    pub fn enable_ecn(&mut self) {
+        self.ecn = true;
+        trace!("enable TCP ECN of {} -> {}", self.dst, self.src);
+    }
+
+    /// Sets if the TCP ECN echo should be sent of the TCP connection.
+    pub fn set_ece(&mut self, ece: bool) {
+        if self.ece != ece {
+            self.ece = ece;
+            trace!(
+                "set TCP ECN echo of {} -> {} to {}",
+                self.dst,
+                self.src,
+                ece
+            );
+        }
+    }
+
+    /// Admits a TCP ECN echo of the TCP connection. The congestion window will be reduced at
+    /// most once per window of data.
+    pub fn admit_ece(&mut self, acknowledgement: u32) {
+        if let Some(recover) = self.ecn_recover {
+            if seq_sub(recover, acknowledgement) as usize <= MAX_U32_WINDOW_SIZE
+                && recover != acknowledgement
+            {
+                return;
+            }
+        }
+
+        if let Some(cc) = &mut self.cc {
+            cc.congestion_experienced();
+        }
+        // The congestion is real even if the retransmission is spurious, so it is not undone
+        if let Some(TcpRecovery { cc: Some(cc), .. }) = &mut self.recovery {
+            cc.congestion_experienced();
+        }
+        self.ecn_recover = Some(self.sequence);
+        self.cwr = true;
+        trace!("admit TCP ECN echo of {} -> {}", self.dst, self.src);
+    }
+
+    /// Clears the TCP congestion window reduced of the TCP connection.
+    pub fn clear_cwr(&mut self) {
+        self.cwr = false;
+        trace!(
+            "clear TCP congestion window reduced of {} -> {}",
+            self.dst,
+            self.src
+        );
+    }
+
+    /// Sets the SACKs of the TCP connection from the filled ranges of the receive window, where
+    /// the latest is the sequence of the segment received most recently. At most the maximum
+    /// count of SACK blocks are selected as RFC 2018 suggests: the block containing the latest
+    /// sequence first, then the blocks reported before, and finally other blocks in order.
+    pub fn set_sacks(&mut self, sacks: &[(u32, u32)], latest: u32) {
+        let prev_sacks = self.sacks.take().unwrap_or_default();
+        let sacks = select_sacks(sacks, latest, &prev_sacks, self.max_sacks);
         if sacks.is_empty() {
-            self.sacks = None;
             trace!("remove TCP SACK of {} -> {}", self.dst, self.src);
         } else {
-            let size = min(4, sacks.len());
-            self.sacks = Some(Vec::from(&sacks[..size]));
-
             let mut desc = format!("[{}, {}]", sacks[0].0, sacks[0].1);
             if sacks.len() > 1 {
                 desc.push_str(format!(" and {} more", sacks.len() - 1).as_str());
             }
             trace!("set TCP SACK of {} -> {} to {}", self.dst, self.src, desc);
+
+            self.sacks = Some(sacks);
         }
     }
 
     /// Acknowledges to the given sequence of the TCP connection.
     pub fn acknowledge(&mut self, sequence: u32) {
         let mut rtt = None;
+        // The FIN follows all the payload in the cache
+        let fin_sequence = self.cache.recv_next();
 
         // SYN
         if let Some(instant) = self.cache_syn {
             let send_next = self.sequence;
-            if sequence
-                .checked_sub(send_next)
-                .unwrap_or_else(|| sequence + (u32::MAX - send_next)) as usize
-                <= MAX_U32_WINDOW_SIZE
-            {
+            if seq_sub(sequence, send_next) as usize <= MAX_U32_WINDOW_SIZE {
                 rtt = Some(instant.elapsed());
 
                 self.cache_syn = None;
+                self.timedout_retrans = 0;
                 trace!("acknowledge TCP SYN of {} -> {}", self.dst, self.src);
 
                 // Update TCP sequence
@@ -720,15 +1024,15 @@ impl TcpTxState {
         }
 
         // ACK
-        let sub_sequence = sequence
-            .checked_sub(self.cache.sequence())
-            .unwrap_or_else(|| sequence + (u32::MAX - self.cache.sequence()));
+        let sub_sequence = seq_sub(sequence, self.cache.sequence());
         if sub_sequence > 0 && sub_sequence as usize <= MAX_U32_WINDOW_SIZE {
             // Invalidate cache
             let cache_rtt = self.cache.invalidate_to(sequence);
+            self.timedout_retrans = 0;
             if rtt.is_none() {
                 rtt = cache_rtt;
             }
+            self.detect_spurious_retrans();
             trace!(
                 "acknowledge TCP cache of {} -> {} to sequence {}",
                 self.dst,
@@ -745,20 +1049,19 @@ impl TcpTxState {
             }
         }
 
-        // FIN
+        // FIN, which consumes a sequence, so it is acknowledged only if the acknowledgement is
+        // beyond the FIN sequence
         if let Some(timer) = self.cache_fin {
-            if sequence
-                .checked_sub(self.cache.recv_next())
-                .unwrap_or_else(|| sequence + (u32::MAX - self.cache.recv_next()))
-                as usize
-                <= MAX_U32_WINDOW_SIZE
-            {
+            let sub_sequence = seq_sub(sequence, fin_sequence);
+            if sub_sequence > 0 && sub_sequence as usize <= MAX_U32_WINDOW_SIZE {
                 if rtt.is_none() && !self.cache_fin_retrans && !timer.is_timedout() {
                     rtt = Some(timer.elapsed());
                 }
 
                 self.cache_fin = None;
                 self.cache_fin_retrans = false;
+                self.timedout_retrans = 0;
+                self.detect_spurious_retrans();
                 trace!("acknowledge TCP FIN of {} -> {}", self.dst, self.src);
 
                 // Update TCP sequence
@@ -787,20 +1090,66 @@ impl TcpTxState {
         trace!("update TCP FIN timer of {} -> {}", self.dst, self.src);
     }
 
-    /// Set the TCP delayed ACK to the cache of the TCP connection.
-    pub fn set_delayed_ack(&mut self) {
-        self.delayed_ack = true;
+    /// Delays the TCP ACK of a segment received in order of the TCP connection, which is going
+    /// to advertise the window. Returns if the ACK should be sent now instead, which is when the
+    /// count of segments not acknowledged reaches the maximum, or the window is a window update.
+    /// A window update is the window closing, reopening, or growing by at least the half of the
+    /// max window.
+    pub fn delay_ack(&mut self, window: u16) -> bool {
+        let is_update = (window == 0) != (self.acked_window == 0)
+            || window.saturating_sub(self.acked_window) >= self.half_max_window();
 
+        self.delayed_ack = self.delayed_ack.checked_add(1).unwrap_or(usize::MAX);
+        if self.delayed_ack_since.is_none() {
+            self.delayed_ack_since = Some(Instant::now());
+        }
         trace!(
-            "set TCP delayed ACK to TCP cache of {} -> {}",
+            "set TCP delayed ACK to TCP cache of {} -> {} for {} segments",
             self.dst,
-            self.src
+            self.src,
+            self.delayed_ack
         );
+
+        is_update || self.delayed_ack >= self.ack_segments
     }
 
-    /// Clears the TCP delayed ACK from the cache of the TCP connection.
-    pub fn clear_delayed_ack(&mut self) {
-        self.delayed_ack = false;
+    /// Admits a duplicate ACK for a segment out of order or a retransmission of the TCP
+    /// connection. Returns if the ACK should be sent now, which is until the count of duplicate
+    /// ACKs of the acknowledgement reaches the maximum. Beyond it, the ACK is suppressed and
+    /// coalesced into a delayed ACK sent after the maximum delay.
+    pub fn admit_dup_ack(&mut self) -> bool {
+        if self.dup_acks.0 != self.acknowledgement {
+            self.dup_acks = (self.acknowledgement, 0);
+        }
+        self.dup_acks.1 = self.dup_acks.1.checked_add(1).unwrap_or(usize::MAX);
+
+        match self.max_dup_acks {
+            Some(max_dup_acks) if self.dup_acks.1 > max_dup_acks => {
+                self.suppressed_acks = self.suppressed_acks.checked_add(1).unwrap_or(usize::MAX);
+                self.delayed_ack = max(self.delayed_ack, 1);
+                if self.delayed_ack_since.is_none() {
+                    self.delayed_ack_since = Some(Instant::now());
+                }
+                trace!(
+                    "suppress TCP duplicate ACK of {} -> {} at {} ({} duplicates)",
+                    self.dst,
+                    self.src,
+                    self.acknowledgement,
+                    self.dup_acks.1
+                );
+
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Clears the TCP delayed ACK from the cache of the TCP connection after an ACK advertising
+    /// the window is sent.
+    pub fn clear_delayed_ack(&mut self, window: u16) {
+        self.delayed_ack = 0;
+        self.delayed_ack_since = None;
+        self.acked_window = window;
 
         trace!(
             "clear TCP delayed ACK to TCP cache of {} -> {}",
@@ -813,6 +1162,23 @@ impl TcpTxState {
     pub fn append_cache(&mut self, size: usize) -> io::Result<Vec<u8>> {
         let payload = self.queue.drain(..size).collect::<Vec<_>>();
 
+        // The payload waits in the queue since its earliest bytes are queued
+        self.queue_latency = self.queued.front().map(|(_, instant)| instant.elapsed());
+        let mut remaining = payload.len();
+        while remaining > 0 {
+            match self.queued.front_mut() {
+                Some((size, _)) if *size <= remaining => {
+                    remaining -= *size;
+                    self.queued.pop_front();
+                }
+                Some((size, _)) => {
+                    *size -= remaining;
+                    remaining = 0;
+                }
+                None => break,
+            }
+        }
+
         // Append to cache
         trace!(
             "append {} Bytes to TCP cache of {} -> {}",
@@ -820,8 +1186,38 @@ impl TcpTxState {
             self.dst,
             self.src
         );
+        // Reserve the cache for the payload queued the source window can take at once, which
+        // avoids reallocating the cache repeatedly in a bulk transfer
+        self.cache
+            .reserve(payload.len() + min(self.queue.len(), self.src_window));
         // TODO: intermediate performance degradation
-        self.cache.append(&payload, self.rto)?;
+        if let Err(e) = self.cache.append(&payload, self.rto) {
+            self.cache_full = self.cache_full.checked_add(1).unwrap_or(usize::MAX);
+
+            return Err(io::Error::new(
+                e.kind(),
+                format!(
+                    "TCP send cache of {} -> {}: {} ({}/{} Bytes, {} times)",
+                    self.dst,
+                    self.src,
+                    e,
+                    self.cache.len(),
+                    self.cache.capacity(),
+                    self.cache_full
+                ),
+            ));
+        }
+        self.cache_full = 0;
+        self.peak_cache = max(self.peak_cache, self.cache.len());
+        self.sent = self.sent.checked_add(payload.len()).unwrap_or(usize::MAX);
+        if let Some(ref mut digest) = self.digest {
+            digest.update(&payload);
+        }
+        if let Some(ref mirror) = self.mirror {
+            if let Err(ref e) = mirror.write(MirrorDirection::Out, self.src, self.dst, &payload) {
+                warn!("mirror TCP {} -> {}: {}", self.dst, self.src, e);
+            }
+        }
 
         Ok(payload)
     }
@@ -841,6 +1237,10 @@ impl TcpTxState {
     pub fn append_queue(&mut self, payload: &[u8]) {
         // TODO: major performance degradation
         self.queue.extend(payload);
+        if !payload.is_empty() {
+            self.queued.push_back((payload.len(), Instant::now()));
+            self.active = Instant::now();
+        }
         trace!(
             "append {} Bytes to TCP queue of {} -> {}",
             payload.len(),
@@ -868,6 +1268,60 @@ impl TcpTxState {
         }
     }
 
+    /// Increases the count of retransmissions of the TCP connection. This method should be
+    /// called before the RTO and the congestion window are updated for the retransmission, so
+    /// they can be restored if the retransmission turns out to be spurious.
+    pub fn increase_retrans(&mut self, is_timedout: bool) {
+        self.retrans = self.retrans.checked_add(1).unwrap_or(usize::MAX);
+        if is_timedout {
+            self.timedout_retrans = self.timedout_retrans.checked_add(1).unwrap_or(usize::MAX);
+        }
+
+        let rto = self.rto;
+        let cc = &self.cc;
+        let recovery = self.recovery.get_or_insert_with(|| TcpRecovery {
+            instant: Instant::now(),
+            retrans: 0,
+            rto,
+            cc: cc.as_ref().map(|cc| cc.clone_box()),
+        });
+        recovery.instant = Instant::now();
+        recovery.retrans = recovery.retrans.checked_add(1).unwrap_or(usize::MAX);
+    }
+
+    /// Detects if the retransmissions are spurious when they are acknowledged, like the Eifel
+    /// detection algorithm (RFC 3522) but without timestamps. An ACK arriving sooner than a rate
+    /// of the SRTT after the latest retransmission can hardly acknowledge the retransmission, so
+    /// it acknowledges the original transmission, which was reordered or delayed instead of
+    /// lost. The RTO and the congestion window reduced for the retransmissions are restored as
+    /// the Eifel response algorithm (RFC 4015).
+    fn detect_spurious_retrans(&mut self) {
+        if let Some(recovery) = self.recovery.take() {
+            let srtt = match self.srtt {
+                Some(srtt) => srtt,
+                None => return,
+            };
+            if recovery.instant.elapsed().as_secs_f64() >= srtt * SPURIOUS_RTT_RATE {
+                return;
+            }
+
+            self.spurious_retrans = self
+                .spurious_retrans
+                .checked_add(recovery.retrans)
+                .unwrap_or(usize::MAX);
+            self.rto = recovery.rto;
+            if recovery.cc.is_some() {
+                self.cc = recovery.cc;
+            }
+            trace!(
+                "detect spurious TCP retransmission of {} -> {}, restore RTO to {}",
+                self.dst,
+                self.src,
+                self.rto
+            );
+        }
+    }
+
     /// Doubles the RTO of the TCP connection.
     pub fn double_rto(&mut self) {
         self.set_rto(self.rto.checked_mul(2).unwrap_or(u64::MAX));
@@ -947,6 +1401,11 @@ impl TcpTxState {
         self.window
     }
 
+    /// Returns the window of the TCP connection advertised in the TCP SYN/ACK.
+    pub fn syn_window(&self) -> u16 {
+        self.syn_window
+    }
+
     /// Returns the half of the max window of the TCP connection.
     pub fn half_max_window(&self) -> u16 {
         RECV_WINDOW / 2
@@ -959,7 +1418,20 @@ impl TcpTxState {
 
     /// Returns if the TCP delayed ACK exists of the TCP connection.
     pub fn delayed_ack(&self) -> bool {
-        self.delayed_ack
+        self.delayed_ack > 0
+    }
+
+    /// Returns the count of duplicate ACKs suppressed of the TCP connection.
+    pub fn suppressed_acks(&self) -> usize {
+        self.suppressed_acks
+    }
+
+    /// Returns if the TCP delayed ACK of the TCP connection is delayed for the maximum delay.
+    pub fn is_delayed_ack_timedout(&self) -> bool {
+        match self.delayed_ack_since {
+            Some(since) => since.elapsed().as_millis() >= self.ack_delay as u128,
+            None => false,
+        }
     }
 
     /// Returns the cache of the TCP connection.
@@ -972,6 +1444,60 @@ impl TcpTxState {
         &mut self.cache
     }
 
+    /// Returns the count of consecutive failures of appending to the cache of the TCP connection
+    /// for it is full.
+    pub fn cache_full(&self) -> usize {
+        self.cache_full
+    }
+
+    /// Returns the peak size of the cache of the TCP connection.
+    pub fn peak_cache(&self) -> usize {
+        self.peak_cache
+    }
+
+    /// Returns the time the payload last appended to the cache of the TCP connection waited in
+    /// the queue, which is measured from the earliest bytes of the payload are queued.
+    pub fn queue_latency(&self) -> Option<Duration> {
+        self.queue_latency
+    }
+
+    /// Returns the size of payload sent of the TCP connection, excluding retransmissions.
+    pub fn sent(&self) -> usize {
+        self.sent
+    }
+
+    /// Returns the digest of payload sent of the TCP connection, excluding retransmissions.
+    pub fn digest(&self) -> Option<&Digest> {
+        self.digest.as_ref()
+    }
+
+    /// Returns the duration since payload from the proxy is last queued of the TCP connection.
+    pub fn idle(&self) -> Duration {
+        self.idle_at(Instant::now())
+    }
+
+    /// Returns the duration from payload from the proxy is last queued of the TCP connection to
+    /// the given instant.
+    pub fn idle_at(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.active)
+    }
+
+    /// Returns the count of retransmissions of the TCP connection.
+    pub fn retrans(&self) -> usize {
+        self.retrans
+    }
+
+    /// Returns the count of retransmissions detected as spurious of the TCP connection, which
+    /// indicates reordering rather than loss on the path.
+    pub fn spurious_retrans(&self) -> usize {
+        self.spurious_retrans
+    }
+
+    /// Returns the count of consecutive retransmissions due to timeout of the TCP connection.
+    pub fn timedout_retrans(&self) -> usize {
+        self.timedout_retrans
+    }
+
     /// Returns the TCP SYN in the cache of the TCP connection.
     pub fn cache_syn(&self) -> Option<Instant> {
         self.cache_syn
@@ -992,6 +1518,13 @@ impl TcpTxState {
         self.queue_fin
     }
 
+    /// Returns if the TCP FIN in the queue of the TCP connection is ready to be sent, which is
+    /// after all the payload queued before it is sent and acknowledged, so the payload is never
+    /// truncated by the FIN.
+    pub fn is_fin_ready(&self) -> bool {
+        self.queue_fin && self.queue.is_empty() && self.cache.is_empty() && !self.paused
+    }
+
     /// Returns the remaining size of the queue of the TCP connection.
     pub fn queue_remaining(&self) -> usize {
         MAX_QUEUE.checked_sub(self.queue().len()).unwrap_or(0)
@@ -1017,6 +1550,31 @@ impl TcpTxState {
         &mut self.cc
     }
 
+    /// Returns the DSCP of the TCP connection.
+    pub fn dscp(&self) -> u8 {
+        self.dscp
+    }
+
+    /// Returns if sending payload from the queue of the TCP connection is paused.
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Returns if the ECN is enabled of the TCP connection.
+    pub fn ecn(&self) -> bool {
+        self.ecn
+    }
+
+    /// Returns if the TCP ECN echo should be sent of the TCP connection.
+    pub fn ece(&self) -> bool {
+        self.ece
+    }
+
+    /// Returns if the TCP congestion window reduced should be sent of the TCP connection.
+    pub fn cwr(&self) -> bool {
+        self.cwr
+    }
+
     /// Returns the send window of the TCP connection. The send window is the minimum one between
     /// the congestion window and the source window.
     pub fn send_window(&self) -> usize {
@@ -1046,7 +1604,40 @@ pub struct TcpRxState {
     wscale: u8,
     sack_perm: bool,
     cache: Window,
+    /// Represents the count of consecutive failures of appending to the cache for it is full.
+    cache_full: usize,
+    /// Represents the peak size of the cache.
+    peak_cache: usize,
+    cache_unfilled: Option<Instant>,
+    /// Represents the capture instant of the earliest payload held in the cache.
+    held_since: Option<Instant>,
     fin_sequence: Option<u32>,
+    /// Represents the continuous payload held instead of being delivered while the TCP connection
+    /// is paused, `None` represents the connection is not paused.
+    paused: Option<Vec<u8>>,
+    unordered: bool,
+    /// Represents the time the payload after an unfilled range is held before the range is
+    /// skipped, `None` represents the range is never skipped.
+    reassembly_deadline: Option<Duration>,
+    /// Represents the unfilled ranges skipped in the unordered mode, in the form of (sequence,
+    /// end).
+    skipped: Vec<(u32, u32)>,
+    /// Represents the instant the current interval of counting TCP segments out of the window
+    /// begins, and the count of these segments before the interval.
+    out_of_window_since: Option<(Instant, usize)>,
+    /// Represents the instant the receive next last advances, and the count of TCP segments out
+    /// of the window at the instant.
+    progress: (Instant, usize),
+    desynced: bool,
+    /// Represents the instant payload from the source is last delivered.
+    active: Instant,
+    /// Represents the size of payload delivered from the source.
+    delivered: usize,
+    /// Represents the instant payload is first buffered behind an unfilled range, which blocks
+    /// delivering it, `None` represents the connection is not head-of-line blocked.
+    hol_blocked_since: Option<Instant>,
+    /// Represents the total duration of the head-of-line blocking ended.
+    hol_blocked: Duration,
 }
 
 impl TcpRxState {
@@ -1058,7 +1649,7 @@ impl TcpRxState {
         wscale: u8,
         sack_perm: bool,
     ) -> TcpRxState {
-        let recv_next = sequence.checked_add(1).unwrap_or(0);
+        let recv_next = seq_add(sequence, 1);
 
         trace!("admit TCP SYN of {} -> {}", src, dst);
 
@@ -1072,16 +1663,33 @@ impl TcpRxState {
             wscale,
             sack_perm,
             cache: Window::with_capacity((RECV_WINDOW as usize) << wscale as usize, recv_next),
+            cache_full: 0,
+            peak_cache: 0,
+            cache_unfilled: None,
+            held_since: None,
             fin_sequence: None,
+            paused: None,
+            unordered: false,
+            reassembly_deadline: None,
+            skipped: Vec::new(),
+            out_of_window_since: None,
+            progress: (Instant::now(), 0),
+            desynced: false,
+            active: Instant::now(),
+            delivered: 0,
+            hol_blocked_since: None,
+            hol_blocked: Duration::from_secs(0),
         }
     }
 
     /// Adds receive next to the TCP connection.
     pub fn add_recv_next(&mut self, n: u32) {
-        self.recv_next = self
-            .recv_next
-            .checked_add(n)
-            .unwrap_or_else(|| n - (u32::MAX - self.recv_next));
+        self.recv_next = seq_add(self.recv_next, n);
+        if n > 0 {
+            self.progress = (Instant::now(), self.cache.out_of_window_count());
+            self.desynced = false;
+            self.active = Instant::now();
+        }
         trace!(
             "add TCP receive next of {} -> {} to {}",
             self.src,
@@ -1090,9 +1698,20 @@ impl TcpRxState {
         );
     }
 
-    /// Admits the acknowledgement of the TCP connection.
-    pub fn admit(&mut self, acknowledgement: u32) {
+    /// Adds the size of payload delivered from the source of the TCP connection.
+    pub fn add_delivered(&mut self, n: usize) {
+        self.delivered = self.delivered.checked_add(n).unwrap_or(usize::MAX);
+    }
+
+    /// Admits the acknowledgement of the TCP connection. An acknowledgement equal to the last
+    /// one is counted as a duplicate only if it may be a duplicate ACK defined in RFC 5681, which
+    /// carries no payload and no window update while payload is in flight. A new acknowledgement
+    /// clears the count of duplicates.
+    pub fn admit(&mut self, acknowledgement: u32, is_duplicate: bool) {
         if self.acknowledgement == acknowledgement {
+            if !is_duplicate {
+                return;
+            }
             self.duplicate = self.duplicate.checked_add(1).unwrap_or(usize::MAX);
             trace!(
                 "increase TCP duplicate of {} -> {} at {} to {}",
@@ -1139,6 +1758,20 @@ impl TcpRxState {
         self.update_last_retrans();
     }
 
+    /// Returns if the TCP connection should fast retransmit, which is when the count of
+    /// duplicate acknowledgements reaches the threshold, and no retransmission is admitted in the
+    /// cool down time in milliseconds.
+    pub fn is_fast_retrans(&self, threshold: usize, cool_down: u128) -> bool {
+        if self.duplicate < threshold {
+            return false;
+        }
+
+        match self.last_retrans {
+            Some(ref instant) => instant.elapsed().as_millis() >= cool_down,
+            None => true,
+        }
+    }
+
     /// Appends the payload to the cache of the TCP connection.
     pub fn append_cache(&mut self, sequence: u32, payload: &[u8]) -> io::Result<Option<Vec<u8>>> {
         trace!(
@@ -1147,11 +1780,303 @@ impl TcpRxState {
             self.src,
             self.dst
         );
-        self.cache.append(sequence, payload)
+        let pressure = self.cache.pressure();
+        let result = match self.fin_sequence {
+            Some(fin_sequence) => self
+                .cache
+                .append_before_fin(sequence, payload, fin_sequence),
+            None => self.cache.append(sequence, payload),
+        };
+        let cont_payload = match result {
+            Ok(cont_payload) => cont_payload,
+            Err(e) => {
+                self.cache_full = self.cache_full.checked_add(1).unwrap_or(usize::MAX);
+
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!(
+                        "TCP receive cache of {} -> {}: {} ({}/{} Bytes, {} times)",
+                        self.src,
+                        self.dst,
+                        e,
+                        self.cache.len(),
+                        self.cache.capacity(),
+                        self.cache_full
+                    ),
+                ));
+            }
+        };
+        self.cache_full = 0;
+        self.peak_cache = max(self.peak_cache, self.cache.len());
+        if self.cache.pressure() != pressure {
+            trace!(
+                "set TCP receive cache pressure of {} -> {} to {:?}",
+                self.src,
+                self.dst,
+                self.cache.pressure()
+            );
+        }
+
+        // Update unfilled timer
+        if self.cache.is_empty() {
+            self.cache_unfilled = None;
+        } else if self.cache_unfilled.is_none() || cont_payload.is_some() {
+            self.cache_unfilled = Some(Instant::now());
+        }
+
+        Ok(cont_payload)
+    }
+
+    /// Updates the head-of-line blocking of the TCP connection by whether payload is buffered
+    /// behind an unfilled range in the cache, which cannot be delivered until the range is
+    /// filled. Returns the duration of the blocking if it ends. Payload is never blocked in the
+    /// unordered mode.
+    pub fn update_hol_blocked(&mut self) -> Option<Duration> {
+        match self.hol_blocked_since {
+            Some(instant) if self.cache.is_empty() || self.unordered => {
+                let duration = instant.elapsed();
+                self.hol_blocked_since = None;
+                self.hol_blocked += duration;
+                trace!(
+                    "unblock TCP of {} -> {} after {} ms",
+                    self.src,
+                    self.dst,
+                    duration.as_millis()
+                );
+
+                Some(duration)
+            }
+            None if !self.cache.is_empty() && !self.unordered => {
+                self.hol_blocked_since = Some(Instant::now());
+                trace!(
+                    "block TCP of {} -> {} at {}",
+                    self.src,
+                    self.dst,
+                    self.recv_next
+                );
+
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the total duration of the head-of-line blocking of the TCP connection, including
+    /// the ongoing one.
+    pub fn hol_blocked(&self) -> Duration {
+        match self.hol_blocked_since {
+            Some(instant) => self.hol_blocked + instant.elapsed(),
+            None => self.hol_blocked,
+        }
+    }
+
+    /// Updates the capture instant of the payload held in the cache of the TCP connection after
+    /// appending the payload captured at the instant, and returns the capture instant of the
+    /// earliest payload delivered if any is delivered.
+    pub fn update_held(&mut self, captured: Instant, is_delivered: bool) -> Option<Instant> {
+        let earliest = match self.held_since {
+            Some(held_since) => min(held_since, captured),
+            None => captured,
+        };
+        self.held_since = match self.cache.is_empty() {
+            true => None,
+            false => Some(earliest),
+        };
+
+        match is_delivered {
+            true => Some(earliest),
+            false => None,
+        }
+    }
+
+    /// Sets if payload of the TCP connection is delivered in the order of arrival instead of being
+    /// reassembled.
+    pub fn set_unordered(&mut self, unordered: bool) {
+        self.unordered = unordered;
+    }
+
+    /// Returns if payload of the TCP connection is delivered in the order of arrival.
+    pub fn is_unordered(&self) -> bool {
+        self.unordered
+    }
+
+    /// Skips the first unfilled range in the cache of the TCP connection in the unordered mode,
+    /// and returns the size skipped and the continuous payload after it. The range skipped is
+    /// tracked, so payload arriving late in it can still be delivered.
+    pub fn advance_cache_unordered(&mut self) -> Option<(usize, Vec<u8>)> {
+        if !self.unordered {
+            return None;
+        }
+
+        let sequence = self.cache.sequence();
+        let (size, payload) = self.cache.advance()?;
+        let end = seq_add(sequence, size as u32);
+        if self.skipped.len() >= MAX_SKIPPED_RANGES {
+            self.skipped.remove(0);
+        }
+        self.skipped.push((sequence, end));
+        trace!(
+            "skip {} Bytes in TCP cache of {} -> {} in unordered mode",
+            size,
+            self.src,
+            self.dst
+        );
+        self.cache_unfilled = match self.cache.is_empty() {
+            true => None,
+            false => Some(Instant::now()),
+        };
+
+        Some((size, payload))
+    }
+
+    /// Takes the payload arriving late in the first unfilled range skipped it overlaps in the
+    /// unordered mode. The payload taken is no longer tracked, so it is delivered only once.
+    pub fn take_skipped(&mut self, sequence: u32, payload: &[u8]) -> Option<Vec<u8>> {
+        let sub = |a: u32, b: u32| seq_sub(a, b) as usize;
+
+        for i in 0..self.skipped.len() {
+            let (begin, end) = self.skipped[i];
+            let size = sub(end, begin);
+            let sub_sequence = sub(sequence, begin);
+
+            // The range of the payload in the range skipped, and the offset in the payload
+            let (from, to, offset) = if sub_sequence <= MAX_U32_WINDOW_SIZE {
+                if sub_sequence >= size {
+                    continue;
+                }
+
+                (sub_sequence, min(sub_sequence + payload.len(), size), 0)
+            } else {
+                let lead = sub(begin, sequence);
+                if lead >= payload.len() {
+                    continue;
+                }
+
+                (0, min(payload.len() - lead, size), lead)
+            };
+
+            // Split the range skipped
+            self.skipped.remove(i);
+            let add = |n: usize| seq_add(begin, n as u32);
+            if to < size {
+                self.skipped.insert(i, (add(to), end));
+            }
+            if from > 0 {
+                self.skipped.insert(i, (begin, add(from)));
+            }
+
+            self.active = Instant::now();
+
+            return Some(payload[offset..offset + (to - from)].to_vec());
+        }
+
+        None
+    }
+
+    /// Pauses delivering payload of the TCP connection. Continuous payload is held instead of
+    /// being delivered, and the window shrinks as the payload held grows.
+    pub fn pause(&mut self) {
+        if self.paused.is_none() {
+            self.paused = Some(Vec::new());
+            trace!("pause TCP {} -> {}", self.src, self.dst);
+        }
+    }
+
+    /// Resumes delivering payload of the TCP connection, and returns the payload held while it
+    /// is paused.
+    pub fn resume(&mut self) -> Vec<u8> {
+        match self.paused.take() {
+            Some(payload) => {
+                trace!(
+                    "resume TCP {} -> {} with {} Bytes held",
+                    self.src,
+                    self.dst,
+                    payload.len()
+                );
+
+                payload
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Holds the continuous payload while the TCP connection is paused. Returns the payload back
+    /// if the connection is not paused.
+    pub fn hold(&mut self, payload: Vec<u8>) -> Option<Vec<u8>> {
+        match self.paused {
+            Some(ref mut held) => {
+                held.extend(payload);
+
+                None
+            }
+            None => Some(payload),
+        }
     }
 
-    /// Sets the TCP FIN sequence of the TCP connection.
+    /// Returns if delivering payload of the TCP connection is paused.
+    pub fn is_paused(&self) -> bool {
+        self.paused.is_some()
+    }
+
+    /// Returns the size of the payload held while the TCP connection is paused.
+    pub fn paused_len(&self) -> usize {
+        match self.paused {
+            Some(ref held) => held.len(),
+            None => 0,
+        }
+    }
+
+    /// Returns the payload held while the TCP connection is paused, which can be peeked before
+    /// it is delivered.
+    pub fn paused_payload(&self) -> &[u8] {
+        match self.paused {
+            Some(ref held) => held,
+            None => &[],
+        }
+    }
+
+    /// Sets the reassembly deadline of the TCP connection, the time the payload after an unfilled
+    /// range is held before the range is skipped. The payload missing is never delivered, which
+    /// breaks the reliability of TCP. `None` represents the range is never skipped.
+    pub fn set_reassembly_deadline(&mut self, deadline: Option<Duration>) {
+        self.reassembly_deadline = deadline;
+    }
+
+    /// Returns if the payload after an unfilled range in the cache of the TCP connection has
+    /// been held for the reassembly deadline.
+    pub fn is_reassembly_deadline_reached(&self) -> bool {
+        match (self.reassembly_deadline, self.cache_unfilled) {
+            (Some(deadline), Some(instant)) => instant.elapsed() >= deadline,
+            _ => false,
+        }
+    }
+
+    /// Skips the first unfilled range in the cache of the TCP connection if it reaches the
+    /// reassembly deadline, and returns the size skipped and the continuous payload after it.
+    pub fn advance_cache_timedout(&mut self) -> Option<(usize, Vec<u8>)> {
+        if !self.is_reassembly_deadline_reached() {
+            return None;
+        }
+
+        let (size, payload) = self.cache.advance()?;
+        debug!(
+            "skip {} Bytes in TCP cache of {} -> {} after reassembly deadline",
+            size, self.src, self.dst
+        );
+        self.cache_unfilled = match self.cache.is_empty() {
+            true => None,
+            false => Some(Instant::now()),
+        };
+
+        Some((size, payload))
+    }
+
+    /// Sets the TCP FIN sequence of the TCP connection. Payload appended to the cache later is
+    /// truncated at the FIN.
     pub fn set_fin_sequence(&mut self, sequence: u32) {
+        if self.fin_sequence == Some(sequence) {
+            return;
+        }
         self.fin_sequence = Some(sequence);
         trace!(
             "set TCP FIN sequence of {} -> {} to {}",
@@ -1161,10 +2086,16 @@ impl TcpRxState {
         );
     }
 
-    /// Admits the TCP FIN of the TCP connection.
+    /// Admits the TCP FIN of the TCP connection. The FIN consumes a sequence, so both the
+    /// receive next and the cache advance by one.
     pub fn admit_fin(&mut self) {
+        if let Some(fin_sequence) = self.fin_sequence {
+            self.cache.admit_fin(fin_sequence);
+        }
         self.fin_sequence = None;
         trace!("admit TCP FIN of {} -> {}", self.src, self.dst);
+
+        self.add_recv_next(1);
     }
 
     /// Returns the receive next of the TCP connection.
@@ -1192,11 +2123,143 @@ impl TcpRxState {
         self.sack_perm
     }
 
+    /// Sets the capacity of the cache of the TCP connection, which is limited by the maximum
+    /// receive window. The cache is recreated, so it takes effect only if the cache is empty and
+    /// should be set before other settings of the cache.
+    pub fn set_cache_capacity(&mut self, capacity: usize) {
+        if !self.cache.is_empty() {
+            return;
+        }
+        let capacity = min(capacity, max_recv_window(self.wscale));
+        self.cache = Window::with_capacity(capacity, self.recv_next);
+    }
+
+    /// Sets if the buffer of the cache of the TCP connection is allocated in advance instead of
+    /// in appending. The cache is recreated, so it takes effect only if the cache is empty and
+    /// should be set before other settings of the cache.
+    pub fn set_cache_alloc(&mut self, alloc: bool) {
+        if !self.cache.is_empty() {
+            return;
+        }
+        self.cache = Window::with_capacity_alloc(self.cache.capacity(), self.recv_next, alloc);
+    }
+
+    /// Sets the soft limit of the cache of the TCP connection in percentage of the capacity.
+    pub fn set_cache_soft_limit_percent(&mut self, percent: usize) {
+        self.cache.set_soft_limit_percent(percent);
+    }
+
+    /// Sets the policy of handling payload beyond the advertised window of the TCP connection.
+    pub fn set_over_window_policy(&mut self, policy: OverWindowPolicy) {
+        self.cache.set_over_window_policy(policy);
+    }
+
+    /// Returns the window of the TCP connection which should be advertised, which shrinks ahead
+    /// of the cache being full, and as the payload held grows while the connection is paused.
+    pub fn window(&self) -> u16 {
+        let window = self
+            .cache
+            .advertised()
+            .checked_sub(self.paused_len())
+            .unwrap_or(0);
+
+        (window >> self.wscale as usize) as u16
+    }
+
+    /// Sets the initial window in bytes of the TCP connection, which is limited by the capacity of
+    /// the cache, and records it as advertised in the cache. Returns the window scaled.
+    pub fn set_initial_window(&mut self, window: usize) -> u16 {
+        let window = min(window, self.cache.capacity());
+        self.cache.set_initial_advertised(window);
+
+        scale_window(window, self.wscale)
+    }
+
+    /// Returns the window of the TCP connection which should be advertised, and records it as
+    /// advertised in the cache.
+    pub fn advertise(&mut self) -> u16 {
+        let window = self.window();
+        self.cache
+            .set_advertised((window as usize) << self.wscale as usize);
+
+        window
+    }
+
     /// Return the cache of the TCP connection.
     pub fn cache(&self) -> &Window {
         &self.cache
     }
 
+    /// Returns the count of consecutive failures of appending to the cache of the TCP connection
+    /// for it is full.
+    pub fn cache_full(&self) -> usize {
+        self.cache_full
+    }
+
+    /// Returns the count of TCP segments out of the window of the TCP connection in the current
+    /// interval. The interval begins at the first segment out of the window after the previous
+    /// interval elapses.
+    pub fn out_of_window_in_interval(&mut self, interval: u64) -> usize {
+        let count = self.cache.out_of_window_count();
+        let since = match self.out_of_window_since {
+            Some((instant, since)) if instant.elapsed() < Duration::from_millis(interval) => since,
+            _ => {
+                let since = count.saturating_sub(1);
+                self.out_of_window_since = Some((Instant::now(), since));
+
+                since
+            }
+        };
+
+        count - since
+    }
+
+    /// Checks if the sequence of the TCP connection is desynchronized from the source, which is
+    /// considered as at least the count of TCP segments out of the window without the receive
+    /// next advancing in the timeout. Returns `true` only at the first check the connection is
+    /// desynchronized until the receive next advances again.
+    pub fn check_desync(&mut self, count: usize, timeout: u64) -> bool {
+        if self.desynced {
+            return false;
+        }
+        let (instant, since) = self.progress;
+        if self.cache.out_of_window_count() - since < count
+            || instant.elapsed() < Duration::from_millis(timeout)
+        {
+            return false;
+        }
+        self.desynced = true;
+
+        true
+    }
+
+    /// Returns the duration since the receive next of the TCP connection last advances.
+    pub fn stalled(&self) -> Duration {
+        self.progress.0.elapsed()
+    }
+
+    /// Returns the duration since payload from the source is last delivered of the TCP
+    /// connection, including payload arriving late in ranges skipped in the unordered mode.
+    pub fn idle(&self) -> Duration {
+        self.idle_at(Instant::now())
+    }
+
+    /// Returns the duration from payload from the source is last delivered of the TCP
+    /// connection to the given instant.
+    pub fn idle_at(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.active)
+    }
+
+    /// Returns the peak size of the cache of the TCP connection.
+    pub fn peak_cache(&self) -> usize {
+        self.peak_cache
+    }
+
+    /// Returns the size of payload delivered from the source of the TCP connection.
+    pub fn delivered(&self) -> usize {
+        self.delivered
+    }
+
     /// Returns the TCP FIN sequence of the TCP connection.
     pub fn fin_sequence(&self) -> Option<u32> {
         self.fin_sequence
@@ -1208,3 +2271,619 @@ impl Display for TcpRxState {
         write!(f, "TCP RX State: {} -> {}", self.src, self.dst)
     }
 }
+
+/// Scales the window in bytes with the window scale, saturating at the maximum window field.
+fn scale_window(window: usize, wscale: u8) -> u16 {
+    min(window >> wscale as usize, u16::MAX as usize) as u16
+}
+
+/// Selects at most the maximum count of SACK blocks from the filled ranges. The block containing
+/// the latest sequence is selected first, followed by blocks containing the SACK blocks
+/// reported before in order, and other blocks in order of sequence.
+fn select_sacks(
+    filled: &[(u32, u32)],
+    latest: u32,
+    prev_sacks: &[(u32, u32)],
+    max_sacks: usize,
+) -> Vec<(u32, u32)> {
+    let mut sacks = Vec::with_capacity(min(filled.len(), max_sacks));
+    let push = |sacks: &mut Vec<(u32, u32)>, sack: (u32, u32)| {
+        if sacks.len() < max_sacks && !sacks.contains(&sack) {
+            sacks.push(sack);
+        }
+    };
+
+    let recent = std::iter::once(latest).chain(prev_sacks.iter().map(|sack| sack.0));
+    for sequence in recent {
+        if let Some(&sack) = filled.iter().find(|sack| sack_contains(**sack, sequence)) {
+            push(&mut sacks, sack);
+        }
+    }
+    for &sack in filled {
+        push(&mut sacks, sack);
+    }
+
+    sacks
+}
+
+fn sack_contains(sack: (u32, u32), sequence: u32) -> bool {
+    let size = seq_sub(sack.1, sack.0);
+    let sub_sequence = seq_sub(sequence, sack.0);
+
+    sub_sequence < size
+}
+
+#[test]
+fn select_sacks_many_gaps() {
+    let filled = (0..10)
+        .map(|i| (i * 200 + 100, i * 200 + 200))
+        .collect::<Vec<_>>();
+
+    // In order without the latest or reported blocks
+    assert_eq!(
+        select_sacks(&filled, 0, &[], 4),
+        vec![(100, 200), (300, 400), (500, 600), (700, 800)]
+    );
+
+    // The latest block first, then the blocks reported before
+    let sacks = select_sacks(&filled, 1550, &[(100, 200), (900, 1000)], 4);
+    assert_eq!(
+        sacks,
+        vec![(1500, 1600), (100, 200), (900, 1000), (300, 400)]
+    );
+
+    // A block reported before merged into a larger block
+    let mut merged = filled.clone();
+    merged[0] = (100, 400);
+    merged.remove(1);
+    let sacks = select_sacks(&merged, 1950, &sacks, 3);
+    assert_eq!(sacks, vec![(1900, 2000), (1500, 1600), (100, 400)]);
+
+    // Capped
+    assert_eq!(select_sacks(&filled, 1550, &[], 1), vec![(1500, 1600)]);
+    assert!(select_sacks(&filled, 1550, &[], 0).is_empty());
+}
+
+#[test]
+fn initial_window_syn_ack() {
+    use crate::packet::layer::tcp::Tcp;
+    use crate::packet::layer::Layer;
+
+    let src = "1.1.1.1:1".parse().unwrap();
+    let dst = "2.2.2.2:2".parse().unwrap();
+
+    // Scaled
+    let mut rx_state = TcpRxState::new(src, dst, 1000, 7, true);
+    let window = rx_state.set_initial_window(1024 * 1024);
+    assert_eq!(window, 8192);
+    assert_eq!(rx_state.cache().advertised_limit(), 1024 * 1024);
+
+    let mut tx_state = TcpTxState::new(src, dst, 0, 1001, 65535, Some(7), true, Some(7), 1460, 10);
+    tx_state.set_initial_window(1024 * 1024);
+    assert_eq!(tx_state.window(), 8192);
+    // The window in the SYN/ACK is never scaled
+    assert_eq!(tx_state.syn_window(), u16::MAX);
+
+    // Not scaled
+    let mut rx_state = TcpRxState::new(src, dst, 1000, 0, true);
+    assert_eq!(rx_state.set_initial_window(16384), 16384);
+    assert_eq!(rx_state.set_initial_window(1024 * 1024), u16::MAX);
+
+    // The initial window replaces the window advertised before
+    rx_state.advertise();
+    assert_eq!(rx_state.cache().advertised_limit(), u16::MAX as usize);
+    rx_state.set_initial_window(16384);
+    assert_eq!(rx_state.cache().advertised_limit(), 16384);
+
+    let mut tx_state = TcpTxState::new(src, dst, 0, 1001, 65535, None, true, None, 1460, 10);
+    tx_state.set_initial_window(16384);
+    let tcp = Tcp::new_ack_syn(
+        dst.port(),
+        src.port(),
+        tx_state.sequence(),
+        tx_state.acknowledgement(),
+        tx_state.syn_window(),
+        Some(1460),
+        tx_state.src_wscale(),
+        tx_state.sack_perm(),
+        None,
+    );
+    let mut buffer = vec![0u8; tcp.len()];
+    tcp.serialize(&mut buffer, tcp.len()).unwrap();
+    assert_eq!(&buffer[14..16], &16384u16.to_be_bytes());
+}
+
+#[test]
+fn rx_state_pause() {
+    let src = "1.1.1.1:1".parse().unwrap();
+    let dst = "2.2.2.2:2".parse().unwrap();
+
+    let mut state = TcpRxState::new(src, dst, 0, 0, true);
+    state.set_cache_soft_limit_percent(100);
+    let window = state.window();
+    assert_eq!(state.hold(vec![0; 4]), Some(vec![0; 4]));
+
+    // Held while paused, and the window shrinks
+    state.pause();
+    let payload = state.append_cache(1, &[1; 1000]).unwrap().unwrap();
+    assert_eq!(state.hold(payload), None);
+    let payload = state.append_cache(1001, &[2; 1000]).unwrap().unwrap();
+    assert_eq!(state.hold(payload), None);
+    assert!(state.is_paused());
+    assert_eq!(state.paused_len(), 2000);
+    assert_eq!(state.window() as usize, window as usize - 2000);
+
+    // Resumed
+    let payload = state.resume();
+    assert_eq!(payload.len(), 2000);
+    assert_eq!(&payload[..1000], &[1; 1000][..]);
+    assert_eq!(&payload[1000..], &[2; 1000][..]);
+    assert!(!state.is_paused());
+    assert_eq!(state.window(), window);
+    assert!(state.resume().is_empty());
+}
+
+#[test]
+fn rx_state_reassembly_deadline() {
+    let src = "1.1.1.1:1".parse().unwrap();
+    let dst = "2.2.2.2:2".parse().unwrap();
+
+    let mut state = TcpRxState::new(src, dst, 999, 0, true);
+    let v = (0..40).into_iter().collect::<Vec<u8>>();
+    assert_eq!(state.append_cache(1020, &v[20..30]).unwrap(), None);
+
+    // Never skipped by default
+    assert!(!state.is_reassembly_deadline_reached());
+    assert_eq!(state.advance_cache_timedout(), None);
+
+    // Held before the deadline
+    state.set_reassembly_deadline(Some(Duration::from_secs(60)));
+    assert!(!state.is_reassembly_deadline_reached());
+    assert_eq!(state.advance_cache_timedout(), None);
+
+    // Skipped after the deadline
+    state.set_reassembly_deadline(Some(Duration::from_secs(0)));
+    assert!(state.is_reassembly_deadline_reached());
+    assert_eq!(
+        state.advance_cache_timedout(),
+        Some((20, v[20..30].to_vec()))
+    );
+    assert!(state.cache().is_empty());
+    assert!(!state.is_reassembly_deadline_reached());
+}
+
+#[test]
+fn rx_state_unordered() {
+    let src = "1.1.1.1:1".parse().unwrap();
+    let dst = "2.2.2.2:2".parse().unwrap();
+
+    let mut state = TcpRxState::new(src, dst, 999, 0, true);
+    state.set_unordered(true);
+    let v = (0..40).into_iter().collect::<Vec<u8>>();
+
+    // In order
+    assert_eq!(
+        state.append_cache(1000, &v[..10]).unwrap(),
+        Some(v[..10].to_vec())
+    );
+    assert_eq!(state.advance_cache_unordered(), None);
+
+    // Forwarded in the order of arrival, skipping the unfilled ranges
+    assert_eq!(state.append_cache(1020, &v[20..30]).unwrap(), None);
+    assert_eq!(
+        state.advance_cache_unordered(),
+        Some((10, v[20..30].to_vec()))
+    );
+    assert_eq!(state.append_cache(1035, &v[35..40]).unwrap(), None);
+    assert_eq!(
+        state.advance_cache_unordered(),
+        Some((5, v[35..40].to_vec()))
+    );
+    assert!(state.cache().is_empty());
+
+    // Arriving late, each byte is taken only once
+    assert_eq!(
+        state.take_skipped(1012, &v[12..15]),
+        Some(v[12..15].to_vec())
+    );
+    assert_eq!(
+        state.take_skipped(1008, &v[8..14]),
+        Some(v[10..12].to_vec())
+    );
+    assert_eq!(
+        state.take_skipped(1015, &v[15..25]),
+        Some(v[15..20].to_vec())
+    );
+    assert_eq!(state.take_skipped(1010, &v[10..20]), None);
+    assert_eq!(
+        state.take_skipped(1030, &v[30..35]),
+        Some(v[30..35].to_vec())
+    );
+    assert_eq!(state.take_skipped(1000, &v[..40]), None);
+}
+
+#[test]
+fn rx_state_fin_fills_gap() {
+    let src = "1.1.1.1:1".parse().unwrap();
+    let dst = "2.2.2.2:2".parse().unwrap();
+
+    let v = (0..200).into_iter().map(|x| x as u8).collect::<Vec<u8>>();
+
+    // The payload with the FIN fills the gap before the payload buffered
+    let mut state = TcpRxState::new(src, dst, 99, 0, true);
+    assert_eq!(state.append_cache(200, &v[100..200]).unwrap(), None);
+    state.set_fin_sequence(300);
+    assert_eq!(
+        state.append_cache(100, &v[..200]).unwrap(),
+        Some(v[..200].to_vec())
+    );
+    state.add_recv_next(200);
+    assert_eq!(state.fin_sequence(), Some(state.recv_next()));
+    state.admit_fin();
+    assert_eq!(state.recv_next(), 301);
+    assert_eq!(state.cache().sequence(), 301);
+    assert!(state.cache().is_empty());
+
+    // The payload buffered beyond the FIN is dropped, so the FIN follows the payload with it
+    let mut state = TcpRxState::new(src, dst, 99, 0, true);
+    assert_eq!(state.append_cache(200, &v[100..200]).unwrap(), None);
+    state.set_fin_sequence(200);
+    assert_eq!(
+        state.append_cache(100, &v[..100]).unwrap(),
+        Some(v[..100].to_vec())
+    );
+    assert!(state.cache().is_empty());
+    state.add_recv_next(100);
+    assert_eq!(state.fin_sequence(), Some(state.recv_next()));
+    state.admit_fin();
+    assert_eq!(state.recv_next(), 201);
+    assert_eq!(state.cache().sequence(), 201);
+}
+
+#[test]
+fn rx_state_hol_blocked() {
+    use std::thread;
+
+    let src = "1.1.1.1:1".parse().unwrap();
+    let dst = "2.2.2.2:2".parse().unwrap();
+
+    let mut state = TcpRxState::new(src, dst, 999, 0, true);
+    let v = (0..30).into_iter().collect::<Vec<u8>>();
+
+    // Payload in order is never blocked
+    state.append_cache(1000, &v[..10]).unwrap();
+    assert_eq!(state.update_hol_blocked(), None);
+    assert_eq!(state.hol_blocked(), Duration::from_secs(0));
+
+    // Blocked until the unfilled range is filled
+    state.append_cache(1020, &v[20..30]).unwrap();
+    assert_eq!(state.update_hol_blocked(), None);
+    thread::sleep(Duration::from_millis(10));
+    assert!(state.hol_blocked() >= Duration::from_millis(10));
+    state.append_cache(1010, &v[10..20]).unwrap();
+    let duration = state.update_hol_blocked().unwrap();
+    assert!(duration >= Duration::from_millis(10));
+    assert_eq!(state.hol_blocked(), duration);
+    assert_eq!(state.update_hol_blocked(), None);
+
+    // Payload is never blocked in the unordered mode
+    state.set_unordered(true);
+    state.append_cache(1040, &v[..10]).unwrap();
+    assert_eq!(state.update_hol_blocked(), None);
+    assert_eq!(state.hol_blocked(), duration);
+}
+
+#[test]
+fn rx_state_out_of_window() {
+    let src = "1.1.1.1:1".parse().unwrap();
+    let dst = "2.2.2.2:2".parse().unwrap();
+
+    let mut state = TcpRxState::new(src, dst, 999, 0, true);
+    let v = (0..10).into_iter().collect::<Vec<u8>>();
+    // Far enough to be out of window even after the window advances
+    let far = 1000 + MAX_U32_WINDOW_SIZE as u32 + 100;
+
+    for i in 1..=3 {
+        assert_eq!(state.append_cache(far, &v).unwrap(), None);
+        assert_eq!(state.out_of_window_in_interval(60000), i);
+    }
+
+    // Segments in the window are not counted
+    state.append_cache(1000, &v).unwrap();
+    assert_eq!(state.cache().out_of_window_count(), 3);
+
+    // A new interval begins after the previous one elapses
+    assert_eq!(state.append_cache(far, &v).unwrap(), None);
+    assert_eq!(state.out_of_window_in_interval(0), 1);
+    assert_eq!(state.append_cache(far, &v).unwrap(), None);
+    assert_eq!(state.out_of_window_in_interval(0), 1);
+}
+
+#[test]
+fn state_cache_capacity() {
+    let src = "1.1.1.1:1".parse().unwrap();
+    let dst = "2.2.2.2:2".parse().unwrap();
+
+    let mut rx_state = TcpRxState::new(src, dst, 999, 2, true);
+    rx_state.set_cache_capacity(4096);
+    assert_eq!(rx_state.cache().capacity(), 4096);
+    assert_eq!(rx_state.cache().sequence(), 1000);
+    // Limited by the maximum receive window
+    rx_state.set_cache_capacity(usize::MAX);
+    assert_eq!(rx_state.cache().capacity(), max_recv_window(2));
+
+    // Ignored once payload is cached
+    rx_state.set_cache_capacity(4096);
+    assert_eq!(rx_state.append_cache(1010, &[0; 10]).unwrap(), None);
+    rx_state.set_cache_capacity(1024);
+    assert_eq!(rx_state.cache().capacity(), 4096);
+
+    let mut tx_state = TcpTxState::new(src, dst, 0, 1000, 65535, None, true, None, 1460, 10);
+    tx_state.set_cache_capacity(4096);
+    assert_eq!(tx_state.cache().capacity(), 4096);
+    tx_state.set_cache_capacity(usize::MAX);
+    assert_eq!(tx_state.cache().capacity(), 4096);
+}
+
+#[test]
+fn rx_state_desync() {
+    let src = "1.1.1.1:1".parse().unwrap();
+    let dst = "2.2.2.2:2".parse().unwrap();
+
+    let mut state = TcpRxState::new(src, dst, 999, 0, true);
+    let v = (0..10).into_iter().collect::<Vec<u8>>();
+    // Far enough to be out of window even after the window advances
+    let far = 1000 + MAX_U32_WINDOW_SIZE as u32 + 100;
+
+    assert_eq!(state.append_cache(far, &v).unwrap(), None);
+    assert!(!state.check_desync(2, 0));
+    assert_eq!(state.append_cache(far, &v).unwrap(), None);
+    assert!(!state.check_desync(2, 60000));
+    // Reported only once
+    assert!(state.check_desync(2, 0));
+    assert!(!state.check_desync(2, 0));
+
+    // Progress recovers the connection
+    assert!(state.append_cache(1000, &v).unwrap().is_some());
+    state.add_recv_next(10);
+    assert!(!state.check_desync(2, 0));
+    assert_eq!(state.append_cache(far, &v).unwrap(), None);
+    assert_eq!(state.append_cache(far, &v).unwrap(), None);
+    assert!(state.check_desync(2, 0));
+}
+
+#[test]
+fn tx_state_ack_frequency() {
+    let src = "1.1.1.1:1".parse().unwrap();
+    let dst = "2.2.2.2:2".parse().unwrap();
+
+    let mut rx_state = TcpRxState::new(src, dst, 999, 0, true);
+    let mut tx_state = TcpTxState::new(src, dst, 0, 1000, 65535, None, true, None, 1460, 10);
+    tx_state.set_ack_frequency(3, 60000);
+    let v = (0..10).into_iter().collect::<Vec<u8>>();
+
+    // A steady stream is acknowledged every 3 segments
+    let mut acks = 0;
+    for i in 0..9 {
+        assert!(rx_state.append_cache(1000 + i * 10, &v).unwrap().is_some());
+        assert_eq!(rx_state.cache().range_count(), 0);
+        if tx_state.delay_ack(RECV_WINDOW) {
+            acks += 1;
+            tx_state.clear_delayed_ack(RECV_WINDOW);
+        }
+    }
+    assert_eq!(acks, 3);
+    assert!(!tx_state.delayed_ack());
+
+    // Delayed for the maximum delay
+    assert!(!tx_state.delay_ack(RECV_WINDOW));
+    assert!(tx_state.delayed_ack());
+    assert!(!tx_state.is_delayed_ack_timedout());
+    tx_state.set_ack_frequency(3, 0);
+    assert!(tx_state.is_delayed_ack_timedout());
+    tx_state.clear_delayed_ack(RECV_WINDOW);
+    tx_state.set_ack_frequency(3, 60000);
+
+    // A gap is acknowledged at once with the delayed ACK cleared, which duplicates the last ACK
+    assert!(!tx_state.delay_ack(RECV_WINDOW));
+    assert_eq!(rx_state.append_cache(1100, &v).unwrap(), None);
+    assert_eq!(rx_state.cache().range_count(), 1);
+    tx_state.clear_delayed_ack(RECV_WINDOW);
+    assert!(!tx_state.delayed_ack());
+
+    // Window updates are acknowledged at once
+    assert!(tx_state.delay_ack(0));
+    tx_state.clear_delayed_ack(0);
+    assert!(tx_state.delay_ack(1024));
+    tx_state.clear_delayed_ack(1024);
+    assert!(!tx_state.delay_ack(2048));
+    assert!(tx_state.delay_ack(RECV_WINDOW));
+}
+
+#[test]
+fn tx_state_dup_acks() {
+    let src = "1.1.1.1:1".parse().unwrap();
+    let dst = "2.2.2.2:2".parse().unwrap();
+
+    let mut tx_state = TcpTxState::new(src, dst, 0, 1000, 65535, None, true, None, 1460, 10);
+
+    // Not suppressed by default
+    for _ in 0..10 {
+        assert!(tx_state.admit_dup_ack());
+    }
+    assert_eq!(tx_state.suppressed_acks(), 0);
+
+    // Suppressed beyond the maximum and coalesced into a delayed ACK
+    tx_state.set_max_dup_acks(Some(3));
+    tx_state.add_acknowledgement(100);
+    for _ in 0..3 {
+        assert!(tx_state.admit_dup_ack());
+    }
+    assert!(!tx_state.delayed_ack());
+    assert!(!tx_state.admit_dup_ack());
+    assert!(!tx_state.admit_dup_ack());
+    assert_eq!(tx_state.suppressed_acks(), 2);
+    assert!(tx_state.delayed_ack());
+    tx_state.set_ack_frequency(ACK_SEGMENTS, 0);
+    assert!(tx_state.is_delayed_ack_timedout());
+    tx_state.clear_delayed_ack(RECV_WINDOW);
+
+    // A new acknowledgement resets the count
+    tx_state.add_acknowledgement(100);
+    assert!(tx_state.admit_dup_ack());
+    assert_eq!(tx_state.suppressed_acks(), 2);
+}
+
+#[test]
+fn rx_state_fast_retrans() {
+    let src = "1.1.1.1:1".parse().unwrap();
+    let dst = "2.2.2.2:2".parse().unwrap();
+
+    let mut state = TcpRxState::new(src, dst, 999, 0, true);
+    state.admit(1000, false);
+    assert_eq!(state.duplicate(), 0);
+
+    // Fired at the third duplicate ACK without waiting for the RTO
+    state.admit(1000, true);
+    state.admit(1000, true);
+    assert!(!state.is_fast_retrans(3, 200));
+    // The same acknowledgement with payload is not a duplicate
+    state.admit(1000, false);
+    assert_eq!(state.duplicate(), 2);
+    state.admit(1000, true);
+    assert!(state.is_fast_retrans(3, 200));
+    state.admit_retrans();
+    assert!(!state.is_fast_retrans(3, 200));
+
+    // Not fired again in the cool down time
+    for _ in 0..3 {
+        state.admit(1000, true);
+    }
+    assert!(!state.is_fast_retrans(3, 60000));
+    assert!(state.is_fast_retrans(3, 0));
+
+    // A new cumulative ACK clears duplicates
+    state.admit(2000, false);
+    assert_eq!(state.duplicate(), 0);
+    assert!(!state.is_fast_retrans(3, 0));
+}
+
+#[test]
+fn tx_state_fin_after_queue() {
+    let src = "1.1.1.1:1".parse().unwrap();
+    let dst = "2.2.2.2:2".parse().unwrap();
+
+    let mut tx_state = TcpTxState::new(src, dst, 0, 1000, 65535, None, true, None, 1460, 10);
+    let v = (0..100).into_iter().collect::<Vec<u8>>();
+
+    // The proxy sends payload and closes at once
+    tx_state.append_queue(&v);
+    tx_state.append_queue_fin();
+    assert!(!tx_state.is_fin_ready());
+
+    // The payload is sent in parts, the FIN waits for all of them
+    let mut payload = tx_state.append_cache(60).unwrap();
+    assert!(!tx_state.is_fin_ready());
+    tx_state.acknowledge(60);
+    assert!(tx_state.cache().is_empty());
+    assert!(!tx_state.is_fin_ready());
+    payload.extend(tx_state.append_cache(40).unwrap());
+    assert!(tx_state.queue().is_empty());
+    assert!(!tx_state.is_fin_ready());
+
+    // Paused
+    tx_state.acknowledge(100);
+    tx_state.set_paused(true);
+    assert!(!tx_state.is_fin_ready());
+    tx_state.set_paused(false);
+    assert!(tx_state.is_fin_ready());
+    assert_eq!(payload, v);
+
+    tx_state.append_cache_fin();
+    assert!(!tx_state.is_fin_ready());
+}
+
+#[test]
+fn tx_state_spurious_retrans() {
+    use std::thread;
+
+    let src = "1.1.1.1:1".parse().unwrap();
+    let dst = "2.2.2.2:2".parse().unwrap();
+
+    // The ACK arrives at once after the retransmission of the FIN, so it acknowledges the
+    // original
+    let mut tx_state = TcpTxState::new(src, dst, 0, 1000, 65535, None, true, None, 1460, 10);
+    tx_state.update_rto(Duration::from_secs(10));
+    let rto = tx_state.rto();
+    let cwnd = tx_state.cc().as_ref().unwrap().cwnd();
+    tx_state.append_queue_fin();
+    tx_state.append_cache_fin();
+
+    tx_state.increase_retrans(true);
+    tx_state.double_rto();
+    tx_state.cc_mut().as_mut().unwrap().timedout();
+    tx_state.update_fin_timer();
+    tx_state.increase_retrans(true);
+    tx_state.double_rto();
+    tx_state.update_fin_timer();
+    assert!(tx_state.rto() > rto);
+    assert!(tx_state.cc().as_ref().unwrap().cwnd() < cwnd);
+
+    tx_state.acknowledge(1);
+    assert!(tx_state.cache_fin().is_none());
+    assert_eq!(tx_state.retrans(), 2);
+    assert_eq!(tx_state.spurious_retrans(), 2);
+    assert_eq!(tx_state.rto(), rto);
+    assert!(tx_state.cc().as_ref().unwrap().cwnd() >= cwnd);
+
+    // The ACK arrives after the SRTT, so the retransmission is real
+    let mut tx_state = TcpTxState::new(src, dst, 0, 1000, 65535, None, true, None, 1460, 10);
+    tx_state.update_rto(Duration::from_millis(1));
+    let cwnd = tx_state.cc().as_ref().unwrap().cwnd();
+    tx_state.append_queue(&[0; 100]);
+    tx_state.append_cache(100).unwrap();
+
+    tx_state.increase_retrans(false);
+    tx_state.cc_mut().as_mut().unwrap().fast_retransmission();
+    thread::sleep(Duration::from_millis(10));
+
+    tx_state.acknowledge(100);
+    assert_eq!(tx_state.retrans(), 1);
+    assert_eq!(tx_state.spurious_retrans(), 0);
+    assert!(tx_state.cc().as_ref().unwrap().cwnd() < cwnd);
+}
+
+#[test]
+fn state_idle() {
+    let src = "1.1.1.1:1".parse().unwrap();
+    let dst = "2.2.2.2:2".parse().unwrap();
+    let timeout = Duration::from_millis(100);
+
+    let mut rx_state = TcpRxState::new(src, dst, 0, 0, true);
+    let mut tx_state = TcpTxState::new(src, dst, 0, 1, 65535, None, true, None, 1460, 10);
+
+    // Tiny payloads like application-layer pings in either direction keep the connection alive
+    for i in 0..8u32 {
+        let now = Instant::now();
+        if i % 2 == 0 {
+            rx_state.add_recv_next(1);
+            assert_eq!(rx_state.idle_at(now), Duration::from_millis(0));
+        } else {
+            tx_state.append_queue(&[0]);
+            assert_eq!(tx_state.idle_at(now), Duration::from_millis(0));
+        }
+        let idle = min(rx_state.idle_at(now), tx_state.idle_at(now));
+        assert_eq!(idle, Duration::from_millis(0));
+    }
+
+    // Segments without payload do not
+    let later = Instant::now() + timeout;
+    let rx_idle = rx_state.idle_at(later);
+    let tx_idle = tx_state.idle_at(later);
+    assert!(rx_idle >= timeout);
+    assert!(tx_idle >= timeout);
+    rx_state.add_recv_next(0);
+    tx_state.append_queue(&[]);
+    assert_eq!(rx_state.idle_at(later), rx_idle);
+    assert_eq!(tx_state.idle_at(later), tx_idle);
+}
@@ -0,0 +1,147 @@
+//! Support for holding TCP segments which arrive before the SYN of their connections.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+/// Represents a holding buffer of segments. Capture may reorder frames, so a segment can be
+/// seen before the SYN establishing its connection. The `Pending` holds these segments briefly
+/// until the SYN arrives, bounded by the total size and the holding time.
+#[derive(Debug)]
+pub struct Pending<K, T> {
+    /// Represents the map mapping a key to the instant its first segment is held, the size of
+    /// its segments and the segments in order of arrival.
+    segments: HashMap<K, (Instant, usize, Vec<T>)>,
+    size: usize,
+    max_size: usize,
+    timeout: Duration,
+}
+
+impl<K: Clone + Eq + Hash, T> Pending<K, T> {
+    /// Creates a new `Pending` holding segments of at most the maximum size in total, for at
+    /// most the timeout.
+    pub fn new(max_size: usize, timeout: u64) -> Pending<K, T> {
+        Pending {
+            segments: HashMap::new(),
+            size: 0,
+            max_size,
+            timeout: Duration::from_millis(timeout),
+        }
+    }
+
+    /// Holds a segment of the given size. Returns if the segment is held, a segment is not held
+    /// if it is over the maximum size.
+    pub fn hold(&mut self, key: K, segment: T, size: usize) -> bool {
+        if self.size.checked_add(size).unwrap_or(usize::MAX) > self.max_size {
+            return false;
+        }
+
+        let entry = self
+            .segments
+            .entry(key)
+            .or_insert_with(|| (Instant::now(), 0, Vec::new()));
+        entry.1 += size;
+        entry.2.push(segment);
+        self.size += size;
+
+        true
+    }
+
+    /// Takes the segments held of the key in order of arrival. Segments held over the timeout
+    /// are dropped.
+    pub fn take(&mut self, key: &K) -> Vec<T> {
+        match self.segments.remove(key) {
+            Some((instant, size, segments)) => {
+                self.size -= size;
+                match instant.elapsed() > self.timeout {
+                    true => Vec::new(),
+                    false => segments,
+                }
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Removes and returns the segments held over the timeout.
+    pub fn expire(&mut self) -> Vec<T> {
+        let timeout = self.timeout;
+        let keys = self
+            .segments
+            .iter()
+            .filter(|(_, (instant, _, _))| instant.elapsed() > timeout)
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+
+        let mut expired = Vec::new();
+        for key in keys {
+            if let Some((_, size, segments)) = self.segments.remove(&key) {
+                self.size -= size;
+                expired.extend(segments);
+            }
+        }
+
+        expired
+    }
+
+    /// Returns if segments of the key are held.
+    pub fn contains(&self, key: &K) -> bool {
+        self.segments.contains_key(key)
+    }
+
+    /// Returns the total size of the segments held.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns if no segment is held.
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+}
+
+#[test]
+fn pending_data_before_syn() {
+    use super::cache::Window;
+
+    let mut pending = Pending::new(1024, 1000);
+    let isn = 1000u32;
+
+    // Data arrives in reversed capture order before the SYN
+    assert!(pending.hold(1, (isn + 5, vec![5, 6, 7]), 3));
+    assert!(pending.hold(1, (isn + 1, vec![1, 2, 3, 4]), 4));
+    assert!(pending.hold(2, (0, vec![0]), 1));
+    assert_eq!(pending.size(), 8);
+
+    // The SYN establishes the connection, and the payload held is reassembled
+    let mut window = Window::with_capacity(1024, isn + 1);
+    let mut delivered = Vec::new();
+    for (sequence, payload) in pending.take(&1) {
+        if let Some(payload) = window.append(sequence, &payload).unwrap() {
+            delivered.extend(payload);
+        }
+    }
+    assert_eq!(delivered, vec![1, 2, 3, 4, 5, 6, 7]);
+    assert!(pending.take(&1).is_empty());
+    assert!(pending.contains(&2));
+    assert_eq!(pending.size(), 1);
+}
+
+#[test]
+fn pending_bound() {
+    // Size
+    let mut pending = Pending::new(4, 1000);
+    assert!(pending.hold(1, (), 3));
+    assert!(!pending.hold(2, (), 2));
+    assert!(pending.hold(2, (), 1));
+    assert_eq!(pending.size(), 4);
+
+    // Timeout
+    let mut pending = Pending::new(4, 0);
+    assert!(pending.hold(1, (), 1));
+    assert!(pending.hold(2, (), 1));
+    std::thread::sleep(Duration::from_millis(1));
+    assert!(pending.take(&1).is_empty());
+    assert_eq!(pending.expire().len(), 1);
+    assert!(pending.is_empty());
+    assert_eq!(pending.size(), 0);
+}
@@ -0,0 +1,112 @@
+//! Support for scheduling sending among TCP connections.
+
+use std::cmp::max;
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+
+/// Represents a weighted round-robin scheduler. A key with weight `n` will be scheduled for `n`
+/// times in a row before the next key is scheduled, so keys with heavier weights will not be
+/// starved behind a key scheduled constantly.
+#[derive(Debug)]
+pub struct Scheduler<K> {
+    queue: VecDeque<K>,
+    /// Represents the map mapping a key to its weight and remaining credit in the round.
+    credits: HashMap<K, (usize, usize)>,
+}
+
+impl<K: Clone + Eq + Hash> Scheduler<K> {
+    /// Creates a new `Scheduler`.
+    pub fn new() -> Scheduler<K> {
+        Scheduler {
+            queue: VecDeque::new(),
+            credits: HashMap::new(),
+        }
+    }
+
+    /// Inserts a key with the given weight into the scheduler. If the key exists, its weight
+    /// will be updated.
+    pub fn insert(&mut self, key: K, weight: usize) {
+        let weight = max(weight, 1);
+        match self.credits.get_mut(&key) {
+            Some(credit) => credit.0 = weight,
+            None => {
+                self.credits.insert(key.clone(), (weight, weight));
+                self.queue.push_back(key);
+            }
+        }
+    }
+
+    /// Removes a key from the scheduler.
+    pub fn remove(&mut self, key: &K) {
+        if self.credits.remove(key).is_some() {
+            self.queue.retain(|k| k != key);
+        }
+    }
+
+    /// Schedules and returns the next key.
+    pub fn schedule(&mut self) -> Option<K> {
+        let key = self.queue.front()?.clone();
+        let credit = self.credits.get_mut(&key).unwrap();
+        credit.1 -= 1;
+        if credit.1 == 0 {
+            // Move to the next key
+            credit.1 = credit.0;
+            self.queue.rotate_left(1);
+        }
+
+        Some(key)
+    }
+
+    /// Returns the count of keys in the scheduler.
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Returns if the scheduler is empty.
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+impl<K: Clone + Eq + Hash> Default for Scheduler<K> {
+    fn default() -> Self {
+        Scheduler::new()
+    }
+}
+
+#[test]
+fn scheduler_schedule() {
+    let mut s = Scheduler::new();
+    s.insert(1, 1);
+    s.insert(2, 2);
+
+    let v = (0..6).map(|_| s.schedule().unwrap()).collect::<Vec<_>>();
+    assert_eq!(v, vec![1, 2, 2, 1, 2, 2]);
+
+    s.remove(&2);
+    assert_eq!(s.schedule(), Some(1));
+    s.remove(&1);
+    assert_eq!(s.schedule(), None);
+    assert!(s.is_empty());
+}
+
+#[test]
+fn scheduler_not_starved() {
+    let mut s = Scheduler::new();
+    // A bulk connection with 100 segments and an interactive connection with 3 segments
+    let mut pending = vec![100, 3];
+    s.insert(0, 1);
+    s.insert(1, 4);
+
+    let mut v = Vec::new();
+    while let Some(key) = s.schedule() {
+        v.push(key);
+        pending[key] -= 1;
+        if pending[key] == 0 {
+            s.remove(&key);
+        }
+    }
+
+    assert_eq!(v.len(), 103);
+    assert_eq!(v[..4], [0, 1, 1, 1]);
+}
@@ -0,0 +1,101 @@
+//! Support for arithmetic and comparison of TCP sequences.
+//!
+//! Sequences wrap around at the end of the `u32` space, so they are compared in the serial number
+//! arithmetic of RFC 1982: a sequence is before another if the distance from it to the other is
+//! less than half of the space. Two sequences exactly half of the space apart are not comparable,
+//! and neither is before the other.
+
+/// Returns the sequence after the sequence by the given size.
+pub fn seq_add(sequence: u32, n: u32) -> u32 {
+    sequence.wrapping_add(n)
+}
+
+/// Returns the distance from the sequence `b` forward to the sequence `a`.
+pub fn seq_sub(a: u32, b: u32) -> u32 {
+    a.wrapping_sub(b)
+}
+
+/// Returns if the sequence `a` is before the sequence `b`.
+pub fn seq_lt(a: u32, b: u32) -> bool {
+    a != b && (seq_sub(b, a) as i32) > 0
+}
+
+/// Returns if the sequence `a` is before or the same as the sequence `b`.
+pub fn seq_le(a: u32, b: u32) -> bool {
+    a == b || seq_lt(a, b)
+}
+
+/// Returns if the sequence `a` is after the sequence `b`.
+pub fn seq_gt(a: u32, b: u32) -> bool {
+    seq_lt(b, a)
+}
+
+/// Returns if the sequence `a` is after or the same as the sequence `b`.
+pub fn seq_ge(a: u32, b: u32) -> bool {
+    seq_le(b, a)
+}
+
+#[test]
+fn seq_arithmetic() {
+    assert_eq!(seq_add(0, 1), 1);
+    assert_eq!(seq_add(u32::MAX, 1), 0);
+    assert_eq!(seq_add(u32::MAX - 4, 8), 3);
+    assert_eq!(seq_add(1, u32::MAX), 0);
+
+    assert_eq!(seq_sub(1, 0), 1);
+    assert_eq!(seq_sub(0, u32::MAX), 1);
+    assert_eq!(seq_sub(3, u32::MAX - 4), 8);
+    assert_eq!(seq_sub(0, 1), u32::MAX);
+
+    // Adding and subtracting are inverse across the wrap boundary
+    for &a in [0, 1, 1 << 31, u32::MAX - 1, u32::MAX].iter() {
+        for &n in [0, 1, 1460, 1 << 31, u32::MAX].iter() {
+            assert_eq!(seq_sub(seq_add(a, n), a), n);
+            assert_eq!(seq_add(a, seq_sub(n, a)), n);
+        }
+    }
+}
+
+#[test]
+fn seq_compare() {
+    assert!(seq_lt(0, 1));
+    assert!(!seq_lt(1, 0));
+    assert!(!seq_lt(0, 0));
+    assert!(seq_le(0, 0));
+    assert!(seq_gt(1, 0));
+    assert!(seq_ge(1, 1));
+
+    // Across the wrap boundary
+    assert!(seq_lt(u32::MAX, 0));
+    assert!(seq_lt(u32::MAX - 4, 3));
+    assert!(seq_gt(0, u32::MAX));
+    assert!(seq_gt(3, u32::MAX - 4));
+    assert!(!seq_lt(0, u32::MAX));
+
+    // Up to half of the space apart
+    assert!(seq_lt(0, (1 << 31) - 1));
+    assert!(seq_gt(0, (1 << 31) + 1));
+    assert!(seq_lt(u32::MAX, (1 << 31) - 2));
+    assert!(seq_gt(u32::MAX, 1 << 31));
+
+    // Exactly half of the space apart is not comparable
+    for &(a, b) in [(0, 1 << 31), (u32::MAX, (1 << 31) - 1)].iter() {
+        assert!(!seq_lt(a, b));
+        assert!(!seq_gt(a, b));
+        assert!(!seq_lt(b, a));
+        assert!(!seq_gt(b, a));
+    }
+
+    // Exhaustively around the wrap boundary
+    for i in 0..64u32 {
+        for j in 0..64u32 {
+            let a = seq_add(u32::MAX - 31, i);
+            let b = seq_add(u32::MAX - 31, j);
+            assert_eq!(seq_lt(a, b), i < j);
+            assert_eq!(seq_le(a, b), i <= j);
+            assert_eq!(seq_gt(a, b), i > j);
+            assert_eq!(seq_ge(a, b), i >= j);
+            assert_eq!(seq_sub(b, a), j.wrapping_sub(i));
+        }
+    }
+}